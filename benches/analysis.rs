@@ -0,0 +1,116 @@
+//! Benchmarks of the analysis the language server runs on each change: parsing, reparsing
+//! after an edit, diagnostics and completion. Run with `cargo bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use tower_lsp::lsp_types::{Position, Range, Url};
+use varnishls::config::LintConfig;
+use varnishls::document::Document;
+use varnishls::varnish_builtins::{get_varnish_builtins, Definition, Definitions, VarnishFlavor};
+use varnishls::vmod::parse_vmod_json;
+
+const LARGE_VCL: &str = include_str!("fixtures/large.vcl");
+const VMOD_STD_JSON: &str = include_str!("fixtures/vmod_std.json");
+/// line in large.vcl replaced with a partial expression to complete
+const COMPLETION_MARKER: &str = "# bench: completion";
+
+fn url() -> Url {
+    Url::parse("file:///benches/fixtures/large.vcl").unwrap()
+}
+
+/// Builtins, the std vmod and the definitions of the document, like the backend's scope
+fn get_scope(doc: &Document) -> Definitions {
+    let mut scope = get_varnish_builtins();
+    scope.properties.insert(
+        "std".to_string(),
+        Definition::new_builtin("std".to_string(), parse_vmod_json(VMOD_STD_JSON).unwrap()),
+    );
+    let doc_defs = doc.get_all_definitions(&scope);
+    scope.properties.extend(
+        doc_defs
+            .into_iter()
+            .map(|def| (def.ident_str.to_string(), def)),
+    );
+    scope
+}
+
+fn parse(c: &mut Criterion) {
+    c.bench_function("parse large vcl", |b| {
+        b.iter(|| Document::new(url(), black_box(LARGE_VCL.to_string()), None))
+    });
+}
+
+// the documents are set up within the benchmark closures, which only run if they match the
+// filter of `cargo bench -- <filter>`
+
+fn incremental_reparse(c: &mut Criterion) {
+    c.bench_function("reparse after typing a line", |b| {
+        let doc = Document::new(url(), LARGE_VCL.to_string(), None);
+        let line = LARGE_VCL
+            .lines()
+            .position(|line| line.contains(COMPLETION_MARKER))
+            .unwrap() as u32;
+        let position = Position::new(line, 0);
+        b.iter_batched(
+            || doc.clone(),
+            |mut doc| {
+                let edit = (
+                    Some(Range::new(position, position)),
+                    "                        set req.http.x-bench = \"1\";\n".to_string(),
+                );
+                assert!(doc.edit(1, black_box(vec![edit]).into_iter()));
+                doc
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn diagnostics(c: &mut Criterion) {
+    c.bench_function("diagnostics of large vcl", |b| {
+        let doc = Document::new(url(), LARGE_VCL.to_string(), None);
+        let lint_config = LintConfig::default();
+        b.iter_batched(
+            || get_scope(&doc),
+            |scope| doc.diagnostics(scope, &lint_config),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn completion(c: &mut Criterion) {
+    c.bench_function("completion in a nested if", |b| {
+        let partial = "std.";
+        let text = LARGE_VCL.replace(COMPLETION_MARKER, partial);
+        let (line, column) = text
+            .lines()
+            .enumerate()
+            .find_map(|(idx, line)| {
+                let column = line.find(partial)?;
+                (line.trim() == partial).then_some((idx, column + partial.len()))
+            })
+            .unwrap();
+        let doc = Document::new(url(), text, None);
+        let position = Position::new(line as u32, column as u32);
+        b.iter_batched(
+            || get_scope(&doc),
+            |scope| doc.autocomplete_for_pos(black_box(position), scope, VarnishFlavor::default()),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn vmod_json(c: &mut Criterion) {
+    c.bench_function("parse vmod json", |b| {
+        b.iter(|| parse_vmod_json(black_box(VMOD_STD_JSON)).unwrap())
+    });
+}
+
+criterion_group!(
+    benches,
+    parse,
+    incremental_reparse,
+    diagnostics,
+    completion,
+    vmod_json
+);
+criterion_main!(benches);
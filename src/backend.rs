@@ -1,31 +1,73 @@
 #![allow(deprecated)]
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use log::{debug, error};
+use serde::{Deserialize, Serialize};
 use serde_json::from_value as from_json;
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::path::{Path, PathBuf};
-use tokio::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OnceCell, RwLock};
 use toml;
 use tower_lsp::jsonrpc::{Error, Result};
+use tower_lsp::lsp_types::request::{GotoDeclarationParams, GotoDeclarationResponse};
 use tower_lsp::lsp_types::*;
-use tower_lsp::{Client, LanguageServer};
+use tower_lsp::{Client, ClientSocket, LanguageServer, LspService};
 use tree_sitter::Point;
 
-use crate::config::Config;
-use crate::document::{DiagnosticData, Document, Include, NestedPos, VmodImport, LEGEND_TYPES};
-use crate::varnish_builtins::{get_varnish_builtins, Definition, Definitions, Type};
+use crate::config::{Config, DiagnosticsOn, InitializationOptions};
+use crate::document::{
+    dedup_lint_errors, get_call_cycle_errors, get_semantic_tokens_edits, get_sub_execution_order,
+    get_unused_declaration_errors, group_diagnostics_by_uri, DiagnosticData, Document, Include,
+    LastPublishedDiagnostics, LintError, NestedPos, PublishedDiagnosticsVersions, ScopeSymbol,
+    SubCalls, VmodImport, LEGEND_MODIFIERS, LEGEND_TYPES,
+};
+use crate::parser;
+use crate::varnish_builtins::{
+    get_varnish_builtins, mark_format_string_args, Definition, Definitions, Type, VarnishFlavor,
+};
 use crate::vcc::parse_vcc_file_by_path;
-use crate::vmod::read_vmod_lib_by_name;
+use crate::vmod::{find_vmod_names, read_vmod_lib, read_vmod_lib_by_name, render_vmod_stub};
 
 #[derive(Debug, Default)]
 pub struct CacheEntry {
     includes: Option<Vec<Include>>,
     vmod_imports: Option<Vec<VmodImport>>,
     definitions: Option<Vec<Definition>>,
+    /// subroutines and the subroutines they call, with the document version they are for
+    sub_calls: Option<(i32, Vec<SubCalls>)>,
 }
 
 type DocumentMap = DashMap<Url, Document>;
 
+/// Errors reading vmod shared objects, by vmod name
+type VmodErrors = DashMap<String, (DiagnosticSeverity, String)>;
+
+/// Server state, for issue reports and checking which config is in effect
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerStatus {
+    pub version: String,
+    pub flavor: VarnishFlavor,
+    pub vmod_paths: Vec<PathBuf>,
+    pub vcc_paths: Vec<PathBuf>,
+    /// vmods read successfully
+    pub loaded_vmods: usize,
+    /// documents opened by the client
+    pub open_documents: usize,
+    /// all documents, including the ones read from disk
+    pub loaded_documents: usize,
+}
+
+/// `workspace/executeCommand` command clearing cached vmods and re-reading them
+pub const RELOAD_VMODS_COMMAND: &str = "varnish-lsp.reloadVmods";
+
+/// `workspace/executeCommand` command compiling a vcl file with `varnishd -C`
+pub const COMPILE_COMMAND: &str = "varnish-lsp.compile";
+
+/// `workspace/executeCommand` command inserting an empty builtin subroutine into a document
+pub const SCAFFOLD_SUB_COMMAND: &str = "varnish-lsp.scaffoldSub";
+
 pub struct Backend {
     pub client: Option<Client>,
     pub document_map: DocumentMap,
@@ -34,19 +76,147 @@ pub struct Backend {
     pub config: RwLock<Config>,
     /// cache to cache e.g. stat-ing includes and discovering definitions in all documents
     pub cache: DashMap<Url, CacheEntry>,
+    pub client_capabilities: RwLock<ClientCapabilities>,
+    /// work done progress tokens the client has asked to cancel
+    pub cancelled_progress_tokens: DashSet<NumberOrString>,
+    /// documents opened by the client and not closed since
+    pub open_documents: DashSet<Url>,
+    /// reverse include index: included document -> include statements including it
+    pub included_from: DashMap<Url, Vec<Location>>,
+    pub published_diagnostics_versions: PublishedDiagnosticsVersions,
+    /// diagnostics last sent to the client, to not send the same ones again
+    pub last_published_diagnostics: LastPublishedDiagnostics,
+    /// vmods which exist but failed to (fully) parse, reported on their import statements
+    pub vmod_errors: VmodErrors,
+    /// names of the vmods read successfully
+    pub loaded_vmods: DashSet<String>,
+    /// include statements nested deeper than the max include depth, and thus not read
+    pub too_deep_includes: DashMap<Url, Vec<Range>>,
+    /// errors from the last `varnishd -C` run, until the document is edited
+    pub compiler_errors: DashMap<Url, Vec<(Range, String, Vec<DiagnosticRelatedInformation>)>>,
+    /// semantic tokens last computed for each document, with the document version they are
+    /// computed for, which is also their result id
+    pub semantic_tokens: DashMap<Url, (i32, Vec<SemanticToken>)>,
+    /// directory generated vmod stubs are written to, created on first use
+    pub vmod_stub_dir: OnceCell<PathBuf>,
+    /// why the grammars could not be loaded, if so. documents are not parsed then, and only
+    /// features not needing a parse tree are served (e.g. completing vmod names)
+    pub grammar_error: Option<String>,
+}
+
+/// Set when dropped. tower-lsp drops the future of a request cancelled with `$/cancelRequest`,
+/// so blocking work moved off the future can check the flag and return early.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Reports progress of long running work to the client through `window/workDoneProgress`
+pub struct ProgressReporter {
+    client: Client,
+    token: NumberOrString,
+}
+
+impl ProgressReporter {
+    /// Create a progress token and begin reporting. Returns None if the client declined.
+    pub async fn begin(client: &Client, token: &str, title: &str) -> Option<ProgressReporter> {
+        let token = NumberOrString::String(token.to_string());
+        client
+            .send_request::<request::WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
+                token: token.clone(),
+            })
+            .await
+            .ok()?;
+
+        let reporter = ProgressReporter {
+            client: client.clone(),
+            token,
+        };
+        reporter
+            .notify(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                title: title.to_string(),
+                cancellable: Some(true),
+                message: None,
+                percentage: Some(0),
+            }))
+            .await;
+        Some(reporter)
+    }
+
+    pub async fn report(&self, message: String, percentage: u32) {
+        self.notify(WorkDoneProgress::Report(WorkDoneProgressReport {
+            cancellable: Some(true),
+            message: Some(message),
+            percentage: Some(percentage.min(100)),
+        }))
+        .await;
+    }
+
+    pub async fn end(self, message: String) {
+        self.notify(WorkDoneProgress::End(WorkDoneProgressEnd {
+            message: Some(message),
+        }))
+        .await;
+    }
+
+    async fn notify(&self, value: WorkDoneProgress) {
+        self.client
+            .send_notification::<notification::Progress>(ProgressParams {
+                token: self.token.clone(),
+                value: ProgressParamsValue::WorkDone(value),
+            })
+            .await;
+    }
+}
+
+/// `$/progress` notification carrying partial results, which lsp_types' ProgressParams can't
+pub enum PartialResults {}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PartialResultsParams<T> {
+    pub token: ProgressToken,
+    pub value: T,
+}
+
+impl notification::Notification for PartialResults {
+    type Params = PartialResultsParams<Vec<SymbolInformation>>;
+    const METHOD: &'static str = "$/progress";
+}
+
+/// Symbol kind of a definition, for document and workspace symbols
+fn get_symbol_kind(r#type: &Type) -> SymbolKind {
+    match r#type {
+        Type::Sub => SymbolKind::FUNCTION,
+        Type::Acl => SymbolKind::STRUCT,
+        Type::Backend => SymbolKind::STRUCT,
+        Type::Probe => SymbolKind::STRUCT,
+        Type::Obj(_) => SymbolKind::STRUCT,
+        _ => SymbolKind::NULL,
+    }
+}
+
+/// Build the LSP service, including methods not (yet) part of tower_lsp's LanguageServer trait
+pub fn build_service() -> (LspService<Backend>, ClientSocket) {
+    LspService::build(Backend::new)
+        .custom_method(
+            "window/workDoneProgress/cancel",
+            Backend::work_done_progress_cancel,
+        )
+        .custom_method("varnishls/includedFrom", Backend::get_included_from)
+        .custom_method("varnishls/debugTree", Backend::get_debug_tree)
+        .custom_method("varnish-lsp/scopeAt", Backend::get_scope_at)
+        .custom_method("varnish-lsp/status", Backend::get_status)
+        .finish()
 }
 
 impl Backend {
     pub fn new(client: Client) -> Backend {
         Backend {
             client: Some(client),
-            document_map: Default::default(),
-            root_uri: RwLock::new(
-                Url::from_directory_path(std::env::current_dir().unwrap()).unwrap(),
-            ),
-            root_document_uri: Default::default(),
-            config: Default::default(),
-            cache: Default::default(),
+            ..Default::default()
         }
     }
 
@@ -113,7 +283,10 @@ impl Backend {
             });
 
         // read all vmods
-        let mut vmod_scope = read_all_vmods(all_vmod_imports, &config).await;
+        let mut vmod_scope = read_all_vmods(all_vmod_imports, &config, &self.vmod_errors).await;
+        for vmod_name in vmod_scope.properties.keys() {
+            self.loaded_vmods.insert(vmod_name.to_string());
+        }
         definitions.properties.append(&mut vmod_scope.properties);
 
         // all objs (e.g. «new awdawd = new director.round_robin()»)
@@ -153,16 +326,822 @@ impl Backend {
         *w = config;
     }
 
+    /**
+     * Run the full analysis on a VCL snippet that isn't backed by a file (e.g. VCL embedded in
+     * a `vcl.inline` CLI script). Positions are relative to the snippet, so callers can offset
+     * them into the host document. The snippet and the files it includes are analyzed on their
+     * own, without adding them to the document map.
+     */
+    pub async fn analyze_snippet(&self, uri: Url, text: String) -> Vec<Diagnostic> {
+        dedup_lint_errors(self.get_snippet_lint_errors(uri, text).await)
+            .iter()
+            .map(LintError::to_diagnostic)
+            .collect()
+    }
+
+    async fn get_snippet_lint_errors(&self, uri: Url, text: String) -> Vec<LintError> {
+        debug!("get_snippet_lint_errors({})", uri);
+        if self.grammar_error.is_some() {
+            return vec![];
+        }
+        let config = self.config.read().await;
+        let document = Document::new(uri, text, None)
+            .with_template_placeholders(&config.template_placeholders);
+        let included_docs = self.get_standalone_includes(&document, &config).await;
+        let documents = std::iter::once(&document)
+            .chain(included_docs.iter())
+            .collect::<Vec<_>>();
+        let scope = self.get_standalone_scope(&documents, &config).await;
+
+        let mut lint_errors = document.get_error_ranges(&scope, &config.lint);
+        let all_sub_calls = documents
+            .iter()
+            .flat_map(|doc| doc.get_sub_calls())
+            .collect::<Vec<_>>();
+        lint_errors.extend(
+            get_call_cycle_errors(&all_sub_calls)
+                .into_iter()
+                .filter(|lint_error| lint_error.loc.uri == document.url),
+        );
+        lint_errors
+    }
+
+    /**
+     * The documents included by a document which isn't in the document map (e.g. a snippet),
+     * including nested includes. Loaded documents are copied, others are read from disk without
+     * adding them to the document map.
+     */
+    async fn get_standalone_includes(&self, doc: &Document, config: &Config) -> Vec<Document> {
+        let mut included_docs: Vec<Document> = vec![];
+        let mut visited = HashSet::from([doc.url.clone()]);
+        let mut includes_to_process = VecDeque::from(doc.get_includes());
+        while let Some(include) = includes_to_process.pop_front() {
+            if include.nested_pos.len() > config.max_include_depth {
+                continue;
+            }
+            let nested_pos = include.nested_pos.clone();
+            let Some(include_url) = include.resolve(&config.vcl_paths).url else {
+                continue;
+            };
+            // also stops at recursive includes
+            if !visited.insert(include_url.clone()) {
+                continue;
+            }
+            let loaded_doc = self.document_map.get(&include_url).map(|doc| doc.clone());
+            let included_doc = match loaded_doc {
+                Some(included_doc) => included_doc,
+                None => {
+                    let Ok(path) = include_url.to_file_path() else {
+                        continue;
+                    };
+                    let Ok(text) = tokio::fs::read_to_string(&path).await else {
+                        continue;
+                    };
+                    Document::new(include_url, text, Some(nested_pos))
+                        .with_template_placeholders(&config.template_placeholders)
+                }
+            };
+            includes_to_process.extend(included_doc.get_includes());
+            included_docs.push(included_doc);
+        }
+        included_docs
+    }
+
+    /// Builtins, vmods and definitions of documents which aren't in the document map, like
+    /// get_all_definitions_across_all_documents does for the loaded ones
+    async fn get_standalone_scope(&self, documents: &[&Document], config: &Config) -> Definitions {
+        let mut definitions = get_varnish_builtins();
+        let all_vmod_imports = documents
+            .iter()
+            .flat_map(|doc| doc.get_vmod_imports())
+            .fold(vec![], |mut set: Vec<VmodImport>, import| {
+                if !set.contains(&import) {
+                    set.push(import);
+                }
+                set
+            });
+        let mut vmod_scope = read_all_vmods(all_vmod_imports, config, &self.vmod_errors).await;
+        definitions.properties.append(&mut vmod_scope.properties);
+
+        let mut temp_map: BTreeMap<String, Definition> = documents
+            .iter()
+            .flat_map(|doc| doc.get_all_definitions(&definitions))
+            .map(|def| (def.ident_str.to_string(), def))
+            .collect();
+        definitions.properties.append(&mut temp_map);
+        definitions
+    }
+
+    /**
+     * Analyze the vcls embedded in a varnishtest, each as a vcl program of its own, with the
+     * diagnostics mapped back to the varnishtest. Diagnostics on the declarations varnishtest
+     * adds in front of the vcl are dropped. Like snippets, the embedded vcls are not added to the
+     * document map, so workspace wide requests don't see them.
+     */
+    async fn get_embedded_vcl_lint_errors(&self, vtc_uri: &Url) -> Vec<LintError> {
+        let Some(embedded_vcls) = self
+            .document_map
+            .get(vtc_uri)
+            .map(|doc| doc.get_embedded_vcls())
+        else {
+            return vec![];
+        };
+
+        let mut lint_errors = vec![];
+        for (i, embedded_vcl) in embedded_vcls.iter().enumerate() {
+            // a path below the varnishtest file can't clash with a file on disk
+            let mut snippet_uri = vtc_uri.clone();
+            snippet_uri.set_path(&format!("{}/vcl-{}.vcl", vtc_uri.path(), i + 1));
+            let snippet_errors = self
+                .get_snippet_lint_errors(snippet_uri.clone(), embedded_vcl.text.clone())
+                .await;
+            lint_errors.extend(snippet_errors.into_iter().filter_map(|mut lint_error| {
+                // e.g. errors in an included file
+                if lint_error.loc.uri != snippet_uri {
+                    return None;
+                }
+                lint_error.loc = Location {
+                    uri: vtc_uri.clone(),
+                    range: embedded_vcl.to_vtc_range(lint_error.loc.range)?,
+                };
+                Some(lint_error)
+            }));
+        }
+        lint_errors
+    }
+
+    /**
+     * Analyze documents and publish their diagnostics, deduplicated and against the uri of the
+     * file each diagnostic belongs to.
+     */
+    async fn publish_diagnostics_for(&self, uris: &[Url], scope: &Definitions) {
+        if self.client.is_none() {
+            return;
+        }
+        let diagnostics = self.get_diagnostics_for(uris, scope).await;
+        self.publish_diagnostics(diagnostics).await;
+    }
+
+    /**
+     * Analyze all open documents again, e.g. after vmods are reloaded or files are created, and
+     * publish the diagnostics together at the end of the pass instead of one document at a time.
+     * Documents with the same diagnostics as last published are left out.
+     */
+    pub async fn republish_diagnostics(&self) {
+        if self.client.is_none() {
+            return;
+        }
+        let mut diagnostics = vec![];
+        for uri in self.published_diagnostics_versions.uris() {
+            let scope = self
+                .get_all_definitions_across_all_documents(Some(&uri))
+                .await;
+            diagnostics.append(&mut self.get_diagnostics_for(&[uri], &scope).await);
+        }
+        self.publish_diagnostics(diagnostics).await;
+    }
+
+    /// Diagnostics of the documents, with the document version they are computed for
+    async fn get_diagnostics_for(
+        &self,
+        uris: &[Url],
+        scope: &Definitions,
+    ) -> Vec<(Url, Vec<Diagnostic>, Option<i32>)> {
+        // the versions the diagnostics are computed for
+        let mut versions = HashMap::new();
+        let mut lint_errors = vec![];
+        for uri in uris {
+            // let the server handle other requests and edits between documents
+            tokio::task::yield_now().await;
+            let config = self.config.read().await;
+            let Some(doc) = self.document_map.get(uri) else {
+                continue;
+            };
+            versions.insert(doc.url.clone(), doc.version());
+            let mut doc_lint_errors = doc.get_error_ranges(scope, &config.lint);
+            // embedded vcls are analyzed as vcl programs of their own below
+            let embedded_vcls = doc.get_embedded_vcls();
+            doc_lint_errors.retain(|lint_error| {
+                !embedded_vcls
+                    .iter()
+                    .any(|embedded_vcl| embedded_vcl.contains(&lint_error.loc.range))
+            });
+            doc_lint_errors.extend(doc.get_vmod_imports().into_iter().filter_map(|import| {
+                let vmod_error = self.vmod_errors.get(&import.name)?;
+                let (severity, ref message) = *vmod_error;
+                Some(LintError {
+                    message: message.clone(),
+                    severity,
+                    loc: import.loc,
+                    data: None,
+                    related_information: None,
+                    tags: None,
+                })
+            }));
+            doc_lint_errors.extend(doc.get_include_locations().into_iter().filter_map(
+                |(include, range)| {
+                    let search_paths = include.get_search_paths(&config.vcl_paths);
+                    if include.resolve(&config.vcl_paths).exists() {
+                        return None;
+                    }
+                    Some(LintError {
+                        message: format!(
+                            "Included file not found. Searched {}",
+                            search_paths
+                                .iter()
+                                .map(|path| format!("«{}»", path.display()))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                        severity: DiagnosticSeverity::ERROR,
+                        loc: Location {
+                            uri: doc.url.clone(),
+                            range,
+                        },
+                        data: None,
+                        related_information: None,
+                        tags: None,
+                    })
+                },
+            ));
+            doc_lint_errors.extend(doc.get_include_locations().into_iter().filter_map(
+                |(include, range)| {
+                    let include_url = include.clone().resolve(&config.vcl_paths).url?;
+                    // each include in a cycle includes the document again, report the
+                    // one leading back towards the main vcl. Includes through a symlink
+                    // back into the cycle aren't read, so they aren't loaded
+                    let leads_away_from_main_vcl =
+                        self.document_map
+                            .get(&include_url)
+                            .is_some_and(|included_doc| {
+                                included_doc.pos_from_main_doc.len() > doc.pos_from_main_doc.len()
+                            });
+                    if leads_away_from_main_vcl || !self.is_include_cycle(&doc.url, &include_url) {
+                        return None;
+                    }
+                    Some(LintError {
+                        message: format!(
+                            "Recursive include of «{}», which is this file or includes it",
+                            include.path.display()
+                        ),
+                        severity: DiagnosticSeverity::ERROR,
+                        loc: Location {
+                            uri: doc.url.clone(),
+                            range,
+                        },
+                        data: None,
+                        related_information: None,
+                        tags: None,
+                    })
+                },
+            ));
+            if let Some(ranges) = self.too_deep_includes.get(&doc.url) {
+                doc_lint_errors.extend(ranges.iter().map(|range| LintError {
+                    message: format!(
+                        "Include not read, it is nested deeper than the max include depth ({})",
+                        config.max_include_depth
+                    ),
+                    severity: DiagnosticSeverity::WARNING,
+                    loc: Location {
+                        uri: doc.url.clone(),
+                        range: *range,
+                    },
+                    data: None,
+                    related_information: None,
+                    tags: None,
+                }));
+            }
+            if let Some(compiler_errors) = self.compiler_errors.get(&doc.url) {
+                doc_lint_errors.extend(compiler_errors.iter().map(|(range, message, related)| {
+                    LintError {
+                        message: format!("[varnishd -C] {message}"),
+                        severity: DiagnosticSeverity::ERROR,
+                        loc: Location {
+                            uri: doc.url.clone(),
+                            range: *range,
+                        },
+                        data: None,
+                        related_information: Some(related.clone())
+                            .filter(|related| !related.is_empty()),
+                        tags: None,
+                    }
+                }));
+            }
+            lint_errors.append(&mut doc_lint_errors);
+        }
+
+        // stop early if all documents have been edited meanwhile, since their diagnostics are
+        // computed again for the edits
+        if self.are_diagnostics_superseded(&versions) {
+            debug!("diagnostics superseded by newer edits");
+            return vec![];
+        }
+
+        for uri in uris.iter().filter(|uri| uri.path().ends_with(".vtc")) {
+            tokio::task::yield_now().await;
+            lint_errors.append(&mut self.get_embedded_vcl_lint_errors(uri).await);
+        }
+
+        // call cycles may span documents, so look at the call graph of all of them. backends
+        // and probes may likewise be used in another document than they are declared in
+        let unused_severity = self
+            .config
+            .read()
+            .await
+            .lint
+            .unused_backends_and_probes
+            .lsp_severity();
+        // the documents searchable from each uri mostly overlap, so look at each one once
+        let mut doc_urls = vec![];
+        let mut seen_doc_urls = HashSet::new();
+        for uri in uris {
+            for doc_url in self.get_searchable_documents(uri).await {
+                if seen_doc_urls.insert(doc_url.clone()) {
+                    doc_urls.push(doc_url);
+                }
+            }
+        }
+        let mut all_sub_calls = vec![];
+        let mut declarations = vec![];
+        let mut referenced_idents = HashSet::new();
+        for doc_url in doc_urls.iter() {
+            all_sub_calls.append(&mut self.get_sub_calls(doc_url));
+            if let Some(doc) = self.document_map.get(doc_url) {
+                if unused_severity.is_some() {
+                    declarations.append(&mut doc.get_backend_and_probe_declarations());
+                    referenced_idents.extend(doc.get_referenced_idents());
+                }
+            }
+        }
+        lint_errors.extend(
+            get_call_cycle_errors(&all_sub_calls)
+                .into_iter()
+                .filter(|lint_error| uris.contains(&lint_error.loc.uri)),
+        );
+        if let Some(severity) = unused_severity {
+            lint_errors.extend(
+                get_unused_declaration_errors(&declarations, &referenced_idents, severity)
+                    .into_iter()
+                    .filter(|lint_error| uris.contains(&lint_error.loc.uri)),
+            );
+        }
+        let mut diagnostics_by_uri = group_diagnostics_by_uri(lint_errors);
+
+        uris.iter()
+            .map(|uri| {
+                let diagnostics = diagnostics_by_uri.remove(uri).unwrap_or_default();
+                (uri.clone(), diagnostics, versions.get(uri).copied())
+            })
+            .collect()
+    }
+
+    /**
+     * The subroutines declared in a document and the subroutines they call. Finding the calls
+     * runs a query over the whole document, so they are cached per document version.
+     */
+    fn get_sub_calls(&self, doc_url: &Url) -> Vec<SubCalls> {
+        let Some(version) = self.document_map.get(doc_url).map(|doc| doc.version()) else {
+            return vec![];
+        };
+        let cached_sub_calls = self
+            .cache
+            .get(doc_url)
+            .and_then(|cache_entry| cache_entry.sub_calls.clone())
+            .filter(|(cached_version, _)| *cached_version == version);
+        if let Some((_, sub_calls)) = cached_sub_calls {
+            return sub_calls;
+        }
+
+        // the document may have been edited meanwhile, so take the version again
+        let Some((version, sub_calls)) = self
+            .document_map
+            .get(doc_url)
+            .map(|doc| (doc.version(), doc.get_sub_calls()))
+        else {
+            return vec![];
+        };
+        self.cache.entry(doc_url.clone()).or_default().sub_calls =
+            Some((version, sub_calls.clone()));
+        sub_calls
+    }
+
+    /// Whether all the documents have been edited since the versions diagnostics are computed for
+    fn are_diagnostics_superseded(&self, versions: &HashMap<Url, i32>) -> bool {
+        !versions.is_empty()
+            && versions.iter().all(|(uri, version)| {
+                self.document_map
+                    .get(uri)
+                    .is_some_and(|doc| doc.version() > *version)
+            })
+    }
+
+    /// Publish diagnostics, unless the document has been edited since they were computed or the
+    /// client already has them
+    async fn publish_diagnostics(&self, diagnostics: Vec<(Url, Vec<Diagnostic>, Option<i32>)>) {
+        let Some(client) = self.client.as_ref() else {
+            return;
+        };
+        for (uri, diagnostics, version) in diagnostics {
+            if let Some(version) = version {
+                // the document has been edited since, or newer diagnostics are already out
+                let latest_version = self.document_map.get(&uri).map(|doc| doc.version());
+                if latest_version.is_some_and(|latest_version| version < latest_version)
+                    || !self
+                        .published_diagnostics_versions
+                        .should_publish(&uri, version)
+                {
+                    debug!("dropping stale diagnostics for {uri} (version {version})");
+                    continue;
+                }
+            }
+            if !self
+                .last_published_diagnostics
+                .should_publish(&uri, version, &diagnostics)
+            {
+                debug!("diagnostics for {uri} unchanged, not publishing them again");
+                continue;
+            }
+            client.publish_diagnostics(uri, diagnostics, version).await;
+        }
+    }
+
+    /**
+     * The set and unset statements of a header in the documents, ordered by when their
+     * subroutine runs in the request flow (see get_sub_execution_order), and then by their
+     * position from the main vcl. Custom subroutines nothing calls go last.
+     */
+    async fn get_header_assignment_locations(&self, src_uri: &Url, header: &str) -> Vec<Location> {
+        let mut all_sub_calls = vec![];
+        let mut assignments = vec![];
+        for doc_url in self.get_searchable_documents(src_uri).await {
+            all_sub_calls.append(&mut self.get_sub_calls(&doc_url));
+            let Some(doc) = self.document_map.get(&doc_url) else {
+                continue;
+            };
+            for (sub_name, location) in doc.get_header_assignments(header) {
+                let mut nested_pos = doc.pos_from_main_doc.clone();
+                nested_pos.push((
+                    location.range.start.line as usize,
+                    location.range.start.character as usize,
+                ));
+                assignments.push((sub_name, nested_pos, location));
+            }
+        }
+
+        let execution_order = get_sub_execution_order(&all_sub_calls);
+        assignments.sort_by_cached_key(|(sub_name, nested_pos, _)| {
+            (
+                execution_order.get(sub_name).copied().unwrap_or(usize::MAX),
+                nested_pos.clone(),
+            )
+        });
+        assignments
+            .into_iter()
+            .map(|(_sub_name, _nested_pos, location)| location)
+            .collect()
+    }
+
+    /// Documents to look up definitions and references in: all loaded documents, or only the
+    /// source document and its includes when workspace indexing is disabled
+    async fn get_searchable_documents(&self, src_doc_url: &Url) -> Vec<Url> {
+        let config = self.config.read().await;
+        if config.workspace_indexing {
+            return self
+                .document_map
+                .iter()
+                .map(|doc| doc.key().clone())
+                .collect();
+        }
+        get_all_documents(&self.document_map, &self.cache, &config, src_doc_url)
+    }
+
+    /// Replace the include statements of a document in the reverse include index
+    fn update_included_from(&self, doc_url: &Url, vcl_paths: &[PathBuf]) {
+        for mut locations in self.included_from.iter_mut() {
+            locations.retain(|location| location.uri != *doc_url);
+        }
+        self.included_from
+            .retain(|_included_url, locations| !locations.is_empty());
+
+        let Some(doc) = self.document_map.get(doc_url) else {
+            return;
+        };
+        for (include, range) in doc.get_include_locations() {
+            let Some(included_url) = include.resolve(vcl_paths).url else {
+                continue;
+            };
+            self.included_from
+                .entry(included_url)
+                .or_default()
+                .push(Location {
+                    uri: doc_url.clone(),
+                    range,
+                });
+        }
+    }
+
+    /// Read a created vcl file if a loaded document includes it (e.g. an include which did not
+    /// exist until now), or it is the main vcl. Returns the documents including it.
+    pub async fn index_created_file(&self, uri: &Url) -> Vec<Url> {
+        let vcl_paths = self.config.read().await.vcl_paths.clone();
+        let mut includers = vec![];
+        let mut includes = vec![];
+        for doc in self.document_map.iter() {
+            for (include, _range) in doc.get_include_locations() {
+                let include = include.resolve(&vcl_paths);
+                if include.url.as_ref() == Some(uri) {
+                    includers.push(doc.url.clone());
+                    includes.push(include);
+                }
+            }
+        }
+        if includes.is_empty() && self.root_document_uri.read().await.as_ref() == Some(uri) {
+            if let Ok(path) = uri.to_file_path() {
+                includes.push(Include {
+                    url: Some(uri.clone()),
+                    path,
+                    nested_pos: vec![],
+                    including_dir: None,
+                });
+            }
+        }
+        if includes.is_empty() {
+            return vec![];
+        }
+
+        debug!("indexing created file {uri}");
+        for includer in includers.iter() {
+            // the includes are cached with the urls they resolved to
+            self.cache.remove(includer);
+            self.update_included_from(includer, &vcl_paths);
+        }
+        self.read_new_includes(includes).await;
+        includers
+    }
+
+    /// Forget a deleted vcl file, unless it is open. Returns the documents including it.
+    pub async fn remove_deleted_file(&self, uri: &Url) -> Vec<Url> {
+        let includers = self
+            .included_from
+            .get(uri)
+            .map(|locations| {
+                locations
+                    .iter()
+                    .map(|location| location.uri.clone())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if self.open_documents.contains(uri) {
+            return vec![];
+        }
+
+        debug!("removing deleted file {uri}");
+        self.document_map.remove(uri);
+        self.cache.remove(uri);
+        self.too_deep_includes.remove(uri);
+        self.compiler_errors.remove(uri);
+        self.semantic_tokens.remove(uri);
+        let vcl_paths = self.config.read().await.vcl_paths.clone();
+        self.update_included_from(uri, &vcl_paths);
+        self.included_from.remove(uri);
+        for includer in includers.iter() {
+            self.cache.remove(includer);
+        }
+        includers
+    }
+
+    /// Update the index after vcl files are created, deleted or renamed on disk, and publish
+    /// diagnostics of the open documents again
+    pub async fn reindex_files(&self, created: Vec<Url>, deleted: Vec<Url>) {
+        let mut affected = vec![];
+        for uri in deleted.iter() {
+            affected.extend(self.remove_deleted_file(uri).await);
+        }
+        for uri in created.iter() {
+            affected.extend(self.index_created_file(uri).await);
+        }
+        if affected.is_empty() {
+            return;
+        }
+
+        self.republish_diagnostics().await;
+    }
+
+    /// Includes of a document within the max include depth. Deeper includes are not read, but
+    /// remembered to be reported on their include statements.
+    fn get_includes_within_max_depth(
+        &self,
+        doc: &Document,
+        max_include_depth: usize,
+    ) -> Vec<Include> {
+        let (includes, too_deep): (Vec<_>, Vec<_>) = doc
+            .get_include_locations()
+            .into_iter()
+            .partition(|(include, _range)| include.nested_pos.len() <= max_include_depth);
+        if too_deep.is_empty() {
+            self.too_deep_includes.remove(&doc.url);
+        } else {
+            debug!("includes in {} are nested too deep", doc.url);
+            self.too_deep_includes.insert(
+                doc.url.clone(),
+                too_deep
+                    .into_iter()
+                    .map(|(_include, range)| range)
+                    .collect(),
+            );
+        }
+        includes
+            .into_iter()
+            .map(|(include, _range)| include)
+            .collect()
+    }
+
+    /**
+     * Whether including a document would include the including document again, i.e. the
+     * included document is the including one or (indirectly) includes it. Urls are kept as
+     * written, so their canonical paths are compared to find cycles through symlinks as well.
+     */
+    fn is_include_cycle(&self, including_url: &Url, included_url: &Url) -> bool {
+        let canonical_path = |url: &Url| {
+            url.to_file_path()
+                .ok()
+                .and_then(|path| std::fs::canonicalize(path).ok())
+        };
+        let included_path = canonical_path(included_url);
+        let mut visited = HashSet::new();
+        let mut to_visit = vec![including_url.clone()];
+        while let Some(url) = to_visit.pop() {
+            if url == *included_url
+                || (included_path.is_some() && canonical_path(&url) == included_path)
+            {
+                return true;
+            }
+            if !visited.insert(url.clone()) {
+                continue;
+            }
+            if let Some(locations) = self.included_from.get(&url) {
+                to_visit.extend(locations.iter().map(|location| location.uri.clone()));
+            }
+        }
+        false
+    }
+
+    /// The includes of a document to read, leaving out the ones nested too deep or including
+    /// the document again
+    fn get_includes_to_read(&self, doc: &Document, config: &Config) -> Vec<Include> {
+        self.get_includes_within_max_depth(doc, config.max_include_depth)
+            .into_iter()
+            .map(|include| include.resolve(&config.vcl_paths))
+            .filter(|include| {
+                let Some(ref include_url) = include.url else {
+                    return true;
+                };
+                if self.is_include_cycle(&doc.url, include_url) {
+                    debug!("not reading recursive include {include_url} in {}", doc.url);
+                    return false;
+                }
+                true
+            })
+            .collect()
+    }
+
+    /// The semantic tokens of a document and the document version, computed again only if the
+    /// document has changed since the last request
+    async fn get_semantic_tokens(&self, uri: &Url) -> Result<(i32, Vec<SemanticToken>)> {
+        let start = std::time::Instant::now();
+        let Some(doc) = self.document_map.get(uri).map(|doc| doc.clone()) else {
+            error!("Could not find document");
+            return Err(Error::internal_error());
+        };
+        let version = doc.version();
+        if let Some(entry) = self.semantic_tokens.get(uri) {
+            if entry.0 == version {
+                return Ok(entry.clone());
+            }
+        }
+
+        // generate tokens on the blocking pool, so the server can read «$/cancelRequest»
+        // meanwhile. if the request is cancelled after the tokens are generated, the result is
+        // just thrown away.
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let _cancel_on_drop = CancelOnDrop(cancelled.clone());
+        let semantic_tokens =
+            tokio::task::spawn_blocking(move || doc.get_semantic_tokens_cancellable(&cancelled))
+                .await
+                .map_err(|_| Error::internal_error())?
+                .ok_or_else(Error::request_cancelled)?;
+
+        debug!(
+            "semantic tokens of {uri} done in {}ms",
+            start.elapsed().as_millis()
+        );
+        self.semantic_tokens
+            .insert(uri.clone(), (version, semantic_tokens.clone()));
+        Ok((version, semantic_tokens))
+    }
+
+    /// Custom request returning the include statements which include a document
+    pub async fn get_included_from(&self, params: TextDocumentIdentifier) -> Result<Vec<Location>> {
+        debug!("get_included_from({})", params.uri);
+        Ok(self
+            .included_from
+            .get(&params.uri)
+            .map(|locations| locations.clone())
+            .unwrap_or_default())
+    }
+
+    /// Custom request returning the parse tree of a document as an S-expression
+    pub async fn get_debug_tree(&self, params: TextDocumentIdentifier) -> Result<String> {
+        debug!("get_debug_tree({})", params.uri);
+        match self.document_map.get(&params.uri) {
+            Some(doc) => Ok(doc.get_parse_tree()),
+            None => Err(Error::invalid_params(format!(
+                "Document not loaded: {}",
+                params.uri
+            ))),
+        }
+    }
+
+    /// Custom request returning the server version and the config in effect
+    pub async fn get_status(&self) -> Result<ServerStatus> {
+        debug!("get_status()");
+        let config = self.config.read().await;
+        Ok(ServerStatus {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            flavor: config.lint.flavor,
+            vmod_paths: config.vmod_paths.clone(),
+            vcc_paths: config.vcc_paths.clone(),
+            loaded_vmods: self.loaded_vmods.len(),
+            open_documents: self.open_documents.len(),
+            loaded_documents: self.document_map.len(),
+        })
+    }
+
+    /// Custom request returning all symbols visible at a position, with their type and source
+    pub async fn get_scope_at(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Vec<ScopeSymbol>> {
+        let uri = params.text_document.uri;
+        debug!("get_scope_at({uri})");
+        if !self.document_map.contains_key(&uri) {
+            return Err(Error::invalid_params(format!("Document not loaded: {uri}")));
+        }
+
+        let scope = self
+            .get_all_definitions_across_all_documents(Some(&uri))
+            .await;
+        let vmod_names = self
+            .get_searchable_documents(&uri)
+            .await
+            .iter()
+            .filter_map(|doc_url| self.document_map.get(doc_url))
+            .flat_map(|doc| doc.get_vmod_imports())
+            .map(|import| import.scope_name().to_string())
+            .collect::<Vec<_>>();
+        let point = Point {
+            row: params.position.line as usize,
+            column: params.position.character as usize,
+        };
+        let flavor = self.config.read().await.lint.flavor;
+
+        let doc = self
+            .document_map
+            .get(&uri)
+            .ok_or_else(Error::internal_error)?;
+        Ok(doc.get_scope_at_point(point, &scope, &vmod_names, flavor))
+    }
+
     /*
      * TODO: doc_uri should be «main vcl» uri unless the import starts with «./»
      * TODO: parallelize with tokio?
      */
     pub async fn read_new_includes(&self, initial_includes: Vec<Include>) {
+        self.read_new_includes_with_progress(initial_includes, None)
+            .await;
+    }
+
+    async fn read_new_includes_with_progress(
+        &self,
+        initial_includes: Vec<Include>,
+        progress: Option<&ProgressReporter>,
+    ) {
         debug!("read_new_includes()");
         let config = self.config.read().await;
         let mut includes_to_process = VecDeque::from(initial_includes);
+        let mut read_count = 0;
 
         while let Some(include) = includes_to_process.pop_front() {
+            // let the server handle cancellations between files
+            tokio::task::yield_now().await;
+
+            if progress
+                .is_some_and(|progress| self.cancelled_progress_tokens.contains(&progress.token))
+            {
+                debug!("reading includes cancelled");
+                break;
+            }
+
             let mut include = include;
             if include.url.is_none() {
                 include = include.resolve(&config.vcl_paths);
@@ -181,7 +1160,8 @@ impl Backend {
                         let mut doc = self.document_map.get_mut(&include_url).unwrap();
                         doc.pos_from_main_doc = include.nested_pos.clone();
                         // ... do the same for nested includes
-                        includes_to_process.append(&mut doc.get_includes().into());
+                        includes_to_process
+                            .append(&mut self.get_includes_to_read(&doc, &config).into());
                         drop(doc);
                     }
                     true
@@ -193,33 +1173,50 @@ impl Backend {
                 continue;
             }
 
+            // resolved relative to the including document or a vcl path
+            let include_path = include_url
+                .to_file_path()
+                .unwrap_or_else(|_| include.path.clone());
             let Some(include_uri) = self
-                .read_doc_from_path(include.path.as_path(), include.nested_pos)
+                .read_doc_from_path(include_path.as_path(), include.nested_pos)
                 .await
             else {
                 // error should already have been logged
                 continue;
             };
 
+            // before reading its includes, to find the ones including it again
+            self.update_included_from(&include_uri, &config.vcl_paths);
             let Some(included_doc) = self.document_map.get(&include_uri) else {
                 continue;
             };
 
             // Wipe cache for this nested doc and read includes.
             let mut cache_entry = CacheEntry::default();
-            let nested_includes = included_doc
-                .get_includes()
-                .into_iter()
-                .map(|include| include.resolve(&config.vcl_paths))
-                .collect::<Vec<_>>();
+            let nested_includes = self.get_includes_to_read(&included_doc, &config);
             cache_entry.includes = Some(nested_includes.clone());
             includes_to_process.append(&mut nested_includes.into());
             self.cache.insert(included_doc.url.clone(), cache_entry);
+            drop(included_doc);
+
+            read_count += 1;
+            if let Some(progress) = progress {
+                let total = read_count + includes_to_process.len();
+                progress
+                    .report(
+                        format!("{read_count}/{total} files"),
+                        (read_count * 100 / total) as u32,
+                    )
+                    .await;
+            }
         }
         debug!("reading includes doneski");
     }
 
     async fn read_doc_from_path(&self, path: &Path, nested_pos: NestedPos) -> Option<Url> {
+        if self.grammar_error.is_some() {
+            return None;
+        }
         let config = self.config.read().await;
         let Some(file_path) = config.vcl_paths.iter().find_map(|search_root_path| {
             let search_path = search_root_path.join(path);
@@ -250,11 +1247,26 @@ impl Backend {
         };
 
         let doc_url = Url::from_file_path(file_path).unwrap();
-        let doc = Document::new(doc_url.clone(), file, Some(nested_pos));
+        let doc = Document::new(doc_url.clone(), file, Some(nested_pos))
+            .with_template_placeholders(&config.template_placeholders);
         self.document_map.insert(doc_url.clone(), doc);
         Some(doc_url)
     }
 
+    /// The vmods installed in the vmod and vcc paths, e.g. to complete imports without parsing
+    async fn get_vmod_name_completions(&self) -> Vec<CompletionItem> {
+        let config = self.config.read().await;
+        find_vmod_names(&config.vmod_paths, &config.vcc_paths)
+            .into_iter()
+            .map(|name| CompletionItem {
+                label: name,
+                kind: Some(CompletionItemKind::MODULE),
+                detail: Some("vmod".to_string()),
+                ..Default::default()
+            })
+            .collect()
+    }
+
     async fn log_error(&self, message: String) {
         match self.client {
             Some(ref client) => {
@@ -265,6 +1277,271 @@ impl Backend {
             }
         }
     }
+
+    /**
+     * Compile a vcl file (by default the main vcl) with the configured `varnishd -C`, and report
+     * its errors as diagnostics until the documents are edited. varnishd reads the files from
+     * disk, so unsaved changes are not compiled. Returns the number of errors.
+     */
+    pub async fn compile_with_varnishd(
+        &self,
+        uri: Option<Url>,
+    ) -> std::result::Result<usize, String> {
+        let (varnishd, vcl_paths, vmod_paths) = {
+            let config = self.config.read().await;
+            let Some(ref varnishd) = config.varnishd_path else {
+                return Err("varnishd_path is not configured".to_string());
+            };
+            // varnishd runs in another directory
+            let absolute = |paths: &[PathBuf]| {
+                paths
+                    .iter()
+                    .map(|path| std::fs::canonicalize(path).unwrap_or(path.clone()))
+                    .collect::<Vec<_>>()
+            };
+            (
+                varnishd.clone(),
+                absolute(&config.vcl_paths),
+                absolute(&config.vmod_paths),
+            )
+        };
+        let Some(uri) = uri.or(self.root_document_uri.read().await.clone()) else {
+            return Err("No vcl to compile, main_vcl is not configured".to_string());
+        };
+        let Ok(vcl_file) = uri.to_file_path() else {
+            return Err(format!("{uri} is not a file"));
+        };
+
+        let errors =
+            crate::varnishd::compile(&varnishd, &vcl_file, &vcl_paths, &vmod_paths).await?;
+        let error_count = errors.len();
+
+        let mut uris = self.published_diagnostics_versions.uris();
+        uris.extend(self.compiler_errors.iter().map(|entry| entry.key().clone()));
+        self.compiler_errors.clear();
+        for error in errors {
+            let Some(location) = self.compiler_location(&error.location, &vcl_file, &vcl_paths)
+            else {
+                continue;
+            };
+            let related = error
+                .related
+                .iter()
+                .filter_map(|(related, message)| {
+                    Some(DiagnosticRelatedInformation {
+                        location: self.compiler_location(related, &vcl_file, &vcl_paths)?,
+                        message: message.clone(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            self.compiler_errors
+                .entry(location.uri.clone())
+                .or_default()
+                .push((location.range, error.message, related));
+            uris.push(location.uri);
+        }
+        uris.sort();
+        uris.dedup();
+
+        let scope = self
+            .get_all_definitions_across_all_documents(Some(&uri))
+            .await;
+        self.publish_diagnostics_for(&uris, &scope).await;
+        Ok(error_count)
+    }
+
+    /// Map a location in varnishd's output to the file it is in, with tab-expanded columns
+    /// converted using the open document or the file on disk
+    fn compiler_location(
+        &self,
+        location: &crate::varnishd::CompilerLocation,
+        vcl_file: &Path,
+        vcl_paths: &[PathBuf],
+    ) -> Option<Location> {
+        let path = crate::varnishd::resolve_path(&location.path, vcl_file, vcl_paths);
+        let uri = Url::from_file_path(std::fs::canonicalize(&path).unwrap_or(path)).ok()?;
+        let line_text = match self.document_map.get(&uri) {
+            Some(doc) => doc
+                .rope
+                .get_line(location.line as usize)
+                .map(|line| line.to_string()),
+            None => std::fs::read_to_string(uri.to_file_path().ok()?)
+                .ok()
+                .and_then(|text| text.lines().nth(location.line as usize).map(String::from)),
+        };
+        Some(Location {
+            uri,
+            range: crate::varnishd::location_range(location, line_text.as_deref()),
+        })
+    }
+
+    /// Read the main vcl from config and all files it includes, reporting progress to the client
+    pub async fn read_main_vcl(&self) {
+        let main_vcl_path = {
+            let config = self.config.read().await;
+            if !config.workspace_indexing {
+                return;
+            }
+            let Some(ref main_vcl_path) = config.main_vcl else {
+                return;
+            };
+            main_vcl_path.clone()
+        };
+
+        let supports_progress = self
+            .client_capabilities
+            .read()
+            .await
+            .window
+            .as_ref()
+            .and_then(|window| window.work_done_progress)
+            .unwrap_or(false);
+        let progress = match self.client {
+            Some(ref client) if supports_progress => {
+                ProgressReporter::begin(client, "varnishls/indexing", "Indexing VCL").await
+            }
+            _ => None,
+        };
+
+        if let Some(main_vcl_url) = self.read_doc_from_path(&main_vcl_path, vec![]).await {
+            *self.root_document_uri.write().await = Some(main_vcl_url.clone());
+            let vcl_paths = self.config.read().await.vcl_paths.clone();
+            self.update_included_from(&main_vcl_url, &vcl_paths);
+
+            let includes = {
+                let main_doc = self.document_map.get(&main_vcl_url).unwrap();
+                main_doc.get_includes()
+            };
+
+            self.read_new_includes_with_progress(includes, progress.as_ref())
+                .await;
+        }
+
+        if let Some(progress) = progress {
+            // a cancelled progress is already ended on the client side
+            if self
+                .cancelled_progress_tokens
+                .remove(&progress.token)
+                .is_none()
+            {
+                let doc_count = self.document_map.len();
+                progress.end(format!("Indexed {doc_count} files")).await;
+            }
+        }
+    }
+
+    pub async fn work_done_progress_cancel(&self, params: WorkDoneProgressCancelParams) {
+        debug!("work_done_progress_cancel({:?})", params.token);
+        self.cancelled_progress_tokens.insert(params.token);
+    }
+
+    /**
+     * Drop everything cached from vmods, so the vmods imported by the loaded documents are read
+     * again, and republish diagnostics. Returns the number of vmods imported.
+     */
+    pub async fn reload_vmods(&self) -> usize {
+        debug!("reload_vmods()");
+        self.vmod_errors.clear();
+        self.loaded_vmods.clear();
+        // definitions are cached too, since e.g. objects depend on the vmods in scope
+        for mut cache_entry in self.cache.iter_mut() {
+            cache_entry.vmod_imports = None;
+            cache_entry.definitions = None;
+        }
+
+        // the vmods are read again when the diagnostics are computed
+        let vmod_count = self
+            .document_map
+            .iter()
+            .flat_map(|doc| doc.get_vmod_imports())
+            .map(|import| import.name)
+            .collect::<HashSet<_>>()
+            .len();
+
+        self.republish_diagnostics().await;
+
+        vmod_count
+    }
+
+    /**
+     * For «import x from "path"», write a stub interface of the functions in the vmod to a
+     * temporary file and return its location, so goto definition has something to show.
+     */
+    async fn get_vmod_stub_location(&self, src_uri: &Url, ident: &str) -> Option<Location> {
+        // vmods are named by a single identifier, unlike their functions and objects
+        if ident.contains('.') {
+            return None;
+        }
+        let import = self
+            .get_searchable_documents(src_uri)
+            .await
+            .iter()
+            .find_map(|doc_url| {
+                self.document_map
+                    .get(doc_url)?
+                    .get_vmod_imports()
+                    .into_iter()
+                    .find(|import| import.scope_name() == ident && import.from.is_some())
+            })?;
+        let (path, type_mappings) = {
+            let config = self.config.read().await;
+            (
+                import.resolve_from_path(&config.vmod_paths)?,
+                config.vmod_types.mappings(),
+            )
+        };
+
+        // only read the vmod goto definition is on
+        let vmod = tokio::task::spawn(read_vmod_lib(
+            import.name.clone(),
+            path.clone(),
+            type_mappings,
+        ))
+        .await
+        .ok()?
+        .ok()?;
+
+        let stub_path = match self.get_vmod_stub_dir().await {
+            Ok(stub_dir) => stub_dir.join(format!("vmod_{ident}.vcc")),
+            Err(err) => {
+                error!("Failed to create a directory for vmod stubs: {err}");
+                return None;
+            }
+        };
+        let stub = render_vmod_stub(ident, &path, &vmod.scope);
+        if let Err(err) = tokio::fs::write(&stub_path, stub).await {
+            error!("Failed to write vmod stub {}: {err}", stub_path.display());
+            return None;
+        }
+
+        Some(Location::new(
+            Url::from_file_path(&stub_path).ok()?,
+            Range::default(),
+        ))
+    }
+
+    /**
+     * The directory vmod stubs are written to, created for this server on first use. Creating
+     * it fails if the path exists already, so e.g. a symlink placed there isn't followed.
+     */
+    async fn get_vmod_stub_dir(&self) -> std::io::Result<&Path> {
+        self.vmod_stub_dir
+            .get_or_try_init(|| async {
+                let created_at = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos();
+                let stub_dir = std::env::temp_dir()
+                    .join(format!("varnishls-{}-{created_at}", std::process::id()));
+                let mut dir_builder = tokio::fs::DirBuilder::new();
+                #[cfg(unix)]
+                dir_builder.mode(0o700);
+                dir_builder.create(&stub_dir).await?;
+                Ok(stub_dir)
+            })
+            .await
+            .map(PathBuf::as_path)
+    }
 }
 
 impl Default for Backend {
@@ -278,6 +1555,19 @@ impl Default for Backend {
             root_document_uri: Default::default(),
             config: Default::default(),
             cache: Default::default(),
+            client_capabilities: Default::default(),
+            cancelled_progress_tokens: Default::default(),
+            open_documents: Default::default(),
+            included_from: Default::default(),
+            published_diagnostics_versions: Default::default(),
+            last_published_diagnostics: Default::default(),
+            vmod_errors: Default::default(),
+            loaded_vmods: Default::default(),
+            too_deep_includes: Default::default(),
+            semantic_tokens: Default::default(),
+            compiler_errors: Default::default(),
+            vmod_stub_dir: Default::default(),
+            grammar_error: parser::check_grammars().err(),
         }
     }
 }
@@ -288,8 +1578,10 @@ unsafe impl Sync for Backend {}
 impl LanguageServer for Backend {
     async fn initialize(&self, init_params: InitializeParams) -> Result<InitializeResult> {
         let root_uri = init_params.root_uri;
+        *self.client_capabilities.write().await = init_params.capabilities;
         // TODO: consider not initializing if uri scheme is not file
 
+        let mut config = self.config.read().await.clone();
         if let Some(mut root_uri) = root_uri {
             // Fix workspace directory missing slash
             if !root_uri.path().ends_with('/') {
@@ -297,22 +1589,38 @@ impl LanguageServer for Backend {
             }
             self.set_root_uri(root_uri.clone()).await;
             if root_uri.scheme() == "file" {
-                self.set_config(read_config(&root_uri.to_file_path().unwrap()).await?)
+                config = read_config(&root_uri.to_file_path().unwrap()).await?;
+            }
+        }
+
+        if let Some(options) = init_params.initialization_options {
+            match serde_json::from_value::<InitializationOptions>(options) {
+                Ok(options) => config.apply_initialization_options(options),
+                Err(err) => error!("Invalid initialization options: {err}"),
+            }
+        }
+        for warning in config
+            .format
+            .validate()
+            .into_iter()
+            .chain(config.vmod_types.validate())
+        {
+            error!("{warning}");
+            if let Some(ref client) = self.client {
+                client.show_message(MessageType::WARNING, warning).await;
+            }
+        }
+        self.set_config(config).await;
+
+        if let Some(ref grammar_error) = self.grammar_error {
+            error!("{grammar_error}");
+            if let Some(ref client) = self.client {
+                client
+                    .show_message(
+                        MessageType::ERROR,
+                        format!("varnishls can't parse vcl, only vmod names are completed. {grammar_error}"),
+                    )
                     .await;
-                let config = self.config.read().await;
-                if let Some(ref main_vcl_path) = config.main_vcl {
-                    if let Some(main_vcl_url) = self.read_doc_from_path(main_vcl_path, vec![]).await
-                    {
-                        *self.root_document_uri.write().await = Some(main_vcl_url.clone());
-
-                        let includes = {
-                            let main_doc = self.document_map.get(&main_vcl_url).unwrap();
-                            main_doc.get_includes()
-                        };
-
-                        self.read_new_includes(includes).await;
-                    }
-                }
             }
         }
 
@@ -329,9 +1637,15 @@ impl LanguageServer for Backend {
             }),
             offset_encoding: None,
             capabilities: ServerCapabilities {
-                inlay_hint_provider: Some(OneOf::Left(false)),
-                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::INCREMENTAL,
+                inlay_hint_provider: Some(OneOf::Left(true)),
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::INCREMENTAL),
+                        // for diagnostics on save
+                        save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                        ..Default::default()
+                    },
                 )),
 
                 completion_provider: Some(CompletionOptions {
@@ -347,7 +1661,12 @@ impl LanguageServer for Backend {
                         supported: Some(true),
                         change_notifications: Some(OneOf::Left(true)),
                     }),
-                    file_operations: None,
+                    file_operations: Some(WorkspaceFileOperationsServerCapabilities {
+                        did_create: Some(vcl_file_operation_options()),
+                        did_rename: Some(vcl_file_operation_options()),
+                        did_delete: Some(vcl_file_operation_options()),
+                        ..Default::default()
+                    }),
                 }),
                 semantic_tokens_provider: Some(
                     SemanticTokensServerCapabilities::SemanticTokensRegistrationOptions(
@@ -363,37 +1682,94 @@ impl LanguageServer for Backend {
                                 work_done_progress_options: Default::default(),
                                 legend: SemanticTokensLegend {
                                     token_types: LEGEND_TYPES.into(),
-                                    token_modifiers: vec![
-                                        "defaultLibrary".into(),
-                                        "declaration".into(),
-                                    ],
+                                    token_modifiers: LEGEND_MODIFIERS.into(),
                                 },
                                 range: Some(false),
-                                full: Some(SemanticTokensFullOptions::Bool(true)),
+                                full: Some(SemanticTokensFullOptions::Delta { delta: Some(true) }),
                             },
                             static_registration_options: StaticRegistrationOptions::default(),
                         },
                     ),
                 ),
                 definition_provider: Some(OneOf::Left(true)),
+                declaration_provider: Some(DeclarationCapability::Simple(true)),
                 references_provider: Some(OneOf::Left(true)),
                 rename_provider: Some(OneOf::Left(true)),
                 hover_provider: Some(HoverProviderCapability::Simple(true)),
+                signature_help_provider: Some(SignatureHelpOptions {
+                    trigger_characters: Some(vec!["(".into(), ",".into()]),
+                    retrigger_characters: None,
+                    work_done_progress_options: Default::default(),
+                }),
                 document_symbol_provider: Some(OneOf::Left(true)),
+                folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
                 code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+                document_highlight_provider: Some(OneOf::Left(true)),
+                document_formatting_provider: Some(OneOf::Left(true)),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        RELOAD_VMODS_COMMAND.to_string(),
+                        COMPILE_COMMAND.to_string(),
+                        SCAFFOLD_SUB_COMMAND.to_string(),
+                    ],
+                    work_done_progress_options: Default::default(),
+                }),
+                linked_editing_range_provider: Some(LinkedEditingRangeServerCapabilities::Simple(
+                    true,
+                )),
+                moniker_provider: Some(OneOf::Left(true)),
                 ..ServerCapabilities::default()
             },
         })
     }
 
+    async fn initialized(&self, _params: InitializedParams) {
+        // the main vcl is read after initialization, since progress can't be reported before
+        self.read_main_vcl().await;
+
+        let can_watch_files = self
+            .client_capabilities
+            .read()
+            .await
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.did_change_watched_files)
+            .and_then(|watched_files| watched_files.dynamic_registration)
+            .unwrap_or(false);
+        if let (Some(client), true) = (self.client.as_ref(), can_watch_files) {
+            let options = DidChangeWatchedFilesRegistrationOptions {
+                watchers: vec![FileSystemWatcher {
+                    glob_pattern: GlobPattern::String("**/*.vcl".to_string()),
+                    kind: None,
+                }],
+            };
+            let registration = Registration {
+                id: "varnishls/watchVclFiles".to_string(),
+                method: "workspace/didChangeWatchedFiles".to_string(),
+                register_options: serde_json::to_value(options).ok(),
+            };
+            if let Err(err) = client.register_capability(vec![registration]).await {
+                error!("could not watch vcl files: {err}");
+            }
+        }
+    }
+
     async fn shutdown(&self) -> Result<()> {
+        if let Some(stub_dir) = self.vmod_stub_dir.get() {
+            let _ = std::fs::remove_dir_all(stub_dir);
+        }
         std::process::exit(0);
         // Ok(())
     }
 
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
         debug!("did_open({})", params.text_document.uri);
+        if self.grammar_error.is_some() {
+            return;
+        }
         let uri = params.text_document.uri;
+        self.open_documents.insert(uri.clone());
         if let Some(mut doc) = self.document_map.get_mut(&uri) {
             let version = params.text_document.version;
             doc.edit_fulltext(version, params.text_document.text);
@@ -401,15 +1777,23 @@ impl LanguageServer for Backend {
             // clear cache
             self.cache.remove(&uri);
         } else {
-            let document = Document::new(uri.to_owned(), params.text_document.text, None);
+            let template_placeholders = self.config.read().await.template_placeholders.clone();
+            let document = Document::new(uri.to_owned(), params.text_document.text, None)
+                .with_version(params.text_document.version)
+                .with_template_placeholders(&template_placeholders);
             self.document_map.insert(uri.clone(), document);
         }
+        self.published_diagnostics_versions.remove(&uri);
+        self.last_published_diagnostics.remove(&uri);
+        // a reopened document may start over at the same version
+        self.semantic_tokens.remove(&uri);
 
-        let config = self.config.read().await;
         let doc_includes = {
             let doc = self.document_map.get(&uri).unwrap();
             doc.get_includes()
         };
+        let vcl_paths = self.config.read().await.vcl_paths.clone();
+        self.update_included_from(&uri, &vcl_paths);
         self.read_new_includes(doc_includes).await;
 
         self.client
@@ -422,21 +1806,15 @@ impl LanguageServer for Backend {
             .get_all_definitions_across_all_documents(Some(&uri))
             .await;
 
-        let doc = self.document_map.get(&uri).unwrap();
-        self.client
-            .as_ref()
-            .unwrap()
-            .publish_diagnostics(
-                uri.clone(),
-                doc.diagnostics(scope, &config.lint),
-                Some(doc.version()),
-            )
-            .await;
+        self.publish_diagnostics_for(&[uri], &scope).await;
         debug!("did open done");
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
         debug!("did_change()");
+        if self.grammar_error.is_some() {
+            return;
+        }
         let uri = params.text_document.uri;
         let version = params.text_document.version;
         let changes = params
@@ -444,56 +1822,171 @@ impl LanguageServer for Backend {
             .into_iter()
             .map(|change| (change.range, change.text));
 
-        {
+        // changes arriving early are applied with the version preceding them
+        let version = {
             let mut doc = self.document_map.get_mut(&uri).unwrap();
-            doc.edit(version, changes);
-        }
+            if !doc.edit(version, changes) {
+                return;
+            }
+            doc.version()
+        };
 
         self.cache.remove(&uri);
-
-        let config = self.config.read().await;
+        // the ranges are outdated, and the error might be fixed
+        self.compiler_errors.remove(&uri);
 
         {
             let doc = self.document_map.get(&uri).unwrap();
             let includes = doc.get_includes();
             drop(doc);
+            let vcl_paths = self.config.read().await.vcl_paths.clone();
+            self.update_included_from(&uri, &vcl_paths);
             self.read_new_includes(includes).await;
         }
 
+        if self.config.read().await.diagnostics_on == DiagnosticsOn::Save {
+            // cleared until the document is saved, instead of pointing at text edited since
+            self.publish_diagnostics(vec![(uri, vec![], Some(version))])
+                .await;
+            return;
+        }
+
         let scope = self
             .get_all_definitions_across_all_documents(Some(&uri))
             .await;
 
-        if let Some(doc) = self.document_map.try_get(&uri).try_unwrap() {
-            let diagnostics = doc.diagnostics(scope, &config.lint);
-            let version = doc.version();
-            drop(doc);
-            self.client
-                .as_ref()
-                .unwrap()
-                .publish_diagnostics(uri.clone(), diagnostics, Some(version))
-                .await;
-        }
+        self.publish_diagnostics_for(&[uri], &scope).await;
 
         debug!("did_change() done!");
     }
 
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        debug!("did_close({})", params.text_document.uri);
+        self.open_documents.remove(&params.text_document.uri);
+    }
+
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        debug!("did_save()");
+        let uri = params.text_document.uri;
+        if self.config.read().await.diagnostics_on != DiagnosticsOn::Save
+            || !self.document_map.contains_key(&uri)
+        {
+            return;
+        }
+        let scope = self
+            .get_all_definitions_across_all_documents(Some(&uri))
+            .await;
+        self.publish_diagnostics_for(&[uri], &scope).await;
+    }
+
+    /// Settings are the same as the initialization options, e.g. «{ "diagnosticsOn": "save" }»,
+    /// optionally within «varnishls». Only `diagnosticsOn` is applied without a restart.
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        debug!("did_change_configuration()");
+        let settings = match params.settings.get("varnishls") {
+            Some(settings) => settings.clone(),
+            None => params.settings,
+        };
+        // clients may send null, or the settings of other servers, which aren't errors
+        let Some(diagnostics_on) = settings.get("diagnosticsOn").cloned() else {
+            return;
+        };
+        let diagnostics_on = match from_json::<DiagnosticsOn>(diagnostics_on) {
+            Ok(diagnostics_on) => diagnostics_on,
+            Err(err) => {
+                self.log_error(format!("Invalid diagnosticsOn setting ({err})"))
+                    .await;
+                return;
+            }
+        };
+        let previous = std::mem::replace(
+            &mut self.config.write().await.diagnostics_on,
+            diagnostics_on,
+        );
+        // the documents may have been edited since their diagnostics were cleared
+        if previous == DiagnosticsOn::Save && diagnostics_on == DiagnosticsOn::Change {
+            self.republish_diagnostics().await;
+        }
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        debug!("did_change_watched_files()");
+        let mut created = vec![];
+        let mut deleted = vec![];
+        for event in params.changes {
+            match event.typ {
+                FileChangeType::CREATED => created.push(event.uri),
+                FileChangeType::DELETED => deleted.push(event.uri),
+                // read the file again, unless it is open
+                FileChangeType::CHANGED if !self.open_documents.contains(&event.uri) => {
+                    deleted.push(event.uri.clone());
+                    created.push(event.uri);
+                }
+                _ => {}
+            }
+        }
+        self.reindex_files(created, deleted).await;
+    }
+
+    async fn did_create_files(&self, params: CreateFilesParams) {
+        let created = params
+            .files
+            .iter()
+            .filter_map(|file| Url::parse(&file.uri).ok())
+            .collect();
+        self.reindex_files(created, vec![]).await;
+    }
+
+    async fn did_delete_files(&self, params: DeleteFilesParams) {
+        let deleted = params
+            .files
+            .iter()
+            .filter_map(|file| Url::parse(&file.uri).ok())
+            .collect();
+        self.reindex_files(vec![], deleted).await;
+    }
+
+    async fn did_rename_files(&self, params: RenameFilesParams) {
+        let (deleted, created) = params
+            .files
+            .iter()
+            .filter_map(|file| {
+                Some((
+                    Url::parse(&file.old_uri).ok()?,
+                    Url::parse(&file.new_uri).ok()?,
+                ))
+            })
+            .unzip();
+        self.reindex_files(created, deleted).await;
+    }
+
     async fn goto_definition(
         &self,
         params: GotoDefinitionParams,
     ) -> Result<Option<GotoDefinitionResponse>> {
         let src_uri = params.text_document_position_params.text_document.uri;
-        let src_doc = self.document_map.get(&src_uri).ok_or(Error {
-            code: tower_lsp::jsonrpc::ErrorCode::InternalError,
-            message: "Could not find source document".into(),
-            data: None,
-        })?;
+        let Some(src_doc) = self.document_map.get(&src_uri) else {
+            return Ok(None);
+        };
         let position = params.text_document_position_params.position;
         let point = Point {
             row: position.line as usize,
             column: position.character as usize,
         };
 
+        // headers are not declared, so go to where they are set and unset instead
+        if let Some(header) = src_doc.get_header_at_point(point) {
+            drop(src_doc);
+            debug!("goto definition for header «{}»", header);
+            let locations = self
+                .get_header_assignment_locations(&src_uri, &header)
+                .await;
+            if locations.is_empty() {
+                return Ok(None);
+            }
+            return Ok(Some(GotoDefinitionResponse::Array(locations)));
+        }
+
         let ident = src_doc.get_ident_at_point(point).ok_or(Error {
             code: tower_lsp::jsonrpc::ErrorCode::InternalError,
             message: "Could not find ident".into(),
@@ -501,8 +1994,16 @@ impl LanguageServer for Backend {
         })?;
 
         debug!("goto definition for ident «{}»", ident);
+        drop(src_doc);
 
-        for doc in self.document_map.iter() {
+        if let Some(location) = self.get_vmod_stub_location(&src_uri, &ident).await {
+            return Ok(Some(GotoDefinitionResponse::Scalar(location)));
+        }
+
+        for doc_url in self.get_searchable_documents(&src_uri).await {
+            let Some(doc) = self.document_map.get(&doc_url) else {
+                continue;
+            };
             let result = doc.get_definition_by_name(&ident);
             if result.is_none() {
                 continue;
@@ -531,31 +2032,103 @@ impl LanguageServer for Backend {
         return Ok(None);
     }
 
+    /// All declarations of an identifier, e.g. every «sub vcl_recv» block, in the order
+    /// Varnish concatenates them
+    async fn goto_declaration(
+        &self,
+        params: GotoDeclarationParams,
+    ) -> Result<Option<GotoDeclarationResponse>> {
+        let src_uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let point = Point {
+            row: position.line as usize,
+            column: position.character as usize,
+        };
+        let Some(ident) = self
+            .document_map
+            .get(&src_uri)
+            .and_then(|src_doc| src_doc.get_ident_at_point(point))
+        else {
+            return Ok(None);
+        };
+
+        debug!("goto declaration for ident «{}»", ident);
+
+        let mut declarations = vec![];
+        for doc_url in self.get_searchable_documents(&src_uri).await {
+            let Some(doc) = self.document_map.get(&doc_url) else {
+                continue;
+            };
+            for (start, end) in doc.get_definitions_by_name(&ident) {
+                let mut nested_pos = doc.pos_from_main_doc.clone();
+                nested_pos.push((start.row, start.column));
+                let range = Range::new(
+                    Position::new(start.row as u32, start.column as u32),
+                    Position::new(end.row as u32, end.column as u32),
+                );
+                declarations.push((nested_pos, Location::new(doc.url.to_owned(), range)));
+            }
+        }
+
+        if declarations.is_empty() {
+            return Ok(None);
+        }
+
+        declarations.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(Some(GotoDeclarationResponse::Array(
+            declarations
+                .into_iter()
+                .map(|(_nested_pos, location)| location)
+                .collect(),
+        )))
+    }
+
     async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
         let src_uri = params.text_document_position.text_document.uri;
-        let doc = self.document_map.get(&src_uri).ok_or(Error {
-            code: tower_lsp::jsonrpc::ErrorCode::InternalError,
-            message: "Could not find source document".into(),
-            data: None,
-        })?;
+        let Some(doc) = self.document_map.get(&src_uri) else {
+            return Ok(None);
+        };
         let position = params.text_document_position.position;
         let point = Point {
             row: position.line as usize,
             column: position.character as usize,
         };
 
+        // references of an include are all include statements including the same file
+        let include_at_point = doc
+            .get_include_locations()
+            .into_iter()
+            .find(|(_include, range)| range.start <= position && position <= range.end);
+        if let Some((include, _range)) = include_at_point {
+            let vcl_paths = self.config.read().await.vcl_paths.clone();
+            let refs = include
+                .resolve(&vcl_paths)
+                .url
+                .and_then(|included_url| self.included_from.get(&included_url))
+                .map(|locations| locations.clone());
+            return Ok(refs);
+        }
+
         let ident = doc.get_ident_at_point(point).ok_or(Error {
             code: tower_lsp::jsonrpc::ErrorCode::InternalError,
             message: "Could not find ident".into(),
             data: None,
         })?;
 
-        let refs = self
-            .document_map
-            .iter()
-            .flat_map(|doc| doc.get_references_for_ident(ident.as_str()))
-            .map(|reference| reference.uri)
-            .collect::<Vec<_>>();
+        drop(doc);
+
+        let mut refs = vec![];
+        for doc_url in self.get_searchable_documents(&src_uri).await {
+            // let the server handle cancellations between files
+            tokio::task::yield_now().await;
+            if let Some(doc) = self.document_map.get(&doc_url) {
+                refs.extend(
+                    doc.get_references_for_ident(ident.as_str())
+                        .into_iter()
+                        .map(|reference| reference.uri),
+                );
+            }
+        }
 
         if refs.is_empty() {
             return Ok(None);
@@ -564,7 +2137,167 @@ impl LanguageServer for Backend {
         Ok(Some(refs))
     }
 
+    async fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let Some(doc) = self.document_map.get(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let format_config = self.config.read().await.format.clone();
+        let Some(formatted) = doc.format(&params.options, &format_config) else {
+            return Ok(None);
+        };
+        if doc.rope == formatted.as_str() {
+            return Ok(Some(vec![]));
+        }
+
+        Ok(Some(vec![TextEdit {
+            range: doc.get_full_range(),
+            new_text: formatted,
+        }]))
+    }
+
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> Result<Option<serde_json::Value>> {
+        debug!("execute_command({})", params.command);
+        match params.command.as_str() {
+            RELOAD_VMODS_COMMAND => {
+                let reloaded = self.reload_vmods().await;
+                Ok(Some(serde_json::json!({
+                    "reloaded": reloaded,
+                    "message": format!("Reloaded {reloaded} vmods"),
+                })))
+            }
+            COMPILE_COMMAND => {
+                let uri = match params.arguments.first() {
+                    Some(argument) => Some(
+                        from_json::<Url>(argument.clone())
+                            .map_err(|err| Error::invalid_params(err.to_string()))?,
+                    ),
+                    None => None,
+                };
+                let error_count = self
+                    .compile_with_varnishd(uri)
+                    .await
+                    .map_err(Error::invalid_params)?;
+                Ok(Some(serde_json::json!({
+                    "errors": error_count,
+                    "message": match error_count {
+                        0 => "varnishd -C compiled the vcl without errors".to_string(),
+                        count => format!("varnishd -C reported {count} errors"),
+                    },
+                })))
+            }
+            SCAFFOLD_SUB_COMMAND => {
+                let (Some(uri), Some(sub_name)) =
+                    (params.arguments.first(), params.arguments.get(1))
+                else {
+                    return Err(Error::invalid_params(
+                        "Expected a document uri and a subroutine name",
+                    ));
+                };
+                let uri = from_json::<Url>(uri.clone())
+                    .map_err(|err| Error::invalid_params(err.to_string()))?;
+                let sub_name = from_json::<String>(sub_name.clone())
+                    .map_err(|err| Error::invalid_params(err.to_string()))?;
+                let edit = self
+                    .document_map
+                    .get(&uri)
+                    .ok_or_else(|| Error::invalid_params(format!("Unknown document: {uri}")))?
+                    .get_sub_scaffold_edit(&sub_name)
+                    .map_err(Error::invalid_params)?;
+                let workspace_edit = WorkspaceEdit::new(HashMap::from([(uri, vec![edit])]));
+                if let Some(ref client) = self.client {
+                    if let Err(err) = client.apply_edit(workspace_edit.clone()).await {
+                        error!("Failed to apply {SCAFFOLD_SUB_COMMAND} edit: {err}");
+                    }
+                }
+                Ok(Some(serde_json::json!({
+                    "edit": workspace_edit,
+                    "message": format!("Added sub {sub_name}"),
+                })))
+            }
+            command => Err(Error::invalid_params(format!("Unknown command: {command}"))),
+        }
+    }
+
+    async fn document_highlight(
+        &self,
+        params: DocumentHighlightParams,
+    ) -> Result<Option<Vec<DocumentHighlight>>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let Some(doc) = self.document_map.get(&uri) else {
+            return Ok(None);
+        };
+        let point = Point {
+            row: position.line as usize,
+            column: position.character as usize,
+        };
+
+        // highlight matching brackets
+        Ok(doc
+            .get_matching_brackets(point)
+            .map(|(bracket, matching_bracket)| {
+                vec![
+                    DocumentHighlight {
+                        range: bracket,
+                        kind: Some(DocumentHighlightKind::TEXT),
+                    },
+                    DocumentHighlight {
+                        range: matching_bracket,
+                        kind: Some(DocumentHighlightKind::TEXT),
+                    },
+                ]
+            }))
+    }
+
+    async fn linked_editing_range(
+        &self,
+        params: LinkedEditingRangeParams,
+    ) -> Result<Option<LinkedEditingRanges>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let Some(doc) = self.document_map.get(&uri) else {
+            return Ok(None);
+        };
+        let point = Point {
+            row: position.line as usize,
+            column: position.character as usize,
+        };
+
+        // only the current document, to keep it responsive while typing
+        Ok(doc
+            .get_linked_editing_ranges(point)
+            .map(|ranges| LinkedEditingRanges {
+                ranges,
+                word_pattern: Some(r"[a-zA-Z][\w-]*".to_string()),
+            }))
+    }
+
+    async fn moniker(&self, params: MonikerParams) -> Result<Option<Vec<Moniker>>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let scope = self
+            .get_all_definitions_across_all_documents(Some(&uri))
+            .await;
+        let Some(doc) = self.document_map.get(&uri) else {
+            return Ok(None);
+        };
+        let point = Point {
+            row: position.line as usize,
+            column: position.character as usize,
+        };
+
+        Ok(doc.get_moniker(point, &scope).map(|moniker| vec![moniker]))
+    }
+
     async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        if self.grammar_error.is_some() {
+            return Ok(Some(CompletionResponse::Array(
+                self.get_vmod_name_completions().await,
+            )));
+        }
         let uri = params.text_document_position.text_document.uri;
         let position = params.text_document_position.position;
         let scope = self
@@ -572,7 +2305,36 @@ impl LanguageServer for Backend {
             .await;
         let doc = self.document_map.get(&uri).unwrap();
         debug!("got doc for autocomplete");
-        let completions = doc.autocomplete_for_pos(position, scope);
+        let http_methods = self.config.read().await.lint.http_methods.clone();
+        if let Some(completions) = doc.autocomplete_http_method(position, &http_methods) {
+            return Ok(Some(CompletionResponse::Array(completions)));
+        }
+        if let Some(completions) = doc.autocomplete_regsub_backreference(position) {
+            return Ok(Some(CompletionResponse::Array(completions)));
+        }
+        if let Some(completions) = doc.autocomplete_postfix(position, &scope) {
+            return Ok(Some(CompletionResponse::Array(completions)));
+        }
+        if let Some(completions) = doc.autocomplete_format_string(position, &scope) {
+            return Ok(Some(CompletionResponse::Array(completions)));
+        }
+        // labels are set up outside of vcl, so suggest the configured ones and the ones used in
+        // any loaded document
+        let configured_labels = self.config.read().await.lint.vcl_labels.clone();
+        let labels = self
+            .document_map
+            .iter()
+            .flat_map(|doc| doc.get_vcl_labels())
+            .map(|(label, _range)| label)
+            .chain(configured_labels.into_iter().flatten())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        if let Some(completions) = doc.autocomplete_vcl_label(position, &labels) {
+            return Ok(Some(CompletionResponse::Array(completions)));
+        }
+        let flavor = self.config.read().await.lint.flavor;
+        let completions = doc.autocomplete_for_pos(position, scope, flavor);
         Ok(completions.map(CompletionResponse::Array))
     }
 
@@ -617,29 +2379,103 @@ impl LanguageServer for Backend {
         let scope = self
             .get_all_definitions_across_all_documents(Some(&uri))
             .await;
-        let doc = self.document_map.get(&uri).unwrap();
+        let vcl_labels = self.config.read().await.lint.vcl_labels.clone();
+        let Some(doc) = self.document_map.get(&uri) else {
+            return Ok(None);
+        };
         let pos = params.text_document_position_params.position;
         let point = Point {
             row: pos.line as usize,
             column: pos.character as usize,
         };
+
+        if let Some((value, range)) = doc.get_vcl_version_hover(point) {
+            return Ok(Some(Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value,
+                }),
+                range: Some(range),
+            }));
+        }
+
+        if let Some((value, range)) = doc.get_new_stmt_hover(point, &scope) {
+            return Ok(Some(Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value,
+                }),
+                range: Some(range),
+            }));
+        }
+
+        if let Some((value, range)) = doc.get_vcl_label_hover(point, vcl_labels.as_deref()) {
+            return Ok(Some(Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value,
+                }),
+                range: Some(range),
+            }));
+        }
+
+        if let Some((value, range)) = doc.get_variable_hover(point, &scope) {
+            return Ok(Some(Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value,
+                }),
+                range: Some(range),
+            }));
+        }
+
+        let sub_name = doc.get_sub_name_at_point(point);
         let r#type = match doc.get_type_at_point(point, scope) {
             Some(r#type) => r#type,
             None => return Ok(None),
         };
 
+        // list the entries of the acl, which might be declared in another document
+        if matches!(r#type, Type::Acl) {
+            let ident = doc.get_ident_at_point(point);
+            drop(doc);
+            if let Some(ident) = ident {
+                for doc_url in self.get_searchable_documents(&uri).await {
+                    let Some(value) = self
+                        .document_map
+                        .get(&doc_url)
+                        .and_then(|acl_doc| acl_doc.get_acl_hover(&ident))
+                    else {
+                        continue;
+                    };
+                    return Ok(Some(Hover {
+                        contents: HoverContents::Markup(MarkupContent {
+                            kind: MarkupKind::Markdown,
+                            value,
+                        }),
+                        range: None,
+                    }));
+                }
+            }
+            return Ok(Some(Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::PlainText,
+                    value: format!("{}", r#type),
+                }),
+                range: None,
+            }));
+        }
+
         if let Type::Func(func) = r#type {
             return Ok(Some(Hover {
                 contents: HoverContents::Markup(MarkupContent {
                     kind: MarkupKind::Markdown,
                     value: format!(
-                        "{} {}{}{}",
-                        func.r#return
-                            .as_ref()
-                            .map(|r| format!("{}", r))
-                            .unwrap_or_else(|| "VOID".to_string()),
-                        func.name,
-                        func.get_signature_string(),
+                        "{}{}{}",
+                        func.get_signature_label(),
+                        func.get_restriction_hover(sub_name.as_deref())
+                            .map(|restriction| format!("\n\n{restriction}"))
+                            .unwrap_or_default(),
                         func.doc.map(|doc| format!("\n\n{doc}")).unwrap_or_default()
                     ),
                 }),
@@ -656,31 +2492,66 @@ impl LanguageServer for Backend {
         }))
     }
 
+    async fn signature_help(&self, params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let scope = self
+            .get_all_definitions_across_all_documents(Some(&uri))
+            .await;
+        let Some(doc) = self.document_map.get(&uri) else {
+            return Ok(None);
+        };
+        let pos = params.text_document_position_params.position;
+        let point = Point {
+            row: pos.line as usize,
+            column: pos.character as usize,
+        };
+
+        Ok(doc.get_signature_help(point, &scope))
+    }
+
     async fn semantic_tokens_full(
         &self,
         params: SemanticTokensParams,
     ) -> Result<Option<SemanticTokensResult>> {
         debug!("semantic_tokens_full()");
-        let start = std::time::Instant::now();
-        let uri = params.text_document.uri;
-        let Some(doc) = self.document_map.get(&uri) else {
-            error!("Could not find document");
-            return Err(Error::internal_error());
-        };
-
-        let semantic_tokens = doc.get_semantic_tokens();
-
-        debug!(
-            "semantic_tokens_full() done in {}ms",
-            start.elapsed().as_millis()
-        );
-
+        let (version, semantic_tokens) =
+            self.get_semantic_tokens(&params.text_document.uri).await?;
         Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
-            result_id: None,
+            result_id: Some(version.to_string()),
             data: semantic_tokens,
         })))
     }
 
+    async fn semantic_tokens_full_delta(
+        &self,
+        params: SemanticTokensDeltaParams,
+    ) -> Result<Option<SemanticTokensFullDeltaResult>> {
+        debug!("semantic_tokens_full_delta()");
+        let uri = params.text_document.uri;
+        let previous = self
+            .semantic_tokens
+            .get(&uri)
+            .filter(|entry| entry.0.to_string() == params.previous_result_id)
+            .map(|entry| entry.1.clone());
+        let (version, semantic_tokens) = self.get_semantic_tokens(&uri).await?;
+
+        // the client has some other tokens than the last ones, so send all of them
+        let Some(previous) = previous else {
+            return Ok(Some(SemanticTokensFullDeltaResult::Tokens(
+                SemanticTokens {
+                    result_id: Some(version.to_string()),
+                    data: semantic_tokens,
+                },
+            )));
+        };
+        Ok(Some(SemanticTokensFullDeltaResult::TokensDelta(
+            SemanticTokensDelta {
+                result_id: Some(version.to_string()),
+                edits: get_semantic_tokens_edits(&previous, &semantic_tokens),
+            },
+        )))
+    }
+
     async fn document_symbol(
         &self,
         params: DocumentSymbolParams,
@@ -696,59 +2567,285 @@ impl LanguageServer for Backend {
             return Ok(None);
         };
 
+        // vcl labels aren't declared in vcl, so list where they are switched to
+        let labels = self
+            .document_map
+            .get(&uri)
+            .map(|doc| doc.get_vcl_labels())
+            .unwrap_or_default();
+
+        // nest the symbols of the vcls embedded in a varnishtest under their «-vcl» argument
+        let embedded_vcls = self
+            .document_map
+            .get(&uri)
+            .map(|doc| doc.get_embedded_vcls())
+            .unwrap_or_default();
+        if !embedded_vcls.is_empty() {
+            return Ok(Some(DocumentSymbolResponse::Nested(
+                embedded_vcls
+                    .iter()
+                    .map(|embedded_vcl| {
+                        let children = defs
+                            .iter()
+                            .filter_map(|def| {
+                                let loc = def.loc.as_ref()?;
+                                if !embedded_vcl.contains(&loc.range) {
+                                    return None;
+                                }
+                                Some(DocumentSymbol {
+                                    name: def.ident_str.to_string(),
+                                    detail: None,
+                                    kind: get_symbol_kind(&def.r#type),
+                                    tags: None,
+                                    deprecated: None,
+                                    range: loc.range,
+                                    selection_range: loc.range,
+                                    children: None,
+                                })
+                            })
+                            .collect();
+                        DocumentSymbol {
+                            name: format!("varnish {}", embedded_vcl.instance),
+                            detail: None,
+                            kind: SymbolKind::MODULE,
+                            tags: None,
+                            deprecated: None,
+                            range: embedded_vcl.range,
+                            selection_range: Range::new(
+                                embedded_vcl.range.start,
+                                embedded_vcl.start,
+                            ),
+                            children: Some(children),
+                        }
+                    })
+                    .collect(),
+            )));
+        }
+
         Ok(Some(DocumentSymbolResponse::Flat(
             defs.iter()
                 .filter(|def| def.loc.is_some())
                 .map(|definition| SymbolInformation {
                     name: definition.ident_str.to_string(),
-                    kind: match *definition.r#type {
-                        Type::Sub => SymbolKind::FUNCTION,
-                        Type::Acl => SymbolKind::STRUCT,
-                        Type::Backend => SymbolKind::STRUCT,
-                        Type::Probe => SymbolKind::STRUCT,
-                        Type::Obj(_) => SymbolKind::STRUCT,
-                        _ => SymbolKind::NULL,
-                    },
+                    kind: get_symbol_kind(&definition.r#type),
                     location: definition.loc.clone().unwrap(),
                     deprecated: None,
                     tags: None,
                     container_name: None,
                 })
+                .chain(labels.into_iter().map(|(label, range)| SymbolInformation {
+                    name: label,
+                    kind: SymbolKind::NAMESPACE,
+                    location: Location {
+                        uri: uri.clone(),
+                        range,
+                    },
+                    deprecated: None,
+                    tags: None,
+                    container_name: Some("vcl label".to_string()),
+                }))
                 .collect(),
         )))
     }
 
+    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+        let uri = params.text_document.uri;
+        debug!("inlay_hint({})", uri);
+        if !self.config.read().await.implicit_return_hints {
+            return Ok(None);
+        }
+        Ok(self
+            .document_map
+            .get(&uri)
+            .map(|doc| doc.get_implicit_return_hints(params.range)))
+    }
+
+    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+        let uri = params.text_document.uri;
+        debug!("folding_range({})", uri);
+        Ok(self
+            .document_map
+            .get(&uri)
+            .map(|doc| doc.get_folding_ranges()))
+    }
+
+    /// Symbols of all loaded documents matching the query. With a partial result token, the
+    /// matches of each document are streamed as `$/progress` notifications instead. tower-lsp
+    /// drops this future when the request is cancelled, which stops the stream.
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<Vec<SymbolInformation>>> {
+        debug!("symbol({})", params.query);
+        let query = params.query.to_lowercase();
+        let partial_result_token = params.partial_result_params.partial_result_token;
+        let builtins = get_varnish_builtins();
+        let doc_urls = self
+            .document_map
+            .iter()
+            .map(|doc| doc.url.clone())
+            .collect::<Vec<_>>();
+
+        let mut symbols = vec![];
+        for doc_url in doc_urls {
+            // let the server handle cancellations between files
+            tokio::task::yield_now().await;
+
+            let cached_definitions = self
+                .cache
+                .get(&doc_url)
+                .and_then(|cache_entry| cache_entry.definitions.clone());
+            let Some(definitions) = cached_definitions.or_else(|| {
+                self.document_map
+                    .get(&doc_url)
+                    .map(|doc| doc.get_all_definitions(&builtins))
+            }) else {
+                continue;
+            };
+
+            let mut doc_symbols = definitions
+                .into_iter()
+                .filter(|def| def.ident_str.to_lowercase().contains(&query))
+                .filter_map(|definition| {
+                    Some(SymbolInformation {
+                        name: definition.ident_str.to_string(),
+                        kind: get_symbol_kind(&definition.r#type),
+                        location: definition.loc?,
+                        deprecated: None,
+                        tags: None,
+                        container_name: None,
+                    })
+                })
+                .collect::<Vec<_>>();
+            if doc_symbols.is_empty() {
+                continue;
+            }
+
+            match (&partial_result_token, &self.client) {
+                (Some(token), Some(client)) => {
+                    client
+                        .send_notification::<PartialResults>(PartialResultsParams {
+                            token: token.clone(),
+                            value: doc_symbols,
+                        })
+                        .await;
+                }
+                _ => symbols.append(&mut doc_symbols),
+            }
+        }
+
+        // results already streamed are not to be repeated in the response
+        Ok(Some(symbols))
+    }
+
     async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
         debug!("code_action({:?})", params.context);
         let doc_uri = params.text_document.uri;
-        let actions = params
+        let mut actions = params
             .context
             .diagnostics
             .iter()
             .filter_map(|diag| {
                 let diag_data = from_json::<DiagnosticData>(diag.data.as_ref()?.to_owned()).ok()?;
+                Some((diag, diag_data))
+            })
+            .flat_map(|(diag, diag_data)| {
+                std::iter::once((diag_data.quickfix_label, diag_data.replace_with))
+                    .chain(diag_data.alternatives)
+                    .map(|(title, new_text)| {
+                        CodeActionOrCommand::CodeAction(CodeAction {
+                            title,
+                            kind: Some(CodeActionKind::QUICKFIX),
+                            diagnostics: Some(vec![diag.clone()]),
+                            edit: Some(WorkspaceEdit {
+                                document_changes: Some(DocumentChanges::Edits(vec![
+                                    TextDocumentEdit {
+                                        text_document: OptionalVersionedTextDocumentIdentifier {
+                                            uri: doc_uri.to_owned(),
+                                            version: None,
+                                        },
+                                        edits: vec![OneOf::Left(TextEdit {
+                                            range: diag.range,
+                                            new_text,
+                                        })],
+                                    },
+                                ])),
+                                changes: None,
+                                change_annotations: None,
+                            }),
+                            ..Default::default()
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let has_selection =
+            params.range.start != params.range.end && self.document_map.contains_key(&doc_uri);
+        let in_inline_probe = self
+            .document_map
+            .get(&doc_uri)
+            .is_some_and(|doc| doc.has_inline_probe_at(params.range.start));
+        // both refactorings need the scope, so read it once, and only when one of them applies
+        let scope = if has_selection || in_inline_probe {
+            self.get_all_definitions_across_all_documents(Some(&doc_uri))
+                .await
+        } else {
+            Definitions::default()
+        };
 
-                Some(CodeActionOrCommand::CodeAction(CodeAction {
-                    title: diag_data.quickfix_label,
-                    kind: Some(CodeActionKind::QUICKFIX),
+        // offer extracting selected statements into a new subroutine
+        if has_selection {
+            let extracted = self
+                .document_map
+                .get(&doc_uri)
+                .and_then(|doc| doc.extract_subroutine(params.range, &scope));
+            if let Some((name, edits)) = extracted {
+                actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                    title: format!("Extract to subroutine «{name}»"),
+                    kind: Some(CodeActionKind::REFACTOR_EXTRACT),
                     edit: Some(WorkspaceEdit {
                         document_changes: Some(DocumentChanges::Edits(vec![TextDocumentEdit {
                             text_document: OptionalVersionedTextDocumentIdentifier {
                                 uri: doc_uri.to_owned(),
                                 version: None,
                             },
-                            edits: vec![OneOf::Left(TextEdit {
-                                range: diag.range,
-                                new_text: diag_data.replace_with,
-                            })],
+                            edits: edits.into_iter().map(OneOf::Left).collect(),
                         }])),
                         changes: None,
                         change_annotations: None,
                     }),
                     ..Default::default()
-                }))
-            })
-            .collect::<Vec<_>>();
+                }));
+            }
+        }
+
+        // offer moving an inline probe into a named probe
+        let extracted_probe = if in_inline_probe {
+            self.document_map
+                .get(&doc_uri)
+                .and_then(|doc| doc.extract_inline_probe(params.range.start, &scope))
+        } else {
+            None
+        };
+        if let Some((name, edits)) = extracted_probe {
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Extract to probe «{name}»"),
+                kind: Some(CodeActionKind::REFACTOR_EXTRACT),
+                edit: Some(WorkspaceEdit {
+                    document_changes: Some(DocumentChanges::Edits(vec![TextDocumentEdit {
+                        text_document: OptionalVersionedTextDocumentIdentifier {
+                            uri: doc_uri.to_owned(),
+                            version: None,
+                        },
+                        edits: edits.into_iter().map(OneOf::Left).collect(),
+                    }])),
+                    changes: None,
+                    change_annotations: None,
+                }),
+                ..Default::default()
+            }));
+        }
 
         if actions.is_empty() {
             return Ok(None);
@@ -781,11 +2878,27 @@ pub async fn read_config(root_path: &Path) -> Result<Config> {
         .iter()
         .flat_map(|vcl_path| std::fs::canonicalize(vcl_path).ok())
         .collect();
+    config.lint.sandbox_paths = config
+        .lint
+        .sandbox_paths
+        .iter()
+        .map(|sandbox_path| {
+            std::fs::canonicalize(root_path.join(sandbox_path))
+                .unwrap_or_else(|_| sandbox_path.to_owned())
+        })
+        .collect();
     Ok(config)
 }
 
-async fn read_all_vmods(imports: Vec<VmodImport>, config: &Config) -> Definitions {
+async fn read_all_vmods(
+    imports: Vec<VmodImport>,
+    config: &Config,
+    vmod_errors: &VmodErrors,
+) -> Definitions {
     let mut definitions = Definitions::default();
+    for import in imports.iter() {
+        vmod_errors.remove(&import.name);
+    }
 
     /*
      * read all vcc files by glob (expects e.g. "{vcc_files_dir}/libvmod_std/vmod.vcc")
@@ -837,7 +2950,7 @@ async fn read_all_vmods(imports: Vec<VmodImport>, config: &Config) -> Definition
             .map_err(Box::<dyn std::error::Error + Send + Sync>::from) // map JoinError into generic error
             .and_then(|result| result); // replace with result from parse_vcc_file_by_path
 
-        let vmod_scope = match result {
+        let mut vmod_scope = match result {
             Err(err) => {
                 error!("Failed to parse vmod vcc: {err}");
                 continue;
@@ -848,52 +2961,124 @@ async fn read_all_vmods(imports: Vec<VmodImport>, config: &Config) -> Definition
             Type::Obj(ref obj) => obj.name.clone(),
             _ => unreachable!(),
         };
-        let Some(import) = imports.iter().find(|import| import.name == vmod_name) else {
-            error!("Failed to find {vmod_name}. Vmod aliases not yet supported.");
+        mark_format_string_args(&vmod_name, &mut vmod_scope);
+        // the same vmod might be imported with different aliases
+        let vmod_imports = imports
+            .iter()
+            .filter(|import| import.name == vmod_name)
+            .collect::<Vec<_>>();
+        if vmod_imports.is_empty() {
+            error!("Failed to find {vmod_name}. The vcc file might declare another vmod name.");
             continue;
-        };
-        let def = Definition {
-            ident_str: vmod_name.clone(),
-            r#type: Box::new(vmod_scope),
-            loc: Some(import.loc.clone()),
-            nested_pos: import.nested_pos.clone(),
-        };
-        definitions.properties.insert(vmod_name, def);
+        }
+        for import in vmod_imports {
+            let def = Definition {
+                ident_str: import.scope_name().to_string(),
+                r#type: Box::new(vmod_scope.clone()),
+                loc: Some(import.loc.clone()),
+                nested_pos: import.nested_pos.clone(),
+            };
+            definitions
+                .properties
+                .insert(import.scope_name().to_string(), def);
+        }
     }
 
     // read all vmods
+    let type_mappings = config.vmod_types.mappings();
     let vmod_futures = imports
         .iter()
-        .filter(|import| !definitions.properties.contains_key(&import.name)) // filter out vmods found by vcc
+        .filter(|import| !definitions.properties.contains_key(import.scope_name())) // filter out vmods found by vcc
         .map(|import| {
             // debug!("spawning task to vmod binary for «{vmod_name}»");
-            tokio::task::spawn(read_vmod_lib_by_name(
-                import.name.clone(),
-                config.vmod_paths.to_owned(),
-            ))
+            let vmod_fut = match import.resolve_from_path(&config.vmod_paths) {
+                Some(path) => {
+                    let name = import.name.clone();
+                    let type_mappings = type_mappings.clone();
+                    tokio::task::spawn(async move {
+                        read_vmod_lib(name, path, type_mappings).await.map(Some)
+                    })
+                }
+                None => tokio::task::spawn(read_vmod_lib_by_name(
+                    import.name.clone(),
+                    config.vmod_paths.to_owned(),
+                    type_mappings.clone(),
+                )),
+            };
+            (import, vmod_fut)
         })
         .collect::<Vec<_>>();
 
-    for vmod_fut in vmod_futures {
-        if let Ok(Ok(Some(vmod))) = vmod_fut.await {
-            let vmod_name = vmod.name;
-            let Some(import) = imports.iter().find(|import| import.name == vmod_name) else {
-                error!("Failed to find {vmod_name}. Vmod aliases not yet supported.");
+    for (import, vmod_fut) in vmod_futures {
+        let import_name = &import.name;
+        let result = vmod_fut
+            .await
+            .map_err(Box::<dyn std::error::Error + Send + Sync>::from)
+            .and_then(|result| result);
+        let vmod = match result {
+            Ok(Some(vmod)) => vmod,
+            // no such vmod
+            Ok(None) => continue,
+            Err(err) => {
+                error!("Failed to read vmod {import_name}: {err}");
+                vmod_errors.insert(
+                    import_name.clone(),
+                    (
+                        DiagnosticSeverity::ERROR,
+                        format!("Failed to read vmod {import_name}: {err}"),
+                    ),
+                );
                 continue;
-            };
-            let def = Definition {
-                ident_str: import.name.clone(),
-                r#type: Box::new(vmod.scope),
-                loc: Some(import.loc.clone()),
-                nested_pos: import.nested_pos.clone(),
-            };
-            definitions.properties.insert(import.name.clone(), def);
+            }
+        };
+        if !vmod.row_errors.is_empty() {
+            vmod_errors.insert(
+                import_name.clone(),
+                (
+                    DiagnosticSeverity::WARNING,
+                    format!(
+                        "Vmod {import_name} was only partially read: {}",
+                        vmod.row_errors.join("; ")
+                    ),
+                ),
+            );
+        }
+
+        let vmod_name = vmod.name;
+        let mut vmod_scope = vmod.scope;
+        mark_format_string_args(&vmod_name, &mut vmod_scope);
+        if vmod_name != *import_name {
+            error!("Failed to find {vmod_name}. The vmod might declare another vmod name.");
+            continue;
         }
+        let def = Definition {
+            ident_str: import.scope_name().to_string(),
+            r#type: Box::new(vmod_scope),
+            loc: Some(import.loc.clone()),
+            nested_pos: import.nested_pos.clone(),
+        };
+        definitions
+            .properties
+            .insert(import.scope_name().to_string(), def);
     }
 
     definitions
 }
 
+/// Filters for the file operations (create, rename, delete) the server is notified of
+fn vcl_file_operation_options() -> FileOperationRegistrationOptions {
+    FileOperationRegistrationOptions {
+        filters: vec![FileOperationFilter {
+            scheme: Some("file".to_string()),
+            pattern: FileOperationPattern {
+                glob: "**/*.vcl".to_string(),
+                matches: Some(FileOperationPatternKind::File),
+                options: None,
+            },
+        }],
+    }
+}
+
 fn get_all_documents(
     doc_map: &DocumentMap,
     cache: &DashMap<Url, CacheEntry>,
@@ -929,3 +3114,476 @@ fn get_all_documents(
     docs.append(&mut deep_includes);
     docs
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A backend using a new temporary directory (named after the test) as its vcl path, with
+    /// the files written to it. `setup` can then change the directory, the backend and the config
+    /// before «main.vcl», if written, is read with its includes.
+    async fn backend_with_files(
+        name: &str,
+        files: &[(&str, &str)],
+        setup: impl FnOnce(&Path, &mut Backend, &mut Config),
+    ) -> (Backend, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("varnishls-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = std::fs::canonicalize(&dir).unwrap();
+        for (path, text) in files {
+            std::fs::write(dir.join(path), text).unwrap();
+        }
+
+        let mut backend: Backend = Default::default();
+        let mut config = Config {
+            vcl_paths: vec![dir.clone()],
+            ..Default::default()
+        };
+        setup(&dir, &mut backend, &mut config);
+        backend.set_config(config).await;
+        if files.iter().any(|(path, _)| *path == "main.vcl") {
+            backend
+                .read_new_includes(vec![Include {
+                    url: Some(Url::from_file_path(dir.join("main.vcl")).unwrap()),
+                    path: dir.join("main.vcl"),
+                    nested_pos: vec![],
+                    including_dir: None,
+                }])
+                .await;
+        }
+        (backend, dir)
+    }
+
+    #[tokio::test]
+    async fn reindex_created_deleted_and_renamed_files() {
+        let (backend, dir) = backend_with_files(
+            "reindex",
+            &[(
+                "main.vcl",
+                "vcl 4.1;\ninclude \"a.vcl\";\ninclude \"b.vcl\";\n",
+            )],
+            |_, _, _| {},
+        )
+        .await;
+        let main_uri = Url::from_file_path(dir.join("main.vcl")).unwrap();
+        let a_uri = Url::from_file_path(dir.join("a.vcl")).unwrap();
+        let b_uri = Url::from_file_path(dir.join("b.vcl")).unwrap();
+        assert!(!backend.document_map.contains_key(&a_uri));
+
+        // created
+        std::fs::write(dir.join("a.vcl"), "sub from_a {}\n").unwrap();
+        backend.reindex_files(vec![a_uri.clone()], vec![]).await;
+        assert!(backend.document_map.contains_key(&a_uri));
+        assert_eq!(backend.included_from.get(&a_uri).unwrap()[0].uri, main_uri);
+        let scope = backend
+            .get_all_definitions_across_all_documents(Some(&main_uri))
+            .await;
+        assert!(scope.properties.contains_key("from_a"));
+
+        // renamed
+        std::fs::rename(dir.join("a.vcl"), dir.join("b.vcl")).unwrap();
+        backend
+            .reindex_files(vec![b_uri.clone()], vec![a_uri.clone()])
+            .await;
+        assert!(!backend.document_map.contains_key(&a_uri));
+        assert!(!backend.included_from.contains_key(&a_uri));
+        assert!(backend.document_map.contains_key(&b_uri));
+        let scope = backend
+            .get_all_definitions_across_all_documents(Some(&main_uri))
+            .await;
+        assert!(scope.properties.contains_key("from_a"));
+
+        // deleted
+        std::fs::remove_file(dir.join("b.vcl")).unwrap();
+        backend.reindex_files(vec![], vec![b_uri.clone()]).await;
+        assert!(!backend.document_map.contains_key(&b_uri));
+        let scope = backend
+            .get_all_definitions_across_all_documents(Some(&main_uri))
+            .await;
+        assert!(!scope.properties.contains_key("from_a"));
+
+        // files nothing includes are not indexed
+        let other_uri = Url::from_file_path(dir.join("other.vcl")).unwrap();
+        std::fs::write(dir.join("other.vcl"), "sub other {}\n").unwrap();
+        backend.reindex_files(vec![other_uri.clone()], vec![]).await;
+        assert!(!backend.document_map.contains_key(&other_uri));
+
+        // open documents are kept when deleted on disk
+        backend.open_documents.insert(main_uri.clone());
+        backend.reindex_files(vec![], vec![main_uri.clone()]).await;
+        assert!(backend.document_map.contains_key(&main_uri));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn recursive_includes_through_symlinks() {
+        let (backend, dir) = backend_with_files(
+            "symlinks",
+            &[
+                ("main.vcl", "vcl 4.1;\ninclude \"a.vcl\";\n"),
+                ("a.vcl", "include \"loop/a.vcl\";\ninclude \"b.vcl\";\n"),
+                ("b.vcl", "include \"loop/main.vcl\";\n"),
+            ],
+            // «loop/» is the directory itself, so «loop/a.vcl», «loop/loop/a.vcl» etc. are all
+            // a.vcl
+            |dir, _, _| std::os::unix::fs::symlink(dir, dir.join("loop")).unwrap(),
+        )
+        .await;
+        let main_uri = Url::from_file_path(dir.join("main.vcl")).unwrap();
+        let a_uri = Url::from_file_path(dir.join("a.vcl")).unwrap();
+        let b_uri = Url::from_file_path(dir.join("b.vcl")).unwrap();
+        let mut uris = backend
+            .document_map
+            .iter()
+            .map(|doc| doc.url.clone())
+            .collect::<Vec<_>>();
+        uris.sort();
+        assert_eq!(uris, vec![a_uri.clone(), b_uri.clone(), main_uri.clone()]);
+        assert!(backend.too_deep_includes.is_empty());
+        // the includes through the symlink keep their url as written
+        let loop_a_uri = Url::from_file_path(dir.join("loop").join("a.vcl")).unwrap();
+        assert_eq!(
+            backend.included_from.get(&loop_a_uri).unwrap()[0].uri,
+            a_uri
+        );
+
+        let scope = backend
+            .get_all_definitions_across_all_documents(Some(&main_uri))
+            .await;
+        let diagnostics = backend
+            .get_diagnostics_for(&[main_uri.clone(), a_uri.clone(), b_uri.clone()], &scope)
+            .await;
+        let messages = |uri: &Url| {
+            diagnostics
+                .iter()
+                .filter(|(diagnostics_uri, _, _)| diagnostics_uri == uri)
+                .flat_map(|(_, diagnostics, _)| diagnostics)
+                .map(|diagnostic| (diagnostic.range.start.line, diagnostic.message.clone()))
+                .collect::<Vec<_>>()
+        };
+        // reported with the path as written
+        assert_eq!(
+            messages(&a_uri),
+            vec![(
+                0,
+                "Recursive include of «loop/a.vcl», which is this file or includes it".to_string()
+            )]
+        );
+        assert_eq!(
+            messages(&b_uri),
+            vec![(
+                0,
+                "Recursive include of «loop/main.vcl», which is this file or includes it"
+                    .to_string()
+            )]
+        );
+        assert!(messages(&main_uri).is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn includes_nested_deeper_than_max_include_depth() {
+        let (backend, dir) = backend_with_files(
+            "depth",
+            &[
+                ("main.vcl", "vcl 4.1;\ninclude \"a.vcl\";\n"),
+                ("a.vcl", "include \"b.vcl\";\n"),
+                ("b.vcl", "sub from_b {}\ninclude \"c.vcl\";\n"),
+                ("c.vcl", "sub from_c {}\n"),
+            ],
+            |_, _, config| config.max_include_depth = 2,
+        )
+        .await;
+        let main_uri = Url::from_file_path(dir.join("main.vcl")).unwrap();
+        let b_uri = Url::from_file_path(dir.join("b.vcl")).unwrap();
+        let c_uri = Url::from_file_path(dir.join("c.vcl")).unwrap();
+        assert!(backend.document_map.contains_key(&b_uri));
+        assert!(!backend.document_map.contains_key(&c_uri));
+
+        let scope = backend
+            .get_all_definitions_across_all_documents(Some(&main_uri))
+            .await;
+        assert!(scope.properties.contains_key("from_b"));
+        assert!(!scope.properties.contains_key("from_c"));
+        let diagnostics = backend
+            .get_diagnostics_for(std::slice::from_ref(&b_uri), &scope)
+            .await;
+        let (_, diagnostics, _) = &diagnostics[0];
+        let too_deep = diagnostics
+            .iter()
+            .filter(|diagnostic| {
+                diagnostic.message
+                    == "Include not read, it is nested deeper than the max include depth (2)"
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(too_deep.len(), 1, "{diagnostics:?}");
+        assert_eq!(too_deep[0].range.start, Position::new(1, 8));
+        assert_eq!(too_deep[0].severity, Some(DiagnosticSeverity::WARNING));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn diagnostics_on_setting_applies_immediately() {
+        let backend: Backend = Default::default();
+        assert_eq!(
+            backend.config.read().await.diagnostics_on,
+            DiagnosticsOn::Change
+        );
+
+        backend
+            .did_change_configuration(DidChangeConfigurationParams {
+                settings: serde_json::json!({ "varnishls": { "diagnosticsOn": "save" } }),
+            })
+            .await;
+        assert_eq!(
+            backend.config.read().await.diagnostics_on,
+            DiagnosticsOn::Save
+        );
+
+        // other settings, null and settings of other servers leave it as is
+        for settings in [
+            serde_json::json!({ "maxIncludeDepth": 5 }),
+            serde_json::Value::Null,
+            serde_json::json!({ "format": "prettier", "files": { "exclude": [] } }),
+        ] {
+            backend
+                .did_change_configuration(DidChangeConfigurationParams { settings })
+                .await;
+            assert_eq!(
+                backend.config.read().await.diagnostics_on,
+                DiagnosticsOn::Save
+            );
+        }
+
+        backend
+            .did_change_configuration(DidChangeConfigurationParams {
+                settings: serde_json::json!({ "diagnosticsOn": "change" }),
+            })
+            .await;
+        assert_eq!(
+            backend.config.read().await.diagnostics_on,
+            DiagnosticsOn::Change
+        );
+    }
+
+    #[tokio::test]
+    async fn analyze_snippet_without_loading_it() {
+        let (backend, dir) = backend_with_files(
+            "snippet",
+            &[(
+                "backends.vcl",
+                "vcl 4.1;\nbackend web { .host = \"localhost\"; }\n",
+            )],
+            |_, _, _| {},
+        )
+        .await;
+
+        let diagnostics = backend
+            .analyze_snippet(
+                Url::parse("untitled:vcl.inline").unwrap(),
+                r#"vcl 4.1;
+include "backends.vcl";
+sub vcl_recv {
+    set req.backend_hint = web;
+    set req.backend_hint = api;
+}
+"#
+                .to_string(),
+            )
+            .await;
+        println!("diagnostics: {diagnostics:?}");
+        // the backend from the include is known, positions are relative to the snippet
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.range.start.line != 3));
+        assert!(diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.range.start == Position::new(4, 27)));
+        // neither the snippet nor its include are kept
+        assert!(backend.document_map.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn embedded_vcl_diagnostics_without_loading_it() {
+        let backend: Backend = Default::default();
+        let uri = Url::parse("file:///test.vtc").unwrap();
+        let doc = Document::new(
+            uri.clone(),
+            r#"varnishtest "embedded vcl"
+
+varnish v1 -vcl {
+    backend b { .host = "localhost"; }
+    sub vcl_recv {
+        set req.backend_hint = nope;
+    }
+} -start
+"#
+            .to_string(),
+            None,
+        );
+        backend.document_map.insert(uri.clone(), doc);
+
+        let scope = backend
+            .get_all_definitions_across_all_documents(Some(&uri))
+            .await;
+        let diagnostics = backend
+            .get_diagnostics_for(std::slice::from_ref(&uri), &scope)
+            .await;
+        println!("diagnostics: {diagnostics:?}");
+        // the embedded vcl is not added to the document map, e.g. for references to find
+        assert_eq!(backend.document_map.len(), 1);
+        let (diagnostics_uri, diagnostics, _version) = &diagnostics[0];
+        assert_eq!(diagnostics_uri, &uri);
+        assert!(diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.range.start == Position::new(5, 31)));
+    }
+
+    #[tokio::test]
+    async fn diagnostics_of_file_included_twice_are_published_once() {
+        let (backend, dir) = backend_with_files(
+            "dedup",
+            &[
+                (
+                    "main.vcl",
+                    r#"vcl 4.1;
+sub vcl_recv {
+    include "included.vcl";
+    if (req.http.a) {
+        include "included.vcl";
+    }
+}
+"#,
+                ),
+                // the same error twice, at different ranges
+                (
+                    "included.vcl",
+                    "sub included {\n    set req.http.y = ;\n    set req.http.z = ;\n}\n",
+                ),
+            ],
+            |_, _, _| {},
+        )
+        .await;
+        let main_uri = Url::from_file_path(dir.join("main.vcl")).unwrap();
+        let included_uri = Url::from_file_path(dir.join("included.vcl")).unwrap();
+
+        let scope = backend
+            .get_all_definitions_across_all_documents(Some(&main_uri))
+            .await;
+        let diagnostics = backend
+            .get_diagnostics_for(&[main_uri.clone(), included_uri.clone()], &scope)
+            .await;
+        println!("diagnostics: {diagnostics:?}");
+        let (uri, main_diagnostics, _version) = &diagnostics[0];
+        assert_eq!(uri, &main_uri);
+        assert!(main_diagnostics.is_empty());
+        // published against the included file, once per range
+        let (uri, included_diagnostics, _version) = &diagnostics[1];
+        assert_eq!(uri, &included_uri);
+        assert_eq!(
+            included_diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.range.start.line)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            included_diagnostics[0].message,
+            included_diagnostics[1].message
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn vmod_stub_dir_per_server() {
+        let backend: Backend = Default::default();
+        let other_backend: Backend = Default::default();
+        let stub_dir = backend.get_vmod_stub_dir().await.unwrap().to_owned();
+        assert_eq!(backend.get_vmod_stub_dir().await.unwrap(), stub_dir);
+        assert_ne!(other_backend.get_vmod_stub_dir().await.unwrap(), stub_dir);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::symlink_metadata(&stub_dir).unwrap();
+            assert!(metadata.is_dir());
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+        }
+
+        std::fs::remove_dir_all(&stub_dir).unwrap();
+        std::fs::remove_dir_all(other_backend.get_vmod_stub_dir().await.unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn degraded_mode_without_grammar() {
+        let (backend, dir) = backend_with_files(
+            "degraded",
+            &[
+                ("libvmod_std.so", ""),
+                ("vmod_xkey.vcc", ""),
+                ("main.vcl", "vcl 4.1;\n"),
+            ],
+            |dir, backend, config| {
+                backend.grammar_error = Some("Incompatible language version".to_string());
+                config.vmod_paths = vec![dir.to_owned()];
+                config.vcc_paths = vec![dir.to_owned()];
+            },
+        )
+        .await;
+        let uri = Url::from_file_path(dir.join("main.vcl")).unwrap();
+
+        // documents are not parsed, neither included nor opened ones
+        assert!(backend.document_map.is_empty());
+        backend
+            .did_open(DidOpenTextDocumentParams {
+                text_document: TextDocumentItem::new(
+                    uri.clone(),
+                    "vcl".to_string(),
+                    1,
+                    "vcl 4.1;\nimport ".to_string(),
+                ),
+            })
+            .await;
+        assert!(backend.document_map.is_empty());
+
+        let position = TextDocumentPositionParams::new(
+            TextDocumentIdentifier::new(uri.clone()),
+            Position::new(1, 7),
+        );
+        let hover = backend
+            .hover(HoverParams {
+                text_document_position_params: position.clone(),
+                work_done_progress_params: Default::default(),
+            })
+            .await
+            .unwrap();
+        assert!(hover.is_none());
+
+        // vmods are still completed from the vmod and vcc paths
+        let Some(CompletionResponse::Array(completions)) = backend
+            .completion(CompletionParams {
+                text_document_position: position,
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: None,
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected completions");
+        };
+        let labels = completions
+            .iter()
+            .map(|completion| completion.label.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec!["std", "xkey"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
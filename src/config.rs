@@ -2,10 +2,14 @@ use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer, Serialize,
 };
+use std::collections::BTreeMap;
 use std::fmt;
 use std::path::PathBuf;
 use tower_lsp::lsp_types::DiagnosticSeverity;
 
+use crate::varnish_builtins::{VarnishFlavor, HTTP_HEADERS, HTTP_METHODS};
+use crate::vmod::{parse_vmod_type_name, VmodTypeMappings, VMOD_TYPE_NAMES};
+
 fn default_vcl_paths() -> Vec<PathBuf> {
     vec!["./".into()]
 }
@@ -25,6 +29,32 @@ fn default_vmod_paths() -> Vec<PathBuf> {
     ]
 }
 
+fn default_workspace_indexing() -> bool {
+    true
+}
+
+fn default_max_include_depth() -> usize {
+    25
+}
+
+fn default_implicit_return_hints() -> bool {
+    true
+}
+
+fn default_http_methods() -> Vec<String> {
+    HTTP_METHODS
+        .iter()
+        .map(|method| method.to_string())
+        .collect()
+}
+
+fn default_http_headers() -> Vec<String> {
+    HTTP_HEADERS
+        .iter()
+        .map(|header| header.to_string())
+        .collect()
+}
+
 fn default_vcc_paths() -> Vec<PathBuf> {
     let default_from_env = std::env::var("VARNISHLS_VCC_PATHS")
         .map(|env_str| env_str.split(';').map(Into::into).collect::<Vec<PathBuf>>())
@@ -42,18 +72,192 @@ fn default_vcc_paths() -> Vec<PathBuf> {
     ]
 }
 
+/// A list of paths, or a colon-separated string of them
+fn deserialize_search_paths<'de, D>(deserializer: D) -> Result<Vec<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SearchPaths {
+        List(Vec<PathBuf>),
+        ColonSeparated(String),
+    }
+
+    Ok(match SearchPaths::deserialize(deserializer)? {
+        SearchPaths::List(paths) => paths,
+        SearchPaths::ColonSeparated(paths) => paths
+            .split(':')
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .collect(),
+    })
+}
+
+/// Delimiters of placeholders left by a templating step (e.g. «${» and «}» for envsubst)
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TemplatePlaceholder {
+    pub start: String,
+    pub end: String,
+}
+
+/// Where the opening brace of subroutines, backends, probes and acls goes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub enum BraceStyle {
+    #[default]
+    SameLine,
+    NextLine,
+}
+
+impl BraceStyle {
+    fn parse(brace_style: &str) -> Option<Self> {
+        match brace_style {
+            "same-line" => Some(BraceStyle::SameLine),
+            "next-line" => Some(BraceStyle::NextLine),
+            _ => None,
+        }
+    }
+}
+
+/// When diagnostics are computed and published
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticsOn {
+    /// on every change, while typing
+    #[default]
+    Change,
+    /// when the document is opened or saved. they are cleared while it is edited
+    Save,
+}
+
+/// Formatter style. Indentation not set here follows the client's formatting options
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct FormatConfig {
+    #[serde(default, alias = "indentWidth")]
+    pub indent_width: Option<usize>,
+    #[serde(default, alias = "useTabs")]
+    pub use_tabs: Option<bool>,
+    /// "same-line" (default) or "next-line"
+    #[serde(default, alias = "braceStyle")]
+    pub brace_style: Option<String>,
+    /// blank lines in a row beyond this are removed
+    #[serde(default, alias = "maxBlankLines")]
+    pub max_blank_lines: Option<usize>,
+}
+
+impl FormatConfig {
+    /// The configured brace style, or the default one if it is unknown
+    pub fn brace_style(&self) -> BraceStyle {
+        self.brace_style
+            .as_deref()
+            .and_then(BraceStyle::parse)
+            .unwrap_or_default()
+    }
+
+    /// Problems with the config, to warn about. The formatter ignores the invalid values
+    pub fn validate(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if let Some(ref brace_style) = self.brace_style {
+            if BraceStyle::parse(brace_style).is_none() {
+                warnings.push(format!(
+                    "Unknown format.brace_style «{brace_style}» (expected «same-line» or «next-line»)"
+                ));
+            }
+        }
+        if self.indent_width == Some(0) {
+            warnings.push("format.indent_width should be at least 1".to_string());
+        }
+        warnings
+    }
+}
+
+/// Type names of in-house vmods mapped to a known vmod type name, e.g. `MY_ENUM = "STRING"`
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct VmodTypesConfig {
+    /// argument types
+    #[serde(default)]
+    pub args: BTreeMap<String, String>,
+    /// return types
+    #[serde(default)]
+    pub returns: BTreeMap<String, String>,
+}
+
+impl VmodTypesConfig {
+    /// The mappings to known types. Mappings to unknown type names are left out
+    pub fn mappings(&self) -> VmodTypeMappings {
+        let parse = |mappings: &BTreeMap<String, String>| {
+            mappings
+                .iter()
+                .filter_map(|(name, type_name)| {
+                    Some((name.clone(), parse_vmod_type_name(type_name)?))
+                })
+                .collect()
+        };
+        VmodTypeMappings {
+            args: parse(&self.args),
+            returns: parse(&self.returns),
+        }
+    }
+
+    /// Problems with the config, to warn about
+    pub fn validate(&self) -> Vec<String> {
+        [("args", &self.args), ("returns", &self.returns)]
+            .into_iter()
+            .flat_map(|(kind, mappings)| {
+                mappings
+                    .iter()
+                    .filter(|(_, type_name)| parse_vmod_type_name(type_name).is_none())
+                    .map(move |(name, type_name)| {
+                        format!(
+                            "Unknown type «{type_name}» for vmod_types.{kind}.{name} (expected one of {})",
+                            VMOD_TYPE_NAMES.join(", ")
+                        )
+                    })
+            })
+            .collect()
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
     #[serde(default)]
     pub main_vcl: Option<PathBuf>,
-    #[serde(default = "default_vcl_paths")]
+    /// directories includes are looked for in, after the directory of the including file. also
+    /// accepts varnishd's colon-separated `vcl_path` parameter, e.g. "/etc/varnish:/usr/share/varnish/vcl"
+    #[serde(
+        default = "default_vcl_paths",
+        alias = "vcl_path",
+        deserialize_with = "deserialize_search_paths"
+    )]
     pub vcl_paths: Vec<PathBuf>,
     #[serde(default = "default_vmod_paths")]
     pub vmod_paths: Vec<PathBuf>,
     #[serde(default = "default_vcc_paths")]
     pub vcc_paths: Vec<PathBuf>,
+    /// read the main vcl and look up symbols across all loaded documents. when disabled, only
+    /// the open document and its includes are analyzed
+    #[serde(default = "default_workspace_indexing")]
+    pub workspace_indexing: bool,
+    /// placeholders treated as opaque identifiers when parsing, instead of syntax errors
+    #[serde(default)]
+    pub template_placeholders: Vec<TemplatePlaceholder>,
+    /// varnishd binary to check vcl with `varnishd -C`, on request. disabled when not set
+    #[serde(default)]
+    pub varnishd_path: Option<PathBuf>,
+    /// includes nested deeper than this are not read, in case cycle detection misses a cycle
+    #[serde(default = "default_max_include_depth")]
+    pub max_include_depth: usize,
+    /// show what builtin subroutines falling through their end return, e.g. «⟶ return(lookup)»
+    #[serde(default = "default_implicit_return_hints")]
+    pub implicit_return_hints: bool,
+    #[serde(default)]
+    pub diagnostics_on: DiagnosticsOn,
     #[serde(default)]
     pub lint: LintConfig,
+    #[serde(default)]
+    pub format: FormatConfig,
+    #[serde(default)]
+    pub vmod_types: VmodTypesConfig,
 }
 
 impl Default for Config {
@@ -62,6 +266,52 @@ impl Default for Config {
     }
 }
 
+impl Config {
+    pub fn apply_initialization_options(&mut self, options: InitializationOptions) {
+        if let Some(workspace_indexing) = options.workspace_indexing {
+            self.workspace_indexing = workspace_indexing;
+        }
+        if let Some(template_placeholders) = options.template_placeholders {
+            self.template_placeholders = template_placeholders;
+        }
+        if let Some(max_include_depth) = options.max_include_depth {
+            self.max_include_depth = max_include_depth;
+        }
+        if let Some(implicit_return_hints) = options.implicit_return_hints {
+            self.implicit_return_hints = implicit_return_hints;
+        }
+        if let Some(diagnostics_on) = options.diagnostics_on {
+            self.diagnostics_on = diagnostics_on;
+        }
+        if let Some(format) = options.format {
+            self.format = format;
+        }
+        if let Some(vmod_types) = options.vmod_types {
+            self.vmod_types = vmod_types;
+        }
+    }
+}
+
+/// Options passed by the client in the initialize request, overriding .varnishls.toml
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InitializationOptions {
+    #[serde(default)]
+    pub workspace_indexing: Option<bool>,
+    #[serde(default)]
+    pub template_placeholders: Option<Vec<TemplatePlaceholder>>,
+    #[serde(default)]
+    pub max_include_depth: Option<usize>,
+    #[serde(default)]
+    pub implicit_return_hints: Option<bool>,
+    #[serde(default)]
+    pub diagnostics_on: Option<DiagnosticsOn>,
+    #[serde(default)]
+    pub format: Option<FormatConfig>,
+    #[serde(default)]
+    pub vmod_types: Option<VmodTypesConfig>,
+}
+
 #[derive(Clone, Debug, Serialize, PartialEq)]
 pub enum LintLevel {
     // Enabled,
@@ -147,6 +397,44 @@ pub struct LintConfig {
     pub prefer_lowercase_headers: LintLevel,
     #[serde(default = "LintLevel::disabled")]
     pub prefer_custom_headers_without_prefix: LintLevel,
+    /// headers set and then unset without being read, or unset twice
+    #[serde(default = "LintLevel::hint")]
+    pub redundant_header_writes: LintLevel,
+    #[serde(default = "LintLevel::hint")]
+    pub empty_builtin_sub: LintLevel,
+    /// backends with a long .connect_timeout, but the default .first_byte_timeout
+    #[serde(default = "LintLevel::disabled")]
+    pub untuned_byte_timeouts: LintLevel,
+    /// methods req.method and bereq.method can be compared with without a hint
+    #[serde(default = "default_http_methods")]
+    pub http_methods: Vec<String>,
+    /// backends and probes nothing refers to, apart from the default backend
+    #[serde(default = "LintLevel::disabled")]
+    pub unused_backends_and_probes: LintLevel,
+    /// std.fileread() called per request, which only reads the file on its first call
+    #[serde(default = "LintLevel::disabled")]
+    pub fileread_in_request: LintLevel,
+    /// acl entries covered by another entry, e.g. «"10.1.2.3"» after «"10.0.0.0"/8»
+    #[serde(default = "LintLevel::disabled")]
+    pub redundant_acl_entries: LintLevel,
+    /// header names one or two edits away from one of `http_headers`, e.g. «Conten-Type»
+    #[serde(default = "LintLevel::disabled")]
+    pub misspelled_headers: LintLevel,
+    /// headers misspelled_headers compares header names with
+    #[serde(default = "default_http_headers")]
+    pub http_headers: Vec<String>,
+    #[serde(default = "LintLevel::disabled")]
+    pub path_outside_sandbox: LintLevel,
+    /// directories includes, imports and std.fileread are allowed to read from
+    #[serde(default)]
+    pub sandbox_paths: Vec<PathBuf>,
+    /// the varnish release checked against, e.g. for the return actions of each subroutine
+    #[serde(default)]
+    pub flavor: VarnishFlavor,
+    /// labels «return (vcl(label))» may switch to, set up outside of vcl with
+    /// «varnishadm vcl.label». Labels are not checked if unset
+    #[serde(default)]
+    pub vcl_labels: Option<Vec<String>>,
 }
 
 impl Default for LintConfig {
@@ -156,6 +444,19 @@ impl Default for LintConfig {
             prefer_else_if: LintLevel::Hint,
             prefer_lowercase_headers: LintLevel::Hint,
             prefer_custom_headers_without_prefix: LintLevel::Disabled,
+            redundant_header_writes: LintLevel::Hint,
+            empty_builtin_sub: LintLevel::Hint,
+            untuned_byte_timeouts: LintLevel::Disabled,
+            http_methods: default_http_methods(),
+            unused_backends_and_probes: LintLevel::Disabled,
+            redundant_acl_entries: LintLevel::Disabled,
+            fileread_in_request: LintLevel::Disabled,
+            misspelled_headers: LintLevel::Disabled,
+            http_headers: default_http_headers(),
+            path_outside_sandbox: LintLevel::Disabled,
+            sandbox_paths: vec![],
+            flavor: VarnishFlavor::default(),
+            vcl_labels: None,
         }
     }
 }
@@ -163,6 +464,7 @@ impl Default for LintConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::varnish_builtins::Type;
 
     fn assert_config_defaults(config: Config) {
         assert_eq!(
@@ -192,9 +494,138 @@ mod tests {
     fn empty_config_with_defaults() {
         let config = Config::default();
         println!("{:?}", config);
+        assert!(config.workspace_indexing);
         assert_config_defaults(config);
     }
 
+    #[test]
+    fn initialization_options_override_config() {
+        let mut config: Config = toml::from_str("workspace_indexing = true").unwrap();
+        config.apply_initialization_options(Default::default());
+        assert!(config.workspace_indexing);
+        assert_eq!(config.max_include_depth, 25);
+
+        let options: InitializationOptions =
+            serde_json::from_str(r#"{ "workspaceIndexing": false }"#).unwrap();
+        config.apply_initialization_options(options);
+        assert!(!config.workspace_indexing);
+        assert!(config.template_placeholders.is_empty());
+
+        let options: InitializationOptions =
+            serde_json::from_str(r#"{ "templatePlaceholders": [{ "start": "${", "end": "}" }] }"#)
+                .unwrap();
+        config.apply_initialization_options(options);
+        assert_eq!(
+            config.template_placeholders,
+            vec![TemplatePlaceholder {
+                start: "${".into(),
+                end: "}".into()
+            }]
+        );
+
+        let options: InitializationOptions =
+            serde_json::from_str(r#"{ "maxIncludeDepth": 5 }"#).unwrap();
+        config.apply_initialization_options(options);
+        assert_eq!(config.max_include_depth, 5);
+
+        assert!(config.implicit_return_hints);
+        let options: InitializationOptions =
+            serde_json::from_str(r#"{ "implicitReturnHints": false }"#).unwrap();
+        config.apply_initialization_options(options);
+        assert!(!config.implicit_return_hints);
+
+        assert_eq!(config.diagnostics_on, DiagnosticsOn::Change);
+        let options: InitializationOptions =
+            serde_json::from_str(r#"{ "diagnosticsOn": "save" }"#).unwrap();
+        config.apply_initialization_options(options);
+        assert_eq!(config.diagnostics_on, DiagnosticsOn::Save);
+
+        let options: InitializationOptions = serde_json::from_str(
+            r#"{ "format": { "indentWidth": 2, "braceStyle": "next-line", "maxBlankLines": 1 } }"#,
+        )
+        .unwrap();
+        config.apply_initialization_options(options);
+        assert_eq!(config.format.indent_width, Some(2));
+        assert_eq!(config.format.use_tabs, None);
+        assert_eq!(config.format.brace_style(), BraceStyle::NextLine);
+        assert_eq!(config.format.max_blank_lines, Some(1));
+    }
+
+    #[test]
+    fn colon_separated_vcl_path() {
+        let config: Config =
+            toml::from_str(r#"vcl_path = "/etc/varnish:/usr/share/varnish/vcl""#).unwrap();
+        assert_eq!(
+            config.vcl_paths,
+            vec![
+                PathBuf::from("/etc/varnish"),
+                PathBuf::from("/usr/share/varnish/vcl")
+            ]
+        );
+        let config: Config = toml::from_str(r#"vcl_paths = ["./", "/etc/varnish"]"#).unwrap();
+        assert_eq!(
+            config.vcl_paths,
+            vec![PathBuf::from("./"), PathBuf::from("/etc/varnish")]
+        );
+    }
+
+    #[test]
+    fn validate_format_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [format]
+            indent_width = 0
+            use_tabs = true
+            brace_style = "allman"
+        "#,
+        )
+        .unwrap();
+        assert_eq!(config.format.use_tabs, Some(true));
+        assert_eq!(config.format.brace_style(), BraceStyle::SameLine);
+        assert_eq!(
+            config.format.validate(),
+            vec![
+                "Unknown format.brace_style «allman» (expected «same-line» or «next-line»)",
+                "format.indent_width should be at least 1",
+            ]
+        );
+        assert!(Config::default().format.validate().is_empty());
+    }
+
+    #[test]
+    fn vmod_type_mappings() {
+        let config: Config = toml::from_str(
+            r#"
+            [vmod_types]
+            args = { MY_KEY = "STRING", MY_FLAGS = "FLAGS" }
+            returns = { MY_RESULT = "DURATION" }
+        "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.vmod_types.validate(),
+            vec!["Unknown type «FLAGS» for vmod_types.args.MY_FLAGS (expected one of STRING, STRING_LIST, REGEX, STRANDS, BOOL, INT, REAL, IP, DURATION, TIME, BYTES, BLOB, BACKEND, PROBE, ACL, HEADER)"]
+        );
+        let mappings = config.vmod_types.mappings();
+        assert!(matches!(mappings.args.get("MY_KEY"), Some(Type::String)));
+        assert!(!mappings.args.contains_key("MY_FLAGS"));
+        assert!(matches!(
+            mappings.returns.get("MY_RESULT"),
+            Some(Type::Duration)
+        ));
+
+        let mut config = Config::default();
+        assert!(config.vmod_types.validate().is_empty());
+        let options: InitializationOptions =
+            serde_json::from_str(r#"{ "vmodTypes": { "returns": { "MY_RESULT": "INT" } } }"#)
+                .unwrap();
+        config.apply_initialization_options(options);
+        assert!(matches!(
+            config.vmod_types.mappings().returns.get("MY_RESULT"),
+            Some(Type::Number)
+        ));
+    }
+
     #[test]
     fn can_parse_lint_config() {
         let toml_str = r#"
@@ -202,6 +633,16 @@ mod tests {
             prefer_else_if = "warning"
             prefer_lowercase_headers = "info"
             prefer_custom_headers_without_prefix = false
+            redundant_header_writes = "warning"
+            empty_builtin_sub = false
+            untuned_byte_timeouts = "hint"
+            http_methods = ["GET", "REPORT"]
+            misspelled_headers = "hint"
+            http_headers = ["Content-Type", "X-Request-Id"]
+            path_outside_sandbox = "warning"
+            sandbox_paths = ["/etc/varnish"]
+            flavor = "varnish-7"
+            vcl_labels = ["l_example"]
         "#;
 
         let parsed: LintConfig = toml::from_str(toml_str).unwrap();
@@ -213,5 +654,24 @@ mod tests {
             parsed.prefer_custom_headers_without_prefix,
             LintLevel::Disabled
         );
+        assert_eq!(parsed.redundant_header_writes, LintLevel::Warning);
+        assert_eq!(parsed.empty_builtin_sub, LintLevel::Disabled);
+        assert_eq!(parsed.untuned_byte_timeouts, LintLevel::Hint);
+        assert_eq!(parsed.http_methods, vec!["GET", "REPORT"]);
+        assert_eq!(parsed.misspelled_headers, LintLevel::Hint);
+        assert_eq!(parsed.http_headers, vec!["Content-Type", "X-Request-Id"]);
+        assert_eq!(parsed.path_outside_sandbox, LintLevel::Warning);
+        assert_eq!(parsed.sandbox_paths, vec![PathBuf::from("/etc/varnish")]);
+        assert_eq!(parsed.flavor, VarnishFlavor::Varnish7);
+        assert_eq!(parsed.vcl_labels, Some(vec!["l_example".to_string()]));
+    }
+
+    #[test]
+    fn lint_config_defaults_to_known_http_methods() {
+        let parsed: LintConfig = toml::from_str("").unwrap();
+        assert!(parsed.http_methods.contains(&"PURGE".to_string()));
+        assert_eq!(parsed.http_methods, LintConfig::default().http_methods);
+        assert_eq!(parsed.flavor, VarnishFlavor::VarnishPlus60);
+        assert_eq!(parsed.vcl_labels, None);
     }
 }
@@ -1,22 +1,33 @@
 use crate::{
-    config::LintConfig,
-    parser,
-    safe_regex::{is_regex_safe, SafeRegexError},
+    config::{FormatConfig, LintConfig, TemplatePlaceholder},
+    formatter, parser,
+    safe_regex::{count_capture_groups, is_regex_safe, SafeRegexError},
     static_autocomplete_items,
     varnish_builtins::{
-        self, get_backend_field_types, get_probe_field_types, AutocompleteSearchOptions,
-        Definition, Definitions, HasTypeProperties, Type,
+        self, get_backend_field_min_vcl_version, get_backend_field_types, get_probe_field_types,
+        is_variable_read_only, AutocompleteSearchOptions, Definition, Definitions,
+        HasTypeProperties, Obj, Type, VarnishFlavor, VclVersion,
     },
 };
 
+use dashmap::DashMap;
 use log::{debug, error};
 use ropey::{iter::Chunks, Rope};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::sync::{Arc, Mutex};
-use std::{cmp::Ordering, iter::Iterator, path::PathBuf};
+use std::{
+    cmp::Ordering,
+    iter::Iterator,
+    path::{Component, Path, PathBuf},
+};
 use streaming_iterator::{convert as convert_to_streaming_iterator, StreamingIterator};
 use tower_lsp::lsp_types::*;
-use tree_sitter::{InputEdit, Node, Parser, Point, Query, QueryCursor, TextProvider, Tree};
+use tree_sitter::{
+    InputEdit, Node, Parser, Point, Query, QueryCursor, TextProvider, Tree, TreeCursor,
+};
 
 const VCL_QUERY: &str = include_str!("../vendor/tree-sitter-vcl/queries/semantic_tokens.scm");
 const VTC_QUERY: &str = include_str!("../vendor/tree-sitter-vtc/queries/highlights.scm"); // TODO:
@@ -31,12 +42,19 @@ pub enum FileType {
 pub struct Document {
     version: i32,
     parser: Arc<Mutex<Parser>>,
+    /// the text. its lines are the lines of LSP positions: a line ends with LF, CRLF or CR.
+    /// tree-sitter parses it with lone CRs as LFs, so its rows are the same lines.
     pub rope: Rope,
     pub ast: Tree,
     pub url: Url,
     pub path: Arc<PathBuf>,
     pub filetype: FileType,
     pub pos_from_main_doc: NestedPos,
+    template_placeholders: Arc<Vec<TemplatePlaceholder>>,
+    /// ranges of template placeholders, which are parsed as identifiers
+    placeholder_ranges: Vec<Range>,
+    /// changes which arrived before the version preceding them, by version
+    pending_changes: BTreeMap<i32, Vec<(Option<Range>, String)>>,
 }
 
 #[derive(Debug, Clone)]
@@ -44,16 +62,91 @@ pub struct Include {
     pub url: Option<Url>,
     pub path: PathBuf,
     pub nested_pos: NestedPos,
+    /// directory of the including document, searched before the vcl paths
+    pub including_dir: Option<PathBuf>,
 }
 
 unsafe impl Send for Include {}
 unsafe impl Sync for Include {}
 
+/// A vcl embedded in a varnishtest, e.g. «varnish v1 -vcl+backend { ... }»
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbeddedVcl {
+    /// the vcl as varnishtest loads it: `prefix` followed by the text within the braces
+    pub text: String,
+    /// declarations varnishtest adds in front of the vcl (the vcl version, and with
+    /// «-vcl+backend» a backend for each server), kept on the first line
+    pub prefix: String,
+    /// where the text within the braces starts in the varnishtest
+    pub start: Position,
+    /// where the text within the braces ends in the varnishtest
+    pub end: Position,
+    /// the varnish instance loading the vcl, e.g. «v1»
+    pub instance: String,
+    /// the «-vcl { ... }» argument in the varnishtest
+    pub range: Range,
+}
+
+impl EmbeddedVcl {
+    /// Whether a range in the varnishtest is within the embedded vcl
+    pub fn contains(&self, range: &Range) -> bool {
+        self.start <= range.start && range.end <= self.end
+    }
+
+    /// Map a range in the embedded vcl to the varnishtest. None for ranges within the prefix.
+    pub fn to_vtc_range(&self, range: Range) -> Option<Range> {
+        let prefix_len = self.prefix.chars().count() as u32;
+        let to_vtc_position = |position: Position| {
+            if position.line > 0 {
+                return Some(Position::new(
+                    position.line + self.start.line,
+                    position.character,
+                ));
+            }
+            Some(Position::new(
+                self.start.line,
+                self.start.character + position.character.checked_sub(prefix_len)?,
+            ))
+        };
+        Some(Range::new(
+            to_vtc_position(range.start)?,
+            to_vtc_position(range.end)?,
+        ))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct VmodImport {
     pub name: String,
     pub loc: Location,
     pub nested_pos: NestedPos,
+    /// path of the vmod shared object in «import x from "path"»
+    pub from: Option<PathBuf>,
+    /// name the vmod is used by in «import directors as d;»
+    pub alias: Option<String>,
+}
+
+/// A subroutine and the subroutines it calls
+#[derive(Debug, Clone)]
+pub struct SubCalls {
+    pub name: String,
+    /// location of the subroutine name
+    pub loc: Location,
+    /// called subroutine names and the location of the call statement
+    pub calls: Vec<(String, Location)>,
+}
+
+/// A backend or probe declaration, for finding the ones nothing refers to
+#[derive(Debug, Clone)]
+pub struct Declaration {
+    pub name: String,
+    /// «backend» or «probe»
+    pub kind: &'static str,
+    /// the whole declaration, up to the next line if nothing follows it
+    pub loc: Location,
+    /// position from the main vcl: the include statements leading to the document, and the
+    /// declaration itself
+    pub nested_pos: NestedPos,
 }
 
 #[derive(Debug, Clone)]
@@ -69,6 +162,10 @@ pub struct Reference {
 pub enum LintErrorInternalType {
     PreferElseIf = 1,
     PreferLowercaseHeader = 2,
+    MissingHttpObject = 3,
+    RegexComparedAsString = 4,
+    MisspelledHeader = 5,
+    UnusedDeclaration = 6,
 }
 
 #[derive(Debug)]
@@ -77,6 +174,297 @@ pub struct LintError {
     pub severity: DiagnosticSeverity,
     pub loc: Location,
     pub data: Option<DiagnosticData>,
+    pub related_information: Option<Vec<DiagnosticRelatedInformation>>,
+    /// e.g. deprecated, for editors to strike through
+    pub tags: Option<Vec<DiagnosticTag>>,
+}
+
+impl LintError {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        Diagnostic {
+            range: self.loc.range,
+            severity: Some(self.severity),
+            message: self.message.to_owned(),
+            data: self
+                .data
+                .as_ref()
+                .and_then(|data| serde_json::to_value(data).ok()),
+            related_information: self.related_information.to_owned(),
+            tags: self.tags.to_owned(),
+            ..Diagnostic::default()
+        }
+    }
+}
+
+/**
+ * Remove duplicate lint errors, e.g. from analyzing a file which is included multiple times.
+ * Lint errors are keyed by uri, range and message.
+ */
+pub fn dedup_lint_errors(lint_errors: Vec<LintError>) -> Vec<LintError> {
+    let mut seen = HashSet::new();
+    lint_errors
+        .into_iter()
+        .filter(|lint_error| {
+            let range = lint_error.loc.range;
+            seen.insert((
+                lint_error.loc.uri.clone(),
+                (range.start.line, range.start.character),
+                (range.end.line, range.end.character),
+                lint_error.message.clone(),
+            ))
+        })
+        .collect()
+}
+
+/**
+ * Find the builtin subroutines (e.g. «vcl_recv») a subroutine is called from, directly or
+ * through other subroutines
+ */
+pub fn get_calling_builtin_subs<'a>(
+    all_sub_calls: &'a [SubCalls],
+    sub_name: &str,
+) -> BTreeSet<&'a str> {
+    let mut builtin_subs = BTreeSet::new();
+    let mut visited: HashSet<&str> = HashSet::new();
+    let mut queue: VecDeque<&str> = VecDeque::from([sub_name]);
+    while let Some(callee) = queue.pop_front() {
+        for caller in all_sub_calls
+            .iter()
+            .filter(|caller| caller.calls.iter().any(|(name, _)| name == callee))
+        {
+            if !visited.insert(caller.name.as_str()) {
+                continue;
+            }
+            if caller.name.starts_with("vcl_") {
+                builtin_subs.insert(caller.name.as_str());
+            } else {
+                queue.push_back(caller.name.as_str());
+            }
+        }
+    }
+    builtin_subs
+}
+
+/**
+ * Find backends and probes nothing refers to (e.g. as a backend hint, in a director or as a
+ * backend's probe), in all documents. The first backend declared is the default backend, so it
+ * is used even if nothing refers to it.
+ */
+pub fn get_unused_declaration_errors(
+    declarations: &[Declaration],
+    referenced_idents: &HashSet<String>,
+    severity: DiagnosticSeverity,
+) -> Vec<LintError> {
+    let default_backend = declarations
+        .iter()
+        .filter(|declaration| declaration.kind == "backend")
+        .min_by(|a, b| a.nested_pos.cmp(&b.nested_pos));
+    declarations
+        .iter()
+        .filter(|declaration| !referenced_idents.contains(&declaration.name))
+        .filter(|declaration| {
+            default_backend.is_none_or(|default_backend| declaration.loc != default_backend.loc)
+        })
+        .map(|declaration| {
+            let Declaration {
+                name, kind, loc, ..
+            } = declaration;
+            LintError {
+                message: format!("[unused_backends_and_probes] «{name}» is never used"),
+                severity,
+                loc: loc.clone(),
+                data: Some(DiagnosticData {
+                    r#type: LintErrorInternalType::UnusedDeclaration,
+                    quickfix_label: format!("Remove unused {kind} «{name}»"),
+                    replace_with: String::new(),
+                    alternatives: vec![],
+                }),
+                related_information: None,
+                tags: None,
+            }
+        })
+        .collect()
+}
+
+/**
+ * Where each subroutine runs in the request flow, as an index into SUBROUTINE_EXECUTION_ORDER:
+ * of the builtin subroutine itself, or of the first builtin subroutine calling it (directly or
+ * through other subroutines). Subroutines nothing calls are left out.
+ */
+pub fn get_sub_execution_order(all_sub_calls: &[SubCalls]) -> HashMap<String, usize> {
+    let mut order = HashMap::new();
+    for (idx, builtin_sub) in varnish_builtins::SUBROUTINE_EXECUTION_ORDER
+        .iter()
+        .enumerate()
+    {
+        let mut stack = vec![builtin_sub.to_string()];
+        while let Some(name) = stack.pop() {
+            if order.contains_key(&name) {
+                continue;
+            }
+            stack.extend(
+                all_sub_calls
+                    .iter()
+                    .filter(|sub_calls| sub_calls.name == name)
+                    .flat_map(|sub_calls| sub_calls.calls.iter().map(|(call, _)| call.clone())),
+            );
+            order.insert(name, idx);
+        }
+    }
+    order
+}
+
+/**
+ * Find subroutines calling themselves, directly or through other subroutines, which Varnish
+ * refuses to load. Reports an error on each subroutine in a cycle, with the call path as
+ * related information.
+ */
+pub fn get_call_cycle_errors(all_sub_calls: &[SubCalls]) -> Vec<LintError> {
+    // builtin subroutines may be defined several times, which varnish appends to each other
+    let mut subs: BTreeMap<&str, SubCalls> = BTreeMap::new();
+    for sub_calls in all_sub_calls {
+        let sub = subs
+            .entry(sub_calls.name.as_str())
+            .or_insert_with(|| SubCalls {
+                calls: vec![],
+                ..sub_calls.clone()
+            });
+        for call in &sub_calls.calls {
+            if !sub.calls.contains(call) {
+                sub.calls.push(call.clone());
+            }
+        }
+    }
+
+    let mut errors = vec![];
+    for sub in subs.values() {
+        // breadth first search for the shortest path back to the sub.
+        // each path is a list of indexes into the calls of the subs along it
+        let mut queue: VecDeque<Vec<(&SubCalls, usize)>> = VecDeque::new();
+        let mut visited = HashSet::new();
+        queue.push_back(vec![]);
+        let mut cycle = None;
+        while let Some(path) = queue.pop_front() {
+            let current = match path.last() {
+                None => sub,
+                Some((caller, call_idx)) => match subs.get(caller.calls[*call_idx].0.as_str()) {
+                    Some(callee) => callee,
+                    // undefined subroutines are reported elsewhere
+                    None => continue,
+                },
+            };
+            if !path.is_empty() && current.name == sub.name {
+                cycle = Some(path);
+                break;
+            }
+            if !visited.insert(current.name.as_str()) {
+                continue;
+            }
+            for call_idx in 0..current.calls.len() {
+                let mut next_path = path.clone();
+                next_path.push((current, call_idx));
+                queue.push_back(next_path);
+            }
+        }
+
+        let Some(cycle) = cycle else {
+            continue;
+        };
+        let path_str = cycle
+            .iter()
+            .map(|(caller, _)| caller.name.as_str())
+            .chain([sub.name.as_str()])
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        errors.push(LintError {
+            message: format!("Recursive call: {path_str}"),
+            severity: DiagnosticSeverity::ERROR,
+            loc: sub.loc.clone(),
+            data: None,
+            related_information: Some(
+                cycle
+                    .iter()
+                    .map(|(caller, call_idx)| {
+                        let (callee, call_loc) = &caller.calls[*call_idx];
+                        DiagnosticRelatedInformation {
+                            location: call_loc.clone(),
+                            message: format!("{} calls {}", caller.name, callee),
+                        }
+                    })
+                    .collect(),
+            ),
+            tags: None,
+        });
+    }
+    errors
+}
+
+/// Deduplicate lint errors and group them as diagnostics by the uri of the file they belong to
+pub fn group_diagnostics_by_uri(lint_errors: Vec<LintError>) -> BTreeMap<Url, Vec<Diagnostic>> {
+    let mut diagnostics_by_uri: BTreeMap<Url, Vec<Diagnostic>> = BTreeMap::new();
+    for lint_error in dedup_lint_errors(lint_errors) {
+        diagnostics_by_uri
+            .entry(lint_error.loc.uri.clone())
+            .or_default()
+            .push(lint_error.to_diagnostic());
+    }
+    diagnostics_by_uri
+}
+
+/// Latest document version diagnostics have been published for, per uri. Diagnostics are
+/// analyzed concurrently, so a slow analysis of an old version can finish after a newer one.
+#[derive(Debug, Default)]
+pub struct PublishedDiagnosticsVersions(DashMap<Url, i32>);
+
+impl PublishedDiagnosticsVersions {
+    /// Returns false if diagnostics for a newer version have already been published,
+    /// otherwise records the version as the latest published one
+    pub fn should_publish(&self, uri: &Url, version: i32) -> bool {
+        let mut latest = self.0.entry(uri.clone()).or_insert(version);
+        if version < *latest {
+            return false;
+        }
+        *latest = version;
+        true
+    }
+
+    pub fn remove(&self, uri: &Url) {
+        self.0.remove(uri);
+    }
+
+    /// Documents diagnostics have been published for
+    pub fn uris(&self) -> Vec<Url> {
+        self.0.iter().map(|entry| entry.key().clone()).collect()
+    }
+}
+
+/// Diagnostics last published per uri, with the document version they were published for. When
+/// all open documents are analyzed again, most of their diagnostics are usually unchanged.
+#[derive(Debug, Default)]
+pub struct LastPublishedDiagnostics(DashMap<Url, (Option<i32>, Vec<Diagnostic>)>);
+
+impl LastPublishedDiagnostics {
+    /// Returns false if the same diagnostics have already been published for the version,
+    /// otherwise records them as the last published ones
+    pub fn should_publish(
+        &self,
+        uri: &Url,
+        version: Option<i32>,
+        diagnostics: &[Diagnostic],
+    ) -> bool {
+        if self.0.get(uri).is_some_and(|last| {
+            let (last_version, ref last_diagnostics) = *last;
+            last_version == version && last_diagnostics == diagnostics
+        }) {
+            return false;
+        }
+        self.0.insert(uri.clone(), (version, diagnostics.to_vec()));
+        true
+    }
+
+    pub fn remove(&self, uri: &Url) {
+        self.0.remove(uri);
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -84,10 +472,37 @@ pub struct DiagnosticData {
     pub r#type: LintErrorInternalType,
     pub quickfix_label: String,
     pub replace_with: String,
+    /// Other fixes for the same range, as (label, replacement)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternatives: Vec<(String, String)>,
 }
 
 pub type NestedPos = Vec<(usize, usize)>;
 
+/// Where a symbol in scope comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ScopeSource {
+    Builtin,
+    Vmod,
+    /// defined in vcl, e.g. subroutines, backends, acls and objects created with «new»
+    Document,
+}
+
+/// A symbol visible at a position, e.g. for debugging why something doesn't complete
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScopeSymbol {
+    pub name: String,
+    /// the resolved type, e.g. «STRING» or the signature of a function
+    pub r#type: String,
+    pub source: ScopeSource,
+    pub location: Option<Location>,
+}
+
+/// Comment directive selecting the varnish flavor of a document, e.g.
+/// «# varnish-lsp flavor: varnish-7»
+const FLAVOR_DIRECTIVE: &str = "varnish-lsp flavor:";
+
 // Reserved keywords: words you can't name e.g. a backend, subroutine etc.
 const RESERVED_KEYWORDS: &[&str] = &[
     "if", "set", "new", "call", "else", "elsif", "unset", "include", "return", "sub", "acl",
@@ -115,11 +530,468 @@ pub const LEGEND_TYPES: &[SemanticTokenType] = &[
 pub const LEGEND_MODIFIERS: &[SemanticTokenModifier] = &[
     SemanticTokenModifier::DEFAULT_LIBRARY,
     SemanticTokenModifier::DECLARATION,
+    SemanticTokenModifier::READONLY,
 ];
 
+/**
+ * Replace template placeholders (e.g. «${BACKEND_HOST}») with identifiers of the same byte
+ * length, so they parse as identifiers while keeping all positions intact. Returns the masked
+ * text and the ranges of the placeholders.
+ */
+fn mask_template_placeholders(
+    text: &str,
+    placeholders: &[TemplatePlaceholder],
+) -> (String, Vec<Range>) {
+    let mut masked = String::with_capacity(text.len());
+    let mut ranges = vec![];
+    let mut line = 0;
+    let mut line_start_byte = 0;
+    let mut idx = 0;
+    while idx < text.len() {
+        let rest = &text[idx..];
+        let placeholder_len = placeholders.iter().find_map(|placeholder| {
+            if placeholder.start.is_empty() {
+                return None;
+            }
+            let after_start = rest.strip_prefix(placeholder.start.as_str())?;
+            let end_idx = after_start.find(placeholder.end.as_str())?;
+            // placeholders never span lines
+            if after_start[..end_idx].contains('\n') {
+                return None;
+            }
+            Some(placeholder.start.len() + end_idx + placeholder.end.len())
+        });
+
+        if let Some(placeholder_len) = placeholder_len {
+            // identifiers have to start with a letter
+            let mut chars = rest[..placeholder_len].chars();
+            masked.push_str(&"x".repeat(chars.next().unwrap().len_utf8()));
+            for c in chars {
+                if c.is_ascii_alphanumeric() || c == '_' {
+                    masked.push(c);
+                } else {
+                    masked.push_str(&"x".repeat(c.len_utf8()));
+                }
+            }
+            let column = idx - line_start_byte;
+            ranges.push(Range::new(
+                Position::new(line, column as u32),
+                Position::new(line, (column + placeholder_len) as u32),
+            ));
+            idx += placeholder_len;
+            continue;
+        }
+
+        let c = rest.chars().next().unwrap();
+        masked.push(c);
+        idx += c.len_utf8();
+        if c == '\n' {
+            line += 1;
+            line_start_byte = idx;
+        }
+    }
+    (masked, ranges)
+}
+
+/**
+ * Replace lone CRs with LFs, for tree-sitter. It only ends rows at LFs, while the lines of LSP
+ * positions (and of the rope) also end at lone CRs. Byte offsets stay the same. `next_byte` is
+ * the byte following `text`, when parsing a chunk of the document.
+ */
+fn lone_crs_as_lf(text: &[u8], next_byte: Option<u8>) -> Cow<'_, [u8]> {
+    let is_lone_cr =
+        |idx: usize| text[idx] == b'\r' && text.get(idx + 1).copied().or(next_byte) != Some(b'\n');
+    if !(0..text.len()).any(is_lone_cr) {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(
+        (0..text.len())
+            .map(|idx| if is_lone_cr(idx) { b'\n' } else { text[idx] })
+            .collect(),
+    )
+}
+
+/// The text tree-sitter parses: lone CRs replaced, see lone_crs_as_lf()
+fn get_parse_text(text: &str) -> Cow<'_, str> {
+    match lone_crs_as_lf(text.as_bytes(), None) {
+        Cow::Borrowed(_) => Cow::Borrowed(text),
+        // only ascii bytes are replaced, so it is still utf-8
+        Cow::Owned(bytes) => Cow::Owned(String::from_utf8(bytes).unwrap()),
+    }
+}
+
+/// The operands of a chain of «+» (e.g. «a + b + c»), or the expression itself
+fn get_concatenated_nodes<'a>(rope: &Rope, node: Node<'a>) -> Vec<Node<'a>> {
+    let mut node = node;
+    while matches!(node.kind(), "expr" | "parenthesized_expression") {
+        match node.named_child(0) {
+            Some(child) => node = child,
+            None => break,
+        }
+    }
+    if node.kind() != "binary_expression" {
+        return vec![node];
+    }
+    let (Some(left_node), Some(operator_node), Some(right_node)) = (
+        node.child_by_field_name("left"),
+        node.child_by_field_name("operator"),
+        node.child_by_field_name("right"),
+    ) else {
+        return vec![node];
+    };
+    if get_node_text(rope, &operator_node) != "+" {
+        return vec![node];
+    }
+    let mut nodes = get_concatenated_nodes(rope, left_node);
+    nodes.append(&mut get_concatenated_nodes(rope, right_node));
+    nodes
+}
+
+/**
+ * Byte range of the first string in «text» not closed on its line, from its opening quote to
+ * the end of the line. Comments and multiline strings are skipped, as are the strings closed
+ * on their line, e.g. «"line one"» in «"line one" "line two;».
+ */
+fn find_unterminated_string(text: &str) -> Option<std::ops::Range<usize>> {
+    let mut idx = 0;
+    while idx < text.len() {
+        let rest = &text[idx..];
+        if rest.starts_with('#') || rest.starts_with("//") {
+            idx += rest.find('\n').unwrap_or(rest.len());
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            idx += comment.find("*/").map_or(rest.len(), |end| end + 4);
+        } else if let Some(string) = rest.strip_prefix(r#"""""#) {
+            idx += string.find(r#"""""#).map_or(rest.len(), |end| end + 6);
+        } else if let Some(string) = rest.strip_prefix(r#"{""#) {
+            idx += string.find(r#""}"#).map_or(rest.len(), |end| end + 4);
+        } else if let Some(string) = rest.strip_prefix('"') {
+            let line = &string[..string.find(['\n', '\r']).unwrap_or(string.len())];
+            let mut chars = line.char_indices();
+            let mut closing_quote = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    // the grammar accepts «\"» within strings
+                    '\\' => {
+                        chars.next();
+                    }
+                    '"' => {
+                        closing_quote = Some(i);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            match closing_quote {
+                Some(i) => idx += i + 2,
+                None => return Some(idx..idx + 1 + line.len()),
+            }
+        } else {
+            idx += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    None
+}
+
+/// The network of an acl entry, e.g. «"10.0.0.0"/8», with the address left-aligned in 128 bits
+/// so IPv4 and IPv6 networks compare alike. None for hostnames and invalid prefix lengths.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AclNetwork {
+    is_ipv4: bool,
+    bits: u128,
+    prefix_len: u32,
+}
+
+impl AclNetwork {
+    fn parse(address: &str, prefix_len: Option<&str>) -> Option<AclNetwork> {
+        let (is_ipv4, bits, max_prefix_len) = match address.parse::<std::net::IpAddr>().ok()? {
+            std::net::IpAddr::V4(ip) => (true, (u32::from(ip) as u128) << 96, 32),
+            std::net::IpAddr::V6(ip) => (false, u128::from(ip), 128),
+        };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u32>().ok()?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return None;
+        }
+        // varnish ignores the host bits, e.g. «"10.1.2.3"/8» is «"10.0.0.0"/8»
+        Some(AclNetwork {
+            is_ipv4,
+            bits: bits & Self::mask(prefix_len),
+            prefix_len,
+        })
+    }
+
+    fn mask(prefix_len: u32) -> u128 {
+        u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0)
+    }
+
+    fn contains(&self, other: &AclNetwork) -> bool {
+        self.is_ipv4 == other.is_ipv4
+            && self.prefix_len <= other.prefix_len
+            && other.bits & Self::mask(self.prefix_len) == self.bits
+    }
+}
+
+/**
+ * Indexes of redundant acl entries, and of the entry covering each of them. Varnish decides
+ * with the most specific entry matching an address, so an entry is redundant when the most
+ * specific other entry containing it is negated the same way. E.g. «"10.1.2.3"» is redundant
+ * after «"10.0.0.0"/8», but not with a «!"10.1.0.0"/16» in between.
+ */
+fn get_redundant_acl_entries(entries: &[(bool, AclNetwork)]) -> Vec<(usize, usize)> {
+    entries
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, (negated, network))| {
+            let (covering_idx, (covering_negated, _)) = entries
+                .iter()
+                .enumerate()
+                .filter(|(other_idx, (_, other))| {
+                    *other_idx != idx
+                        && other.contains(network)
+                        // of identical entries, the first one counts
+                        && (other.prefix_len < network.prefix_len || *other_idx < idx)
+                })
+                .max_by_key(|(other_idx, (_, other))| {
+                    (other.prefix_len, std::cmp::Reverse(*other_idx))
+                })?;
+            (covering_negated == negated).then_some((idx, covering_idx))
+        })
+        .collect()
+}
+
+/// Whether the statements always end in a return, e.g. in every branch of an if statement with
+/// an else. Calls are not followed.
+fn stmts_always_return<'a>(mut stmts: impl Iterator<Item = Node<'a>>) -> bool {
+    stmts.any(|stmt| {
+        let Some(stmt) = stmt.named_child(0) else {
+            return false;
+        };
+        match stmt.kind() {
+            "ret_stmt" => true,
+            "if_stmt" => {
+                let branch_stmts = |node: Node<'a>| {
+                    let mut cursor = node.walk();
+                    node.named_children(&mut cursor)
+                        .filter(|child| child.kind() == "stmt")
+                        .collect::<Vec<_>>()
+                };
+                let mut cursor = stmt.walk();
+                let branches = stmt
+                    .named_children(&mut cursor)
+                    .filter(|child| matches!(child.kind(), "elsif_stmt" | "else_stmt"))
+                    .collect::<Vec<_>>();
+                branches.iter().any(|branch| branch.kind() == "else_stmt")
+                    && stmts_always_return(branch_stmts(stmt).into_iter())
+                    && branches
+                        .iter()
+                        .all(|branch| stmts_always_return(branch_stmts(*branch).into_iter()))
+            }
+            _ => false,
+        }
+    })
+}
+
+/// The expression within expr and parenthesized_expression nodes, e.g. «x» for «((x))»
+fn unwrap_expr_node(node: Node) -> Node {
+    let mut node = node;
+    while matches!(node.kind(), "expr" | "parenthesized_expression")
+        && node.named_child_count() == 1
+    {
+        match node.named_child(0) {
+            Some(child) => node = child,
+            None => break,
+        }
+    }
+    node
+}
+
+/// The string literal of an argument value, unwrapping e.g. expr and literal nodes
+fn get_string_literal_node(node: Node) -> Option<Node> {
+    let mut node = node;
+    while node.kind() != "string" {
+        if node.named_child_count() != 1 {
+            return None;
+        }
+        node = node.named_child(0)?;
+    }
+    Some(node)
+}
+
+/**
+ * Get the calls before the identifier written at the end of a line, and the identifier, e.g.
+ * («["a.b", "c"]», «d») for «set req.http.x = a.b(1).c().d». None unless it follows a call.
+ */
+fn get_call_chain(line_prefix: &str) -> Option<(Vec<&str>, &str)> {
+    let before_partial =
+        line_prefix.trim_end_matches(|c: char| c.is_alphanumeric() || matches!(c, '_' | '-'));
+    let partial_ident = &line_prefix[before_partial.len()..];
+    let mut rest = before_partial.strip_suffix('.')?;
+
+    let mut calls = vec![];
+    loop {
+        if !rest.ends_with(')') {
+            return None;
+        }
+        // skip the arguments, to the matching «(»
+        let mut depth = 0;
+        let args_start = rest.char_indices().rev().find_map(|(idx, c)| {
+            match c {
+                ')' => depth += 1,
+                '(' => depth -= 1,
+                _ => {}
+            }
+            (depth == 0).then_some(idx)
+        })?;
+        rest = &rest[..args_start];
+
+        let before_call = rest.trim_end_matches(is_ident_char);
+        let call = &rest[before_call.len()..];
+        rest = before_call;
+        match call.strip_prefix('.') {
+            // a method of the object returned by the previous call, e.g. «.c» in «b().c()»
+            Some(method) if !method.is_empty() && rest.ends_with(')') => calls.push(method),
+            None if call.starts_with(|c: char| c.is_alphabetic()) => {
+                calls.push(call);
+                break;
+            }
+            _ => return None,
+        }
+    }
+    calls.reverse();
+    Some((calls, partial_ident))
+}
+
+/// e.g. «1 address» or «256 addresses»
+fn format_address_count(count: u64) -> String {
+    match count {
+        1 => "1 address".to_string(),
+        count => format!("{count} addresses"),
+    }
+}
+
+/// The label of «return (vcl(label))», unwrapping the expr node
+fn get_label_ident(node: Node) -> Option<Node> {
+    let mut node = node;
+    while node.kind() == "expr" {
+        if node.named_child_count() != 1 {
+            return None;
+        }
+        node = node.named_child(0)?;
+    }
+    (node.kind() == "ident").then_some(node)
+}
+
+/// The bool literal of a value (e.g. «true»), unwrapping expr and literal nodes
+fn get_bool_literal_node(node: Node) -> Option<Node> {
+    let mut node = node;
+    while node.kind() != "bool" {
+        if node.named_child_count() != 1 {
+            return None;
+        }
+        node = node.named_child(0)?;
+    }
+    Some(node)
+}
+
+/// Strip the quotes of a string literal («"a"», «{"a"}» or «"""a"""»)
+fn unquote_string(text: &str) -> &str {
+    text.strip_prefix("\"\"\"")
+        .and_then(|text| text.strip_suffix("\"\"\""))
+        .or_else(|| {
+            text.strip_prefix("{\"")
+                .and_then(|text| text.strip_suffix("\"}"))
+        })
+        .or_else(|| {
+            text.strip_prefix('"')
+                .and_then(|text| text.strip_suffix('"'))
+        })
+        .unwrap_or(text)
+}
+
+/// A well-known header name, lowercased if the misspelled one is (e.g. «content-type»)
+fn match_header_case(misspelled: &str, header: &str) -> String {
+    if misspelled.chars().any(char::is_uppercase) {
+        header.to_string()
+    } else {
+        header.to_lowercase()
+    }
+}
+
+/// The candidate closest to a misspelled name, if it is close enough to be a typo
+fn get_closest_name<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).clamp(1, 3);
+    get_closest_name_within(name, candidates, max_distance)
+}
+
+/// The candidate closest to a name, at most max_distance edits away
+fn get_closest_name_within<'a>(
+    name: &str,
+    candidates: impl Iterator<Item = &'a str>,
+    max_distance: usize,
+) -> Option<&'a str> {
+    candidates
+        .map(|candidate| (get_edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance between two strings
+fn get_edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut distances = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut previous = distances[0];
+        distances[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous + usize::from(a_char != *b_char);
+            previous = distances[j + 1];
+            distances[j + 1] = substitution.min(distances[j] + 1).min(previous + 1);
+        }
+    }
+    distances[b.len()]
+}
+
+/// Whether a string compared with «==» was probably meant as a regex
+fn looks_like_regex(text: &str) -> bool {
+    text.starts_with('^')
+        || (text.ends_with('$') && !text.ends_with("\\$"))
+        || [".*", ".+", "\\.", "(?i)"]
+            .iter()
+            .any(|pattern| text.contains(pattern))
+}
+
+/// Whether an url includes a scheme or host, e.g. «http://example.com/» or «example.com/a»
+fn url_has_host(url: &str) -> bool {
+    if url.starts_with('/') {
+        // protocol-relative, e.g. «//example.com/a»
+        return url.starts_with("//");
+    }
+    let (first_segment, _) = url.split_once('/').unwrap_or((url, ""));
+    first_segment.ends_with(':') || (first_segment.contains('.') && url.contains('/'))
+}
+
+/// Capture group numbers referenced in a regsub replacement (e.g. 1 for «\1»)
+fn get_backreferences(replacement: &str) -> Vec<usize> {
+    let mut groups = vec![];
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            continue;
+        }
+        // «\\» is an escaped backslash
+        if let Some(group) = chars.next().and_then(|c| c.to_digit(10)) {
+            groups.push(group as usize);
+        }
+    }
+    groups
+}
+
 pub fn get_node_text<'a>(rope: &'a Rope, node: &'a Node) -> String {
     let mut text = rope.byte_slice(node.byte_range()).to_string();
-    if let Some((first_part, _)) = text.split_once('\n') {
+    if let Some((first_part, _)) = text.split_once(['\n', '\r']) {
         text = first_part.to_string();
     }
 
@@ -145,7 +1017,7 @@ impl Document {
             FileType::Vtc => parser::vtc(),
         };
 
-        let ast = parser.parse(&text, None).unwrap();
+        let ast = parser.parse(get_parse_text(&text).as_ref(), None).unwrap();
         let parser = Arc::new(Mutex::new(parser));
         let rope = Rope::from(text);
 
@@ -154,18 +1026,95 @@ impl Document {
             rope,
             parser,
             ast,
-            path: Arc::new(url.to_file_path().unwrap()),
+            // snippets may have virtual (non file) uris
+            path: Arc::new(
+                url.to_file_path()
+                    .unwrap_or_else(|_| PathBuf::from(url.path())),
+            ),
             url,
             filetype,
             pos_from_main_doc: nested_pos.unwrap_or_default(),
+            template_placeholders: Default::default(),
+            placeholder_ranges: vec![],
+            pending_changes: Default::default(),
         }
     }
 
+    /// Reparse the document with template placeholders masked as identifiers
+    pub fn with_template_placeholders(mut self, placeholders: &[TemplatePlaceholder]) -> Self {
+        if placeholders.is_empty() {
+            return self;
+        }
+        self.template_placeholders = Arc::new(placeholders.to_vec());
+        let (masked_text, placeholder_ranges) = mask_template_placeholders(
+            &get_parse_text(&self.rope.to_string()),
+            &self.template_placeholders,
+        );
+        self.ast = self
+            .parser
+            .lock()
+            .unwrap()
+            .parse(&masked_text, None)
+            .unwrap();
+        self.placeholder_ranges = placeholder_ranges;
+        self
+    }
+
     pub fn version(&self) -> i32 {
         self.version
     }
 
-    pub fn edit(&mut self, version: i32, edits: impl Iterator<Item = (Option<Range>, String)>) {
+    pub fn with_version(mut self, version: i32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /**
+     * Apply the changes of a `textDocument/didChange` in version order. Changes older than the
+     * current version are ignored, as applying them would corrupt the buffer. Changes arriving
+     * before the version preceding them are kept until it arrives, unless they replace the
+     * whole text. Returns whether the document changed.
+     *
+     * The changes are applied to a copy which replaces the document when all are applied, so
+     * the document is never left with only some of the changes applied.
+     */
+    pub fn edit(
+        &mut self,
+        version: i32,
+        edits: impl Iterator<Item = (Option<Range>, String)>,
+    ) -> bool {
+        if version <= self.version {
+            error!(
+                "Ignoring changes for version {version} of {}, it is already at version {}",
+                self.url, self.version
+            );
+            return false;
+        }
+
+        let edits = edits.collect::<Vec<_>>();
+        let is_fulltext = edits.first().is_some_and(|(range, _)| range.is_none());
+        if version > self.version + 1 && !is_fulltext {
+            debug!(
+                "Keeping changes for version {version} of {} until version {} arrives",
+                self.url,
+                self.version + 1
+            );
+            self.pending_changes.insert(version, edits);
+            return false;
+        }
+
+        let mut next = self.clone();
+        next.apply_changes(version, edits);
+        next.pending_changes
+            .retain(|pending_version, _| *pending_version > version);
+        while let Some(edits) = next.pending_changes.remove(&(next.version + 1)) {
+            next.apply_changes(next.version + 1, edits);
+        }
+        *self = next;
+        true
+    }
+
+    fn apply_changes(&mut self, version: i32, edits: Vec<(Option<Range>, String)>) {
         for (range, text) in edits {
             match range {
                 Some(range) => {
@@ -176,6 +1125,7 @@ impl Document {
                 }
             }
         }
+        self.version = version;
     }
 
     fn edit_range(&mut self, _version: i32, range: Range, text: String) {
@@ -232,25 +1182,47 @@ impl Document {
             new_end_position,
         });
 
-        let new_new_ast = self
-            .parser
-            .lock()
-            .unwrap()
-            .parse_with(
-                &mut |offset, _pos| {
-                    let (chunk, chunk_byte_idx, _, _) = self.rope.chunk_at_byte(offset);
-                    &chunk.as_bytes()[(offset - chunk_byte_idx)..]
-                },
-                Some(&new_ast),
-            )
-            .unwrap();
+        let new_new_ast = if self.template_placeholders.is_empty() {
+            self.parser
+                .lock()
+                .unwrap()
+                .parse_with(
+                    &mut |offset, _pos| {
+                        let (chunk, chunk_byte_idx, _, _) = self.rope.chunk_at_byte(offset);
+                        let next_byte = self.rope.get_byte(chunk_byte_idx + chunk.len());
+                        lone_crs_as_lf(&chunk.as_bytes()[(offset - chunk_byte_idx)..], next_byte)
+                    },
+                    Some(&new_ast),
+                )
+                .unwrap()
+        } else {
+            // masking keeps byte offsets, so the edited tree can still be reused
+            let (masked_text, placeholder_ranges) = mask_template_placeholders(
+                &get_parse_text(&self.rope.to_string()),
+                &self.template_placeholders,
+            );
+            self.placeholder_ranges = placeholder_ranges;
+            self.parser
+                .lock()
+                .unwrap()
+                .parse(&masked_text, Some(&new_ast))
+                .unwrap()
+        };
 
         // self.rope = new_rope;
         self.ast = new_new_ast;
     }
 
-    pub fn edit_fulltext(&mut self, _version: i32, text: String) {
+    pub fn edit_fulltext(&mut self, version: i32, text: String) {
+        self.version = version;
+        self.pending_changes
+            .retain(|pending_version, _| *pending_version > version);
         let rope = Rope::from(text.clone());
+        let mut text = get_parse_text(&text).into_owned();
+        if !self.template_placeholders.is_empty() {
+            (text, self.placeholder_ranges) =
+                mask_template_placeholders(&text, &self.template_placeholders);
+        }
         let ast = self.parser.lock().unwrap().parse(&text, None).unwrap();
         self.rope = rope;
         self.ast = ast;
@@ -288,6 +1260,200 @@ impl Document {
         None
     }
 
+    /// Signature help for the function call around point, with the argument at point as the
+    /// active parameter
+    pub fn get_signature_help(&self, point: Point, scope: &Definitions) -> Option<SignatureHelp> {
+        let mut node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+
+        let args_node = loop {
+            if node.kind() == "func_call_args"
+                && node
+                    .parent()
+                    .is_some_and(|parent| parent.kind() == "ident_call_expr")
+            {
+                break node;
+            }
+            node = node.parent()?;
+        };
+
+        // only inside the parentheses
+        let mut cursor = args_node.walk();
+        let children = args_node.children(&mut cursor).collect::<Vec<_>>();
+        let open_paren = children.first().filter(|n| n.kind() == "(")?;
+        if point < open_paren.end_position() {
+            return None;
+        }
+        if let Some(close_paren) = children
+            .last()
+            .filter(|n| n.kind() == ")" && !n.is_missing())
+        {
+            if point > close_paren.start_position() {
+                return None;
+            }
+        }
+
+        let call_node = args_node.parent()?;
+        let ident_node = call_node.child_by_field_name("ident")?;
+        let ident = get_node_text(&self.rope, &ident_node);
+        let Some(Type::Func(func)) =
+            scope.get_type_property_by_nested_idents(ident.split('.').collect())
+        else {
+            return None;
+        };
+
+        // named arguments are matched by name, positional arguments by the commas before point
+        let named_arg_idx = children
+            .iter()
+            .find(|n| {
+                n.kind() == "func_call_named_arg"
+                    && n.start_position() <= point
+                    && point <= n.end_position()
+            })
+            .and_then(|n| n.child_by_field_name("arg_name"))
+            .and_then(|arg_name_node| {
+                let arg_name = get_node_text(&self.rope, &arg_name_node);
+                func.args
+                    .iter()
+                    .position(|arg| arg.name.as_deref() == Some(&*arg_name))
+            });
+        let active_parameter = named_arg_idx.unwrap_or_else(|| {
+            children
+                .iter()
+                .filter(|n| n.kind() == "," && n.end_position() <= point)
+                .count()
+        }) as u32;
+
+        Some(SignatureHelp {
+            signatures: vec![SignatureInformation {
+                label: func.get_signature_label(),
+                documentation: func.doc.as_ref().map(|doc| {
+                    Documentation::MarkupContent(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value: doc.to_string(),
+                    })
+                }),
+                parameters: Some(
+                    func.args
+                        .iter()
+                        .map(|arg| ParameterInformation {
+                            label: ParameterLabel::Simple(arg.get_label()),
+                            documentation: None,
+                        })
+                        .collect(),
+                ),
+                active_parameter: Some(active_parameter),
+            }],
+            active_signature: Some(0),
+            active_parameter: Some(active_parameter),
+        })
+    }
+
+    /**
+     * All symbols visible at point: builtins (leaving out variables which don't exist in the
+     * builtin subroutine at point), vmods and their members, and symbols defined in vcl.
+     */
+    pub fn get_scope_at_point(
+        &self,
+        point: Point,
+        scope: &Definitions,
+        vmod_names: &[String],
+        workspace_flavor: VarnishFlavor,
+    ) -> Vec<ScopeSymbol> {
+        let flavor = self.get_flavor(workspace_flavor);
+        let sub_name = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)
+            .map(get_toplev_declaration_from_node)
+            .filter(|toplev_decl| toplev_decl.kind() == "sub_declaration")
+            .and_then(|sub_node| sub_node.child_by_field_name("ident"))
+            .map(|ident_node| get_node_text(&self.rope, &ident_node))
+            .filter(|sub_name| sub_name.starts_with("vcl_"));
+
+        let mut symbols = vec![];
+        for (name, def) in scope.properties.iter() {
+            let source = if vmod_names.contains(name) {
+                ScopeSource::Vmod
+            } else if def.loc.is_none() {
+                ScopeSource::Builtin
+            } else {
+                ScopeSource::Document
+            };
+            if let (ScopeSource::Builtin, Some(sub_name)) = (source, sub_name.as_deref()) {
+                if !varnish_builtins::is_variable_available_in_sub(flavor, name, sub_name) {
+                    continue;
+                }
+            }
+
+            symbols.push(ScopeSymbol {
+                name: name.to_string(),
+                r#type: format!("{}", def.r#type),
+                source,
+                location: def.loc.clone(),
+            });
+            if let (ScopeSource::Vmod, Type::Obj(vmod_obj)) = (source, &*def.r#type) {
+                symbols.extend(vmod_obj.properties.iter().map(|(member_name, r#type)| {
+                    ScopeSymbol {
+                        name: format!("{name}.{member_name}"),
+                        r#type: format!("{type}"),
+                        source,
+                        location: None,
+                    }
+                }));
+            }
+        }
+        symbols
+    }
+
+    /// Type of an expression, e.g. STRING for a concatenation of strings
+    pub fn get_expr_type(&self, node: Node, scope: &Definitions) -> Option<Type> {
+        match node.kind() {
+            "expr" | "parenthesized_expression" => self.get_expr_type(node.named_child(0)?, scope),
+            "ident" | "nested_ident" => {
+                let ident = get_node_text(&self.rope, &node);
+                scope
+                    .get_type_property_by_nested_idents(ident.split('.').collect())
+                    .cloned()
+            }
+            "ident_call_expr" => {
+                let ident = get_node_text(&self.rope, &node.child_by_field_name("ident")?);
+                match scope.get_type_property_by_nested_idents(ident.split('.').collect()) {
+                    Some(Type::Func(func)) => func.r#return.as_deref().cloned(),
+                    Some(_) => None,
+                    None => varnish_builtins::get_known_vmod_func_return_type(
+                        &self.unalias_vmod_ident(&ident),
+                    ),
+                }
+            }
+            "binary_expression" => {
+                let operator = get_node_text(&self.rope, &node.child_by_field_name("operator")?);
+                let left_type = self.get_expr_type(node.child_by_field_name("left")?, scope);
+                match operator.as_str() {
+                    // concatenating anything with a string is a string
+                    "+" => {
+                        let right_type =
+                            self.get_expr_type(node.child_by_field_name("right")?, scope);
+                        if [&left_type, &right_type]
+                            .iter()
+                            .any(|r#type| matches!(r#type, Some(Type::String)))
+                        {
+                            Some(Type::String)
+                        } else {
+                            left_type
+                        }
+                    }
+                    "-" | "*" | "/" => left_type,
+                    _ => Some(Type::Bool),
+                }
+            }
+            "neg_expr" => Some(Type::Bool),
+            _ => node_to_type(&node),
+        }
+    }
+
     /// get identifier at point, only first part of nested idents
     pub fn get_ident_at_point(&self, point: Point) -> Option<String> {
         let node = self
@@ -303,7 +1469,75 @@ impl Document {
         Some(name)
     }
 
+    /// Name of the subroutine around point
+    pub fn get_sub_name_at_point(&self, point: Point) -> Option<String> {
+        let node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+        let toplev_decl = get_toplev_declaration_from_node(node);
+        if toplev_decl.kind() != "sub_declaration" {
+            return None;
+        }
+        Some(get_node_text(
+            &self.rope,
+            &toplev_decl.child_by_field_name("ident")?,
+        ))
+    }
+
+    /// Moniker of the symbol at point, e.g. «backend/default», for correlating subroutines,
+    /// backends, etc. across projects sharing includes. Exported where the symbol is declared.
+    pub fn get_moniker(&self, point: Point, scope: &Definitions) -> Option<Moniker> {
+        let node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+        if !matches!(node.kind(), "ident" | "nested_ident") {
+            return None;
+        }
+        let name = self.get_ident_at_point(point)?;
+        // builtins and vmods are not declared in vcl
+        let definition = scope
+            .properties
+            .get(&name)
+            .filter(|def| def.loc.is_some())?;
+        let kind = match *definition.r#type {
+            Type::Sub => "sub",
+            Type::Backend => "backend",
+            Type::Acl => "acl",
+            Type::Probe => "probe",
+            Type::Obj(_) => "object",
+            _ => return None,
+        };
+        let is_declaration = node.parent().is_some_and(|parent_node| {
+            matches!(
+                parent_node.kind(),
+                "sub_declaration"
+                    | "backend_declaration"
+                    | "acl_declaration"
+                    | "probe_declaration"
+                    | "new_stmt"
+            ) && parent_node.child_by_field_name("ident") == Some(node)
+        });
+        Some(Moniker {
+            scheme: "varnish-lsp".to_string(),
+            identifier: format!("{kind}/{name}"),
+            unique: UniquenessLevel::Scheme,
+            kind: Some(if is_declaration {
+                MonikerKind::Export
+            } else {
+                MonikerKind::Import
+            }),
+        })
+    }
+
     pub fn get_definition_by_name(&self, name: &str) -> Option<(Point, Point)> {
+        self.get_definitions_by_name(name).into_iter().next()
+    }
+
+    /// All declarations of a name in this document. Builtin subroutines (e.g. vcl_recv) can
+    /// be declared multiple times, and Varnish concatenates them in order.
+    pub fn get_definitions_by_name(&self, name: &str) -> Vec<(Point, Point)> {
         let name_escaped = name.replace('"', "\\\"");
         let q = Query::new(
             &self.ast.language(),
@@ -318,21 +1552,22 @@ impl Document {
         );
         if let Err(err) = q {
             log::error!("Failed exec query for goto definition: {}", err);
-            return None;
+            return vec![];
         }
         let q = q.unwrap();
         let mut qc = QueryCursor::new();
         let mut all_matches = qc.matches(&q, self.ast.root_node(), self);
         let capt_idx = q.capture_index_for_name("node").unwrap();
 
+        let mut definitions = vec![];
         while let Some(each_match) = all_matches.next() {
             if let Some(capture) = each_match.captures.iter().find(|c| c.index == capt_idx) {
                 let range = capture.node.range();
-                return Some((range.start_point, range.end_point));
+                definitions.push((range.start_point, range.end_point));
             }
         }
 
-        None
+        definitions
     }
 
     pub fn get_definition_by_point(&self, point: Point) -> Option<(Point, Point)> {
@@ -340,6 +1575,63 @@ impl Document {
         self.get_definition_by_name(&name)
     }
 
+    /**
+     * Find the bracket at (or right before) point and its matching bracket, using the syntax
+     * tree so brackets within strings and comments are ignored.
+     */
+    pub fn get_matching_brackets(&self, point: Point) -> Option<(Range, Range)> {
+        let root_node = self.ast.root_node();
+        let bracket_node = [
+            Some(point),
+            point
+                .column
+                .checked_sub(1)
+                .map(|column| Point { column, ..point }),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|point| {
+            root_node.descendant_for_point_range(
+                point,
+                Point {
+                    column: point.column + 1,
+                    ..point
+                },
+            )
+        })
+        .find(|node| matches!(node.kind(), "{" | "}" | "(" | ")") && !node.is_named())?;
+
+        let (open, close) = match bracket_node.kind() {
+            "{" | "}" => ("{", "}"),
+            _ => ("(", ")"),
+        };
+        let is_open = bracket_node.kind() == open;
+
+        // brackets are siblings, but count depth in case of error recovery
+        let mut depth = 0;
+        let mut sibling = Some(bracket_node);
+        while let Some(node) = sibling {
+            if node.kind() == bracket_node.kind() {
+                depth += 1;
+            } else if node.kind() == if is_open { close } else { open } {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((
+                        ts_range_to_lsp_range(bracket_node.range()),
+                        ts_range_to_lsp_range(node.range()),
+                    ));
+                }
+            }
+            sibling = if is_open {
+                node.next_sibling()
+            } else {
+                node.prev_sibling()
+            };
+        }
+
+        None
+    }
+
     // TODO: only check definition line numbers for certain types
     // TO CHECK: probes, ident_call_exprs
     // TO NOT CHECK: backends, subroutines
@@ -355,6 +1647,34 @@ impl Document {
             pub line: usize,
         }
         let mut varnishls_ignore: Option<VarnishlsIgnore> = None;
+        let vcl_version = self.get_vcl_version();
+        // subroutine calls in this document, read when first needed
+        let mut sub_calls: Option<Vec<SubCalls>> = None;
+        // a «# varnish-lsp flavor: …» comment overrides the workspace flavor for this document
+        let flavor = match self.get_flavor_directive() {
+            Some((name, range)) => VarnishFlavor::from_name(&name).unwrap_or_else(|| {
+                error_ranges.push(LintError {
+                    message: format!(
+                        "Unknown varnish flavor «{name}», expected one of {}",
+                        VarnishFlavor::ALL
+                            .iter()
+                            .map(VarnishFlavor::name)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                    severity: DiagnosticSeverity::WARNING,
+                    loc: Location {
+                        uri: self.url.to_owned(),
+                        range,
+                    },
+                    data: None,
+                    related_information: None,
+                    tags: None,
+                });
+                config.flavor
+            }),
+            None => config.flavor,
+        };
 
         loop {
             if (recurse && cursor.goto_first_child()) || cursor.goto_next_sibling() {
@@ -386,6 +1706,8 @@ impl Document {
                         loc: get_location!(node: $node),
                         severity: $severity,
                         data: None,
+                        related_information: None,
+                        tags: None,
                     });
                 };
                 (node: $node:expr, $($arg:tt)+) => {
@@ -417,7 +1739,23 @@ impl Document {
             }
 
             if node.is_error() {
-                add_error!("Syntax error");
+                match self.get_unterminated_string_range(node) {
+                    Some(range) => error_ranges.push(LintError {
+                        message: "Unterminated string, expected a closing «\"» on the same line"
+                            .to_string(),
+                        severity: DiagnosticSeverity::ERROR,
+                        loc: Location {
+                            uri: self.url.to_owned(),
+                            range,
+                        },
+                        data: None,
+                        related_information: None,
+                        tags: None,
+                    }),
+                    None => {
+                        add_error!("Syntax error");
+                    }
+                }
                 recurse = false;
                 continue;
             }
@@ -428,6 +1766,67 @@ impl Document {
                 continue;
             }
 
+            if config.redundant_header_writes.is_enabled()
+                && matches!(
+                    node.kind(),
+                    "sub_declaration" | "if_stmt" | "elsif_stmt" | "else_stmt"
+                )
+            {
+                error_ranges.append(&mut self.get_redundant_header_writes_errors(
+                    node,
+                    config.redundant_header_writes.lsp_severity().unwrap(),
+                ));
+            }
+
+            if config.path_outside_sandbox.is_enabled() {
+                if let Some(string_node) = get_filesystem_path_node(self, node) {
+                    let path_str = get_node_text(&self.rope, &string_node);
+                    let path = Path::new(path_str.trim_matches('"'));
+                    let doc_dir = self.path.parent().unwrap_or(Path::new("/"));
+                    let resolved_path = normalize_path(&doc_dir.join(path));
+                    if !config
+                        .sandbox_paths
+                        .iter()
+                        .any(|sandbox_path| resolved_path.starts_with(normalize_path(sandbox_path)))
+                    {
+                        add_error!(
+                            node: string_node,
+                            severity: config.path_outside_sandbox.lsp_severity().unwrap(),
+                            "[path_outside_sandbox] Path is outside of sandbox: {}",
+                            resolved_path.display()
+                        );
+                    }
+                }
+            }
+
+            // a literal other than true or false as condition, e.g. «if ("foo")», is cast to
+            // BOOL, and always the same
+            if matches!(node.kind(), "if_stmt" | "elsif_stmt") {
+                if let Some(condition_node) = node.child_by_field_name("condition") {
+                    let expr_node = unwrap_expr_node(condition_node);
+                    let r#type = (expr_node.kind() == "literal")
+                        .then(|| self.get_expr_type(expr_node, global_scope))
+                        .flatten();
+                    let literal = get_node_text(&self.rope, &expr_node);
+                    let is_true = match r#type {
+                        Some(Type::String) => Some(true),
+                        Some(Type::Number | Type::Duration) => literal
+                            .trim_end_matches(char::is_alphabetic)
+                            .parse::<f64>()
+                            .ok()
+                            .map(|number| number != 0.0),
+                        _ => None,
+                    };
+                    if let (Some(r#type), Some(is_true)) = (r#type, is_true) {
+                        add_error!(
+                            node: condition_node,
+                            severity: DiagnosticSeverity::WARNING,
+                            "Condition is always {is_true}: the {type} literal «{literal}» is used as a BOOL"
+                        );
+                    }
+                }
+            }
+
             match node.kind() {
                 "set_stmt" => {
                     let Some(left_node) = node.child_by_field_name("left") else {
@@ -517,6 +1916,20 @@ impl Document {
                             }
                         }
 
+                        // req.url is only the path, the host is in req.http.host
+                        if matches!(left_ident_text.as_str(), "req.url" | "bereq.url") {
+                            if let Some(string_node) = get_string_literal_node(right_node) {
+                                let url = get_node_text(&self.rope, &string_node);
+                                if url_has_host(unquote_string(&url)) {
+                                    add_error!(
+                                        node: string_node,
+                                        severity: DiagnosticSeverity::WARNING,
+                                        "{left_ident_text} should be a path (e.g. «/path?query»), without a scheme or host. Set the host with {first_left_part}.http.host instead"
+                                    );
+                                }
+                            }
+                        }
+
                         if config.prefer_lowercase_headers.is_enabled()
                             && is_http
                             && left_parts
@@ -533,7 +1946,10 @@ impl Document {
                                     quickfix_label: "Downcase".into(),
                                     replace_with: get_node_text(&self.rope, &left_node)
                                         .to_lowercase(),
+                                    alternatives: vec![],
                                 }),
+                                related_information: None,
+                                tags: None,
                             });
                         }
 
@@ -550,6 +1966,95 @@ impl Document {
                             }
                         }
                     }
+
+                    // headers are strings, so values appended to them must convert into one
+                    let is_header = left_parts.len() > 2 && left_parts[1] == "http";
+                    let operator = node
+                        .child_by_field_name("operator")
+                        .map(|operator_node| get_node_text(&self.rope, &operator_node));
+
+                    // booleans convert into and from strings, but rarely the intended way
+                    if operator.as_deref() == Some("=") {
+                        let left_type =
+                            global_scope.get_type_property_by_nested_idents(left_parts.clone());
+                        let right_type = self.get_expr_type(right_node, global_scope);
+                        match (left_type, right_type) {
+                            (Some(Type::Bool), Some(Type::String)) => {
+                                let detail = if node_to_type(&right_node).is_some()
+                                    || get_string_literal_node(right_node).is_some()
+                                {
+                                    "Any string is true, even «\"false\"»"
+                                } else {
+                                    "The string is true if it is set"
+                                };
+                                add_error!(
+                                    node: right_node,
+                                    severity: DiagnosticSeverity::WARNING,
+                                    "Expected BOOL, found STRING. {detail}"
+                                );
+                            }
+                            (
+                                Some(Type::Bool),
+                                Some(
+                                    right_type @ (Type::Obj(_)
+                                    | Type::Func(_)
+                                    | Type::Acl
+                                    | Type::Sub
+                                    | Type::Probe
+                                    | Type::Blob
+                                    | Type::Body
+                                    | Type::IP
+                                    | Type::Enum(_)),
+                                ),
+                            ) => {
+                                add_error!(
+                                    node: right_node,
+                                    "Expected BOOL, found {right_type}"
+                                );
+                            }
+                            (Some(Type::String), Some(Type::Bool))
+                                if get_bool_literal_node(right_node).is_some() =>
+                            {
+                                add_error!(
+                                    node: right_node,
+                                    severity: DiagnosticSeverity::WARNING,
+                                    "Expected STRING, found BOOL. It is converted into «\"{}\"», quote it if a string was intended",
+                                    get_node_text(&self.rope, &right_node)
+                                );
+                            }
+                            _ => {}
+                        }
+                    }
+                    if is_header {
+                        let appended = match operator.as_deref() {
+                            Some("+=") => vec![right_node],
+                            Some("*=" | "/=") => {
+                                add_error!(
+                                    node: node.child_by_field_name("operator").unwrap(),
+                                    "«{}» cannot be used on headers, only «=» and «+=»",
+                                    operator.as_deref().unwrap()
+                                );
+                                continue;
+                            }
+                            _ => get_concatenated_nodes(&self.rope, right_node),
+                        };
+                        if appended.len() > 1 || operator.as_deref() == Some("+=") {
+                            for operand_node in appended {
+                                let Some(operand_type) =
+                                    self.get_expr_type(operand_node, global_scope)
+                                else {
+                                    continue;
+                                };
+                                if !operand_type.is_string_compatible() {
+                                    add_error!(
+                                        node: operand_node,
+                                        "Cannot append {} to a header, it does not convert into STRING",
+                                        operand_type
+                                    );
+                                }
+                            }
+                        }
+                    }
                 }
                 "new_stmt" => {
                     let left = node.child_by_field_name("ident");
@@ -591,6 +2096,47 @@ impl Document {
                         add_error!("Property {left_ident} cannot contain string list");
                     }
 
+                    let is_backend_property = !matches!(
+                        parent_parent_node_kind,
+                        "probe_declaration" | "inline_probe"
+                    );
+                    let min_vcl_version = is_backend_property
+                        .then(|| get_backend_field_min_vcl_version(&left_ident))
+                        .flatten();
+                    if let (Some(vcl_version), Some(min_vcl_version)) =
+                        (vcl_version, min_vcl_version)
+                    {
+                        if vcl_version < min_vcl_version {
+                            add_error!(
+                                "Backend property «.{}» requires vcl {}",
+                                left_ident,
+                                min_vcl_version
+                            );
+                        }
+                    }
+
+                    // ports are strings, resolved as a number or a service name
+                    if is_backend_property && left_ident == "port" {
+                        if let Some(string_node) = get_string_literal_node(right_node) {
+                            let text = get_node_text(&self.rope, &string_node);
+                            let port = unquote_string(&text);
+                            match port.parse::<u64>() {
+                                Ok(1..=65535) => {}
+                                Ok(number) => {
+                                    add_error!("Port {number} is out of range (expected 1-65535)");
+                                }
+                                Err(_) if varnish_builtins::get_service_port(port).is_some() => {}
+                                Err(_) => {
+                                    add_error!(
+                                        node: node,
+                                        severity: DiagnosticSeverity::WARNING,
+                                        "Unknown port «{port}» (expected a port number, or a service name like «http» or «https»)"
+                                    );
+                                }
+                            }
+                        }
+                    }
+
                     match r#type {
                         None => {
                             add_error!("Backend property «{}» does not exist", left_ident);
@@ -611,6 +2157,33 @@ impl Document {
                                         ident_type
                                     );
                                 }
+                                // vcc only knows the probes declared before the backend, with
+                                // includes expanded in place
+                                let mut probe_use_nested_pos = self.pos_from_main_doc.clone();
+                                probe_use_nested_pos
+                                    .push(point_to_tuple(right_node.start_position()));
+                                let later_probe_loc = (left_ident == "probe")
+                                    .then(|| global_scope.properties.get(&*right_ident))
+                                    .flatten()
+                                    .filter(|def| def.nested_pos.gt(&probe_use_nested_pos))
+                                    .and_then(|def| def.loc.as_ref());
+                                if let Some(probe_loc) = later_probe_loc {
+                                    error_ranges.push(LintError {
+                                        message: format!(
+                                            "Probe «{right_ident}» is used before it is defined"
+                                        ),
+                                        loc: get_location!(node: right_node),
+                                        severity: DiagnosticSeverity::ERROR,
+                                        data: None,
+                                        related_information: Some(vec![
+                                            DiagnosticRelatedInformation {
+                                                location: probe_loc.clone(),
+                                                message: format!("«{right_ident}» is defined here"),
+                                            },
+                                        ]),
+                                        tags: None,
+                                    });
+                                }
                                 continue;
                             }
 
@@ -618,69 +2191,400 @@ impl Document {
                                 add_error!("Unexpected value"); // unrecognized type
                                 continue;
                             };
+
+                            if matches!(r#type, Type::Duration) {
+                                let value = get_node_text(&self.rope, &right_node);
+                                let default =
+                                    varnish_builtins::get_duration_field_default(&left_ident)
+                                        .unwrap_or("5s");
+                                if matches!(right_node_type, Type::Number) {
+                                    add_error!(
+                                        node: right_node,
+                                        "«.{left_ident} = {value}» is missing a duration unit (e.g. «{default}»)"
+                                    );
+                                    continue;
+                                }
+                                if varnish_builtins::parse_duration(&value) == Some(0.0) {
+                                    add_error!(
+                                        node: right_node,
+                                        severity: DiagnosticSeverity::WARNING,
+                                        "«.{left_ident} = {value}» is likely a misconfiguration (a sane default is «{default}»)"
+                                    );
+                                    continue;
+                                }
+                            }
                             if !right_node_type.can_this_cast_into(r#type) {
                                 add_error!("Expected {}, found {}", r#type, right_node_type);
                             }
                         }
                     }
                 }
-                "elsif_stmt" => {
-                    let Some(keyword_node) = node.child_by_field_name("keyword") else {
-                        error!("could not find keyword for elsif_stmt");
+                "sub_declaration" => {
+                    let Some(ident_node) = node.child_by_field_name("ident") else {
                         continue;
                     };
-
-                    let keyword = self.rope.byte_slice(keyword_node.byte_range()).to_string();
-                    if config.prefer_else_if.is_enabled() && keyword != "else if" {
+                    let sub_name = get_node_text(&self.rope, &ident_node);
+                    let mut stmts_cursor = node.walk();
+                    let is_empty = !node
+                        .named_children(&mut stmts_cursor)
+                        .any(|child| child.kind() == "stmt");
+                    // empty custom subs might be placeholders
+                    if config.empty_builtin_sub.is_enabled()
+                        && is_empty
+                        && varnish_builtins::BUILTIN_SUBROUTINES.contains(&sub_name.as_str())
+                    {
+                        add_error!(
+                            node: ident_node,
+                            severity: config.empty_builtin_sub.lsp_severity().unwrap(),
+                            "[empty_builtin_sub] Empty {sub_name}. The builtin {sub_name} still runs afterwards, but the empty body might be unintentional."
+                        );
+                    }
+                }
+                "acl_declaration" if config.redundant_acl_entries.is_enabled() => {
+                    let mut entries_cursor = node.walk();
+                    let entries = node
+                        .named_children(&mut entries_cursor)
+                        .filter(|child| child.kind() == "acl_entry")
+                        .filter_map(|entry_node| {
+                            let negated =
+                                entry_node.child(0).is_some_and(|node| node.kind() == "!");
+                            let string_node = entry_node
+                                .named_child(0)
+                                .filter(|node| node.kind() == "string")?;
+                            let mut entry_cursor = entry_node.walk();
+                            let prefix_len = entry_node
+                                .children(&mut entry_cursor)
+                                .skip_while(|node| node.kind() != "/")
+                                .nth(1)
+                                .map(|node| get_node_text(&self.rope, &node));
+                            let network = AclNetwork::parse(
+                                unquote_string(&get_node_text(&self.rope, &string_node)),
+                                prefix_len.as_deref(),
+                            )?;
+                            Some((entry_node, (negated, network)))
+                        })
+                        .collect::<Vec<_>>();
+                    let networks = entries.iter().map(|(_, entry)| *entry).collect::<Vec<_>>();
+                    for (idx, covering_idx) in get_redundant_acl_entries(&networks) {
+                        let (entry_node, covering_node) = (entries[idx].0, entries[covering_idx].0);
+                        let entry_text = |node: Node| {
+                            get_node_text(&self.rope, &node)
+                                .trim_end_matches(';')
+                                .trim()
+                                .to_string()
+                        };
                         error_ranges.push(LintError {
-                            message: "Prefer «else if»".into(),
-                            loc: get_location!(node: keyword_node),
-                            severity: config.prefer_else_if.lsp_severity().unwrap(),
-                            data: Some(DiagnosticData {
-                                r#type: LintErrorInternalType::PreferElseIf,
-                                quickfix_label: format!(
-                                    "Replace {} with else if",
-                                    get_node_text(&self.rope, &keyword_node)
-                                ),
-                                replace_with: "else if".into(),
-                            }),
+                            message: format!(
+                                "[redundant_acl_entries] «{}» is already covered by «{}»",
+                                entry_text(entry_node),
+                                entry_text(covering_node)
+                            ),
+                            loc: get_location!(node: entry_node),
+                            severity: config.redundant_acl_entries.lsp_severity().unwrap(),
+                            data: None,
+                            related_information: Some(vec![DiagnosticRelatedInformation {
+                                location: get_location!(node: covering_node),
+                                message: "covered by this entry".into(),
+                            }]),
+                            tags: None,
                         });
                     }
                 }
-                "call_stmt" => {
-                    let Some(ident_node) = node.child_by_field_name("ident") else {
-                        add_error!("Missing identifier");
-                        continue;
-                    };
-                    let ident = get_node_text(&self.rope, &ident_node);
-                    let Some(definition) = global_scope.get(ident.as_str()) else {
-                        add_error!("Undefined subroutine");
-                        continue;
+                "probe_declaration" | "inline_probe" => {
+                    let mut props_cursor = node.walk();
+                    let props = node
+                        .named_children(&mut props_cursor)
+                        .filter(|child| child.kind() == "backend_property")
+                        .filter_map(|prop_node| {
+                            let left_node = prop_node.child_by_field_name("left")?;
+                            Some((get_node_text(&self.rope, &left_node), prop_node))
+                        })
+                        .collect::<Vec<_>>();
+                    let get_prop = |name: &str| props.iter().find(|(prop, _)| prop == name);
+                    let get_number_prop = |name: &str| {
+                        let (_, prop_node) = get_prop(name)?;
+                        let right_node = prop_node.child_by_field_name("right")?;
+                        let value = get_node_text(&self.rope, &right_node).parse::<u32>().ok()?;
+                        Some((value, *prop_node))
                     };
 
-                    match &*definition.r#type {
-                        Type::Sub => {
-                            /*
-                            if let Some(ref doc_nested_pos) = self.pos_from_main_doc {
-                                let mut use_nested_pos = doc_nested_pos.clone();
-                                use_nested_pos.push(point_to_tuple(node.start_position()));
+                    if get_prop("url").is_none() && get_prop("request").is_none() {
+                        let mut bracket_cursor = node.walk();
+                        let opening_bracket_node = node
+                            .children(&mut bracket_cursor)
+                            .find(|child| child.kind() == "{")
+                            .unwrap_or(node);
+                        add_error!(
+                            node: opening_bracket_node,
+                            severity: DiagnosticSeverity::WARNING,
+                            "Probe has neither .url nor .request, so Varnish probes «GET /» (.url defaults to \"/\")"
+                        );
+                    }
 
-                                if definition
-                                    .nested_pos
-                                    .as_ref()
-                                    .is_some_and(|nested_pos| nested_pos.gt(&use_nested_pos))
-                                {
-                                    let line = definition.loc.as_ref().unwrap().range.start.line;
-                                    add_error!("sub {ident} is defined at line {line}");
-                                }
-                            }
-                            */
-                        }
+                    if let (Some((_, url_node)), Some((_, request_node))) =
+                        (get_prop("url"), get_prop("request"))
+                    {
+                        error_ranges.push(LintError {
+                            message: "Probe .url and .request are mutually exclusive".into(),
+                            loc: get_location!(node: request_node),
+                            severity: DiagnosticSeverity::ERROR,
+                            data: None,
+                            related_information: Some(vec![DiagnosticRelatedInformation {
+                                location: get_location!(node: url_node),
+                                message: ".url is defined here".into(),
+                            }]),
+                            tags: None,
+                        });
+                    }
+
+                    let window = get_number_prop("window");
+                    if let Some((window, window_node)) = window {
+                        // the probe history is a 64 bit bitmap
+                        if window > 64 {
+                            add_error!(node: window_node, "Probe .window must be at most 64");
+                        }
+                    }
+
+                    // defaults from vcc_backend.c
+                    let window_value = window.map_or(8, |(window, _)| window);
+                    let threshold = get_number_prop("threshold");
+                    if let Some((threshold, threshold_node)) = threshold {
+                        if threshold > window_value {
+                            add_error!(
+                                node: threshold_node,
+                                "Probe .threshold ({threshold}) must be less than or equal to .window ({window_value})"
+                            );
+                        }
+                    }
+
+                    let threshold_value = threshold.map_or(3, |(threshold, _)| threshold);
+                    if let Some((initial, initial_node)) = get_number_prop("initial") {
+                        if initial > threshold_value {
+                            add_error!(
+                                node: initial_node,
+                                "Probe .initial ({initial}) must be less than or equal to .threshold ({threshold_value})"
+                            );
+                        }
+                    }
+                }
+                "backend_declaration" => {
+                    // «backend default none;» has no properties to check
+                    if node.child_by_field_name("body").is_none() {
+                        continue;
+                    }
+
+                    let mut props_cursor = node.walk();
+                    let props = node
+                        .named_children(&mut props_cursor)
+                        .filter(|child| child.kind() == "backend_property")
+                        .filter_map(|prop_node| {
+                            let left_node = prop_node.child_by_field_name("left")?;
+                            Some((get_node_text(&self.rope, &left_node), prop_node))
+                        })
+                        .collect::<Vec<_>>();
+                    let host_prop = props.iter().find(|(name, _)| name == "host");
+                    let path_prop = props.iter().find(|(name, _)| name == "path");
+
+                    match (host_prop, path_prop) {
+                        (Some((_, host_node)), Some((_, path_node))) => {
+                            error_ranges.push(LintError {
+                                message: "Backend .host and .path are mutually exclusive".into(),
+                                loc: get_location!(node: path_node),
+                                severity: DiagnosticSeverity::ERROR,
+                                data: None,
+                                related_information: Some(vec![DiagnosticRelatedInformation {
+                                    location: get_location!(node: host_node),
+                                    message: ".host is defined here".into(),
+                                }]),
+                                tags: None,
+                            });
+                        }
+                        (None, None) => {
+                            let ident_node = node.child_by_field_name("ident").unwrap_or(node);
+                            add_error!(
+                                node: ident_node,
+                                "Backend must have either a .host or a .path property"
+                            );
+                        }
+                        _ => {}
+                    }
+
+                    // a backend slow to connect to is likely slow to respond too
+                    if config.untuned_byte_timeouts.is_enabled()
+                        && !props.iter().any(|(name, _)| name == "first_byte_timeout")
+                    {
+                        let connect_timeout = props
+                            .iter()
+                            .find(|(name, _)| name == "connect_timeout")
+                            .and_then(|(_, prop_node)| {
+                                let right_node = prop_node.child_by_field_name("right")?;
+                                let value = get_node_text(&self.rope, &right_node);
+                                let seconds = varnish_builtins::parse_duration(&value)?;
+                                Some((value, seconds, *prop_node))
+                            });
+                        if let Some((value, _, prop_node)) =
+                            connect_timeout.filter(|(_, seconds, _)| {
+                                *seconds > varnish_builtins::LONG_CONNECT_TIMEOUT
+                            })
+                        {
+                            let default =
+                                varnish_builtins::get_duration_field_default("first_byte_timeout")
+                                    .unwrap_or("60s");
+                            add_error!(
+                                node: prop_node,
+                                severity: config.untuned_byte_timeouts.lsp_severity().unwrap(),
+                                "[untuned_byte_timeouts] .connect_timeout is {value}, but .first_byte_timeout is left at its default ({default}). Slow backends might need longer byte timeouts too."
+                            );
+                        }
+                    }
+                }
+                "elsif_stmt" => {
+                    let Some(keyword_node) = node.child_by_field_name("keyword") else {
+                        error!("could not find keyword for elsif_stmt");
+                        continue;
+                    };
+
+                    // «else if» is two tokens, which may be separated by any whitespace or
+                    // comments. the single token forms are elsif, elseif and elif.
+                    let is_else_if = keyword_node.child_count() > 1;
+                    if config.prefer_else_if.is_enabled() && !is_else_if {
+                        // not get_node_text, since elsif is a reserved keyword
+                        let keyword = self.rope.byte_slice(keyword_node.byte_range()).to_string();
+                        error_ranges.push(LintError {
+                            message: "Prefer «else if»".into(),
+                            loc: get_location!(node: keyword_node),
+                            severity: config.prefer_else_if.lsp_severity().unwrap(),
+                            data: Some(DiagnosticData {
+                                r#type: LintErrorInternalType::PreferElseIf,
+                                quickfix_label: format!("Replace {keyword} with else if"),
+                                replace_with: "else if".into(),
+                                alternatives: vec![],
+                            }),
+                            related_information: None,
+                            tags: None,
+                        });
+                    }
+                }
+                "call_stmt" => {
+                    let Some(ident_node) = node.child_by_field_name("ident") else {
+                        add_error!("Missing identifier");
+                        continue;
+                    };
+                    let ident = get_node_text(&self.rope, &ident_node);
+                    let Some(definition) = global_scope.get(ident.as_str()) else {
+                        add_error!("Undefined subroutine");
+                        continue;
+                    };
+
+                    match &*definition.r#type {
+                        Type::Sub => {
+                            /*
+                            if let Some(ref doc_nested_pos) = self.pos_from_main_doc {
+                                let mut use_nested_pos = doc_nested_pos.clone();
+                                use_nested_pos.push(point_to_tuple(node.start_position()));
+
+                                if definition
+                                    .nested_pos
+                                    .as_ref()
+                                    .is_some_and(|nested_pos| nested_pos.gt(&use_nested_pos))
+                                {
+                                    let line = definition.loc.as_ref().unwrap().range.start.line;
+                                    add_error!("sub {ident} is defined at line {line}");
+                                }
+                            }
+                            */
+                        }
                         t => {
                             add_error!("{ident} is of type {t}");
                         }
                     }
                 }
+                // lint return actions, e.g. return (pass) in vcl_hash. like hash_data(), a custom
+                // subroutine is checked against the builtin subroutines calling it.
+                "ret_stmt" => {
+                    let Some(methods_node) = node
+                        .named_child(0)
+                        .filter(|n| n.kind() == "varnish_internal_return_methods")
+                    else {
+                        continue;
+                    };
+                    let Some(action_node) = methods_node.child(0) else {
+                        continue;
+                    };
+                    let action = action_node.kind();
+
+                    // «return (vcl(label))» takes the name of a label, set up with
+                    // «varnishadm vcl.label»
+                    if action == "vcl"
+                        && (methods_node.named_child_count() != 1
+                            || methods_node
+                                .named_child(0)
+                                .is_some_and(|label_node| get_label_ident(label_node).is_none()))
+                    {
+                        add_error!(
+                            node: methods_node,
+                            "return (vcl(…)) expects a single vcl label, e.g. «return (vcl(l_example))»"
+                        );
+                    } else if let Some(label_node) = (action == "vcl")
+                        .then(|| methods_node.named_child(0).and_then(get_label_ident))
+                        .flatten()
+                    {
+                        // the grammar only parses valid label names as an ident, so just check
+                        // the label is a known one
+                        let label = get_node_text(&self.rope, &label_node);
+                        if let Some(ref labels) = config.vcl_labels {
+                            if !labels.iter().any(|known| *known == *label) {
+                                add_error!(
+                                    node: label_node,
+                                    "Unknown vcl label «{label}», expected one of the labels in lint.vcl_labels: {}",
+                                    labels.join(", ")
+                                );
+                            }
+                        }
+                    }
+
+                    let toplev_decl = get_toplev_declaration_from_node(node);
+                    let Some(ident_node) = (toplev_decl.kind() == "sub_declaration")
+                        .then(|| toplev_decl.child_by_field_name("ident"))
+                        .flatten()
+                    else {
+                        continue;
+                    };
+                    let sub_name = get_node_text(&self.rope, &ident_node);
+                    // e.g. «. return (purge) is only allowed in vcl_recv»
+                    let allowed_in = varnish_builtins::get_return_action_subs(flavor, action);
+                    let allowed_in = if allowed_in.is_empty() {
+                        String::new()
+                    } else {
+                        format!(
+                            ". return ({action}) is only allowed in {}",
+                            allowed_in.join(", ")
+                        )
+                    };
+                    if let Some(actions) =
+                        varnish_builtins::get_sub_return_actions(flavor, &sub_name)
+                    {
+                        if !actions.contains(&action) {
+                            add_error!(
+                                node: action_node,
+                                "return ({action}) is not allowed in {sub_name}, expected one of {}{allowed_in}",
+                                actions.join(", ")
+                            );
+                        }
+                    } else if !sub_name.starts_with("vcl_") {
+                        let sub_calls = sub_calls.get_or_insert_with(|| self.get_sub_calls());
+                        let calling_subs = get_calling_builtin_subs(sub_calls, &sub_name);
+                        if let Some(calling_sub) = calling_subs.iter().find(|calling_sub| {
+                            varnish_builtins::get_sub_return_actions(flavor, calling_sub)
+                                .is_some_and(|actions| !actions.contains(&action))
+                        }) {
+                            add_error!(
+                                node: action_node,
+                                "return ({action}) is not allowed in {calling_sub}, which calls {sub_name}{allowed_in}"
+                            );
+                        }
+                    }
+                }
                 // lint ident_call_expr (e.g. brotli.init(BOTH, br_q = 1))
                 "ident_call_expr" => {
                     let Some(ident_node) = node.child_by_field_name("ident") else {
@@ -688,6 +2592,38 @@ impl Document {
                     };
                     let full_ident = get_node_text(&self.rope, &ident_node);
                     let ident_parts = full_ident.split('.').collect::<Vec<_>>();
+
+                    // std.fileread() caches the file on the first call, so it is read once per
+                    // vcl, not per request. a custom subroutine is only flagged if it is called
+                    // from a request or backend request subroutine.
+                    if config.fileread_in_request.is_enabled()
+                        && full_ident.ends_with(".fileread")
+                        && self.unalias_vmod_ident(&full_ident) == "std.fileread"
+                    {
+                        let toplev_decl = get_toplev_declaration_from_node(node);
+                        if let Some(ident_node) = (toplev_decl.kind() == "sub_declaration")
+                            .then(|| toplev_decl.child_by_field_name("ident"))
+                            .flatten()
+                        {
+                            let sub_name = get_node_text(&self.rope, &ident_node);
+                            let sub_calls = sub_calls.get_or_insert_with(|| self.get_sub_calls());
+                            let request_sub = match sub_name.starts_with("vcl_") {
+                                true => Some(sub_name.as_str()),
+                                false => get_calling_builtin_subs(sub_calls, &sub_name)
+                                    .into_iter()
+                                    .next(),
+                            }
+                            .filter(|sub_name| !matches!(*sub_name, "vcl_init" | "vcl_fini"));
+                            if let Some(request_sub) = request_sub {
+                                add_error!(
+                                    node: node,
+                                    severity: config.fileread_in_request.lsp_severity().unwrap(),
+                                    "[fileread_in_request] std.fileread() in {request_sub} only reads the file on its first call, and returns the cached contents until the vcl is discarded. Changes to the file are only seen after loading the vcl again."
+                                );
+                            }
+                        }
+                    }
+
                     // check first part exists (e.g. «brotli»)
                     let Some(definition) = global_scope.get(ident_parts[0]) else {
                         add_error!(node: ident_node, "{} is undefined", ident_parts[0]);
@@ -706,7 +2642,7 @@ impl Document {
                             .and_then(|loc| {
                                 loc.uri
                                     .path_segments()
-                                    .and_then(|path_segments| path_segments.last())
+                                    .and_then(|mut path_segments| path_segments.next_back())
                             })
                             .unwrap_or("<UNKNOWN>");
 
@@ -732,6 +2668,39 @@ impl Document {
                         .iter()
                         .filter(|arg| !arg.optional && arg.default_value.is_none())
                         .collect::<Vec<_>>();
+                    // hash_data() only works in vcl_hash. a custom subroutine is only flagged if
+                    // it is called from another builtin subroutine, as it might be called from
+                    // another file.
+                    if full_ident == "hash_data" {
+                        let toplev_decl = get_toplev_declaration_from_node(node);
+                        if let Some(ident_node) = (toplev_decl.kind() == "sub_declaration")
+                            .then(|| toplev_decl.child_by_field_name("ident"))
+                            .flatten()
+                        {
+                            let sub_name = get_node_text(&self.rope, &ident_node);
+                            if sub_name.starts_with("vcl_") {
+                                if sub_name != "vcl_hash" {
+                                    add_error!(
+                                        node: node,
+                                        "hash_data() can only be called from vcl_hash, not {sub_name}"
+                                    );
+                                }
+                            } else {
+                                let sub_calls =
+                                    sub_calls.get_or_insert_with(|| self.get_sub_calls());
+                                let calling_subs = get_calling_builtin_subs(sub_calls, &sub_name);
+                                if let Some(calling_sub) =
+                                    calling_subs.iter().find(|name| **name != "vcl_hash")
+                                {
+                                    add_error!(
+                                        node: node,
+                                        "hash_data() can only be called from vcl_hash, but {sub_name} is called from {calling_sub}"
+                                    );
+                                }
+                            }
+                        }
+                    }
+
                     let Some(args_node) = node.child_by_field_name("args") else {
                         debug!("could not find args node");
                         continue;
@@ -740,15 +2709,90 @@ impl Document {
                     let arg_nodes = args_node
                         .children_by_field_name("arg", &mut args_cursor)
                         .collect::<Vec<Node>>();
-                    if required_args.len() > arg_nodes.len() {
-                        let missing_arg = required_args[arg_nodes.len()];
+
+                    // ban() can be called from any subroutine, but the ban expression can only
+                    // test fields of the cached objects
+                    if matches!(full_ident.as_str(), "ban" | "std.ban") {
+                        let operands = arg_nodes
+                            .first()
+                            .map(|arg_node| get_concatenated_nodes(&self.rope, *arg_node))
+                            .unwrap_or_default();
+                        for (idx, operand_node) in operands.into_iter().enumerate() {
+                            let Some(string_node) = get_string_literal_node(operand_node) else {
+                                continue;
+                            };
+                            let expression = get_node_text(&self.rope, &string_node);
+                            for field in varnish_builtins::get_invalid_ban_fields(
+                                unquote_string(&expression),
+                                idx > 0,
+                            ) {
+                                add_error!(
+                                    node: string_node,
+                                    severity: DiagnosticSeverity::WARNING,
+                                    "Bans can't test «{field}», only {}",
+                                    varnish_builtins::BAN_FIELDS
+                                        .iter()
+                                        .map(|ban_field| ban_field.replace("http.", "http.*"))
+                                        .collect::<Vec<_>>()
+                                        .join(", ")
+                                );
+                            }
+                        }
+                    }
+                    // required args can be provided by position or by name
+                    let positional_arg_count = arg_nodes
+                        .iter()
+                        .take_while(|arg_node| arg_node.kind() != "func_call_named_arg")
+                        .count();
+                    let named_arg_names = arg_nodes
+                        .iter()
+                        .filter_map(|arg_node| arg_node.child_by_field_name("arg_name"))
+                        .map(|arg_name_node| get_node_text(&self.rope, &arg_name_node))
+                        .collect::<Vec<_>>();
+                    let missing_arg = func.args.iter().enumerate().find(|(idx, arg)| {
+                        !arg.optional
+                            && arg.default_value.is_none()
+                            && *idx >= positional_arg_count
+                            && !arg
+                                .name
+                                .as_ref()
+                                .is_some_and(|name| named_arg_names.contains(name))
+                    });
+                    if let Some((_, missing_arg)) = missing_arg {
                         debug!("missing_arg: {:?}", missing_arg);
                         let mut message = "Missing required argument".to_string();
                         if let Some(ref missing_arg_name) = missing_arg.name {
                             message.push(' ');
                             message.push_str(missing_arg_name);
                         }
-                        add_error!("{}", message);
+                        add_error!(
+                            "{} ({full_ident} takes at least {} argument{}, {} provided)",
+                            message,
+                            required_args.len(),
+                            if required_args.len() == 1 { "" } else { "s" },
+                            arg_nodes.len()
+                        );
+                    }
+
+                    // check backreferences in the replacement refer to existing capture groups
+                    if matches!(full_ident.as_str(), "regsub" | "regsuball") && arg_nodes.len() > 2
+                    {
+                        let pattern = self.rope.byte_slice(arg_nodes[1].byte_range()).to_string();
+                        let replacement =
+                            self.rope.byte_slice(arg_nodes[2].byte_range()).to_string();
+                        if let Some(group_count) = count_capture_groups(unquote_string(&pattern)) {
+                            for group in get_backreferences(unquote_string(&replacement)) {
+                                if group > group_count {
+                                    add_error!(
+                                        node: arg_nodes[2],
+                                        severity: DiagnosticSeverity::WARNING,
+                                        "Backreference \\{} refers to a capture group which does not exist (the pattern has {} capture groups)",
+                                        group,
+                                        group_count
+                                    );
+                                }
+                            }
+                        }
                     }
 
                     // check the arguments exists and that their provided type is correct
@@ -794,6 +2838,18 @@ impl Document {
                         if let Some(arg_type) = arg.r#type.as_ref() {
                             if let Type::Enum(enum_values) = arg_type {
                                 // validate enums
+                                if let Some(string_node) = get_string_literal_node(arg_value_node) {
+                                    let value = get_node_text(&self.rope, &string_node);
+                                    let value = unquote_string(&value);
+                                    if enum_values.iter().any(|enum_value| enum_value == value) {
+                                        add_error!(
+                                            node: arg_value_node,
+                                            "Enum values are not quoted, expected {}",
+                                            value
+                                        );
+                                        continue;
+                                    }
+                                }
                                 if arg_value_node.kind() != "ident" {
                                     add_error!(node: arg_node, "Enum not found");
                                     continue;
@@ -808,6 +2864,66 @@ impl Document {
                                     );
                                     continue;
                                 }
+                            } else if let Some(ref values) = arg.values {
+                                // validate strings restricted to some values
+                                if let Some(string_node) = get_string_literal_node(arg_value_node) {
+                                    let value = get_node_text(&self.rope, &string_node);
+                                    let value = unquote_string(&value);
+                                    if !values.iter().any(|allowed| allowed == value) {
+                                        add_error!(
+                                            node: arg_value_node,
+                                            "Value \"{}\" not allowed. Must be one of: {}",
+                                            value,
+                                            values
+                                                .iter()
+                                                .map(|value| format!("\"{value}\""))
+                                                .collect::<Vec<_>>()
+                                                .join(", ")
+                                        );
+                                        continue;
+                                    }
+                                } else if arg_value_node.kind() == "ident" {
+                                    let value = get_node_text(&self.rope, &arg_value_node);
+                                    if values.contains(&value.to_string()) {
+                                        add_error!(
+                                            node: arg_value_node,
+                                            "Expected a quoted string, \"{}\"",
+                                            value
+                                        );
+                                        continue;
+                                    }
+                                }
+                            } else if matches!(arg_type, Type::Backend)
+                                && arg_value_node.kind() == "ident"
+                            {
+                                // backends are referenced by name, e.g. «rr.add_backend(web1)»
+                                let name = get_node_text(&self.rope, &arg_value_node);
+                                match global_scope.get_type_property(&name) {
+                                    Some(arg_value_type) => {
+                                        if !arg_value_type.can_this_cast_into(arg_type) {
+                                            add_error!(
+                                                node: arg_value_node,
+                                                "{} cannot cast into {}",
+                                                arg_value_type,
+                                                arg_type
+                                            );
+                                        }
+                                    }
+                                    None => {
+                                        let backend_names = global_scope
+                                            .properties
+                                            .iter()
+                                            .filter(|(_, def)| matches!(*def.r#type, Type::Backend))
+                                            .map(|(backend_name, _)| backend_name.as_str());
+                                        let suggestion = get_closest_name(&name, backend_names)
+                                            .map(|closest| format!(" (did you mean «{closest}»?)"))
+                                            .unwrap_or_default();
+                                        add_error!(
+                                            node: arg_value_node,
+                                            "Backend «{name}» is not defined{suggestion}"
+                                        );
+                                    }
+                                }
                             } else if matches!(
                                 arg_value_node.kind(),
                                 "literal" | "ident" | "nested_ident"
@@ -836,36 +2952,49 @@ impl Document {
                             }
                         }
 
-                        if let Some(restricted) = func.restricted.as_ref() {
-                            let toplev_decl = get_toplev_declaration_from_node(node);
-                            if toplev_decl.kind() == "sub_declaration" {
-                                if let Some(ident_node) = toplev_decl.child_by_field_name("ident") {
-                                    let sub_name = &*get_node_text(&self.rope, &ident_node);
-                                    if sub_name.starts_with("vcl_") {
-                                        let mut search = vec![sub_name];
-                                        let mapping = [
-                                            ("vcl_recv", "client"),
-                                            ("vcl_deliver", "client"),
-                                            ("vcl_backend_fetch", "backend"),
-                                            ("vcl_backend_response", "backend"),
-                                            ("vcl_init", "housekeeping"),
-                                            ("vcl_fini", "housekeeping"),
-                                        ];
-
-                                        mapping
-                                            .iter()
-                                            .filter(|(search_sub_name, _)| {
-                                                search_sub_name == &sub_name
-                                            })
-                                            .for_each(|(_, alias)| search.push(alias));
-
-                                        if !search
-                                            .iter()
-                                            .any(|search| restricted.contains(&search.to_string()))
-                                        {
-                                            add_error!(node: node, "Cannnot be called from {sub_name}");
-                                        }
+                        // validate placeholders in format strings, e.g. of std.strftime()
+                        if let Some(format) = arg.format {
+                            if let Some(string_node) = get_string_literal_node(arg_value_node) {
+                                let text = get_node_text(&self.rope, &string_node);
+                                let format_str = unquote_string(&text);
+                                // the opening and closing quotes are equally long
+                                let quote_len = (text.len() - format_str.len()) / 2;
+                                let start = string_node.start_position();
+                                let is_single_line = start.row == string_node.end_position().row;
+                                for (offset, placeholder) in
+                                    format.get_invalid_placeholders(format_str)
+                                {
+                                    let mut loc = get_location!(node: string_node);
+                                    if is_single_line {
+                                        let column = (start.column + quote_len + offset) as u32;
+                                        loc.range = Range::new(
+                                            Position::new(start.row as u32, column),
+                                            Position::new(
+                                                start.row as u32,
+                                                column + placeholder.len() as u32,
+                                            ),
+                                        );
                                     }
+                                    error_ranges.push(LintError {
+                                        message: format!(
+                                            "Invalid format specifier «{placeholder}»"
+                                        ),
+                                        loc,
+                                        severity: DiagnosticSeverity::WARNING,
+                                        data: None,
+                                        related_information: None,
+                                        tags: None,
+                                    });
+                                }
+                            }
+                        }
+
+                        let toplev_decl = get_toplev_declaration_from_node(node);
+                        if toplev_decl.kind() == "sub_declaration" {
+                            if let Some(ident_node) = toplev_decl.child_by_field_name("ident") {
+                                let sub_name = &*get_node_text(&self.rope, &ident_node);
+                                if !func.is_callable_from(sub_name) {
+                                    add_error!(node: node, "Cannot be called from {sub_name}");
                                 }
                             }
                         }
@@ -884,47 +3013,142 @@ impl Document {
                         continue;
                     }
 
+                    let toplev_decl = get_toplev_declaration_from_node(node);
+
+                    // «set http.host = ...» is missing the object the header belongs to. suggest
+                    // the one most likely used in this subroutine.
+                    if text.starts_with("http.")
+                        && node.parent().is_some_and(|parent_node| {
+                            matches!(parent_node.kind(), "set_stmt" | "unset_stmt")
+                                && parent_node.named_child(0) == Some(node)
+                        })
+                    {
+                        let sub_name = (toplev_decl.kind() == "sub_declaration")
+                            .then(|| toplev_decl.child_by_field_name("ident"))
+                            .flatten()
+                            .map(|ident_node| get_node_text(&self.rope, &ident_node));
+                        let object = match sub_name.as_deref() {
+                            Some("vcl_deliver" | "vcl_synth") => "resp",
+                            Some("vcl_backend_fetch") => "bereq",
+                            Some("vcl_backend_response" | "vcl_backend_error") => "beresp",
+                            _ => "req",
+                        };
+                        let suggestion = format!("{object}.{text}");
+                        error_ranges.push(LintError {
+                            message: format!(
+                                "«{text}» is missing the object it belongs to, e.g. «{suggestion}»"
+                            ),
+                            loc: get_location!(node: node),
+                            severity: DiagnosticSeverity::ERROR,
+                            data: Some(DiagnosticData {
+                                r#type: LintErrorInternalType::MissingHttpObject,
+                                quickfix_label: format!("Replace with {suggestion}"),
+                                replace_with: suggestion,
+                                alternatives: vec![],
+                            }),
+                            related_information: None,
+                            tags: None,
+                        });
+                        continue;
+                    }
+
+                    // compare header names with well-known ones, ignoring case. short names
+                    // only allow one edit, since e.g. «X-Cache» is two from «Cache»
+                    if config.misspelled_headers.is_enabled() {
+                        let header_name = text
+                            .split_once(".http.")
+                            .filter(|(object, _)| !object.contains('.'))
+                            .map(|(_, header_name)| header_name)
+                            .filter(|header_name| !header_name.contains('.'));
+                        let lowercase_headers = config
+                            .http_headers
+                            .iter()
+                            .map(|header| header.to_lowercase())
+                            .collect::<Vec<_>>();
+                        if let Some(header_name) = header_name.filter(|header_name| {
+                            !lowercase_headers.contains(&header_name.to_lowercase())
+                        }) {
+                            let max_distance = if header_name.len() < 10 { 1 } else { 2 };
+                            let suggestion = get_closest_name_within(
+                                &header_name.to_lowercase(),
+                                lowercase_headers.iter().map(String::as_str),
+                                max_distance,
+                            )
+                            .and_then(|lowercase_header| {
+                                lowercase_headers
+                                    .iter()
+                                    .position(|header| header == lowercase_header)
+                            })
+                            .map(|i| match_header_case(header_name, &config.http_headers[i]));
+                            if let Some(suggestion) = suggestion {
+                                let object = &text[..text.len() - header_name.len()];
+                                error_ranges.push(LintError {
+                                    message: format!(
+                                        "[misspelled_headers] Unknown header «{header_name}», did you mean «{suggestion}»?"
+                                    ),
+                                    loc: get_location!(node: node),
+                                    severity: config.misspelled_headers.lsp_severity().unwrap(),
+                                    data: Some(DiagnosticData {
+                                        r#type: LintErrorInternalType::MisspelledHeader,
+                                        quickfix_label: format!("Change to «{suggestion}»"),
+                                        replace_with: format!("{object}{suggestion}"),
+                                        alternatives: vec![],
+                                    }),
+                                    related_information: None,
+                                    tags: None,
+                                });
+                            }
+                        }
+                    }
+
+                    if let Some(replacement) =
+                        varnish_builtins::get_deprecated_variable_replacement(flavor, &text)
+                    {
+                        error_ranges.push(LintError {
+                            message: format!(
+                                "«{text}» is deprecated in {}, use «{replacement}» instead",
+                                flavor.name()
+                            ),
+                            loc: get_location!(node: node),
+                            severity: DiagnosticSeverity::WARNING,
+                            data: None,
+                            related_information: None,
+                            tags: Some(vec![DiagnosticTag::DEPRECATED]),
+                        });
+                    }
+                    if let Some(replacement) =
+                        varnish_builtins::get_removed_variable_replacement(flavor, &text)
+                    {
+                        add_error!(
+                            "«{text}» was removed in {}, use «{replacement}» instead",
+                            flavor.name()
+                        );
+                    }
+                    if let (Some(vcl_version), Some((replaced_in, replacement))) = (
+                        vcl_version,
+                        varnish_builtins::get_variable_replaced_in_vcl_version(&text),
+                    ) {
+                        if vcl_version >= replaced_in {
+                            add_error!(
+                                "«{text}» is replaced by «{replacement}» in vcl {replaced_in}"
+                            );
+                        }
+                    }
+
                     // check whether e.g. req/resp is allowed from this builtin subroutine
                     // TODO: check where custom subroutines are called from
-                    let toplev_decl = get_toplev_declaration_from_node(node);
                     if toplev_decl.kind() == "sub_declaration" {
                         if let Some(ident_node) = toplev_decl.child_by_field_name("ident") {
                             let sub_name = &*get_node_text(&self.rope, &ident_node);
-                            let parts = text.split('.').collect::<Vec<&str>>();
                             if sub_name.starts_with("vcl_") {
-                                let exists_in_sub = match parts[0] {
-                                    "req" | "req_top" => matches!(
-                                        sub_name,
-                                        "vcl_recv"
-                                            | "vcl_deliver"
-                                            | "vcl_synth"
-                                            | "vcl_miss"
-                                            | "vcl_hit"
-                                            | "vcl_pass"
-                                            | "vcl_purge"
-                                            | "vcl_pipe"
-                                            | "vcl_hash"
-                                    ),
-                                    "bereq" => matches!(
-                                        sub_name,
-                                        "vcl_backend_fetch"
-                                            | "vcl_backend_response"
-                                            | "vcl_pipe"
-                                            | "vcl_backend_error"
-                                    ),
-                                    "beresp" => matches!(
-                                        sub_name,
-                                        "vcl_backend_response" | "vcl_backend_error"
-                                    ),
-                                    "resp" => {
-                                        matches!(sub_name, "vcl_deliver" | "vcl_miss" | "vcl_synth")
-                                    }
-                                    "obj" => matches!(sub_name, "vcl_hit" | "vcl_deliver"),
-                                    _ => true,
-                                };
-
-                                if !exists_in_sub {
-                                    add_error!("«{}» does not exist in «{}»", parts[0], sub_name);
+                                if let Some((variable, _)) =
+                                    varnish_builtins::get_variable_subroutines(&text).filter(|_| {
+                                        !varnish_builtins::is_variable_available_in_sub(
+                                            flavor, &text, sub_name,
+                                        )
+                                    })
+                                {
+                                    add_error!("«{}» does not exist in «{}»", variable, sub_name);
                                 }
                             }
                         }
@@ -950,31 +3174,185 @@ impl Document {
                         }
                     }
                 }
-                "rmatch" | "nmatch" => {
-                    // right hand side is the regex
-                    let re_node = node
-                        .parent()
-                        .unwrap()
-                        .parent()
-                        .unwrap()
-                        .child_by_field_name("right");
-                    if let Some(re_node) = re_node {
-                        let re_str = get_node_text(&self.rope, &re_node);
-                        match is_regex_safe(re_str) {
-                            Err(SafeRegexError::ParseError) => {
-                                add_error!(node: re_node, "Regex might be invalid");
-                            }
-                            Err(SafeRegexError::StarHeightError) => {
-                                add_error!(
-                                    node: re_node,
-                                    severity: DiagnosticSeverity::WARNING,
-                                    "Regex might be exponentially slow"
-                                );
-                            }
-                            Err(SafeRegexError::TooManyRepititions) => {
-                                add_error!(
-                                    node: re_node,
-                                    severity: DiagnosticSeverity::WARNING,
+                "binary_expression" => {
+                    let (Some(left_node), Some(operator_node), Some(right_node)) = (
+                        node.child_by_field_name("left"),
+                        node.child_by_field_name("operator"),
+                        node.child_by_field_name("right"),
+                    ) else {
+                        continue;
+                    };
+
+                    let operator = get_node_text(&self.rope, &operator_node);
+
+                    // only ip addresses can be matched against an acl, e.g. «client.ip ~ purgers»
+                    if matches!(operator.as_str(), "~" | "!~")
+                        && matches!(
+                            self.get_expr_type(right_node, global_scope),
+                            Some(Type::Acl)
+                        )
+                    {
+                        if let Some(left_type) =
+                            self.get_expr_type(left_node, global_scope)
+                                .filter(|left_type| {
+                                    !matches!(left_type, Type::IP | Type::Obj(_) | Type::Func(_))
+                                })
+                        {
+                            add_error!(
+                                node: left_node,
+                                "Expected IP to match against the acl, found {left_type}"
+                            );
+                        }
+                        continue;
+                    }
+
+                    // comparing a variable or literal to itself, e.g. «req.http.x == req.http.x»
+                    let (left_expr_node, right_expr_node) =
+                        (unwrap_expr_node(left_node), unwrap_expr_node(right_node));
+                    let is_same_operand = match (left_expr_node.kind(), right_expr_node.kind()) {
+                        ("ident" | "nested_ident", "ident" | "nested_ident") => {
+                            get_node_text(&self.rope, &left_expr_node).to_lowercase()
+                                == get_node_text(&self.rope, &right_expr_node).to_lowercase()
+                        }
+                        ("literal", "literal") => {
+                            get_node_text(&self.rope, &left_expr_node)
+                                == get_node_text(&self.rope, &right_expr_node)
+                        }
+                        _ => false,
+                    };
+                    let always = match operator.as_str() {
+                        "==" | "<=" | ">=" => Some(true),
+                        "!=" | "<" | ">" => Some(false),
+                        _ => None,
+                    };
+                    if let Some(always) = always.filter(|_| is_same_operand) {
+                        add_error!(
+                            node: node,
+                            severity: DiagnosticSeverity::WARNING,
+                            "«{}» is compared to itself, so this is always {always}",
+                            get_node_text(&self.rope, &left_expr_node)
+                        );
+                        continue;
+                    }
+
+                    let string_text = get_string_literal_node(right_node)
+                        .map(|string_node| get_node_text(&self.rope, &string_node));
+                    if let Some(string_text) = string_text
+                        .as_deref()
+                        .filter(|string_text| looks_like_regex(unquote_string(string_text)))
+                    {
+                        let pattern = unquote_string(string_text);
+                        let regex_operator = match operator.as_str() {
+                            "==" => "~",
+                            "!=" => "!~",
+                            _ => continue,
+                        };
+                        let left_text = get_node_text(&self.rope, &left_node);
+                        let mut alternatives = vec![];
+                        if !pattern.starts_with('^') {
+                            if let Some(prefix) = pattern.strip_suffix(".*") {
+                                let quote_len = (string_text.len() - pattern.len()) / 2;
+                                let anchored = format!(
+                                    "{left_text} {regex_operator} {}^{prefix}{}",
+                                    &string_text[..quote_len],
+                                    &string_text[string_text.len() - quote_len..]
+                                );
+                                alternatives.push((format!("Replace with {anchored}"), anchored));
+                            }
+                        }
+                        error_ranges.push(LintError {
+                            message: format!(
+                                "«{operator}» compares strings exactly, but this looks like a regex (use «{regex_operator}»)"
+                            ),
+                            loc: get_location!(node: node),
+                            severity: DiagnosticSeverity::WARNING,
+                            data: Some(DiagnosticData {
+                                r#type: LintErrorInternalType::RegexComparedAsString,
+                                quickfix_label: format!("Replace «{operator}» with «{regex_operator}»"),
+                                replace_with: format!(
+                                    "{}{regex_operator}{}",
+                                    self.rope.byte_slice(node.start_byte()..operator_node.start_byte()),
+                                    self.rope.byte_slice(operator_node.end_byte()..node.end_byte()),
+                                ),
+                                alternatives,
+                            }),
+                            related_information: None,
+                            tags: None,
+                        });
+                        continue;
+                    }
+
+                    let left_text = get_node_text(&self.rope, &left_node);
+                    if !matches!(left_text.as_str(), "req.method" | "bereq.method")
+                        || !matches!(operator.as_str(), "==" | "!=")
+                    {
+                        continue;
+                    }
+
+                    let Some(string_node) = right_node
+                        .named_child(0)
+                        .filter(|string_node| string_node.kind() == "string")
+                    else {
+                        continue;
+                    };
+                    let string_text = get_node_text(&self.rope, &string_node);
+                    let Some(method) = string_text
+                        .strip_prefix('"')
+                        .and_then(|method| method.strip_suffix('"'))
+                    else {
+                        continue;
+                    };
+
+                    if config.http_methods.iter().any(|known| known == method) {
+                        continue;
+                    }
+
+                    // methods are tokens (RFC 9110), e.g. no whitespace or separators
+                    let is_token = !method.is_empty()
+                        && method
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+                    if !is_token {
+                        add_error!(
+                            node: string_node,
+                            severity: DiagnosticSeverity::WARNING,
+                            "Malformed HTTP method"
+                        );
+                    } else if method.chars().any(|c| c.is_ascii_lowercase()) {
+                        add_hint!(
+                            node: string_node,
+                            "HTTP methods are case sensitive, and Varnish does not normalize them (did you mean «{}»?)",
+                            method.to_uppercase()
+                        );
+                    } else {
+                        add_hint!(node: string_node, "Unknown HTTP method «{method}»");
+                    }
+                }
+                "rmatch" | "nmatch" => {
+                    // right hand side is the regex
+                    let re_node = node
+                        .parent()
+                        .unwrap()
+                        .parent()
+                        .unwrap()
+                        .child_by_field_name("right");
+                    if let Some(re_node) = re_node {
+                        let re_str = get_node_text(&self.rope, &re_node);
+                        match is_regex_safe(re_str) {
+                            Err(SafeRegexError::ParseError) => {
+                                add_error!(node: re_node, "Regex might be invalid");
+                            }
+                            Err(SafeRegexError::StarHeightError) => {
+                                add_error!(
+                                    node: re_node,
+                                    severity: DiagnosticSeverity::WARNING,
+                                    "Regex might be exponentially slow"
+                                );
+                            }
+                            Err(SafeRegexError::TooManyRepititions) => {
+                                add_error!(
+                                    node: re_node,
+                                    severity: DiagnosticSeverity::WARNING,
                                     "Regex might be slow (too many repititions)"
                                 );
                             }
@@ -994,32 +3372,361 @@ impl Document {
             }
         }
 
+        // placeholders are opaque, so e.g. their type is unknown
+        error_ranges.retain(|error| {
+            !self.placeholder_ranges.iter().any(|placeholder_range| {
+                placeholder_range.start < error.loc.range.end
+                    && error.loc.range.start < placeholder_range.end
+            })
+        });
+
         error_ranges
     }
 
+    /**
+     * Find headers that are set and then unset without being read in between, and headers that
+     * are unset twice, within the statements of a block (sub, if, else if or else).
+     * Nested blocks and subroutine calls might read anything, so they reset the tracking.
+     */
+    fn get_redundant_header_writes_errors(
+        &self,
+        block_node: Node,
+        severity: DiagnosticSeverity,
+    ) -> Vec<LintError> {
+        let mut errors = Vec::new();
+        // header (lowercased) -> set statement which hasn't been read yet
+        let mut pending_sets: HashMap<String, Node> = HashMap::new();
+        // header (lowercased) -> unset statement, without any set since
+        let mut unsets: HashMap<String, Node> = HashMap::new();
+
+        let get_header = |ident_node: &Node| -> Option<String> {
+            let ident = get_node_text(&self.rope, ident_node).to_lowercase();
+            let mut parts = ident.splitn(3, '.');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(_), Some("http"), Some(header)) if !header.is_empty() => Some(ident),
+                _ => None,
+            }
+        };
+
+        let location = |node: &Node| Location {
+            uri: self.url.to_owned(),
+            range: ts_range_to_lsp_range(node.range()),
+        };
+
+        let mut cursor = block_node.walk();
+        for stmt_node in block_node
+            .named_children(&mut cursor)
+            .filter(|child| child.kind() == "stmt")
+        {
+            let Some(stmt_node) = stmt_node.named_child(0) else {
+                continue;
+            };
+
+            match stmt_node.kind() {
+                "set_stmt" => {
+                    if let Some(right_node) = stmt_node.child_by_field_name("right") {
+                        self.mark_headers_as_read(right_node, &mut pending_sets);
+                    }
+
+                    let Some(left_node) = stmt_node.child_by_field_name("left") else {
+                        continue;
+                    };
+                    let Some(header) = get_header(&left_node) else {
+                        continue;
+                    };
+
+                    let operator = stmt_node
+                        .child_by_field_name("operator")
+                        .map(|operator_node| get_node_text(&self.rope, &operator_node));
+                    if operator.as_deref() != Some("=") {
+                        pending_sets.remove(&header);
+                        continue;
+                    }
+
+                    unsets.remove(&header);
+                    pending_sets.insert(header, stmt_node);
+                }
+                "unset_stmt" => {
+                    let Some(ident_node) = stmt_node.named_child(0) else {
+                        continue;
+                    };
+                    let Some(header) = get_header(&ident_node) else {
+                        continue;
+                    };
+
+                    if let Some(set_node) = pending_sets.remove(&header) {
+                        errors.push(LintError {
+                            message: format!(
+                                "Redundant set, {header} is unset later without being read"
+                            ),
+                            loc: location(&set_node),
+                            severity,
+                            data: None,
+                            related_information: Some(vec![DiagnosticRelatedInformation {
+                                location: location(&stmt_node),
+                                message: "Unset here".into(),
+                            }]),
+                            tags: None,
+                        });
+                    } else if let Some(prev_unset_node) = unsets.get(&header) {
+                        errors.push(LintError {
+                            message: format!("Redundant unset, {header} is already unset"),
+                            loc: location(&stmt_node),
+                            severity,
+                            data: None,
+                            related_information: Some(vec![DiagnosticRelatedInformation {
+                                location: location(prev_unset_node),
+                                message: "Previously unset here".into(),
+                            }]),
+                            tags: None,
+                        });
+                    }
+
+                    unsets.insert(header, stmt_node);
+                }
+                "ident_call_stmt" | "new_stmt" => {
+                    // vmod functions might both read and write the headers passed to them
+                    self.mark_headers_as_read(stmt_node, &mut pending_sets);
+                    self.mark_headers_as_read(stmt_node, &mut unsets);
+                }
+                _ => {
+                    // if statements, sub calls, returns and includes can read (or set) anything
+                    pending_sets.clear();
+                    unsets.clear();
+                }
+            }
+        }
+
+        errors
+    }
+
+    /// Remove headers referenced anywhere within node from headers
+    fn mark_headers_as_read(&self, node: Node, headers: &mut HashMap<String, Node>) {
+        if headers.is_empty() {
+            return;
+        }
+
+        if node.kind() == "nested_ident" {
+            let ident = get_node_text(&self.rope, &node).to_lowercase();
+            // reading e.g. «req.http» reads all headers
+            headers.retain(|header, _| {
+                header != &ident && !header.starts_with(format!("{ident}.").as_str())
+            });
+            return;
+        }
+
+        let mut cursor = node.walk();
+        for child in node.named_children(&mut cursor) {
+            self.mark_headers_as_read(child, headers);
+        }
+    }
+
     pub fn diagnostics(
         &self,
         global_scope: Definitions,
         lint_config: &LintConfig,
     ) -> Vec<Diagnostic> {
-        return self
-            .get_error_ranges(&global_scope, lint_config)
+        let mut lint_errors = self.get_error_ranges(&global_scope, lint_config);
+        lint_errors.append(&mut get_call_cycle_errors(&self.get_sub_calls()));
+        dedup_lint_errors(lint_errors)
             .iter()
-            .map(|lint_error| Diagnostic {
-                range: lint_error.loc.range,
-                severity: Some(lint_error.severity),
-                message: lint_error.message.to_owned(),
-                data: lint_error
-                    .data
-                    .as_ref()
-                    .and_then(|data| serde_json::to_value(data).ok()),
-                ..Diagnostic::default()
+            .map(LintError::to_diagnostic)
+            .collect()
+    }
+
+    /// Get the subroutines declared in this document and the subroutines they call
+    pub fn get_sub_calls(&self) -> Vec<SubCalls> {
+        let q = Query::new(
+            &self.ast.language(),
+            "(call_stmt ident: (ident) @ident) @call",
+        )
+        .unwrap();
+        let ident_capt_idx = q.capture_index_for_name("ident").unwrap();
+        let call_capt_idx = q.capture_index_for_name("call").unwrap();
+
+        let root_node = self.ast.root_node();
+        let mut cursor = root_node.walk();
+        let toplev_declarations = root_node.named_children(&mut cursor).collect::<Vec<_>>();
+        toplev_declarations
+            .into_iter()
+            .filter_map(|toplev_node| toplev_node.named_child(0))
+            .filter(|node| node.kind() == "sub_declaration")
+            .filter_map(|sub_node| {
+                let ident_node = sub_node.child_by_field_name("ident")?;
+                let mut calls = vec![];
+                let mut qc = QueryCursor::new();
+                let mut all_matches = qc.matches(&q, sub_node, self);
+                while let Some(each_match) = all_matches.next() {
+                    let capture = |idx| each_match.captures.iter().find(|c| c.index == idx);
+                    let (Some(ident), Some(call)) =
+                        (capture(ident_capt_idx), capture(call_capt_idx))
+                    else {
+                        continue;
+                    };
+                    calls.push((
+                        get_node_text(&self.rope, &ident.node),
+                        Location {
+                            uri: self.url.clone(),
+                            range: ts_range_to_lsp_range(call.node.range()),
+                        },
+                    ));
+                }
+                Some(SubCalls {
+                    name: get_node_text(&self.rope, &ident_node),
+                    loc: Location {
+                        uri: self.url.clone(),
+                        range: ts_range_to_lsp_range(ident_node.range()),
+                    },
+                    calls,
+                })
             })
-            .collect();
+            .collect()
+    }
+
+    /// The backends and probes declared in this document
+    pub fn get_backend_and_probe_declarations(&self) -> Vec<Declaration> {
+        let root_node = self.ast.root_node();
+        let mut cursor = root_node.walk();
+        let toplev_declarations = root_node.named_children(&mut cursor).collect::<Vec<_>>();
+        toplev_declarations
+            .into_iter()
+            .filter_map(|toplev_node| toplev_node.named_child(0))
+            .filter_map(|node| {
+                let kind = match node.kind() {
+                    "backend_declaration" => "backend",
+                    "probe_declaration" => "probe",
+                    _ => return None,
+                };
+                let ident_node = node.child_by_field_name("ident")?;
+                let mut range = ts_range_to_lsp_range(node.range());
+                let end_line = node.end_position().row;
+                let rest_of_line = self
+                    .rope
+                    .get_line(end_line)
+                    .and_then(|line| line.get_byte_slice(node.end_position().column..))
+                    .map(|rest| rest.to_string())
+                    .unwrap_or_default();
+                if rest_of_line.trim().is_empty() && end_line + 1 < self.rope.len_lines() {
+                    range.end = Position::new(end_line as u32 + 1, 0);
+                }
+                let mut nested_pos = self.pos_from_main_doc.clone();
+                nested_pos.push((node.start_position().row, node.start_position().column));
+                Some(Declaration {
+                    name: get_node_text(&self.rope, &ident_node),
+                    kind,
+                    loc: Location {
+                        uri: self.url.clone(),
+                        range,
+                    },
+                    nested_pos,
+                })
+            })
+            .collect()
+    }
+
+    /// The header at point, lowercased, e.g. «req.http.x-foo»
+    pub fn get_header_at_point(&self, point: Point) -> Option<String> {
+        let mut node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+        while node.kind() != "nested_ident" {
+            node = node.parent()?;
+        }
+        let ident = get_node_text(&self.rope, &node).to_lowercase();
+        let mut parts = ident.splitn(3, '.');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("req" | "bereq" | "resp" | "beresp" | "obj"), Some("http"), Some(header))
+                if !header.is_empty() =>
+            {
+                Some(ident)
+            }
+            _ => None,
+        }
+    }
+
+    /// The set and unset statements of a header (lowercased, e.g. «req.http.x-foo»), with the
+    /// name of the subroutine they are in
+    pub fn get_header_assignments(&self, header: &str) -> Vec<(String, Location)> {
+        let mut assignments = vec![];
+        let mut cursor = self.ast.walk();
+        let mut recurse = true;
+        loop {
+            if (recurse && cursor.goto_first_child()) || cursor.goto_next_sibling() {
+                recurse = true;
+            } else if cursor.goto_parent() {
+                recurse = false;
+                continue;
+            } else {
+                break;
+            }
+
+            let node = cursor.node();
+            let ident_node = match node.kind() {
+                "set_stmt" => node.child_by_field_name("left"),
+                "unset_stmt" => node.named_child(0),
+                _ => continue,
+            };
+            let Some(ident_node) = ident_node else {
+                continue;
+            };
+            if get_node_text(&self.rope, &ident_node).to_lowercase() != header {
+                continue;
+            }
+            let toplev_decl = get_toplev_declaration_from_node(node);
+            let Some(sub_ident_node) = (toplev_decl.kind() == "sub_declaration")
+                .then(|| toplev_decl.child_by_field_name("ident"))
+                .flatten()
+            else {
+                continue;
+            };
+            assignments.push((
+                get_node_text(&self.rope, &sub_ident_node),
+                Location {
+                    uri: self.url.to_owned(),
+                    range: ts_range_to_lsp_range(node.range()),
+                },
+            ));
+        }
+        assignments
+    }
+
+    /// Identifiers this document refers to, apart from the names of its declarations
+    pub fn get_referenced_idents(&self) -> HashSet<String> {
+        let mut idents = HashSet::new();
+        let mut cursor = self.ast.walk();
+        let mut recurse = true;
+        loop {
+            if (recurse && cursor.goto_first_child()) || cursor.goto_next_sibling() {
+                recurse = true;
+            } else if cursor.goto_parent() {
+                recurse = false;
+                continue;
+            } else {
+                break;
+            }
+
+            let node = cursor.node();
+            if node.kind() != "ident" {
+                continue;
+            }
+            let is_declared_name = node.parent().is_some_and(|parent_node| {
+                parent_node.kind().ends_with("_declaration")
+                    && parent_node.child_by_field_name("ident") == Some(node)
+            });
+            if !is_declared_name {
+                idents.insert(get_node_text(&self.rope, &node));
+            }
+        }
+        idents
     }
 
     pub fn get_vmod_imports(&self) -> Vec<VmodImport> {
-        let q = Query::new(&self.ast.language(), "(import_declaration (ident) @ident)").unwrap();
+        let q = Query::new(
+            &self.ast.language(),
+            "(import_declaration ident: (ident) @ident)",
+        )
+        .unwrap();
         let mut qc = QueryCursor::new();
         let mut all_matches = qc.matches(&q, self.ast.root_node(), self);
         let capt_idx = q.capture_index_for_name("ident").unwrap();
@@ -1032,6 +3739,19 @@ impl Document {
                 let range = ts_range_to_lsp_range(ts_range);
                 let mut nested_pos = self.pos_from_main_doc.clone();
                 nested_pos.push(point_to_tuple(ts_range.start_point));
+                let from = capture.node.parent().and_then(|import_node| {
+                    let mut cursor = import_node.walk();
+                    let string_node = import_node
+                        .children(&mut cursor)
+                        .find(|node| node.kind() == "string")?;
+                    let text = get_node_text(&self.rope, &string_node);
+                    Some(PathBuf::from(unquote_string(&text)))
+                });
+                let alias = capture
+                    .node
+                    .parent()
+                    .and_then(|import_node| import_node.child_by_field_name("alias"))
+                    .map(|alias_node| get_node_text(&self.rope, &alias_node).to_string());
                 imports.push(VmodImport {
                     name,
                     loc: Location {
@@ -1039,6 +3759,8 @@ impl Document {
                         range,
                     },
                     nested_pos,
+                    from,
+                    alias,
                 });
             }
         }
@@ -1046,37 +3768,669 @@ impl Document {
         imports
     }
 
-    pub fn get_includes(&self) -> Vec<Include> {
-        let q = Query::new(
-            &self.ast.language(),
-            "(include_declaration (string) @string)",
-        )
-        .unwrap();
-        let mut qc = QueryCursor::new();
-        let mut all_matches = qc.matches(&q, self.ast.root_node(), self);
-        let capt_idx = q.capture_index_for_name("string").unwrap();
+    /// A vmod function or object named by the vmod instead of its import alias, e.g.
+    /// «std.fileread» for «s.fileread» after «import std as s;»
+    pub fn unalias_vmod_ident(&self, ident: &str) -> String {
+        let Some((scope_name, member)) = ident.split_once('.') else {
+            return ident.to_string();
+        };
+        self.get_vmod_imports()
+            .into_iter()
+            .find(|import| import.scope_name() == scope_name)
+            .map_or_else(
+                || ident.to_string(),
+                |import| format!("{}.{member}", import.name),
+            )
+    }
 
-        let mut includes = Vec::new();
-        while let Some(each_match) = all_matches.next() {
-            for capture in each_match.captures.iter().filter(|c| c.index == capt_idx) {
-                let range = capture.node.range();
-                let text = get_node_text(&self.rope, &capture.node);
-                let path_str = text.trim_matches('"');
-                let path = PathBuf::from(path_str);
-                let url = if path.starts_with("./") || path.starts_with("../") {
-                    // relative to current vcl
-                    self.url.join(path_str).ok()
-                } else {
-                    None
-                };
+    /// Hover text and range of the vcl version declaration at point
+    pub fn get_vcl_version_hover(&self, point: Point) -> Option<(String, Range)> {
+        let mut node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+        while node.kind() != "vcl_version_declaration" {
+            node = node.parent()?;
+        }
 
-                let mut nested_pos = self.pos_from_main_doc.clone();
-                nested_pos.push(point_to_tuple(range.start_point));
-                includes.push(Include {
-                    url,
-                    path,
-                    nested_pos,
-                });
+        let mut cursor = node.walk();
+        let number_node = node
+            .named_children(&mut cursor)
+            .find(|child| child.kind() == "number")?;
+        let version_str = get_node_text(&self.rope, &number_node);
+        let summary = VclVersion::parse(&version_str)
+            .and_then(varnish_builtins::get_vcl_version_summary)
+            .unwrap_or("Unknown VCL version. Varnish supports VCL 4.0 and 4.1.");
+        Some((
+            format!("**VCL {version_str}**\n\n{summary}"),
+            ts_range_to_lsp_range(node.range()),
+        ))
+    }
+
+    /// Markdown describing the builtin variable at point, e.g. «obj.ttl», with the builtin
+    /// subroutines it is readable from
+    pub fn get_variable_hover(&self, point: Point, scope: &Definitions) -> Option<(String, Range)> {
+        let mut node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+        while node.kind() != "nested_ident" {
+            node = node.parent()?;
+        }
+        let variable = get_node_text(&self.rope, &node);
+        let doc = varnish_builtins::get_variable_doc(&variable)?;
+        let r#type = scope.get_type_property_by_nested_idents(variable.split('.').collect());
+
+        let mut value = match r#type {
+            Some(r#type) => format!("`{type} {variable}`\n\n{doc}"),
+            None => format!("`{variable}`\n\n{doc}"),
+        };
+        if let Some((_, subs)) = varnish_builtins::get_variable_subroutines(&variable) {
+            value.push_str(&format!("\n\nReadable from: {}", subs.join(", ")));
+        }
+        Some((value, ts_range_to_lsp_range(node.range())))
+    }
+
+    /// Markdown for a «new» statement: the constructor signature, the vmod it comes from and the
+    /// methods of the resulting object
+    pub fn get_new_stmt_hover(&self, point: Point, scope: &Definitions) -> Option<(String, Range)> {
+        let mut node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+        // arguments have hovers of their own
+        while node.kind() != "new_stmt" {
+            if node.kind() == "func_call_args" {
+                return None;
+            }
+            node = node.parent()?;
+        }
+
+        let ident = get_node_text(&self.rope, &node.child_by_field_name("ident")?);
+        let constructor_ident = get_node_text(
+            &self.rope,
+            &node
+                .child_by_field_name("def_right")?
+                .child_by_field_name("ident")?,
+        );
+        let Some(Type::Func(constructor)) =
+            scope.get_type_property_by_nested_idents(constructor_ident.split('.').collect())
+        else {
+            return None;
+        };
+        let Some(Type::Obj(obj)) = constructor.r#return.as_deref() else {
+            return None;
+        };
+        let vmod_name = constructor_ident
+            .split_once('.')
+            .map(|(vmod_name, _)| vmod_name)
+            .unwrap_or(&constructor_ident);
+
+        let mut value = format!(
+            "**new {ident}**: object `{}` from vmod `{vmod_name}`\n\n```vcl\nnew {ident} = {constructor_ident}{}\n```",
+            obj.name,
+            constructor.get_signature_string()
+        );
+        let methods = obj
+            .properties
+            .values()
+            .filter_map(|property| match property {
+                Type::Func(method) => Some(format!(
+                    "{} {ident}.{}{}",
+                    method.get_return_type_label(),
+                    method.name,
+                    method.get_signature_string()
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if !methods.is_empty() {
+            value.push_str(&format!(
+                "\n\nMethods:\n\n```vcl\n{}\n```",
+                methods.join("\n")
+            ));
+        }
+        if let Some(doc) = constructor
+            .doc
+            .as_ref()
+            .filter(|doc| !doc.trim().is_empty())
+        {
+            value.push_str(&format!("\n\n{doc}"));
+        }
+
+        Some((value, ts_range_to_lsp_range(node.range())))
+    }
+
+    /// Markdown listing the entries of an acl declared in this document, with the number of
+    /// addresses each entry covers
+    pub fn get_acl_hover(&self, name: &str) -> Option<String> {
+        let root_node = self.ast.root_node();
+        let mut cursor = root_node.walk();
+        let acl_node = root_node
+            .named_children(&mut cursor)
+            .filter_map(|toplev_node| toplev_node.named_child(0))
+            .find(|decl_node| {
+                decl_node.kind() == "acl_declaration"
+                    && decl_node
+                        .child_by_field_name("ident")
+                        .is_some_and(|ident_node| get_node_text(&self.rope, &ident_node) == name)
+            })?;
+
+        let mut lines = vec![format!("**acl {name}**"), String::new()];
+        let mut ipv4_total: u64 = 0;
+        let mut cursor = acl_node.walk();
+        for entry_node in acl_node
+            .named_children(&mut cursor)
+            .filter(|node| node.kind() == "acl_entry")
+        {
+            let negated = entry_node.child(0).is_some_and(|node| node.kind() == "!");
+            let Some(string_node) = entry_node.named_child(0).filter(|n| n.kind() == "string")
+            else {
+                continue;
+            };
+            let address_text = get_node_text(&self.rope, &string_node);
+            let address = unquote_string(&address_text);
+            let mut entry_cursor = entry_node.walk();
+            let prefix_text = entry_node
+                .children(&mut entry_cursor)
+                .skip_while(|node| node.kind() != "/")
+                .nth(1)
+                .map(|node| get_node_text(&self.rope, &node));
+            let prefix_len = prefix_text.as_deref().map(|text| text.parse::<u32>());
+
+            let entry = format!(
+                "{}{address_text}{}",
+                if negated { "!" } else { "" },
+                prefix_text
+                    .as_ref()
+                    .map(|prefix| format!("/{prefix}"))
+                    .unwrap_or_default()
+            );
+            let size = match (address.parse::<std::net::IpAddr>(), prefix_len) {
+                (_, Some(Err(_))) => "invalid prefix length".to_string(),
+                (Ok(std::net::IpAddr::V4(_)), prefix_len) => {
+                    let prefix_len = prefix_len.and_then(Result::ok).unwrap_or(32);
+                    match 32u32.checked_sub(prefix_len) {
+                        Some(host_bits) => {
+                            let count = 1u64 << host_bits;
+                            if !negated {
+                                ipv4_total += count;
+                            }
+                            format_address_count(count)
+                        }
+                        None => "invalid prefix length".to_string(),
+                    }
+                }
+                (Ok(std::net::IpAddr::V6(_)), prefix_len) => {
+                    let prefix_len = prefix_len.and_then(Result::ok).unwrap_or(128);
+                    match 128u32.checked_sub(prefix_len) {
+                        // too many addresses to be meaningful, show the prefix length instead
+                        Some(host_bits) if host_bits > 32 => format!("/{prefix_len} range"),
+                        Some(host_bits) => format_address_count(1u64 << host_bits),
+                        None => "invalid prefix length".to_string(),
+                    }
+                }
+                (Err(_), _) => "hostname, resolved when the vcl is loaded".to_string(),
+            };
+            lines.push(format!("- `{entry}` ({size})"));
+        }
+
+        if lines.len() == 2 {
+            lines.push("No entries".to_string());
+        } else if ipv4_total > 0 {
+            lines.push(String::new());
+            lines.push(format!(
+                "Matches up to {} (IPv4, not counting negated entries)",
+                format_address_count(ipv4_total)
+            ));
+        }
+
+        Some(lines.join("\n"))
+    }
+
+    /**
+     * Inlay hints after builtin subroutines that can fall through their end, with what the
+     * builtin.vcl implementation running afterwards returns, e.g. «⟶ return(lookup)»
+     */
+    pub fn get_implicit_return_hints(&self, range: Range) -> Vec<InlayHint> {
+        let root_node = self.ast.root_node();
+        let mut cursor = root_node.walk();
+        let toplev_declarations = root_node.named_children(&mut cursor).collect::<Vec<_>>();
+        toplev_declarations
+            .into_iter()
+            .filter_map(|toplev_node| toplev_node.named_child(0))
+            .filter(|node| node.kind() == "sub_declaration")
+            .filter_map(|sub_node| {
+                let sub_name = get_node_text(&self.rope, &sub_node.child_by_field_name("ident")?);
+                let action = varnish_builtins::get_sub_default_return_action(&sub_name)?;
+                let closing_brace = sub_node.child(sub_node.child_count().checked_sub(1)?)?;
+                if closing_brace.kind() != "}" || closing_brace.is_missing() {
+                    return None;
+                }
+                let mut cursor = sub_node.walk();
+                let stmts = sub_node
+                    .named_children(&mut cursor)
+                    .filter(|child| child.kind() == "stmt")
+                    .collect::<Vec<_>>();
+                if stmts_always_return(stmts.into_iter()) {
+                    return None;
+                }
+                let position = point_to_position(closing_brace.end_position());
+                if position < range.start || range.end < position {
+                    return None;
+                }
+                Some(InlayHint {
+                    position,
+                    label: InlayHintLabel::String(format!("⟶ return({action})")),
+                    kind: None,
+                    text_edits: None,
+                    tooltip: Some(InlayHintTooltip::String(format!(
+                        "{sub_name} has no return here, so the builtin {sub_name} runs afterwards, which usually ends in return({action})"
+                    ))),
+                    padding_left: Some(true),
+                    padding_right: None,
+                    data: None,
+                })
+            })
+            .collect()
+    }
+
+    /**
+     * The range of a string starting within a syntax error and not closed on its line, e.g.
+     * «"line two;» in «"line one" "line two;». The lexer can't read the string, so the error
+     * node might only cover the quote, or the strings before it as well.
+     */
+    fn get_unterminated_string_range(&self, error_node: Node) -> Option<Range> {
+        let end_line = self.rope.byte_to_line(error_node.end_byte());
+        let end_byte = match end_line + 1 < self.rope.len_lines() {
+            true => self.rope.line_to_byte(end_line + 1),
+            false => self.rope.len_bytes(),
+        };
+        let text = self
+            .rope
+            .get_byte_slice(error_node.start_byte()..end_byte)?
+            .to_string();
+        let string_range = find_unterminated_string(&text)
+            .filter(|range| error_node.start_byte() + range.start < error_node.end_byte())?;
+        let to_position = |byte: usize| {
+            let line = self.rope.byte_to_line(byte);
+            Position::new(line as u32, (byte - self.rope.line_to_byte(line)) as u32)
+        };
+        Some(Range::new(
+            to_position(error_node.start_byte() + string_range.start),
+            to_position(error_node.start_byte() + string_range.end),
+        ))
+    }
+
+    /// The flavor of this document: the one named by its flavor directive, if known, or else the
+    /// workspace flavor
+    pub fn get_flavor(&self, workspace_flavor: VarnishFlavor) -> VarnishFlavor {
+        self.get_flavor_directive()
+            .and_then(|(name, _range)| VarnishFlavor::from_name(&name))
+            .unwrap_or(workspace_flavor)
+    }
+
+    /// The flavor named by a «# varnish-lsp flavor: varnish-7» comment at the top of the
+    /// document (before anything but comments and the vcl version), and the range of the name
+    pub fn get_flavor_directive(&self) -> Option<(String, Range)> {
+        let root_node = self.ast.root_node();
+        let mut cursor = root_node.walk();
+        let leading_comments = root_node
+            .children(&mut cursor)
+            .flat_map(|node| match node.kind() {
+                "toplev_declaration" => node.named_child(0),
+                _ => Some(node),
+            })
+            .take_while(|node| matches!(node.kind(), "COMMENT" | "vcl_version_declaration"))
+            .filter(|node| node.kind() == "COMMENT")
+            .collect::<Vec<_>>();
+        leading_comments.into_iter().find_map(|node| {
+            let content = get_node_text(&self.rope, &node);
+            let (before, after) = content.split_once(FLAVOR_DIRECTIVE)?;
+            let name = after
+                .split(|c: char| c.is_whitespace() || c == '*')
+                .find(|name| !name.is_empty())?;
+            let preceding = &content[..before.len() + FLAVOR_DIRECTIVE.len() + after.find(name)?];
+            let mut start = node.start_position();
+            match preceding.rsplit_once('\n') {
+                Some((lines, column)) => {
+                    start.row += lines.matches('\n').count() + 1;
+                    start.column = column.len();
+                }
+                None => start.column += preceding.len(),
+            }
+            let end = Point::new(start.row, start.column + name.len());
+            Some((
+                name.to_string(),
+                Range::new(point_to_position(start), point_to_position(end)),
+            ))
+        })
+    }
+
+    /// get the vcl version declared in this document (e.g. «vcl 4.1;»)
+    pub fn get_vcl_version(&self) -> Option<VclVersion> {
+        let q = Query::new(
+            &self.ast.language(),
+            "(vcl_version_declaration (number) @version)",
+        )
+        .unwrap();
+        let mut qc = QueryCursor::new();
+        let mut all_matches = qc.matches(&q, self.ast.root_node(), self);
+        let capt_idx = q.capture_index_for_name("version").unwrap();
+
+        while let Some(each_match) = all_matches.next() {
+            if let Some(capture) = each_match.captures.iter().find(|c| c.index == capt_idx) {
+                let text = get_node_text(&self.rope, &capture.node);
+                return VclVersion::parse(&text);
+            }
+        }
+
+        None
+    }
+
+    /// vcl labels switched to with «return (vcl(label))», and the ranges of their names
+    pub fn get_vcl_labels(&self) -> Vec<(String, Range)> {
+        let q = Query::new(
+            &self.ast.language(),
+            "(varnish_internal_return_methods \"vcl\" (expr) @label)",
+        )
+        .unwrap();
+        let mut qc = QueryCursor::new();
+        let mut all_matches = qc.matches(&q, self.ast.root_node(), self);
+        let mut labels = vec![];
+        while let Some(each_match) = all_matches.next() {
+            for capture in each_match.captures {
+                let Some(ident_node) = get_label_ident(capture.node) else {
+                    continue;
+                };
+                labels.push((
+                    get_node_text(&self.rope, &ident_node),
+                    ts_range_to_lsp_range(ident_node.range()),
+                ));
+            }
+        }
+        labels
+    }
+
+    /**
+     * Hover of the label in «return (vcl(label))», telling whether it is one of the configured
+     * labels, or that labels are not checked without any configured
+     */
+    pub fn get_vcl_label_hover(
+        &self,
+        point: Point,
+        labels: Option<&[String]>,
+    ) -> Option<(String, Range)> {
+        let position = point_to_position(point);
+        let (label, range) = self
+            .get_vcl_labels()
+            .into_iter()
+            .find(|(_, range)| range.start <= position && position <= range.end)?;
+        let status = match labels {
+            Some(labels) if labels.contains(&label) => "One of the labels in `lint.vcl_labels`.",
+            Some(_) => "Not one of the labels in `lint.vcl_labels`.",
+            None => "Not checked, set `lint.vcl_labels` to the labels set up to check it.",
+        };
+        Some((
+            format!(
+                "`vcl label {label}`\n\nSet up outside of vcl with `varnishadm vcl.label`. {status}"
+            ),
+            range,
+        ))
+    }
+
+    pub fn get_includes(&self) -> Vec<Include> {
+        self.get_include_locations()
+            .into_iter()
+            .map(|(include, _range)| include)
+            .collect()
+    }
+
+    /**
+     * The vcls embedded in a varnishtest («varnish v1 -vcl { ... }»), each a separate vcl
+     * program. Like varnishtest, the vcl version is prepended, and with «-vcl+backend» a backend
+     * for each server.
+     */
+    pub fn get_embedded_vcls(&self) -> Vec<EmbeddedVcl> {
+        if !matches!(self.filetype, FileType::Vtc) {
+            return vec![];
+        }
+
+        let root_node = self.ast.root_node();
+        let mut server_names = vec![];
+        let mut vcl_nodes = vec![];
+        let mut cursor = root_node.walk();
+        let mut recurse = true;
+        loop {
+            let node = cursor.node();
+            match node.kind() {
+                "server" => {
+                    if let Some(ident_node) = node.named_child(0) {
+                        server_names.push(get_node_text(&self.rope, &ident_node));
+                    }
+                    recurse = false;
+                }
+                "vcl_argument" => {
+                    vcl_nodes.push(node);
+                    recurse = false;
+                }
+                _ => {}
+            }
+            if (recurse && cursor.goto_first_child()) || cursor.goto_next_sibling() {
+                recurse = true;
+            } else if cursor.goto_parent() && cursor.node() != root_node {
+                recurse = false;
+            } else {
+                break;
+            }
+        }
+
+        vcl_nodes
+            .into_iter()
+            .filter_map(|vcl_node| {
+                let mut cursor = vcl_node.walk();
+                let children = vcl_node.children(&mut cursor).collect::<Vec<_>>();
+                let open_node = children.iter().find(|child| child.kind() == "{")?;
+                let close_node = children.iter().rfind(|child| child.kind() == "}")?;
+                let with_backends = children.iter().any(|child| child.kind() == "+backend");
+
+                let mut prefix = "vcl 4.1; ".to_string();
+                if with_backends {
+                    for server_name in server_names.iter() {
+                        prefix.push_str(&format!(
+                            "backend {server_name} {{ .host = \"127.0.0.1\"; }} "
+                        ));
+                    }
+                }
+                let vcl = self
+                    .rope
+                    .byte_slice(open_node.end_byte()..close_node.start_byte())
+                    .to_string();
+                Some(EmbeddedVcl {
+                    text: format!("{prefix}{vcl}"),
+                    prefix,
+                    start: point_to_position(open_node.end_position()),
+                    end: point_to_position(close_node.start_position()),
+                    instance: vcl_node
+                        .parent()
+                        .and_then(|varnish_node| varnish_node.named_child(0))
+                        .map(|ident_node| get_node_text(&self.rope, &ident_node))
+                        .unwrap_or_default(),
+                    range: ts_range_to_lsp_range(vcl_node.range()),
+                })
+            })
+            .collect()
+    }
+
+    /**
+     * Folding ranges for blocks spanning multiple lines, from the line of the opening brace to
+     * the line before the closing one. In varnishtests this includes the embedded vcls and the
+     * blocks within them.
+     */
+    pub fn get_folding_ranges(&self) -> Vec<FoldingRange> {
+        let mut folding_ranges = vec![];
+        let mut cursor = self.ast.walk();
+        let mut recurse = true;
+        loop {
+            if (recurse && cursor.goto_first_child()) || cursor.goto_next_sibling() {
+                recurse = true;
+            } else if cursor.goto_parent() {
+                recurse = false;
+                continue;
+            } else {
+                break;
+            }
+
+            let node = cursor.node();
+            if node.child_count() == 0 {
+                continue;
+            }
+            let mut child_cursor = node.walk();
+            let children = node.children(&mut child_cursor).collect::<Vec<_>>();
+            let Some(open_node) = children.iter().find(|child| child.kind() == "{") else {
+                continue;
+            };
+            let Some(close_node) = children.iter().rfind(|child| child.kind() == "}") else {
+                continue;
+            };
+            let start_line = open_node.start_position().row as u32;
+            let end_line = (close_node.start_position().row as u32).saturating_sub(1);
+            if end_line <= start_line {
+                continue;
+            }
+            folding_ranges.push(FoldingRange {
+                start_line,
+                start_character: None,
+                end_line,
+                end_character: None,
+                kind: match node.kind() {
+                    "vcl_argument" => Some(FoldingRangeKind::Region),
+                    _ => None,
+                },
+                collapsed_text: None,
+            });
+        }
+        folding_ranges
+    }
+
+    /// The formatted document, or None if it can't be formatted (e.g. due to syntax errors)
+    pub fn format(&self, options: &FormattingOptions, config: &FormatConfig) -> Option<String> {
+        if matches!(self.filetype, FileType::Vtc) {
+            return None;
+        }
+        let style = formatter::FormatStyle::new(config, options);
+        formatter::format_vcl(&self.rope.to_string(), &self.ast, &style)
+    }
+
+    /// Range of the whole document, e.g. for replacing it
+    pub fn get_full_range(&self) -> Range {
+        let last_line = self.rope.len_lines() - 1;
+        Range::new(
+            Position::new(0, 0),
+            Position::new(
+                last_line as u32,
+                self.rope.line(last_line).len_utf16_cu() as u32,
+            ),
+        )
+    }
+
+    /// S-expression of the parse tree with positions, for troubleshooting
+    /// syntax errors. Error and missing nodes are marked with «<-- ERROR» and
+    /// «<-- MISSING».
+    pub fn get_parse_tree(&self) -> String {
+        fn write_node(cursor: &mut TreeCursor, depth: usize, out: &mut String) {
+            let node = cursor.node();
+            let start = node.start_position();
+            let end = node.end_position();
+            out.push_str(&"  ".repeat(depth));
+            if let Some(field) = cursor.field_name() {
+                out.push_str(&format!("{field}: "));
+            }
+            let kind = if node.is_named() {
+                node.kind().to_string()
+            } else {
+                format!("\"{}\"", node.kind())
+            };
+            out.push_str(&format!(
+                "({kind} [{}:{} - {}:{}]",
+                start.row, start.column, end.row, end.column
+            ));
+
+            let mut has_children = false;
+            if cursor.goto_first_child() {
+                loop {
+                    // anonymous nodes are only interesting when they are missing
+                    if cursor.node().is_named() || cursor.node().is_missing() {
+                        if !has_children {
+                            out.push('\n');
+                        }
+                        has_children = true;
+                        write_node(cursor, depth + 1, out);
+                    }
+                    if !cursor.goto_next_sibling() {
+                        break;
+                    }
+                }
+                cursor.goto_parent();
+            }
+
+            if has_children {
+                out.push_str(&"  ".repeat(depth));
+            }
+            out.push(')');
+            if node.is_error() {
+                out.push_str(" <-- ERROR");
+            } else if node.is_missing() {
+                out.push_str(" <-- MISSING");
+            }
+            out.push('\n');
+        }
+
+        let mut out = String::new();
+        write_node(&mut self.ast.walk(), 0, &mut out);
+        out
+    }
+
+    /// Get includes and the range of their path string
+    pub fn get_include_locations(&self) -> Vec<(Include, Range)> {
+        let q = Query::new(
+            &self.ast.language(),
+            "(include_declaration (string) @string)",
+        )
+        .unwrap();
+        let mut qc = QueryCursor::new();
+        let mut all_matches = qc.matches(&q, self.ast.root_node(), self);
+        let capt_idx = q.capture_index_for_name("string").unwrap();
+
+        let mut includes = Vec::new();
+        while let Some(each_match) = all_matches.next() {
+            for capture in each_match.captures.iter().filter(|c| c.index == capt_idx) {
+                let range = capture.node.range();
+                let text = get_node_text(&self.rope, &capture.node);
+                let path_str = text.trim_matches('"');
+                let path = PathBuf::from(path_str);
+                let url = if path.starts_with("./") || path.starts_with("../") {
+                    // relative to current vcl
+                    self.url.join(path_str).ok()
+                } else {
+                    None
+                };
+
+                let mut nested_pos = self.pos_from_main_doc.clone();
+                nested_pos.push(point_to_tuple(range.start_point));
+                includes.push((
+                    Include {
+                        url,
+                        path,
+                        nested_pos,
+                        including_dir: self
+                            .url
+                            .to_file_path()
+                            .ok()
+                            .and_then(|path| Some(path.parent()?.to_path_buf())),
+                    },
+                    ts_range_to_lsp_range(range),
+                ));
             }
         }
 
@@ -1088,6 +4442,7 @@ impl Document {
             url: Some(self.url.to_owned()),
             path: (*self.path).to_owned(),
             nested_pos: self.pos_from_main_doc.to_owned(),
+            including_dir: None,
         }
     }
 
@@ -1171,6 +4526,10 @@ impl Document {
                         }
                     }
 
+                    if let Type::Obj(ref mut obj) = *r#type_box {
+                        obj.vmod_name = def_text.split_once('.').map(|(vmod, _)| vmod.to_string());
+                    }
+
                     r#type_box
                 }
                 _ => continue,
@@ -1238,296 +4597,1127 @@ impl Document {
     }
 
     /**
-     * Expand identifiers into req, res etc. and their properties.
+     * Get ranges of the symbol under point and all its references in this document, if the
+     * symbol is declared in this document (e.g. a backend or a sub and its calls).
      */
-    pub fn autocomplete_for_pos(
-        &self,
-        pos: Position,
-        global_scope: Definitions,
-    ) -> Option<Vec<CompletionItem>> {
-        debug!("starting autocomplete2");
+    pub fn get_linked_editing_ranges(&self, point: Point) -> Option<Vec<Range>> {
+        let ident = self.get_ident_at_point(point)?;
+        if ident.is_empty() {
+            return None;
+        }
+        self.get_definition_by_name(&ident)?;
 
-        let mut target_point = Point {
-            row: pos.line as usize,
-            column: pos.character as usize,
-        };
+        let ident_len = ident.chars().count() as u32;
+        let ranges = self
+            .get_references_for_ident(&ident)
+            .into_iter()
+            .filter(|reference| {
+                reference.ident_str == ident
+                    || reference
+                        .ident_str
+                        .starts_with(format!("{ident}.").as_str())
+            })
+            .map(|reference| {
+                let start = reference.uri.range.start;
+                Range {
+                    start,
+                    end: Position {
+                        character: start.character + ident_len,
+                        ..start
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
 
-        let target_row = self.rope.line(target_point.row);
-        if target_point.column > 0
-            && matches!(
-                target_row.get_char(target_point.column),
-                None | Some('\n') | Some(',') | Some(')')
-            )
-            && !matches!(target_row.get_char(target_point.column - 1), Some('('))
-        {
-            debug!(
-                "decrementing by one ({:?})",
-                target_row.get_char(target_point.column)
-            );
-            target_point.column -= 1;
+        let position = point_to_position(point);
+        ranges
+            .iter()
+            .any(|range| range.start <= position && position <= range.end)
+            .then_some(ranges)
+    }
+
+    /**
+     * Move the statements within range into a new subroutine, replacing them with a call to it.
+     * The range is snapped to whole statements within a single block. Returns the name of the
+     * new subroutine and the edits.
+     */
+    pub fn extract_subroutine(
+        &self,
+        range: Range,
+        scope: &Definitions,
+    ) -> Option<(String, Vec<TextEdit>)> {
+        let start = Point {
+            row: range.start.line as usize,
+            column: range.start.character as usize,
+        };
+        let end = Point {
+            row: range.end.line as usize,
+            column: range.end.character as usize,
+        };
+
+        // find the innermost block with statements overlapping the selection
+        let mut block_node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(start, end)?;
+        let stmts = loop {
+            if matches!(
+                block_node.kind(),
+                "sub_declaration" | "if_stmt" | "elsif_stmt" | "else_stmt"
+            ) {
+                let mut cursor = block_node.walk();
+                let stmts = block_node
+                    .named_children(&mut cursor)
+                    .filter(|child| child.kind() == "stmt")
+                    .filter(|stmt| stmt.end_position() > start && stmt.start_position() < end)
+                    .collect::<Vec<_>>();
+                if !stmts.is_empty() {
+                    break stmts;
+                }
+            }
+            block_node = block_node.parent()?;
+        };
+
+        // «new» is only allowed directly in vcl_init
+        let contains_new_stmt = stmts.iter().any(|stmt| {
+            let mut cursor = stmt.walk();
+            let mut recurse = true;
+            loop {
+                if cursor.node().kind() == "new_stmt" {
+                    return true;
+                }
+                if (recurse && cursor.goto_first_child()) || cursor.goto_next_sibling() {
+                    recurse = true;
+                } else if cursor.goto_parent() && cursor.node() != *stmt {
+                    recurse = false;
+                } else {
+                    return false;
+                }
+            }
+        });
+        if contains_new_stmt || stmts.iter().any(|stmt| stmt.has_error()) {
+            return None;
         }
 
-        debug!("target point: {}", target_point);
+        let sub_names = self.get_subroutines();
+        let name = (1..)
+            .map(|n| format!("extracted_{n}"))
+            .find(|name| !sub_names.contains(name) && !scope.properties.contains_key(name))?;
 
-        let mut text = "".to_string();
-        let mut search_type: Option<Type> = None;
-        let mut must_be_writable = false;
-        let mut keyword_suggestions = vec![];
+        let first_stmt = stmts.first()?;
+        let last_stmt = stmts.last()?;
+        let stmts_text = self
+            .rope
+            .byte_slice(first_stmt.start_byte()..last_stmt.end_byte())
+            .to_string();
 
-        let mut cursor = self.ast.root_node().walk();
-        // Walk down the ast tree to the narrowest node (then up again later.)
-        while cursor.goto_first_child_for_point(target_point).is_some() {
+        // re-indent the statements relative to the first one
+        let first_line = self.rope.line(first_stmt.start_position().row).to_string();
+        let base_indent = &first_line[..first_stmt.start_position().column];
+        let base_indent = if base_indent.trim().is_empty() {
+            base_indent
+        } else {
+            ""
+        };
+        let body = stmts_text
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| {
+                let line = if idx == 0 {
+                    line
+                } else {
+                    line.strip_prefix(base_indent).unwrap_or(line)
+                };
+                if line.trim().is_empty() {
+                    String::new()
+                } else {
+                    format!("    {line}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let toplev_decl = get_toplev_declaration_from_node(block_node);
+        let insert_pos = point_to_position(toplev_decl.end_position());
+
+        Some((
+            name.clone(),
+            vec![
+                TextEdit {
+                    range: Range {
+                        start: point_to_position(first_stmt.start_position()),
+                        end: point_to_position(last_stmt.end_position()),
+                    },
+                    new_text: format!("call {name};"),
+                },
+                TextEdit {
+                    range: Range {
+                        start: insert_pos,
+                        end: insert_pos,
+                    },
+                    new_text: format!("\n\nsub {name} {{\n{body}\n}}"),
+                },
+            ],
+        ))
+    }
+
+    /**
+     * Move the inline probe at the position into a named probe declared before the backend,
+     * replacing it with a reference. Lines within (multi-line) strings are kept as is. Returns
+     * the name of the new probe and the edits.
+     */
+    fn get_inline_probe_node(&self, position: Position) -> Option<Node<'_>> {
+        let point = Point {
+            row: position.line as usize,
+            column: position.character as usize,
+        };
+        let mut probe_node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+        while probe_node.kind() != "inline_probe" {
+            probe_node = probe_node.parent()?;
+        }
+        Some(probe_node)
+    }
+
+    /// Whether the position is in a backend's inline probe, e.g. «.probe = { … }»
+    pub fn has_inline_probe_at(&self, position: Position) -> bool {
+        self.get_inline_probe_node(position).is_some()
+    }
+
+    pub fn extract_inline_probe(
+        &self,
+        position: Position,
+        scope: &Definitions,
+    ) -> Option<(String, Vec<TextEdit>)> {
+        let probe_node = self.get_inline_probe_node(position)?;
+        if probe_node.has_error() {
+            return None;
+        }
+
+        let root_node = self.ast.root_node();
+        let mut cursor = root_node.walk();
+        let toplev_names = root_node
+            .named_children(&mut cursor)
+            .filter_map(|toplev_node| toplev_node.named_child(0)?.child_by_field_name("ident"))
+            .map(|ident_node| get_node_text(&self.rope, &ident_node))
+            .collect::<Vec<_>>();
+        let name = (1..)
+            .map(|n| format!("generated_{n}"))
+            .find(|name| !toplev_names.contains(name) && !scope.properties.contains_key(name))?;
+
+        let mut cursor = probe_node.walk();
+        let open_node = probe_node.child(0).filter(|node| node.kind() == "{")?;
+        let close_node = probe_node
+            .children(&mut cursor)
+            .last()
+            .filter(|node| node.kind() == "}")?;
+
+        // rows continuing a string started on a previous row
+        let mut string_rows = vec![];
+        let mut cursor = probe_node.walk();
+        let mut recurse = true;
+        loop {
             let node = cursor.node();
-            let range = node.range();
-            if range.start_point > target_point || range.end_point < target_point {
-                cursor.goto_parent();
+            if node.kind() == "string" {
+                string_rows.push(node.start_position().row + 1..=node.end_position().row);
+                recurse = false;
+            }
+            if (recurse && cursor.goto_first_child()) || cursor.goto_next_sibling() {
+                recurse = true;
+            } else if cursor.goto_parent() && cursor.node() != probe_node {
+                recurse = false;
+            } else {
                 break;
             }
         }
+        let is_in_string = |row: usize| string_rows.iter().any(|rows| rows.contains(&row));
 
-        // Move the cursor one node back if comma or end parenthesis
-        if matches!(cursor.node().kind(), "," | ")") {
-            // move cursor one point back
-            if let Some(prev_sibling) = cursor.node().prev_sibling() {
-                if prev_sibling.kind() != "(" {
-                    debug!("prev_sibling: {prev_sibling:?}");
-                    cursor.reset(prev_sibling);
+        let first_row = open_node.end_position().row;
+        let props_text = self
+            .rope
+            .byte_slice(open_node.end_byte()..close_node.start_byte())
+            .to_string();
+        let lines = props_text
+            .split('\n')
+            .enumerate()
+            .map(|(idx, line)| (first_row + idx, line))
+            .filter(|(row, line)| is_in_string(*row) || !line.trim().is_empty())
+            .collect::<Vec<_>>();
+        let base_indent = lines
+            .iter()
+            .filter(|(row, _)| *row != first_row && !is_in_string(*row))
+            .map(|(_, line)| line.len() - line.trim_start().len())
+            .min()
+            .unwrap_or(0);
+        let body = lines
+            .iter()
+            .map(|(row, line)| {
+                if is_in_string(*row) {
+                    line.to_string()
+                } else if *row == first_row {
+                    format!("    {}", line.trim())
+                } else {
+                    format!("    {}", line[base_indent..].trim_end())
                 }
-            }
+            })
+            .map(|line| format!("{line}\n"))
+            .collect::<String>();
+
+        let toplev_decl = get_toplev_declaration_from_node(probe_node);
+        let insert_pos = point_to_position(toplev_decl.start_position());
+
+        Some((
+            name.clone(),
+            vec![
+                TextEdit {
+                    range: Range {
+                        start: insert_pos,
+                        end: insert_pos,
+                    },
+                    new_text: format!("probe {name} {{\n{body}}}\n\n"),
+                },
+                TextEdit {
+                    range: ts_range_to_lsp_range(probe_node.range()),
+                    new_text: format!("{name};"),
+                },
+            ],
+        ))
+    }
+
+    /**
+     * Complete HTTP methods as string literals after «req.method ==» or «bereq.method ==».
+     * Works on the line text, since the comparison is often incomplete while typing.
+     */
+    pub fn autocomplete_http_method(
+        &self,
+        pos: Position,
+        methods: &[String],
+    ) -> Option<Vec<CompletionItem>> {
+        let line = self.rope.get_line(pos.line as usize)?;
+        let column = pos.character as usize;
+        let prefix = line.slice(..column.min(line.len_chars())).to_string();
+
+        // the partially typed method, including the opening quote
+        let partial_method = prefix.trim_end_matches(|c: char| c.is_ascii_alphanumeric());
+        let with_quote = partial_method.strip_suffix('"');
+        let before_literal = with_quote.unwrap_or(partial_method).trim_end();
+        let left = before_literal
+            .strip_suffix("==")
+            .or_else(|| before_literal.strip_suffix("!="))?
+            .trim_end();
+        let left = left
+            .strip_suffix("bereq.method")
+            .or_else(|| left.strip_suffix("req.method"))?;
+        if left.ends_with(|c: char| c.is_alphanumeric() || c == '_' || c == '.') {
+            return None;
         }
 
-        // Walk up the tree from the narrowest node
-        let mut stop = false;
-        while !stop {
-            let node = cursor.node();
-            debug!("visiting node {:?}", node);
+        let start = partial_method.len() - usize::from(with_quote.is_some());
+        let start = prefix[..start].chars().count() as u32;
+        // replace the closing quote too, if the editor added one
+        let end = if line.get_char(column) == Some('"') {
+            pos.character + 1
+        } else {
+            pos.character
+        };
+        let range = Range {
+            start: Position::new(pos.line, start),
+            end: Position::new(pos.line, end),
+        };
 
-            let Some(parent_node) = node.parent() else {
-                break;
-            };
+        Some(
+            methods
+                .iter()
+                .map(|method| CompletionItem {
+                    label: method.to_string(),
+                    kind: Some(CompletionItemKind::CONSTANT),
+                    detail: Some("HTTP method".to_string()),
+                    filter_text: Some(format!("\"{method}")),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range,
+                        new_text: format!("\"{method}\""),
+                    })),
+                    ..Default::default()
+                })
+                .collect(),
+        )
+    }
 
-            match parent_node.kind() {
-                "set_stmt" => {
-                    let mut field = cursor.field_name();
+    /**
+     * Postfix completions wrapping a string variable in a std function, e.g. «req.url.tolower»
+     * into «std.tolower(req.url)». If std isn't imported, the import is added too.
+     */
+    pub fn autocomplete_postfix(
+        &self,
+        pos: Position,
+        global_scope: &Definitions,
+    ) -> Option<Vec<CompletionItem>> {
+        const POSTFIX_FUNCTIONS: &[(&str, &str)] = &[
+            ("tolower", "Convert to lowercase"),
+            ("toupper", "Convert to uppercase"),
+            ("querysort", "Sort the query string parameters"),
+        ];
 
-                    // When the node under the cursor is «;», select the right field instead
-                    if node.kind() == ";" || node.kind() == "=" {
-                        field = Some("right");
-                    }
+        let point = Point {
+            row: pos.line as usize,
+            column: pos.character as usize,
+        };
+        let node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+        if matches!(node.kind(), "string" | "COMMENT") && node.end_position() != point {
+            return None;
+        }
 
-                    match field {
-                        None | Some("left") => {
-                            // autocomplete for left part of set statements must be writeable
-                            must_be_writable = true;
-                        }
-                        Some("right") => {
-                            if let Some(ctx_node) = parent_node.child_by_field_name("left") {
-                                let ctx_node_str = get_node_text(&self.rope, &ctx_node);
-                                let left_type = global_scope.get_type_property_by_nested_idents(
-                                    ctx_node_str.split('.').collect(),
-                                );
+        let line = self.rope.get_line(point.row)?;
+        let before_cursor = line.get_slice(..point.column)?.to_string();
+        let expr_start = before_cursor
+            .rfind(|c: char| !(c.is_alphanumeric() || matches!(c, '.' | '_' | '-')))
+            .map_or(0, |idx| idx + 1);
+        let expr = &before_cursor[expr_start..];
+        let (receiver, postfix) = expr.rsplit_once('.')?;
+        if !receiver.contains('.') {
+            return None;
+        }
+        let receiver_type =
+            global_scope.get_type_property_by_nested_idents(receiver.split('.').collect())?;
+        if !matches!(receiver_type, Type::String) {
+            return None;
+        }
 
-                                if let Some(Type::Obj(obj)) = left_type {
-                                    // Filter by string for headers
-                                    if obj.is_http_headers {
-                                        search_type = Some(Type::String);
-                                    }
-                                } else {
-                                    // Autocomplete same type as left type
-                                    search_type = left_type.cloned();
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
-                    stop = true;
-                }
-                // Autocomplete backend/probe properties
-                "backend_property" | "backend_declaration" | "probe_declaration" => {
-                    let mut field = cursor.field_name();
-                    if node.kind() == ";" || node.kind() == "=" {
-                        field = Some("right");
-                    } else if node.kind() == "." {
-                        field = Some("left");
-                        // node = node.parent().unwrap();
-                    }
+        let range = Range::new(
+            Position::new(pos.line, before_cursor[..expr_start].chars().count() as u32),
+            pos,
+        );
+        // std may be imported under an alias
+        let std_import = self
+            .get_vmod_imports()
+            .into_iter()
+            .find(|import| import.name == "std");
+        let std_name = std_import
+            .as_ref()
+            .map_or("std", |import| import.scope_name())
+            .to_string();
+        let is_std_imported = std_import.is_some() || global_scope.properties.contains_key("std");
+        let import_edits = (!is_std_imported).then(|| {
+            vec![TextEdit::new(
+                Range::new(self.get_import_position(), self.get_import_position()),
+                "import std;\n".to_string(),
+            )]
+        });
 
-                    match field {
-                        Some("left") => {
-                            text = get_node_text(&self.rope, &node);
-                            let text = text.strip_prefix('.').unwrap_or(text.as_str());
-                            let parent_parent_node_kind = parent_node.parent().unwrap().kind();
-                            let r#type = match parent_parent_node_kind {
-                                "probe_declaration" => Type::Probe,
-                                _ => Type::Backend,
-                            };
-                            return Some(get_probe_backend_fields(r#type, text));
-                        }
-                        Some("right") => {
-                            if let Some(ctx_node) = parent_node.child_by_field_name("left") {
-                                let ctx_node_str = get_node_text(&self.rope, &ctx_node);
-                                if ctx_node_str == "probe" {
-                                    search_type = Some(Type::Probe);
-                                }
-                                stop = true;
-                            }
-                        }
-                        _ => {}
-                    };
-                }
-                // Filter by subroutines for call statements
-                "call_stmt" => {
-                    search_type = Some(Type::Sub);
-                    stop = true;
-                }
-                // Autocomplete return methods
-                "ret_stmt" => {
-                    return Some(
-                        varnish_builtins::RETURN_METHODS
-                            .iter()
-                            .map(|field| CompletionItem {
-                                label: field.to_string(),
-                                detail: Some(field.to_string()),
-                                kind: Some(CompletionItemKind::FUNCTION),
-                                ..Default::default()
-                            })
-                            .collect(),
-                    );
-                }
-                // Autocomplete named arguments
-                "func_call_args" => {
-                    let func_node = find_parent(node, "ident_call_expr".to_string()).unwrap();
-                    let func_ident =
-                        get_node_text(&self.rope, &func_node.child_by_field_name("ident").unwrap());
-                    if let Some(Type::Func(func)) = global_scope
-                        .get_type_property_by_nested_idents(func_ident.split('.').collect())
-                    {
-                        // Add to suggestions
-                        keyword_suggestions = func
-                            .args
-                            .iter()
-                            .filter_map(|arg| {
-                                arg.name.as_ref().map(|name| CompletionItem {
-                                    label: name.to_string(),
-                                    kind: Some(CompletionItemKind::PROPERTY), // closest?
-                                    label_details: Some(CompletionItemLabelDetails {
-                                        detail: Some("Parameter".to_string()),
-                                        description: None,
-                                    }),
-                                    insert_text: Some(format!("{name} = ")),
-                                    detail: arg.r#type.as_ref().map(|t| format!("{t}")),
-                                    ..Default::default()
-                                })
-                            })
-                            .collect();
-                    }
-                }
-                // Autocomplete enum arguments
-                "func_call_named_arg" => {
-                    if cursor.field_name() == Some("arg_value") || node.kind() == "=" {
-                        let arg_name = get_node_text(
-                            &self.rope,
-                            &node
-                                .parent()
-                                .unwrap()
-                                .child_by_field_name("arg_name")
-                                .unwrap(),
-                        );
-                        let func_node = find_parent(node, "ident_call_expr".to_string()).unwrap();
-                        let func_ident = get_node_text(
-                            &self.rope,
-                            &func_node.child_by_field_name("ident").unwrap(),
-                        );
-                        if let Some(Type::Func(func)) = global_scope
-                            .get_type_property_by_nested_idents(func_ident.split('.').collect())
-                        {
-                            if let Some(arg) = func
-                                .args
-                                .iter()
-                                .find(|arg| arg.name.as_ref() == Some(&arg_name))
-                            {
-                                if let Some(Type::Enum(enum_values)) = arg.r#type.as_ref() {
-                                    return Some(
-                                        enum_values
-                                            .iter()
-                                            .map(|enum_value| CompletionItem {
-                                                label: enum_value.to_string(),
-                                                kind: Some(CompletionItemKind::ENUM),
-                                                ..Default::default()
-                                            })
-                                            .collect(),
-                                    );
-                                }
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    if parent_node.kind() == "new_stmt" || node.kind() == "new_stmt" {
-                        match cursor.field_name() {
-                            Some("ident") => {
-                                return Some(vec![]);
-                            }
-                            Some("def_right") => search_type = Some(Type::Func(Default::default())),
-                            _ => {}
-                        }
-                        stop = true;
-                    }
+        let completions = POSTFIX_FUNCTIONS
+            .iter()
+            .filter(|(name, _)| name.starts_with(postfix))
+            .map(|(name, doc)| {
+                let new_text = format!("{std_name}.{name}({receiver})");
+                CompletionItem {
+                    label: format!(".{name}"),
+                    kind: Some(CompletionItemKind::SNIPPET),
+                    detail: Some(match import_edits {
+                        Some(_) => format!("{new_text} (adds «import std;»)"),
+                        None => new_text.clone(),
+                    }),
+                    documentation: Some(Documentation::String(doc.to_string())),
+                    filter_text: Some(format!("{receiver}.{name}")),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit { range, new_text })),
+                    additional_text_edits: import_edits.clone(),
+                    ..Default::default()
                 }
+            })
+            .collect::<Vec<_>>();
+        (!completions.is_empty()).then_some(completions)
+    }
+
+    /**
+     * Edit inserting an empty builtin subroutine, e.g. «sub vcl_deliver {}». It goes after the
+     * builtin subroutines running before it in a request (see SUBROUTINE_EXECUTION_ORDER), or
+     * before the ones running after it, or at the end of the document.
+     */
+    pub fn get_sub_scaffold_edit(&self, sub_name: &str) -> Result<TextEdit, String> {
+        let execution_order = varnish_builtins::SUBROUTINE_EXECUTION_ORDER;
+        let Some(order) = execution_order.iter().position(|name| *name == sub_name) else {
+            return Err(format!(
+                "Unknown builtin subroutine «{sub_name}» (expected one of {})",
+                execution_order.join(", ")
+            ));
+        };
+        if self.get_subroutines().iter().any(|name| name == sub_name) {
+            return Err(format!("«{sub_name}» is already declared"));
+        }
+
+        let root_node = self.ast.root_node();
+        let mut cursor = root_node.walk();
+        let toplev_nodes = root_node.named_children(&mut cursor).collect::<Vec<_>>();
+        let builtin_subs = toplev_nodes
+            .iter()
+            .filter(|toplev_node| toplev_node.kind() == "toplev_declaration")
+            .filter_map(|toplev_node| {
+                let sub_node = toplev_node
+                    .named_child(0)
+                    .filter(|node| node.kind() == "sub_declaration")?;
+                let name = get_node_text(&self.rope, &sub_node.child_by_field_name("ident")?);
+                let order = execution_order
+                    .iter()
+                    .position(|builtin| *builtin == name)?;
+                Some((order, *toplev_node))
+            })
+            .collect::<Vec<_>>();
+
+        let scaffold = format!("sub {sub_name} {{\n}}");
+        let insert_at = |point: Point, new_text: String| TextEdit {
+            range: Range::new(
+                Position::new(point.row as u32, point.column as u32),
+                Position::new(point.row as u32, point.column as u32),
+            ),
+            new_text,
+        };
+        if let Some((_, preceding_node)) = builtin_subs
+            .iter()
+            .filter(|(sub_order, _)| *sub_order < order)
+            .max_by_key(|(sub_order, _)| *sub_order)
+        {
+            return Ok(insert_at(
+                preceding_node.end_position(),
+                format!("\n\n{scaffold}"),
+            ));
+        }
+        if let Some((_, following_node)) =
+            builtin_subs.iter().min_by_key(|(sub_order, _)| *sub_order)
+        {
+            // keep comments above the following subroutine with it
+            let mut start_node = *following_node;
+            while let Some(comment_node) = start_node.prev_sibling().filter(|prev_node| {
+                prev_node.kind() == "COMMENT"
+                    && prev_node.end_position().row + 1 >= start_node.start_position().row
+            }) {
+                start_node = comment_node;
             }
+            return Ok(insert_at(
+                start_node.start_position(),
+                format!("{scaffold}\n\n"),
+            ));
+        }
+        Ok(match toplev_nodes.last() {
+            Some(last_node) => insert_at(last_node.end_position(), format!("\n\n{scaffold}")),
+            None => insert_at(Point::new(0, 0), format!("{scaffold}\n")),
+        })
+    }
 
-            // now, try to gather best identifier for autocomplete
-            match node.kind() {
-                "nested_ident" => {
-                    text = get_node_text(&self.rope, &node).to_string();
-                    debug!("got text for nested_ident {:?} {}", node, text);
-                }
-                "ident" if node.parent().unwrap().kind() != "nested_ident" => {
-                    text = get_node_text(&self.rope, &node).to_string();
-                    debug!("got text for ident {:?} {}", node, text);
-                }
-                "sub_declaration" | "if_stmt" | "elsif_stmt" | "else_stmt" => {
-                    if !text.contains('.') {
-                        keyword_suggestions.append(&mut static_autocomplete_items::subroutine());
-                    }
-                    stop = true;
-                }
-                "source_file" => {
-                    return Some(static_autocomplete_items::source_file());
-                }
-                _ => {}
+    /// Where to insert an import: the line after the last import, or after the vcl version
+    fn get_import_position(&self) -> Position {
+        let root_node = self.ast.root_node();
+        let mut cursor = root_node.walk();
+        let toplev_declarations = root_node.named_children(&mut cursor).collect::<Vec<_>>();
+        let last_node = toplev_declarations
+            .iter()
+            .rev()
+            .filter_map(|toplev_node| toplev_node.named_child(0))
+            .find(|node| node.kind() == "import_declaration")
+            .or_else(|| {
+                toplev_declarations
+                    .iter()
+                    .filter_map(|toplev_node| toplev_node.named_child(0))
+                    .find(|node| node.kind() == "vcl_version_declaration")
+            });
+        match last_node {
+            Some(node) => Position::new(node.end_position().row as u32 + 1, 0),
+            None => Position::new(0, 0),
+        }
+    }
+
+    /// Complete backreferences (e.g. «\1») in the replacement argument of regsub() and
+    /// regsuball(), based on the number of capture groups in the pattern argument
+    pub fn autocomplete_regsub_backreference(&self, pos: Position) -> Option<Vec<CompletionItem>> {
+        let point = Point {
+            row: pos.line as usize,
+            column: pos.character as usize,
+        };
+        let string_node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+        if string_node.kind() != "string" || string_node.end_position() == point {
+            return None;
+        }
+
+        // walk up to the argument (the string might be wrapped in expr nodes)
+        let mut arg_node = string_node;
+        while arg_node.parent()?.kind() != "func_call_args" {
+            arg_node = arg_node.parent()?;
+        }
+        let args_node = arg_node.parent()?;
+        let call_node = args_node.parent()?;
+        let ident = get_node_text(&self.rope, &call_node.child_by_field_name("ident")?);
+        if !matches!(ident.as_str(), "regsub" | "regsuball") {
+            return None;
+        }
+
+        let mut args_cursor = args_node.walk();
+        let arg_nodes = args_node
+            .children_by_field_name("arg", &mut args_cursor)
+            .collect::<Vec<_>>();
+        if arg_nodes.get(2) != Some(&arg_node) {
+            return None;
+        }
+        let pattern = self.rope.byte_slice(arg_nodes[1].byte_range()).to_string();
+        let group_count = count_capture_groups(unquote_string(&pattern))?;
+
+        // replace the backslash too, if it has already been typed
+        let line = self.rope.get_line(point.row)?;
+        let start = match pos.character.checked_sub(1) {
+            Some(column) if line.get_char(column as usize) == Some('\\') => column,
+            _ => pos.character,
+        };
+        let range = Range {
+            start: Position::new(pos.line, start),
+            end: pos,
+        };
+
+        Some(
+            (0..=group_count)
+                .map(|group| CompletionItem {
+                    label: format!("\\{group}"),
+                    kind: Some(CompletionItemKind::VALUE),
+                    detail: Some(match group {
+                        0 => "Whole match".to_string(),
+                        _ => format!("Capture group {group}"),
+                    }),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range,
+                        new_text: format!("\\{group}"),
+                    })),
+                    ..Default::default()
+                })
+                .collect(),
+        )
+    }
+
+    /// Complete vcl labels in «return (vcl(…))», from the labels used across documents
+    pub fn autocomplete_vcl_label(
+        &self,
+        pos: Position,
+        labels: &[String],
+    ) -> Option<Vec<CompletionItem>> {
+        let point = Point {
+            row: pos.line as usize,
+            column: pos.character as usize,
+        };
+        let node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+        let methods_node = if node.kind() == "varnish_internal_return_methods" {
+            node
+        } else {
+            find_parent(node, "varnish_internal_return_methods".to_string())?
+        };
+        let open_paren_node = methods_node.child(1)?;
+        if methods_node.child(0)?.kind() != "vcl"
+            || open_paren_node.kind() != "("
+            || point < open_paren_node.end_position()
+        {
+            return None;
+        }
+
+        Some(
+            labels
+                .iter()
+                .map(|label| CompletionItem {
+                    label: label.to_string(),
+                    kind: Some(CompletionItemKind::MODULE),
+                    detail: Some("VCL label".to_string()),
+                    ..Default::default()
+                })
+                .collect(),
+        )
+    }
+
+    /// Complete placeholders (e.g. «%Y») in format string arguments, e.g. of std.strftime()
+    pub fn autocomplete_format_string(
+        &self,
+        pos: Position,
+        scope: &Definitions,
+    ) -> Option<Vec<CompletionItem>> {
+        let point = Point {
+            row: pos.line as usize,
+            column: pos.character as usize,
+        };
+        let string_node = self
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)?;
+        if string_node.kind() != "string" || string_node.end_position() == point {
+            return None;
+        }
+
+        // walk up to the argument (the string might be wrapped in expr nodes)
+        let mut arg_node = string_node;
+        while arg_node.parent()?.kind() != "func_call_args" {
+            arg_node = arg_node.parent()?;
+        }
+        let args_node = arg_node.parent()?;
+        let call_node = args_node.parent()?;
+        let ident = get_node_text(&self.rope, &call_node.child_by_field_name("ident")?);
+        let Some(Type::Func(func)) =
+            scope.get_type_property_by_nested_idents(ident.split('.').collect())
+        else {
+            return None;
+        };
+
+        let arg = if arg_node.kind() == "func_call_named_arg" {
+            let arg_name = get_node_text(&self.rope, &arg_node.child_by_field_name("arg_name")?);
+            func.args
+                .iter()
+                .find(|arg| arg.name.as_deref() == Some(&*arg_name))?
+        } else {
+            let mut args_cursor = args_node.walk();
+            let arg_idx = args_node
+                .children_by_field_name("arg", &mut args_cursor)
+                .filter(|node| node.kind() != "func_call_named_arg")
+                .position(|node| node == arg_node)?;
+            func.args.get(arg_idx)?
+        };
+        let format = arg.format?;
+
+        // replace the percent sign too, if it has already been typed
+        let line = self.rope.get_line(point.row)?;
+        let start = match pos.character.checked_sub(1) {
+            Some(column) if line.get_char(column as usize) == Some('%') => column,
+            _ => pos.character,
+        };
+        let range = Range {
+            start: Position::new(pos.line, start),
+            end: pos,
+        };
+
+        Some(
+            format
+                .specifiers()
+                .iter()
+                .map(|(specifier, description)| CompletionItem {
+                    label: format!("%{specifier}"),
+                    kind: Some(CompletionItemKind::VALUE),
+                    detail: Some(description.to_string()),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range,
+                        new_text: format!("%{specifier}"),
+                    })),
+                    ..Default::default()
+                })
+                .collect(),
+        )
+    }
+
+    /**
+     * Expand identifiers into req, res etc. and their properties.
+     */
+    pub fn autocomplete_for_pos(
+        &self,
+        pos: Position,
+        global_scope: Definitions,
+        workspace_flavor: VarnishFlavor,
+    ) -> Option<Vec<CompletionItem>> {
+        debug!("starting autocomplete2");
+        let flavor = self.get_flavor(workspace_flavor);
+
+        let mut target_point = Point {
+            row: pos.line as usize,
+            column: pos.character as usize,
+        };
+
+        let target_row = self.rope.line(target_point.row);
+        if target_point.column > 0
+            && matches!(
+                target_row.get_char(target_point.column),
+                None | Some('\n') | Some(',') | Some(')')
+            )
+            && !matches!(target_row.get_char(target_point.column - 1), Some('('))
+        {
+            debug!(
+                "decrementing by one ({:?})",
+                target_row.get_char(target_point.column)
+            );
+            target_point.column -= 1;
+        }
+
+        debug!("target point: {}", target_point);
+
+        let mut text = "".to_string();
+        let mut search_type: Option<Type> = None;
+        let mut must_be_writable = false;
+        let mut keyword_suggestions = vec![];
+
+        // conditions are often incomplete while typing, so look at the line text as well
+        let line_prefix = target_row
+            .slice(..(pos.character as usize).min(target_row.len_chars()))
+            .to_string();
+        let mut in_condition = is_in_condition(&line_prefix);
+        let comparison = get_comparison_operand(&line_prefix);
+        if let Some((left_ident, operator)) = comparison {
+            in_condition = true;
+            let left_type =
+                global_scope.get_type_property_by_nested_idents(left_ident.split('.').collect());
+            search_type = match (left_type, operator) {
+                (Some(Type::IP), "~" | "!~") => Some(Type::Acl),
+                // the right hand side of a regex match must be a string literal
+                (_, "~" | "!~") => return None,
+                (Some(Type::Obj(obj)), _) if obj.is_http_headers => Some(Type::String),
+                (left_type, _) => left_type.cloned(),
+            };
+            if let Some(ref search_type) = search_type {
+                keyword_suggestions.append(&mut static_autocomplete_items::literals_for_type(
+                    search_type,
+                ));
             }
+        }
 
-            let node_range = node.start_position().row..node.end_position().row;
-            if node_range.contains(&target_point.row) {
+        let mut cursor = self.ast.root_node().walk();
+        // Walk down the ast tree to the narrowest node (then up again later.)
+        while cursor.goto_first_child_for_point(target_point).is_some() {
+            let node = cursor.node();
+            let range = node.range();
+            if range.start_point > target_point || range.end_point < target_point {
+                cursor.goto_parent();
                 break;
             }
+        }
 
-            if !cursor.goto_parent() {
-                debug!("no parent??");
-                break;
+        // Move the cursor one node back if comma or end parenthesis
+        if matches!(cursor.node().kind(), "," | ")") {
+            // move cursor one point back
+            if let Some(prev_sibling) = cursor.node().prev_sibling() {
+                if prev_sibling.kind() != "(" {
+                    debug!("prev_sibling: {prev_sibling:?}");
+                    cursor.reset(prev_sibling);
+                }
             }
         }
 
-        debug!("text: «{:?}»", text);
+        // Walk up the tree from the narrowest node
+        let mut stop = false;
+        while !stop {
+            let node = cursor.node();
+            debug!("visiting node {:?}", node);
 
-        // identifiers written so far (split by dot)
-        let idents: Vec<&str> = text.split('.').collect();
+            let Some(parent_node) = node.parent() else {
+                break;
+            };
 
-        let mut suggestions = global_scope
-            .get_type_properties_by_idents(
-                idents,
-                AutocompleteSearchOptions {
-                    search_type,
-                    must_be_writable: Some(must_be_writable),
-                },
-            )?
+            match parent_node.kind() {
+                "set_stmt" => {
+                    let mut field = cursor.field_name();
+
+                    // When the node under the cursor is «;», select the right field instead
+                    if node.kind() == ";" || node.kind() == "=" {
+                        field = Some("right");
+                    }
+
+                    match field {
+                        None | Some("left") => {
+                            // autocomplete for left part of set statements must be writeable
+                            must_be_writable = true;
+                        }
+                        Some("right") => {
+                            if let Some(ctx_node) = parent_node.child_by_field_name("left") {
+                                let ctx_node_str = get_node_text(&self.rope, &ctx_node);
+                                let left_type = global_scope.get_type_property_by_nested_idents(
+                                    ctx_node_str.split('.').collect(),
+                                );
+
+                                if let Some(Type::Obj(obj)) = left_type {
+                                    // Filter by string for headers
+                                    if obj.is_http_headers {
+                                        search_type = Some(Type::String);
+                                    }
+                                } else {
+                                    // Autocomplete same type as left type
+                                    search_type = left_type.cloned();
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                    stop = true;
+                }
+                // Autocomplete backend/probe properties
+                "backend_property" | "backend_declaration" | "probe_declaration" => {
+                    let mut field = cursor.field_name();
+                    if node.kind() == ";" || node.kind() == "=" {
+                        field = Some("right");
+                    } else if node.kind() == "." {
+                        field = Some("left");
+                        // node = node.parent().unwrap();
+                    }
+
+                    match field {
+                        Some("left") => {
+                            text = get_node_text(&self.rope, &node);
+                            let text = text.strip_prefix('.').unwrap_or(text.as_str());
+                            let parent_parent_node_kind = parent_node.parent().unwrap().kind();
+                            let r#type = match parent_parent_node_kind {
+                                "probe_declaration" => Type::Probe,
+                                _ => Type::Backend,
+                            };
+                            return Some(get_probe_backend_fields(
+                                r#type,
+                                text,
+                                self.get_vcl_version(),
+                            ));
+                        }
+                        Some("right") => {
+                            if let Some(ctx_node) = parent_node.child_by_field_name("left") {
+                                let ctx_node_str = get_node_text(&self.rope, &ctx_node);
+                                if ctx_node_str == "probe" {
+                                    search_type = Some(Type::Probe);
+                                }
+                                stop = true;
+                            }
+                        }
+                        _ => {}
+                    };
+                }
+                // Filter by subroutines for call statements
+                "call_stmt" => {
+                    search_type = Some(Type::Sub);
+                    stop = true;
+                }
+                // Autocomplete return methods
+                "ret_stmt" => {
+                    return Some(
+                        varnish_builtins::RETURN_METHODS
+                            .iter()
+                            .map(|field| CompletionItem {
+                                label: field.to_string(),
+                                detail: Some(field.to_string()),
+                                kind: Some(CompletionItemKind::FUNCTION),
+                                ..Default::default()
+                            })
+                            .collect(),
+                    );
+                }
+                // Autocomplete named arguments
+                "func_call_args" => {
+                    let func_node = find_parent(node, "ident_call_expr".to_string()).unwrap();
+                    let func_ident =
+                        get_node_text(&self.rope, &func_node.child_by_field_name("ident").unwrap());
+                    if let Some(Type::Func(func)) = global_scope
+                        .get_type_property_by_nested_idents(func_ident.split('.').collect())
+                    {
+                        // Add to suggestions
+                        keyword_suggestions = func
+                            .args
+                            .iter()
+                            .filter_map(|arg| {
+                                arg.name.as_ref().map(|name| CompletionItem {
+                                    label: name.to_string(),
+                                    kind: Some(CompletionItemKind::PROPERTY), // closest?
+                                    label_details: Some(CompletionItemLabelDetails {
+                                        detail: Some("Parameter".to_string()),
+                                        description: None,
+                                    }),
+                                    insert_text: Some(format!("{name} = ")),
+                                    detail: arg.r#type.as_ref().map(|t| format!("{t}")),
+                                    ..Default::default()
+                                })
+                            })
+                            .collect();
+                    }
+                }
+                // Autocomplete enum arguments
+                "func_call_named_arg" => {
+                    if cursor.field_name() == Some("arg_value") || node.kind() == "=" {
+                        let arg_name = get_node_text(
+                            &self.rope,
+                            &node
+                                .parent()
+                                .unwrap()
+                                .child_by_field_name("arg_name")
+                                .unwrap(),
+                        );
+                        let func_node = find_parent(node, "ident_call_expr".to_string()).unwrap();
+                        let func_ident = get_node_text(
+                            &self.rope,
+                            &func_node.child_by_field_name("ident").unwrap(),
+                        );
+                        if let Some(Type::Func(func)) = global_scope
+                            .get_type_property_by_nested_idents(func_ident.split('.').collect())
+                        {
+                            if let Some(arg) = func
+                                .args
+                                .iter()
+                                .find(|arg| arg.name.as_ref() == Some(&arg_name))
+                            {
+                                if let Some(Type::Enum(enum_values)) = arg.r#type.as_ref() {
+                                    return Some(
+                                        enum_values
+                                            .iter()
+                                            .map(|enum_value| CompletionItem {
+                                                label: enum_value.to_string(),
+                                                kind: Some(CompletionItemKind::ENUM),
+                                                ..Default::default()
+                                            })
+                                            .collect(),
+                                    );
+                                }
+                                // restricted strings are quoted, unlike enums
+                                if let Some(ref values) = arg.values {
+                                    return Some(
+                                        values
+                                            .iter()
+                                            .map(|value| CompletionItem {
+                                                label: format!("\"{value}\""),
+                                                kind: Some(CompletionItemKind::VALUE),
+                                                detail: Some("STRING".to_string()),
+                                                ..Default::default()
+                                            })
+                                            .collect(),
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    if parent_node.kind() == "new_stmt" || node.kind() == "new_stmt" {
+                        match cursor.field_name() {
+                            Some("ident") => {
+                                return Some(vec![]);
+                            }
+                            Some("def_right") => search_type = Some(Type::Func(Default::default())),
+                            _ => {}
+                        }
+                        stop = true;
+                    }
+                }
+            }
+
+            // now, try to gather best identifier for autocomplete
+            match node.kind() {
+                "nested_ident" => {
+                    text = get_node_text(&self.rope, &node).to_string();
+                    debug!("got text for nested_ident {:?} {}", node, text);
+                }
+                "ident" if node.parent().unwrap().kind() != "nested_ident" => {
+                    text = get_node_text(&self.rope, &node).to_string();
+                    debug!("got text for ident {:?} {}", node, text);
+                }
+                "parenthesized_expression"
+                    if node.parent().is_some_and(|parent| {
+                        matches!(parent.kind(), "if_stmt" | "elsif_stmt")
+                    }) =>
+                {
+                    in_condition = true;
+                }
+                "sub_declaration" | "if_stmt" | "elsif_stmt" | "else_stmt" => {
+                    // statement keywords don't belong in conditions
+                    if !text.contains('.') && !in_condition {
+                        keyword_suggestions.append(&mut static_autocomplete_items::subroutine());
+                    }
+                    stop = true;
+                }
+                "source_file" => {
+                    return Some(static_autocomplete_items::source_file());
+                }
+                _ => {}
+            }
+
+            let node_range = node.start_position().row..node.end_position().row;
+            if node_range.contains(&target_point.row) {
+                break;
+            }
+
+            if !cursor.goto_parent() {
+                debug!("no parent??");
+                break;
+            }
+        }
+
+        // the node under the cursor might be the left operand, when the right one is missing
+        if comparison.is_some() {
+            text = line_prefix
+                .chars()
+                .rev()
+                .take_while(|c| is_ident_char(*c))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+                .collect();
+        }
+
+        debug!("text: «{:?}»", text);
+
+        // methods of vmod objects are described with the object and vmod (e.g. «rr.backend()»)
+        let get_method_of = |obj: &Obj| {
+            obj.vmod_name
+                .as_ref()
+                .map(|vmod_name| format!("{vmod_name}.{}", obj.name))
+        };
+
+        // members of objects returned by calls (e.g. «a.b().»), which aren't parsed as identifiers
+        let (properties, method_of) = match get_call_chain(&line_prefix) {
+            Some((calls, partial_ident)) => {
+                let Some(Type::Obj(obj)) = global_scope.get_call_chain_return_type(&calls) else {
+                    return None;
+                };
+                (
+                    obj.get_type_properties_by_range(partial_ident),
+                    get_method_of(obj),
+                )
+            }
+            None => {
+                // identifiers written so far (split by dot)
+                let idents: Vec<&str> = text.split('.').collect();
+
+                let method_of = match idents.split_last() {
+                    Some((_, parent_idents)) if !parent_idents.is_empty() => match global_scope
+                        .get_type_property_by_nested_idents(parent_idents.to_vec())
+                    {
+                        Some(Type::Obj(obj)) => get_method_of(obj),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                (
+                    global_scope.get_type_properties_by_idents(
+                        idents,
+                        AutocompleteSearchOptions {
+                            search_type,
+                            must_be_writable: Some(must_be_writable),
+                        },
+                    )?,
+                    method_of,
+                )
+            }
+        };
+
+        // the full name of a suggested variable, e.g. «beresp.storage_hint» for «storage_hint»
+        let variable_name = |prop_name: &str| match text.rsplit_once('.') {
+            Some((parent, _)) => format!("{parent}.{prop_name}"),
+            None => prop_name.to_string(),
+        };
+
+        let mut suggestions = properties
             .iter()
+            // leave out variables this flavor doesn't have anymore
+            .filter(|(prop_name, _)| {
+                varnish_builtins::get_removed_variable_replacement(
+                    flavor,
+                    &variable_name(prop_name),
+                )
+                .is_none()
+            })
             .map(|(prop_name, property)| CompletionItem {
                 label: prop_name.to_string(),
-                detail: Some(match property {
-                    Type::Func(_func) => format!("{}", property),
-                    Type::Backend => format!("BACKEND {}", prop_name),
+                detail: Some(match (property, &method_of) {
+                    (Type::Func(_func), Some(method_of)) => {
+                        format!("{} (method of {})", property, method_of)
+                    }
+                    (Type::Func(_func), None) => format!("{}", property),
+                    (Type::Backend, _) => format!("BACKEND {}", prop_name),
                     _ => format!("{} {}", property, prop_name),
                 }),
                 kind: Some(match property {
@@ -1559,629 +5749,4686 @@ impl Document {
                     }
                     _ => prop_name.to_string(),
                 }),
-                insert_text_format: Some(InsertTextFormat::SNIPPET),
-                documentation: match property {
-                    Type::Func(func) => func.doc.to_owned().map(|doc| {
-                        Documentation::MarkupContent(MarkupContent {
-                            kind: MarkupKind::Markdown,
-                            value: doc,
-                        })
+                insert_text_format: Some(InsertTextFormat::SNIPPET),
+                documentation: match property {
+                    Type::Func(func) => {
+                        let doc = func.get_documentation();
+                        Some(Documentation::MarkupContent(MarkupContent {
+                            kind: MarkupKind::Markdown,
+                            value: match method_of {
+                                Some(ref method_of) => format!("Method of `{method_of}`\n\n{doc}"),
+                                None => doc,
+                            },
+                        }))
+                    }
+                    _ => None,
+                },
+                // prioritize boolean expressions in conditions
+                sort_text: (in_condition && comparison.is_none()).then(|| {
+                    let is_bool = |property: &Type| match property {
+                        Type::Bool => true,
+                        Type::Func(func) => func.returns_bool(),
+                        _ => false,
+                    };
+                    let priority = match property {
+                        property if is_bool(property) => 0,
+                        // e.g. vmods with boolean functions, like «std» (std.healthy())
+                        Type::Obj(obj) if obj.properties.values().any(is_bool) => 1,
+                        Type::Obj(_) => 2,
+                        _ => 3,
+                    };
+                    format!("{priority}{prop_name}")
+                }),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        // label variables by the context they exist in, and sort the ones that don't exist in
+        // this builtin subroutine last (e.g. «req» in vcl_backend_response)
+        if !text.contains('.') {
+            let sub_name = self
+                .ast
+                .root_node()
+                .descendant_for_point_range(target_point, target_point)
+                .map(get_toplev_declaration_from_node)
+                .filter(|toplev_decl| toplev_decl.kind() == "sub_declaration")
+                .and_then(|sub_node| sub_node.child_by_field_name("ident"))
+                .map(|ident_node| get_node_text(&self.rope, &ident_node));
+            for suggestion in suggestions.iter_mut() {
+                let Some(contexts) =
+                    varnish_builtins::get_variable_contexts(flavor, &suggestion.label)
+                else {
+                    continue;
+                };
+                let mut description = format!("{} variable", contexts.join("/"));
+                if let Some(ref sub_name) =
+                    sub_name.as_ref().filter(|name| name.starts_with("vcl_"))
+                {
+                    if !varnish_builtins::is_variable_available_in_sub(
+                        flavor,
+                        &suggestion.label,
+                        sub_name,
+                    ) {
+                        description = format!("not available in {sub_name}");
+                        let sort_text = suggestion
+                            .sort_text
+                            .take()
+                            .unwrap_or(suggestion.label.clone());
+                        suggestion.sort_text = Some(format!("~{sort_text}"));
+                    }
+                }
+                suggestion.label_details = Some(CompletionItemLabelDetails {
+                    detail: None,
+                    description: Some(description),
+                });
+            }
+        }
+
+        // mark variables this flavor deprecates, e.g. «beresp.storage_hint» in varnish 6.0
+        for suggestion in suggestions.iter_mut() {
+            if let Some(replacement) = varnish_builtins::get_deprecated_variable_replacement(
+                flavor,
+                &variable_name(&suggestion.label),
+            ) {
+                suggestion.tags = Some(vec![CompletionItemTag::DEPRECATED]);
+                suggestion.label_details = Some(CompletionItemLabelDetails {
+                    detail: None,
+                    description: Some(format!("deprecated, use {replacement}")),
+                });
+            }
+        }
+
+        suggestions.append(&mut keyword_suggestions);
+
+        Some(suggestions)
+    }
+
+    pub fn get_semantic_tokens(&self) -> Vec<SemanticToken> {
+        self.get_semantic_tokens_cancellable(&AtomicBool::new(false))
+            .unwrap_or_default()
+    }
+
+    /// Same as get_semantic_tokens, but returns None as soon as `cancelled` is set
+    pub fn get_semantic_tokens_cancellable(
+        &self,
+        cancelled: &AtomicBool,
+    ) -> Option<Vec<SemanticToken>> {
+        let node = self.ast.root_node();
+        let mut query_str = VCL_QUERY.to_string();
+        if matches!(self.filetype, FileType::Vtc) {
+            query_str.push_str(VTC_QUERY);
+        }
+        let q = Query::new(&self.ast.language(), query_str.as_str()).unwrap();
+        let mut qc = QueryCursor::new();
+
+        let names = q.capture_names();
+
+        #[derive(Debug, Eq, PartialEq, PartialOrd, Ord)]
+        struct Token {
+            line: usize,
+            start: usize,
+            token_type: usize, // LEGEND_TYPES is ordered, so we can order by this for precendence
+            modifier_bitset: usize,
+            length: usize,
+        }
+
+        let mut tokens = vec![];
+        let mut captures = qc
+            .matches(&q, node, self)
+            .flat_map(|m| convert_to_streaming_iterator(m.captures));
+        let mut prev_node: Option<Node> = None;
+        while let Some(c) = captures.next() {
+            if cancelled.load(AtomicOrdering::Relaxed) {
+                debug!("semantic tokens cancelled");
+                return None;
+            }
+
+            let node = c.node;
+            let range = node.range();
+            let mut capture_name = names.get(c.index as usize).unwrap().splitn(2, '.');
+            let name = capture_name.next().unwrap();
+            let modifier_name = capture_name.next();
+            let Some(token_type) = LEGEND_TYPES.iter().position(|s| s.as_str() == name) else {
+                continue;
+            };
+            let modifier = modifier_name.and_then(|modifier_name| {
+                LEGEND_MODIFIERS
+                    .iter()
+                    .position(|s| s.as_str() == modifier_name)
+            });
+
+            let mut modifier_bitset = 0;
+            if let Some(modifier) = modifier {
+                modifier_bitset |= 1 << modifier;
+            }
+
+            // mark variables which are read-only in this subroutine
+            if LEGEND_TYPES[token_type] == SemanticTokenType::PROPERTY
+                && node.kind() == "nested_ident"
+            {
+                let toplev_decl = get_toplev_declaration_from_node(node);
+                let sub_name = (toplev_decl.kind() == "sub_declaration")
+                    .then(|| toplev_decl.child_by_field_name("ident"))
+                    .flatten()
+                    .map(|ident_node| get_node_text(&self.rope, &ident_node));
+                let ident = get_node_text(&self.rope, &node);
+                if is_variable_read_only(&ident, sub_name.as_deref()) {
+                    let readonly = LEGEND_MODIFIERS
+                        .iter()
+                        .position(|s| *s == SemanticTokenModifier::READONLY)
+                        .unwrap();
+                    modifier_bitset |= 1 << readonly;
+                }
+            }
+
+            let is_multiline = range.start_point.row != range.end_point.row;
+            for row in range.start_point.row..=range.end_point.row {
+                let start = if row == range.start_point.row {
+                    let start_line = self.rope.line(row);
+                    start_line.char_to_utf16_cu(start_line.byte_to_char(range.start_point.column))
+                } else {
+                    0
+                };
+
+                let end_line = self.rope.line(row);
+                let end = if row == range.end_point.row {
+                    end_line.char_to_utf16_cu(end_line.byte_to_char(range.end_point.column))
+                } else {
+                    end_line.len_utf16_cu()
+                };
+
+                if !is_multiline && prev_node.is_some_and(|prev_node| prev_node == node) {
+                    let prev_token: &mut Token = tokens.last_mut().unwrap();
+                    if prev_token.token_type == token_type {
+                        prev_token.modifier_bitset |= modifier_bitset;
+                        continue;
+                    }
+                }
+
+                tokens.push(Token {
+                    start,
+                    line: row,
+                    length: end - start,
+                    token_type,
+                    modifier_bitset,
+                });
+                prev_node = Some(node);
+            }
+        }
+
+        tokens.sort_unstable();
+
+        let mut prev_line = 0;
+        let mut prev_start = 0;
+        let semantic_tokens = tokens
+            .into_iter()
+            .map(|tok| {
+                let delta_line = tok.line - prev_line;
+                let delta_start = if delta_line == 0 {
+                    tok.start - prev_start
+                } else {
+                    tok.start
+                };
+                let semtok = SemanticToken {
+                    delta_start: delta_start as u32,
+                    delta_line: delta_line as u32,
+                    length: tok.length as u32,
+                    token_type: tok.token_type as u32,
+                    token_modifiers_bitset: tok.modifier_bitset as u32,
+                };
+
+                prev_line = tok.line;
+                prev_start = tok.start;
+                semtok
+            })
+            .collect();
+        Some(semantic_tokens)
+    }
+}
+
+/**
+ * The edit turning the previous semantic tokens of a document into the current ones, for
+ * «textDocument/semanticTokens/full/delta»: the tokens between the common prefix and suffix
+ * are replaced. Offsets count integers in the encoded data, five per token.
+ */
+pub fn get_semantic_tokens_edits(
+    previous: &[SemanticToken],
+    tokens: &[SemanticToken],
+) -> Vec<SemanticTokensEdit> {
+    let prefix_len = previous
+        .iter()
+        .zip(tokens)
+        .take_while(|(previous, token)| previous == token)
+        .count();
+    let suffix_len = previous[prefix_len..]
+        .iter()
+        .rev()
+        .zip(tokens[prefix_len..].iter().rev())
+        .take_while(|(previous, token)| previous == token)
+        .count();
+    let deleted = &previous[prefix_len..previous.len() - suffix_len];
+    let inserted = &tokens[prefix_len..tokens.len() - suffix_len];
+    if deleted.is_empty() && inserted.is_empty() {
+        return vec![];
+    }
+    vec![SemanticTokensEdit {
+        start: (prefix_len * 5) as u32,
+        delete_count: (deleted.len() * 5) as u32,
+        data: Some(inserted.to_vec()),
+    }]
+}
+
+pub struct RopeChunkBytesIterator<'a> {
+    chunks: Chunks<'a>,
+}
+
+impl<'a> Iterator for RopeChunkBytesIterator<'a> {
+    type Item = &'a [u8];
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chunks.next().map(|chunk| chunk.as_bytes())
+    }
+}
+
+// TextProvider for tree-sitter to use ropey as efficiently as possible
+impl<'a> TextProvider<&'a [u8]> for &'a Document {
+    type I = RopeChunkBytesIterator<'a>;
+    fn text(&mut self, node: Node) -> Self::I {
+        RopeChunkBytesIterator {
+            chunks: self.rope.byte_slice(node.byte_range()).chunks(),
+        }
+    }
+}
+
+impl PartialEq for VmodImport {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.alias == other.alias
+    }
+}
+
+impl Eq for VmodImport {}
+
+impl VmodImport {
+    /// The name the vmod's functions and objects are used by: the alias, if any
+    pub fn scope_name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Path of the vmod in «import x from "path"». Relative paths are looked up in the vmod
+    /// paths, like varnish does.
+    pub fn resolve_from_path(&self, vmod_paths: &[PathBuf]) -> Option<PathBuf> {
+        let from = self.from.as_ref()?;
+        if from.is_absolute() {
+            return from.exists().then(|| from.to_owned());
+        }
+        vmod_paths
+            .iter()
+            .map(|search_path| search_path.join(from))
+            .find(|path| path.exists())
+    }
+}
+
+impl Include {
+    /**
+     * Find the included file like varnishd does: in the directory of the including document,
+     * and then in each of the vcl paths. Includes starting with «./» or «../» are only relative
+     * to the including document. The url is the path found, as written, without resolving
+     * symlinks.
+     */
+    pub fn resolve(mut self, vcl_paths: &[PathBuf]) -> Self {
+        if self.url.is_none() {
+            self.url = self
+                .get_search_paths(vcl_paths)
+                .into_iter()
+                .find(|path| path.exists())
+                .and_then(|path| Url::from_file_path(path).ok());
+        }
+        self
+    }
+
+    /// Where the included file is looked for, in order
+    pub fn get_search_paths(&self, vcl_paths: &[PathBuf]) -> Vec<PathBuf> {
+        if let Some(path) = self.url.as_ref().and_then(|url| url.to_file_path().ok()) {
+            return vec![path];
+        }
+        if self.path.is_absolute() {
+            return vec![self.path.clone()];
+        }
+        let relative_to_includer = matches!(
+            self.path.components().next(),
+            Some(Component::CurDir | Component::ParentDir)
+        );
+        if let Some(including_dir) = self.including_dir.as_ref().filter(|_| relative_to_includer) {
+            return vec![including_dir.join(&self.path)];
+        }
+        self.including_dir
+            .iter()
+            .chain(vcl_paths)
+            .map(|search_path| search_path.join(&self.path))
+            .fold(vec![], |mut paths, path| {
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+                paths
+            })
+    }
+
+    /// Whether the included file exists, after resolving
+    pub fn exists(&self) -> bool {
+        self.url
+            .as_ref()
+            .and_then(|url| url.to_file_path().ok())
+            .is_some_and(|path| path.exists())
+    }
+}
+
+// Misc helper functions
+
+fn point_to_position(point: Point) -> Position {
+    Position {
+        line: point.row as u32,
+        character: point.column as u32,
+    }
+}
+
+fn ts_range_to_lsp_range(input: tree_sitter::Range) -> Range {
+    Range {
+        start: point_to_position(input.start_point),
+        end: point_to_position(input.end_point),
+    }
+}
+
+fn get_probe_backend_fields(
+    r#type: Type,
+    text: &str,
+    vcl_version: Option<VclVersion>,
+) -> Vec<CompletionItem> {
+    let map = match r#type {
+        Type::Probe => get_probe_field_types(),
+        _ => get_backend_field_types(),
+    };
+    map.keys()
+        .filter(|field| field.starts_with(text))
+        .filter(|field| {
+            // only filter by version when the document declares one
+            let Some(vcl_version) = vcl_version else {
+                return true;
+            };
+            let is_backend_field = !matches!(r#type, Type::Probe);
+            !is_backend_field
+                || get_backend_field_min_vcl_version(field)
+                    .is_none_or(|min_version| vcl_version >= min_version)
+        })
+        .map(|field| CompletionItem {
+            label: field.to_string(),
+            detail: Some(field.to_string()),
+            kind: Some(CompletionItemKind::PROPERTY),
+            ..Default::default()
+        })
+        .collect()
+}
+
+pub fn node_to_type(node: &Node) -> Option<Type> {
+    match node.kind() {
+        "string" => Some(Type::String),
+        "string_list" => Some(Type::String),
+        "number" => Some(Type::Number),
+        "duration" => Some(Type::Duration),
+        "bool" => Some(Type::Bool),
+        "inline_probe" => Some(Type::Probe),
+        "literal" => node_to_type(&node.child(0)?),
+        _ => None,
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '.' | '_' | '-')
+}
+
+/// Whether the end of a line is within the condition of an if statement (e.g. «if (req.»)
+fn is_in_condition(line_prefix: &str) -> bool {
+    let Some(if_idx) = ["if", "elsif", "elseif", "elif"]
+        .iter()
+        .filter_map(|keyword| {
+            line_prefix.rmatch_indices(keyword).find(|(idx, _)| {
+                let before = &line_prefix[..*idx];
+                let after = line_prefix[idx + keyword.len()..].trim_start();
+                !before.ends_with(is_ident_char) && after.starts_with('(')
+            })
+        })
+        .map(|(idx, _)| idx)
+        .max()
+    else {
+        return false;
+    };
+
+    // check that the parenthesis is still open
+    let mut depth = 0;
+    for c in line_prefix[if_idx..].chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+/**
+ * Get the left operand and operator of a comparison being written at the end of a line, e.g.
+ * («beresp.ttl», «>») for «if (beresp.ttl > 1».
+ */
+fn get_comparison_operand(line_prefix: &str) -> Option<(&str, &str)> {
+    let before_right = line_prefix
+        .trim_end_matches(is_ident_char)
+        .trim_end_matches('"')
+        .trim_end();
+    let (before_operator, operator) = ["==", "!=", ">=", "<=", "!~", ">", "<", "~"]
+        .iter()
+        .find_map(|operator| {
+            before_right
+                .strip_suffix(operator)
+                .map(|before_operator| (before_operator.trim_end(), *operator))
+        })?;
+    let left_start = before_operator
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_ident_char(*c))
+        .last()?
+        .0;
+    let left = &before_operator[left_start..];
+    left.starts_with(|c: char| c.is_alphabetic())
+        .then_some((left, operator))
+}
+
+/// Get the string literal of includes, vmod imports from a path and std.fileread calls
+fn get_filesystem_path_node<'a>(doc: &Document, node: Node<'a>) -> Option<Node<'a>> {
+    let string_node = match node.kind() {
+        "include_declaration" | "import_declaration" => {
+            let mut cursor = node.walk();
+            let string_node = node
+                .named_children(&mut cursor)
+                .find(|child| child.kind() == "string");
+            string_node
+        }
+        "ident_call_expr" => {
+            let ident_node = node.child_by_field_name("ident")?;
+            let ident = get_node_text(&doc.rope, &ident_node);
+            if !ident.ends_with(".fileread") || doc.unalias_vmod_ident(&ident) != "std.fileread" {
+                return None;
+            }
+            let arg_node = node
+                .child_by_field_name("args")?
+                .child_by_field_name("arg")?;
+            arg_node.named_child(0)
+        }
+        _ => None,
+    }?;
+    (string_node.kind() == "string").then_some(string_node)
+}
+
+/// Normalize «.» and «..» in a path without touching the filesystem
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn point_to_tuple(point: Point) -> (usize, usize) {
+    (point.row, point.column)
+}
+
+fn get_toplev_declaration_from_node(node: Node) -> Node {
+    let mut node = node;
+    while let Some(parent_node) = node.parent() {
+        if parent_node.kind() == "toplev_declaration" {
+            break;
+        }
+        node = parent_node;
+    }
+    node
+}
+
+fn find_parent(node: Node, kind: String) -> Option<Node> {
+    let mut node = node;
+    loop {
+        let parent_node = node.parent()?;
+        node = parent_node;
+        if node.kind() == kind {
+            return Some(node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::config::LintLevel;
+    use crate::varnish_builtins::get_varnish_builtins;
+    use crate::varnish_builtins::{Func, FuncArg, Obj};
+
+    use super::*;
+
+    #[test]
+    fn autocomplete_expands_h_to_http() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.h
+}
+"#
+            .to_string(),
+            None,
+        );
+        let result = doc
+            .autocomplete_for_pos(
+                Position {
+                    line: 2,
+                    character: 12,
+                },
+                get_varnish_builtins(),
+                VarnishFlavor::default(),
+            )
+            .unwrap();
+        println!("result: {:?}", result);
+        assert!(result.iter().any(|item| item.label == "http"));
+    }
+
+    #[test]
+    fn autocomplete_lists_all_on_req() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.
+}
+"#
+            .to_string(),
+            None,
+        );
+        let result = doc
+            .autocomplete_for_pos(
+                Position {
+                    line: 2,
+                    character: 11,
+                },
+                get_varnish_builtins(),
+                VarnishFlavor::default(),
+            )
+            .unwrap();
+        assert!(!result.is_empty());
+    }
+
+    #[test]
+    fn get_all_vmod_imports() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+import brotli;
+import jwt;
+import xkey;
+"#
+            .to_string(),
+            None,
+        );
+        let vmod_names: Vec<_> = doc
+            .get_vmod_imports()
+            .iter()
+            .map(|import| import.name.clone())
+            .collect();
+        println!("imports: {:?}", vmod_names);
+        assert_eq!(vmod_names, vec!["brotli", "jwt", "xkey"]);
+    }
+
+    #[test]
+    fn vmod_import_alias() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+import directors as d;
+import std from "/usr/lib/varnish/vmods/libvmod_std.so";
+
+sub vcl_recv {
+    set req.backend_hint = d.
+}
+"#
+            .to_string(),
+            None,
+        );
+        let imports = doc.get_vmod_imports();
+        assert_eq!(imports.len(), 2);
+        assert_eq!(imports[0].name, "directors");
+        assert_eq!(imports[0].alias.as_deref(), Some("d"));
+        assert_eq!(imports[0].scope_name(), "d");
+        assert_eq!(imports[1].alias, None);
+        assert_eq!(imports[1].scope_name(), "std");
+
+        // the backend adds the vmod to the scope by the name it is used by
+        let vmod_scope = crate::vmod::parse_vmod_json(
+            r#"[
+                ["$VMOD", "1.0", "directors", "Vmod_directors_Func", "0", "", "7", "1"],
+                ["$OBJ", "round_robin", {}, "struct vmod_directors_round_robin",
+                    ["$INIT", [["VOID"], "Vmod_directors_Func.round_robin__init", ""]]
+                ]
+            ]"#,
+        )
+        .unwrap();
+        let mut defs = get_varnish_builtins();
+        defs.properties.insert(
+            imports[0].scope_name().to_string(),
+            Definition::new_builtin(imports[0].scope_name().to_string(), vmod_scope),
+        );
+        let result = doc
+            .autocomplete_for_pos(Position::new(5, 29), defs, VarnishFlavor::default())
+            .unwrap();
+        assert!(result.iter().any(|item| item.label == "round_robin"));
+    }
+
+    #[test]
+    fn get_all_subroutines() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_init {}
+sub vcl_recv {}
+sub my_custom_sub {}
+"#
+            .to_string(),
+            None,
+        );
+        let result = doc.get_subroutines();
+        println!("subroutines: {:?}", result);
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0], "vcl_init");
+        assert_eq!(result[1], "vcl_recv");
+        assert_eq!(result[2], "my_custom_sub");
+    }
+
+    #[test]
+    fn get_definition_for_sub() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub my_custom_sub {}
+sub vcl_recv {
+    call my_custom_sub;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let result = doc
+            .get_definition_by_point(Point { row: 3, column: 9 })
+            .unwrap();
+        assert_eq!(result.0.row, 1);
+        assert_eq!(result.0.column, 0);
+        assert_eq!(result.1.row, 1);
+        assert_eq!(result.1.column, 20);
+    }
+
+    #[test]
+    fn get_all_declarations_of_appended_subs() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.http.x-first = "1";
+}
+sub my_custom_sub {}
+sub vcl_recv {
+    call my_custom_sub;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let result = doc.get_definitions_by_name("vcl_recv");
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].0.row, 1);
+        assert_eq!(result[1].0.row, 5);
+        assert_eq!(doc.get_definition_by_name("vcl_recv"), Some(result[0]));
+        assert_eq!(doc.get_definitions_by_name("my_custom_sub").len(), 1);
+    }
+
+    #[test]
+    fn get_all_definitions_works() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+acl my_ips {}
+sub my_custom_sub {}
+sub vcl_recv {}
+"#
+            .to_string(),
+            None,
+        );
+        let result = doc.get_all_definitions(&get_varnish_builtins());
+        assert_eq!(result.len(), 3);
+    }
+
+    #[test]
+    fn nested_identifier_before_if_works() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+acl a_not_this {}
+backend a_match_this {}
+sub vcl_recv {
+    if (req.
+
+    if (true) {}
+}
+"#
+            .to_string(),
+            None,
+        );
+        let result = doc.autocomplete_for_pos(
+            Position {
+                line: 4,
+                character: 11,
+            },
+            get_varnish_builtins(),
+            VarnishFlavor::default(),
+        );
+        println!("result: {:?}", result);
+        assert_eq!(
+            result.unwrap()[0].detail,
+            Some("BACKEND backend_hint".to_string())
+        );
+    }
+
+    #[ignore]
+    #[test]
+    fn autocomplete_backend_after_equal_sign() {
+        let mut scope = get_varnish_builtins();
+
+        scope.properties.insert(
+            "localhost".to_string(),
+            Definition::new_builtin("localhost".to_string(), Type::Backend),
+        );
+        scope.properties.insert(
+            "localhost_probe".to_string(),
+            Definition::new_builtin("localhost_probe".to_string(), Type::Probe),
+        );
+
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+backend localhost {}
+sub vcl_recv {
+    set req.backend_hint = 
+}
+"#
+            .to_string(),
+            None,
+        );
+        let result = doc.autocomplete_for_pos(
+            Position {
+                line: 3,
+                character: 26,
+            },
+            scope,
+            VarnishFlavor::default(),
+        );
+        println!("result: {:?}", result);
+        let result = result.unwrap();
+        /*
+        assert_eq!(
+            result.first().unwrap().detail,
+            Some("localhost".to_string())
+        );
+        assert_eq!(result.len(), 1);
+        */
+        assert!(!result.is_empty());
+        assert!(result.iter().any(|item| item.label == *"localhost"));
+        assert!(!result.iter().any(|item| item.label == *"localhost_probe"));
+    }
+
+    #[test]
+    fn autocomplete_backend_def_properties() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+backend localhost {
+    .po
+}
+"#
+            .to_string(),
+            None,
+        );
+        let result = doc.autocomplete_for_pos(
+            Position {
+                line: 2,
+                character: 6,
+            },
+            get_varnish_builtins(),
+            VarnishFlavor::default(),
+        );
+        println!("result: {:?}", result);
+        let result = result.unwrap();
+        assert_eq!(result.first().unwrap().detail, Some("port".to_string()));
+        assert_eq!(result.len(), 1);
+    }
+
+    #[test]
+    fn autocomplete_backend_path_requires_vcl_4_1() {
+        let src = r#"
+vcl {version};
+backend localhost {
+    .pa
+}
+"#;
+        let pos = Position {
+            line: 3,
+            character: 6,
+        };
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            src.replace("{version}", "4.0"),
+            None,
+        );
+        let result = doc
+            .autocomplete_for_pos(pos, get_varnish_builtins(), VarnishFlavor::default())
+            .unwrap();
+        assert!(result.is_empty(), ".path should not complete in vcl 4.0");
+
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            src.replace("{version}", "4.1"),
+            None,
+        );
+        let result = doc
+            .autocomplete_for_pos(pos, get_varnish_builtins(), VarnishFlavor::default())
+            .unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].label, "path");
+    }
+
+    #[test]
+    fn diagnostics_of_file_included_twice_are_deduplicated() {
+        let main_doc = Document::new(
+            Url::parse("file:///etc/varnish/main.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    include "./included.vcl";
+    if (req.http.a) {
+        include "./included.vcl";
+    }
+    set req.http.x = ;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let included_doc = Document::new(
+            Url::parse("file:///etc/varnish/included.vcl").unwrap(),
+            r#"sub included { set req.http.y = ; }"#.to_string(),
+            None,
+        );
+        assert_eq!(main_doc.get_includes().len(), 2);
+
+        // analyze the included doc once per include
+        let scope = get_varnish_builtins();
+        let lint_config = Default::default();
+        let lint_errors = main_doc
+            .get_error_ranges(&scope, &lint_config)
+            .into_iter()
+            .chain(
+                main_doc
+                    .get_includes()
+                    .iter()
+                    .flat_map(|_| included_doc.get_error_ranges(&scope, &lint_config)),
+            )
+            .collect::<Vec<_>>();
+
+        let diagnostics = group_diagnostics_by_uri(lint_errors);
+        println!("diagnostics: {:?}", diagnostics);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[&main_doc.url].len(), 1);
+        assert_eq!(diagnostics[&main_doc.url][0].range.start.line, 6);
+        assert_eq!(diagnostics[&included_doc.url].len(), 1);
+        assert_eq!(diagnostics[&included_doc.url][0].range.start.line, 0);
+    }
+
+    #[test]
+    fn backend_resolve_requires_vcl_4_1() {
+        let src = r#"
+vcl {version};
+backend app {
+    .host = "app.example.com";
+    .resolve = "now";
+}
+"#;
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            src.replace("{version}", "4.1"),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert!(errors.is_empty());
+
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            src.replace("{version}", "4.0"),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].range.start.line, 4);
+        assert_eq!(
+            errors[0].message,
+            "Backend property «.resolve» requires vcl 4.1"
+        );
+
+        // completion
+        let src = src.replace("    .resolve = \"now\";", "    .reso");
+        let pos = Position::new(4, 8);
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            src.replace("{version}", "4.1"),
+            None,
+        );
+        let result = doc
+            .autocomplete_for_pos(pos, get_varnish_builtins(), VarnishFlavor::default())
+            .unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].label, "resolve");
+
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            src.replace("{version}", "4.0"),
+            None,
+        );
+        let result = doc
+            .autocomplete_for_pos(pos, get_varnish_builtins(), VarnishFlavor::default())
+            .unwrap();
+        assert!(result.is_empty(), ".resolve should not complete in vcl 4.0");
+    }
+
+    #[test]
+    fn backend_requires_either_host_or_path() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+vcl 4.1;
+
+backend with_both {
+    .host = "localhost";
+    .path = "/run/app.sock";
+}
+
+backend with_neither {
+    .port = "8080";
+}
+
+backend with_path {
+    .path = "/run/app.sock";
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].range.start.line, 5);
+        assert_eq!(
+            errors[0].related_information.as_ref().unwrap()[0]
+                .location
+                .range
+                .start
+                .line,
+            4
+        );
+        assert_eq!(errors[1].range.start.line, 8);
+    }
+
+    #[test]
+    fn backend_ports() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+backend numeric {
+    .host = "localhost";
+    .port = "8080";
+}
+
+backend service {
+    .host = "localhost";
+    .port = "https";
+}
+
+backend out_of_range {
+    .host = "localhost";
+    .port = "99999";
+}
+
+backend typo {
+    .host = "localhost";
+    .port = "htttp";
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].range.start.line, 13);
+        assert_eq!(errors[0].severity, Some(DiagnosticSeverity::ERROR));
+        assert_eq!(
+            errors[0].message,
+            "Port 99999 is out of range (expected 1-65535)"
+        );
+        assert_eq!(errors[1].range.start.line, 18);
+        assert_eq!(errors[1].severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(
+            errors[1].message,
+            "Unknown port «htttp» (expected a port number, or a service name like «http» or «https»)"
+        );
+    }
+
+    #[test]
+    fn append_to_headers() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+acl local {
+    "localhost";
+}
+
+sub vcl_deliver {
+    set resp.http.cache-control = resp.http.cache-control + ", no-store";
+    set resp.http.cache-control += ", no-transform";
+    set resp.http.x-restarts = "restarts: " + req.restarts;
+    set resp.http.x-acl = "acl: " + local;
+    set resp.http.x-acl += local;
+    set resp.http.x-double *= 2;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let mut scope = get_varnish_builtins();
+        let mut doc_defs = BTreeMap::from_iter(
+            doc.get_all_definitions(&scope)
+                .into_iter()
+                .map(|def| (def.ident_str.to_string(), def)),
+        );
+        scope.properties.append(&mut doc_defs);
+        let errors = doc.get_error_ranges(&scope, &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| (error.loc.range.start, error.message.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    Position::new(9, 36),
+                    "Cannot append ACL to a header, it does not convert into STRING"
+                ),
+                (
+                    Position::new(10, 27),
+                    "Cannot append ACL to a header, it does not convert into STRING"
+                ),
+                (
+                    Position::new(11, 27),
+                    "«*=» cannot be used on headers, only «=» and «+=»"
+                ),
+            ]
+        );
+
+        // the concatenation is a string
+        let set_node = doc
+            .ast
+            .root_node()
+            .descendant_for_point_range(Point::new(8, 4), Point::new(8, 4))
+            .and_then(|node| find_parent(node, "set_stmt".to_string()))
+            .unwrap();
+        let right_node = set_node.child_by_field_name("right").unwrap();
+        assert!(matches!(
+            doc.get_expr_type(right_node, &scope),
+            Some(Type::String)
+        ));
+    }
+
+    #[test]
+    fn url_with_host() {
+        assert!(url_has_host("http://example.com/path"));
+        assert!(url_has_host("https://example.com"));
+        assert!(url_has_host("//example.com/path"));
+        assert!(url_has_host("example.com/path"));
+        assert!(!url_has_host("/path"));
+        assert!(!url_has_host("/path.html?a=http://example.com/"));
+        assert!(!url_has_host("*"));
+        assert!(!url_has_host("index.html"));
+
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.url = "http://example.com/path";
+    set req.url = "/path";
+    set req.url = req.http.x-url;
+}
+
+sub vcl_backend_fetch {
+    set bereq.url = {"//example.com/path"};
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.get_error_ranges(&get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].loc.range.start, Position::new(2, 18));
+        assert_eq!(errors[0].severity, DiagnosticSeverity::WARNING);
+        assert!(errors[0].message.starts_with("req.url should be a path"));
+        assert_eq!(errors[1].loc.range.start, Position::new(8, 20));
+    }
+
+    #[test]
+    fn vcl_labels() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"vcl 4.0;
+
+sub vcl_recv {
+    if (req.http.host ~ "example\.com$") {
+        return (vcl(l_example));
+    }
+    return (vcl("l_string"));
+}
+
+sub vcl_deliver {
+    return (vcl(l_example));
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.get_error_ranges(&get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].loc.range.start, Position::new(6, 12));
+        assert_eq!(
+            errors[0].message,
+            "return (vcl(…)) expects a single vcl label, e.g. «return (vcl(l_example))»"
+        );
+        assert_eq!(errors[1].loc.range.start, Position::new(10, 12));
+        assert!(errors[1]
+            .message
+            .starts_with("return (vcl) is not allowed in vcl_deliver"));
+
+        let labels = doc.get_vcl_labels();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[0].0, "l_example");
+        assert_eq!(labels[0].1.start, Position::new(4, 20));
+        assert_eq!(labels[1].1.start, Position::new(10, 16));
+
+        let labels = vec!["l_example".to_string(), "l_other".to_string()];
+        let completions = doc
+            .autocomplete_vcl_label(Position::new(4, 22), &labels)
+            .unwrap();
+        assert_eq!(completions.len(), 2);
+        assert_eq!(completions[1].label, "l_other");
+        assert!(doc
+            .autocomplete_vcl_label(Position::new(3, 10), &labels)
+            .is_none());
+    }
+
+    #[test]
+    fn configured_vcl_labels() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"vcl 4.1;
+
+sub vcl_recv {
+    if (req.http.host == "a.example.com") {
+        return (vcl(l_a));
+    }
+    if (req.http.host == "b.example.com") {
+        return (vcl(l_b));
+    }
+    return (vcl(l.c));
+}
+"#
+            .to_string(),
+            None,
+        );
+
+        // without configured labels, only the label syntax is checked
+        let errors = doc.get_error_ranges(&get_varnish_builtins(), &Default::default());
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert_eq!(errors[0].loc.range.start, Position::new(9, 12));
+        assert_eq!(
+            errors[0].message,
+            "return (vcl(…)) expects a single vcl label, e.g. «return (vcl(l_example))»"
+        );
+        let (hover, range) = doc.get_vcl_label_hover(Point::new(4, 21), None).unwrap();
+        assert_eq!(range.start, Position::new(4, 20));
+        assert_eq!(
+            hover,
+            "`vcl label l_a`\n\nSet up outside of vcl with `varnishadm vcl.label`. Not checked, set `lint.vcl_labels` to the labels set up to check it."
+        );
+
+        let lint_config = LintConfig {
+            vcl_labels: Some(vec!["l_a".to_string(), "l_other".to_string()]),
+            ..Default::default()
+        };
+        let errors = doc.get_error_ranges(&get_varnish_builtins(), &lint_config);
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert_eq!(errors[0].loc.range.start, Position::new(7, 20));
+        assert_eq!(
+            errors[0].message,
+            "Unknown vcl label «l_b», expected one of the labels in lint.vcl_labels: l_a, l_other"
+        );
+        assert!(errors[1]
+            .message
+            .starts_with("return (vcl(…)) expects a single vcl label"));
+        let labels = lint_config.vcl_labels.as_deref();
+        let (hover, _) = doc.get_vcl_label_hover(Point::new(4, 21), labels).unwrap();
+        assert!(hover.ends_with("One of the labels in `lint.vcl_labels`."));
+        let (hover, _) = doc.get_vcl_label_hover(Point::new(7, 21), labels).unwrap();
+        assert!(hover.ends_with("Not one of the labels in `lint.vcl_labels`."));
+        assert!(doc.get_vcl_label_hover(Point::new(3, 8), labels).is_none());
+    }
+
+    #[test]
+    fn acl_hover() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+acl purgers {
+    "localhost";
+    "192.168.0.0"/24;
+    !"192.168.0.5";
+    "10.0.0.0"/8;
+    "2001:db8::"/32;
+    "2001:db8::"/120;
+}
+
+acl empty {
+}
+"#
+            .to_string(),
+            None,
+        );
+        assert_eq!(
+            doc.get_acl_hover("purgers").unwrap(),
+            r#"**acl purgers**
+
+- `"localhost"` (hostname, resolved when the vcl is loaded)
+- `"192.168.0.0"/24` (256 addresses)
+- `!"192.168.0.5"` (1 address)
+- `"10.0.0.0"/8` (16777216 addresses)
+- `"2001:db8::"/32` (/32 range)
+- `"2001:db8::"/120` (256 addresses)
+
+Matches up to 16777472 addresses (IPv4, not counting negated entries)"#
+        );
+        assert_eq!(
+            doc.get_acl_hover("empty").unwrap(),
+            "**acl empty**\n\nNo entries"
+        );
+        assert!(doc.get_acl_hover("vcl_recv").is_none());
+    }
+
+    #[test]
+    fn new_stmt_hover() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_init {
+    new rr = directors.round_robin();
+}
+"#
+            .to_string(),
+            None,
+        );
+        let mut scope = get_varnish_builtins();
+        scope.properties.insert(
+            "directors".to_string(),
+            Definition::new_builtin(
+                "directors".to_string(),
+                Type::Obj(Obj {
+                    name: "directors".to_string(),
+                    properties: BTreeMap::from([(
+                        "round_robin".to_string(),
+                        Type::Func(Func {
+                            name: "round_robin".to_string(),
+                            doc: Some("Create a round robin director.".to_string()),
+                            r#return: Some(Box::new(Type::Obj(Obj {
+                                name: "round_robin".to_string(),
+                                properties: BTreeMap::from([(
+                                    "add_backend".to_string(),
+                                    Type::Func(Func {
+                                        name: "add_backend".to_string(),
+                                        args: vec![FuncArg {
+                                            r#type: Some(Type::Backend),
+                                            ..Default::default()
+                                        }],
+                                        ..Default::default()
+                                    }),
+                                )]),
+                                ..Default::default()
+                            }))),
+                            ..Default::default()
+                        }),
+                    )]),
+                    ..Default::default()
+                }),
+            ),
+        );
+
+        let (value, range) = doc.get_new_stmt_hover(Point::new(2, 9), &scope).unwrap();
+        assert_eq!(
+            value,
+            r#"**new rr**: object `round_robin` from vmod `directors`
+
+```vcl
+new rr = directors.round_robin()
+```
+
+Methods:
+
+```vcl
+VOID rr.add_backend(BACKEND)
+```
+
+Create a round robin director."#
+        );
+        assert_eq!(range.start, Position::new(2, 4));
+
+        // no hover for unknown constructors or inside the arguments
+        assert!(doc
+            .get_new_stmt_hover(Point::new(2, 9), &get_varnish_builtins())
+            .is_none());
+        assert!(doc.get_new_stmt_hover(Point::new(2, 35), &scope).is_none());
+    }
+
+    #[test]
+    fn untuned_byte_timeouts() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+backend slow {
+    .host = "slow.example.com";
+    .connect_timeout = 30s;
+}
+
+backend tuned {
+    .host = "tuned.example.com";
+    .connect_timeout = 30s;
+    .first_byte_timeout = 300s;
+}
+
+backend fast {
+    .host = "fast.example.com";
+    .connect_timeout = 2s;
+}
+"#
+            .to_string(),
+            None,
+        );
+
+        // off by default
+        let errors = doc.get_error_ranges(&get_varnish_builtins(), &Default::default());
+        assert!(errors.is_empty());
+
+        let lint_config = LintConfig {
+            untuned_byte_timeouts: LintLevel::Hint,
+            ..Default::default()
+        };
+        let errors = doc.get_error_ranges(&get_varnish_builtins(), &lint_config);
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].loc.range.start.line, 3);
+        assert_eq!(errors[0].severity, DiagnosticSeverity::HINT);
+        assert!(errors[0].message.starts_with("[untuned_byte_timeouts]"));
+    }
+
+    #[test]
+    fn semantic_tokens_mark_read_only_variables() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"sub vcl_backend_response {
+    set beresp.http.x-ip = client.ip;
+    set beresp.http.x-retries = bereq.retries;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let readonly = 1 << 2;
+        let tokens = doc.get_semantic_tokens();
+        let property = LEGEND_TYPES
+            .iter()
+            .position(|s| *s == SemanticTokenType::PROPERTY)
+            .unwrap() as u32;
+        let properties = tokens
+            .iter()
+            .filter(|tok| tok.token_type == property)
+            .collect::<Vec<_>>();
+        println!("properties: {:?}", properties);
+        assert_eq!(properties.len(), 4);
+        // beresp is writable in vcl_backend_response
+        assert_eq!(properties[0].token_modifiers_bitset & readonly, 0);
+        // client.ip is always read-only
+        assert_eq!(properties[1].token_modifiers_bitset & readonly, readonly);
+        assert_eq!(properties[2].token_modifiers_bitset & readonly, 0);
+        assert_eq!(properties[3].token_modifiers_bitset & readonly, readonly);
+    }
+
+    #[test]
+    fn linked_editing_ranges_for_sub_and_calls() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"backend app { .host = "localhost"; }
+
+sub normalize {
+    set req.backend_hint = app;
+}
+
+sub vcl_recv {
+    call normalize;
+    call normalize_more;
+}
+"#
+            .to_string(),
+            None,
+        );
+
+        let ranges = doc.get_linked_editing_ranges(Point::new(7, 10)).unwrap();
+        assert_eq!(
+            ranges,
+            vec![
+                Range::new(Position::new(2, 4), Position::new(2, 13)),
+                Range::new(Position::new(7, 9), Position::new(7, 18)),
+            ]
+        );
+
+        let ranges = doc.get_linked_editing_ranges(Point::new(0, 9)).unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[1].start, Position::new(3, 27));
+
+        // builtin variables are not declared in the document
+        assert!(doc.get_linked_editing_ranges(Point::new(3, 9)).is_none());
+    }
+
+    #[test]
+    fn lint_paths_outside_sandbox() {
+        let doc = Document::new(
+            Url::parse("file:///etc/varnish/default.vcl").unwrap(),
+            r#"
+import secrets from "/usr/lib/varnish/vmods/libvmod_secrets.so";
+include "./conf/acl.vcl";
+include "../passwd.vcl";
+
+sub vcl_recv {
+    set req.http.x-key = std.fileread("/etc/varnish/conf/../../shadow");
+}
+"#
+            .to_string(),
+            None,
+        );
+        let lint_config = LintConfig {
+            path_outside_sandbox: LintLevel::Warning,
+            sandbox_paths: vec![
+                PathBuf::from("/etc/varnish"),
+                PathBuf::from("/usr/lib/varnish/vmods/"),
+            ],
+            ..Default::default()
+        };
+        let errors = doc
+            .get_error_ranges(&get_varnish_builtins(), &lint_config)
+            .into_iter()
+            .filter(|error| error.message.starts_with("[path_outside_sandbox]"))
+            .collect::<Vec<_>>();
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].loc.range.start.line, 3);
+        assert!(errors[0].message.ends_with("/etc/passwd.vcl"));
+        assert_eq!(errors[1].loc.range.start.line, 6);
+        assert!(errors[1].message.ends_with("/etc/shadow"));
+    }
+
+    #[test]
+    fn matching_brackets_ignore_strings() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"probe healthcheck {
+    .request = "GET /{ HTTP/1.1" "Host: {example.com";
+    .interval = 5s;
+}
+"#
+            .to_string(),
+            None,
+        );
+
+        let (bracket, matching_bracket) = doc.get_matching_brackets(Point::new(0, 18)).unwrap();
+        assert_eq!(bracket.start, Position::new(0, 18));
+        assert_eq!(matching_bracket.start, Position::new(3, 0));
+
+        // cursor right after the closing bracket
+        let (bracket, matching_bracket) = doc.get_matching_brackets(Point::new(3, 1)).unwrap();
+        assert_eq!(bracket.start, Position::new(3, 0));
+        assert_eq!(matching_bracket.start, Position::new(0, 18));
+
+        // brackets within strings are not brackets
+        assert!(doc.get_matching_brackets(Point::new(1, 21)).is_none());
+    }
+
+    #[test]
+    fn autocomplete_in_conditions() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"sub vcl_backend_response {
+    if (beresp.ttl > ) {}
+    if (beresp.) {}
+}
+"#
+            .to_string(),
+            None,
+        );
+
+        let result = doc
+            .autocomplete_for_pos(
+                Position::new(1, 21),
+                get_varnish_builtins(),
+                VarnishFlavor::default(),
+            )
+            .unwrap();
+        let labels = result
+            .iter()
+            .map(|item| item.label.as_str())
+            .collect::<Vec<_>>();
+        println!("labels: {:?}", labels);
+        assert!(labels.contains(&"1m"), "should suggest duration literals");
+        assert!(labels.contains(&"beresp"), "objects containing durations");
+        assert!(
+            !labels.contains(&"set"),
+            "no statement keywords in conditions"
+        );
+
+        let result = doc
+            .autocomplete_for_pos(
+                Position::new(2, 15),
+                get_varnish_builtins(),
+                VarnishFlavor::default(),
+            )
+            .unwrap();
+        let mut result = result
+            .into_iter()
+            .map(|item| (item.sort_text.unwrap(), item.label))
+            .collect::<Vec<_>>();
+        result.sort();
+        println!("result: {:?}", result);
+        assert!(!result.iter().any(|(_, label)| label == "set"));
+        // booleans first
+        assert_eq!(result[0].1, "do_esi");
+    }
+
+    #[test]
+    fn autocomplete_bool_functions_in_conditions() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"sub vcl_recv {
+    if (std.) {}
+    if () {}
+}
+"#
+            .to_string(),
+            None,
+        );
+        let get_defs = || {
+            let mut defs = get_varnish_builtins();
+            defs.properties.insert(
+                "std".to_string(),
+                Definition::new_builtin(
+                    "std".to_string(),
+                    Type::Obj(Obj {
+                        name: "std".to_string(),
+                        properties: BTreeMap::from([
+                            (
+                                "healthy".to_string(),
+                                Type::Func(Func {
+                                    name: "healthy".to_string(),
+                                    ret_type: Some("BOOL".to_string()),
+                                    r#return: Some(Box::new(Type::Bool)),
+                                    args: vec![FuncArg {
+                                        name: Some("be".to_string()),
+                                        r#type: Some(Type::Backend),
+                                        ..Default::default()
+                                    }],
+                                    ..Default::default()
+                                }),
+                            ),
+                            (
+                                "file_exists".to_string(),
+                                Type::Func(Func {
+                                    name: "file_exists".to_string(),
+                                    ret_type: Some("BOOL".to_string()),
+                                    ..Default::default()
+                                }),
+                            ),
+                            (
+                                "tolower".to_string(),
+                                Type::Func(Func {
+                                    name: "tolower".to_string(),
+                                    ret_type: Some("STRING".to_string()),
+                                    r#return: Some(Box::new(Type::String)),
+                                    ..Default::default()
+                                }),
+                            ),
+                        ]),
+                        ..Default::default()
+                    }),
+                ),
+            );
+            defs
+        };
+
+        let mut result = doc
+            .autocomplete_for_pos(Position::new(1, 12), get_defs(), VarnishFlavor::default())
+            .unwrap()
+            .into_iter()
+            .map(|item| (item.sort_text.unwrap(), item.label))
+            .collect::<Vec<_>>();
+        result.sort();
+        assert_eq!(
+            result
+                .iter()
+                .map(|(_, label)| label.as_str())
+                .collect::<Vec<_>>(),
+            vec!["file_exists", "healthy", "tolower"]
+        );
+        assert!(result[1].0 < result[2].0);
+
+        // vmods with boolean functions are ranked above other objects
+        let result = doc
+            .autocomplete_for_pos(Position::new(2, 8), get_defs(), VarnishFlavor::default())
+            .unwrap();
+        let sort_text = |label: &str| {
+            result
+                .iter()
+                .find(|item| item.label == label)
+                .and_then(|item| item.sort_text.clone())
+                .unwrap()
+        };
+        assert!(sort_text("std") < sort_text("client"));
+    }
+
+    #[test]
+    fn template_placeholders_are_parsed_as_identifiers() {
+        let placeholders = vec![TemplatePlaceholder {
+            start: "${".into(),
+            end: "}".into(),
+        }];
+        let (masked, ranges) = mask_template_placeholders(
+            "backend default {\n    .host = \"${HOST}\";\n    .port = ${PORT};\n}\n",
+            &placeholders,
+        );
+        assert_eq!(
+            masked,
+            "backend default {\n    .host = \"xxHOSTx\";\n    .port = xxPORTx;\n}\n"
+        );
+        assert_eq!(
+            ranges,
+            vec![
+                Range::new(Position::new(1, 13), Position::new(1, 20)),
+                Range::new(Position::new(2, 12), Position::new(2, 19)),
+            ]
+        );
+
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+backend default {
+    .host = "127.0.0.1";
+    .port = ${PORT};
+}
+sub vcl_recv {
+    set req.backend_hint = ${BACKEND};
+}
+"#
+            .to_string(),
+            None,
+        )
+        .with_template_placeholders(&placeholders);
+        assert!(!doc.ast.root_node().has_error());
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 0);
+    }
+
+    #[test]
+    fn recursive_calls() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub a {
+    call b;
+}
+sub b {
+    if (req.http.x-test) {
+        call a;
+    }
+}
+sub c {
+    call c;
+}
+sub vcl_recv {
+    call a;
+    call c;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let sub_calls = doc.get_sub_calls();
+        assert_eq!(sub_calls.len(), 4);
+        assert_eq!(sub_calls[1].calls[0].0, "a");
+        assert_eq!(sub_calls[3].calls.len(), 2);
+
+        let errors = get_call_cycle_errors(&sub_calls);
+        let messages = errors
+            .iter()
+            .map(|error| error.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                "Recursive call: a -> b -> a",
+                "Recursive call: b -> a -> b",
+                "Recursive call: c -> c",
+            ]
+        );
+        assert_eq!(errors[0].loc.range.start, Position::new(1, 4));
+        let related = errors[0].related_information.as_ref().unwrap();
+        assert_eq!(related[0].location.range.start, Position::new(2, 4));
+        assert_eq!(related[1].location.range.start, Position::new(6, 8));
+        assert_eq!(related[1].message, "b calls a");
+    }
+
+    #[test]
+    fn call_cycles_across_documents() {
+        let call = |name: &str, line: u32| {
+            (
+                name.to_string(),
+                Location {
+                    uri: Url::parse("file:///b.vcl").unwrap(),
+                    range: Range::new(Position::new(line, 4), Position::new(line, 11)),
+                },
+            )
+        };
+        let sub = |name: &str, uri: &str, calls| SubCalls {
+            name: name.to_string(),
+            loc: Location {
+                uri: Url::parse(uri).unwrap(),
+                range: Default::default(),
+            },
+            calls,
+        };
+        let all_sub_calls = vec![
+            sub("a", "file:///a.vcl", vec![call("b", 2)]),
+            sub("b", "file:///b.vcl", vec![call("d", 5), call("a", 6)]),
+            sub("d", "file:///b.vcl", vec![]),
+        ];
+        let errors = get_call_cycle_errors(&all_sub_calls);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].loc.uri.path(), "/a.vcl");
+        assert_eq!(errors[1].message, "Recursive call: b -> a -> b");
+        assert!(get_call_cycle_errors(&all_sub_calls[1..]).is_empty());
+
+        // through the second definition of a builtin subroutine
+        let all_sub_calls = vec![
+            sub("vcl_recv", "file:///a.vcl", vec![call("log", 2)]),
+            sub("log", "file:///a.vcl", vec![]),
+            sub("vcl_recv", "file:///b.vcl", vec![call("helper", 3)]),
+            sub("helper", "file:///b.vcl", vec![call("vcl_recv", 7)]),
+        ];
+        let errors = get_call_cycle_errors(&all_sub_calls);
+        let messages = errors
+            .iter()
+            .map(|error| error.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                "Recursive call: helper -> vcl_recv -> helper",
+                "Recursive call: vcl_recv -> helper -> vcl_recv",
+            ]
+        );
+    }
+
+    #[test]
+    fn sub_scaffold() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"vcl 4.1;
+
+sub vcl_recv {
+}
+
+# deliver
+sub vcl_deliver {
+}
+"#
+            .to_string(),
+            None,
+        );
+        let apply = |edit: TextEdit| {
+            let mut rope = doc.rope.clone();
+            let start = rope.line_to_char(edit.range.start.line as usize)
+                + edit.range.start.character as usize;
+            rope.insert(start, &edit.new_text);
+            rope.to_string()
+        };
+
+        // after the subroutines running before it
+        assert_eq!(
+            apply(doc.get_sub_scaffold_edit("vcl_backend_response").unwrap()),
+            "vcl 4.1;\n\nsub vcl_recv {\n}\n\nsub vcl_backend_response {\n}\n\n# deliver\nsub vcl_deliver {\n}\n"
+        );
+        // before the ones running after it, with their comments
+        assert_eq!(
+            apply(doc.get_sub_scaffold_edit("vcl_init").unwrap()),
+            "vcl 4.1;\n\nsub vcl_init {\n}\n\nsub vcl_recv {\n}\n\n# deliver\nsub vcl_deliver {\n}\n"
+        );
+        assert_eq!(
+            doc.get_sub_scaffold_edit("vcl_recv").unwrap_err(),
+            "«vcl_recv» is already declared"
+        );
+        assert!(doc
+            .get_sub_scaffold_edit("vcl_foo")
+            .unwrap_err()
+            .starts_with("Unknown builtin subroutine «vcl_foo»"));
+
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            "vcl 4.1;\n".to_string(),
+            None,
+        );
+        assert_eq!(
+            doc.get_sub_scaffold_edit("vcl_recv").unwrap().new_text,
+            "\n\nsub vcl_recv {\n}"
+        );
+    }
+
+    #[test]
+    fn constant_conditions() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    if ("foo") {}
+    else if (0) {}
+    if (req.http.X-Foo == req.http.x-foo) {}
+    if (req.url != (req.url)) {}
+    if (true) {}
+    if (req.http.x-foo) {}
+    if (req.http.x-foo == req.http.x-bar) {}
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| (error.range.start.line, error.message.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    2,
+                    "Condition is always true: the STRING literal «\"foo\"» is used as a BOOL"
+                ),
+                (
+                    3,
+                    "Condition is always false: the NUMBER literal «0» is used as a BOOL"
+                ),
+                (
+                    4,
+                    "«req.http.X-Foo» is compared to itself, so this is always true"
+                ),
+                (
+                    5,
+                    "«req.url» is compared to itself, so this is always false"
+                ),
+            ]
+        );
+        assert!(errors
+            .iter()
+            .all(|error| error.severity == Some(DiagnosticSeverity::WARNING)));
+    }
+
+    #[test]
+    fn sub_execution_order() {
+        let sub = |name: &str, calls: &[&str]| SubCalls {
+            name: name.to_string(),
+            loc: Location {
+                uri: Url::parse("file:///test.vcl").unwrap(),
+                range: Default::default(),
+            },
+            calls: calls
+                .iter()
+                .map(|call| {
+                    (
+                        call.to_string(),
+                        Location {
+                            uri: Url::parse("file:///test.vcl").unwrap(),
+                            range: Default::default(),
+                        },
+                    )
+                })
+                .collect(),
+        };
+        let all_sub_calls = vec![
+            sub("vcl_deliver", &["add_headers"]),
+            sub("vcl_recv", &["normalize"]),
+            sub("normalize", &["strip_cookies"]),
+            sub("add_headers", &["strip_cookies"]),
+            sub("unused", &[]),
+        ];
+        let order = get_sub_execution_order(&all_sub_calls);
+        assert!(order["vcl_recv"] < order["vcl_backend_fetch"]);
+        assert!(order["vcl_backend_fetch"] < order["vcl_deliver"]);
+        assert_eq!(order["normalize"], order["vcl_recv"]);
+        // the first builtin subroutine calling it
+        assert_eq!(order["strip_cookies"], order["vcl_recv"]);
+        assert_eq!(order["add_headers"], order["vcl_deliver"]);
+        assert!(!order.contains_key("unused"));
+    }
+
+    #[test]
+    fn header_assignments() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_deliver {
+    set resp.http.X-Foo = "deliver";
+}
+sub vcl_recv {
+    set req.http.x-foo = "recv";
+    if (req.http.X-Foo) {
+        unset req.http.X-Foo;
+    }
+}
+"#
+            .to_string(),
+            None,
+        );
+        assert_eq!(
+            doc.get_header_at_point(Point::new(6, 18)).as_deref(),
+            Some("req.http.x-foo")
+        );
+        assert_eq!(doc.get_header_at_point(Point::new(1, 6)), None);
+        let assignments = doc.get_header_assignments("req.http.x-foo");
+        assert_eq!(
+            assignments
+                .iter()
+                .map(|(sub_name, location)| (sub_name.as_str(), location.range.start.line))
+                .collect::<Vec<_>>(),
+            vec![("vcl_recv", 5), ("vcl_recv", 7)]
+        );
+        assert_eq!(doc.get_header_assignments("resp.http.x-foo").len(), 1);
+    }
+
+    #[test]
+    fn autocomplete_acls_after_ip_match() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"acl purgers { "127.0.0.1"; }
+backend default { .host = "127.0.0.1"; }
+sub vcl_recv {
+    if (client.ip ~ ) {}
+    if (req.url ~ ) {}
+}
+"#
+            .to_string(),
+            None,
+        );
+
+        let get_defs = || {
+            let mut defs = get_varnish_builtins();
+            let mut map: BTreeMap<String, Definition> = BTreeMap::from_iter(
+                doc.get_all_definitions(&defs)
+                    .into_iter()
+                    .map(|def| (def.ident_str.to_string(), def)),
+            );
+            defs.properties.append(&mut map);
+            defs
+        };
+
+        let result = doc
+            .autocomplete_for_pos(Position::new(3, 19), get_defs(), VarnishFlavor::default())
+            .unwrap();
+        let labels = result
+            .iter()
+            .map(|item| item.label.as_str())
+            .collect::<Vec<_>>();
+        println!("labels: {:?}", labels);
+        assert!(labels.contains(&"purgers"));
+        assert!(!labels.contains(&"default"), "backends are not acls");
+        assert!(!labels.contains(&"req"));
+
+        // regexes are string literals
+        assert!(doc
+            .autocomplete_for_pos(Position::new(4, 17), get_defs(), VarnishFlavor::default())
+            .is_none());
+    }
+
+    #[test]
+    fn autocomplete_variables_by_context() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_backend_response {
+    set beresp.http.x-url = re;
+    set beresp.http.x-host = be;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let mut result = doc
+            .autocomplete_for_pos(
+                Position::new(2, 29),
+                get_varnish_builtins(),
+                VarnishFlavor::default(),
+            )
+            .unwrap();
+        result.append(
+            &mut doc
+                .autocomplete_for_pos(
+                    Position::new(3, 30),
+                    get_varnish_builtins(),
+                    VarnishFlavor::default(),
+                )
+                .unwrap(),
+        );
+        let get_item = |label: &str| result.iter().find(|item| item.label == label).unwrap();
+        let description = |label: &str| {
+            get_item(label)
+                .label_details
+                .as_ref()
+                .and_then(|details| details.description.clone())
+        };
+
+        assert_eq!(
+            description("req"),
+            Some("not available in vcl_backend_response".to_string())
+        );
+        assert!(get_item("req").sort_text.as_ref().unwrap().starts_with('~'));
+        assert_eq!(description("resp"), description("req"));
+        assert_eq!(description("beresp"), Some("backend variable".to_string()));
+        assert_eq!(
+            description("bereq"),
+            Some("client/backend variable".to_string())
+        );
+        assert_eq!(get_item("beresp").sort_text, None);
+
+        // beresp.storage_hint is deprecated in varnish 6.0 and gone in varnish 7, whether the
+        // workspace or the document picks the flavor
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"sub vcl_backend_response {
+    if (beresp.) {}
+}
+"#
+            .to_string(),
+            None,
+        );
+        let storage_hint = |doc: &Document, line: u32, flavor: VarnishFlavor| {
+            doc.autocomplete_for_pos(Position::new(line, 15), get_varnish_builtins(), flavor)
+                .unwrap()
+                .into_iter()
+                .find(|item| item.label == "storage_hint")
+        };
+        let deprecated = storage_hint(&doc, 1, VarnishFlavor::Varnish60).unwrap();
+        assert_eq!(deprecated.tags, Some(vec![CompletionItemTag::DEPRECATED]));
+        assert_eq!(
+            deprecated.label_details.unwrap().description,
+            Some("deprecated, use beresp.storage".to_string())
+        );
+        assert!(storage_hint(&doc, 1, VarnishFlavor::Varnish7).is_none());
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"# varnish-lsp flavor: varnish-7
+sub vcl_backend_response {
+    if (beresp.) {}
+}
+"#
+            .to_string(),
+            None,
+        );
+        assert!(storage_hint(&doc, 2, VarnishFlavor::default()).is_none());
+        assert!(!varnish_builtins::is_variable_available_in_sub(
+            VarnishFlavor::Varnish7,
+            "beresp.storage_hint",
+            "vcl_backend_response"
+        ));
+    }
+
+    #[test]
+    fn headers_without_object() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set http.host = "example.com";
+}
+sub vcl_backend_response {
+    unset http.set-cookie;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].range.start.line, 2);
+        assert_eq!(
+            errors[0].message,
+            "«http.host» is missing the object it belongs to, e.g. «req.http.host»"
+        );
+        let data: DiagnosticData = serde_json::from_value(errors[0].data.clone().unwrap()).unwrap();
+        assert_eq!(data.replace_with, "req.http.host");
+        let data: DiagnosticData = serde_json::from_value(errors[1].data.clone().unwrap()).unwrap();
+        assert_eq!(data.replace_with, "beresp.http.set-cookie");
+    }
+
+    #[test]
+    fn regex_compared_as_string() {
+        assert!(looks_like_regex("^/api/"));
+        assert!(looks_like_regex("\\.(png|jpg)$"));
+        assert!(!looks_like_regex("/index.html"));
+        assert!(!looks_like_regex("US$"));
+
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    if (req.url == "^/api/.*") {}
+    if (req.url != "/static/.*") {}
+    if (req.url == "/index.html") {}
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].message,
+            "«==» compares strings exactly, but this looks like a regex (use «~»)"
+        );
+        let data: DiagnosticData = serde_json::from_value(errors[0].data.clone().unwrap()).unwrap();
+        assert_eq!(data.replace_with, r#"req.url ~ "^/api/.*""#);
+        assert!(data.alternatives.is_empty());
+
+        let data: DiagnosticData = serde_json::from_value(errors[1].data.clone().unwrap()).unwrap();
+        assert_eq!(data.replace_with, r#"req.url !~ "/static/.*""#);
+        assert_eq!(
+            data.alternatives,
+            vec![(
+                r#"Replace with req.url !~ "^/static/""#.to_string(),
+                r#"req.url !~ "^/static/""#.to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn comparison_operand_from_line() {
+        assert_eq!(
+            get_comparison_operand("    if (beresp.ttl > 1"),
+            Some(("beresp.ttl", ">"))
+        );
+        assert_eq!(
+            get_comparison_operand("if (req.http.host == \"www"),
+            Some(("req.http.host", "=="))
+        );
+        assert_eq!(
+            get_comparison_operand("if (client.ip ~ "),
+            Some(("client.ip", "~"))
+        );
+        assert_eq!(get_comparison_operand("if (req.url"), None);
+        assert!(is_in_condition("    if (req."));
+        assert!(is_in_condition("} else if (req.http.a && (req."));
+        assert!(!is_in_condition("    if (req.url) {"));
+        assert!(!is_in_condition("    set req.http.if = (1"));
+    }
+
+    #[test]
+    fn autocomplete_http_methods() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"sub vcl_recv {
+    if (req.method == "PU") {}
+    if (bereq.method != ) {}
+    if (req.url == "") {}
+}
+"#
+            .to_string(),
+            None,
+        );
+        let methods = vec!["GET".to_string(), "PUT".to_string()];
+
+        let completions = doc
+            .autocomplete_http_method(Position::new(1, 25), &methods)
+            .unwrap();
+        assert_eq!(completions.len(), 2);
+        let Some(CompletionTextEdit::Edit(ref edit)) = completions[1].text_edit else {
+            panic!("expected text edit");
+        };
+        assert_eq!(edit.new_text, "\"PUT\"");
+        assert_eq!(edit.range.start, Position::new(1, 22));
+        assert_eq!(edit.range.end, Position::new(1, 26));
+
+        let completions = doc
+            .autocomplete_http_method(Position::new(2, 24), &methods)
+            .unwrap();
+        let Some(CompletionTextEdit::Edit(ref edit)) = completions[0].text_edit else {
+            panic!("expected text edit");
+        };
+        assert_eq!(edit.range.start, Position::new(2, 24));
+        assert_eq!(edit.range.end, Position::new(2, 24));
+
+        assert!(doc
+            .autocomplete_http_method(Position::new(3, 20), &methods)
+            .is_none());
+    }
+
+    #[test]
+    fn lint_http_methods() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    if (req.method == "PURGE" || req.method == "BAN") {}
+    if (req.method == "get") {}
+    if (req.method == "REPORT") {}
+    if (req.method == "GET ") {}
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].range.start.line, 3);
+        assert_eq!(errors[0].severity, Some(DiagnosticSeverity::HINT));
+        assert_eq!(errors[1].range.start.line, 4);
+        assert_eq!(errors[1].severity, Some(DiagnosticSeverity::HINT));
+        assert_eq!(errors[2].range.start.line, 5);
+        assert_eq!(errors[2].severity, Some(DiagnosticSeverity::WARNING));
+    }
+
+    #[test]
+    fn all_elsif_spellings_are_chained_conditionals() {
+        for keyword in [
+            "else if",
+            "else\n    if",
+            "else /* c */ if",
+            "elsif",
+            "elseif",
+            "elif",
+        ] {
+            for trailing_else in ["", " else {\n        set req.http.c = \"1\";\n    }"] {
+                let doc = Document::new(
+                    Url::parse("file:///test.vcl").unwrap(),
+                    format!(
+                        r#"
+sub vcl_recv {{
+    if (req.http.a) {{
+        set req.http.a = "1";
+    }} {keyword} (req.http.b) {{
+        set req.http.b = "1";
+    }}{trailing_else}
+}}
+"#
+                    ),
+                    None,
+                );
+                let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+                println!("{keyword:?} {trailing_else:?} errors: {:?}", errors);
+
+                let if_stmt = doc
+                    .ast
+                    .root_node()
+                    .descendant_for_point_range(Point::new(2, 4), Point::new(2, 6))
+                    .and_then(|node| find_parent(node, "if_stmt".into()))
+                    .unwrap();
+                let mut cursor = if_stmt.walk();
+                let kinds = if_stmt
+                    .named_children(&mut cursor)
+                    .map(|child| child.kind())
+                    .collect::<Vec<_>>();
+                assert!(kinds.contains(&"elsif_stmt"), "{keyword:?} is not chained");
+                assert_eq!(kinds.contains(&"else_stmt"), !trailing_else.is_empty());
+
+                let expected_hints = if keyword.starts_with("else") && keyword.ends_with("if") {
+                    0
+                } else {
+                    1
+                };
+                assert_eq!(errors.len(), expected_hints, "{keyword:?}");
+                if let Some(hint) = errors.first() {
+                    assert_eq!(hint.severity, Some(DiagnosticSeverity::HINT));
+                    assert_eq!(hint.message, "Prefer «else if»");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn extract_statements_to_subroutine() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"sub extracted_1 {}
+
+sub vcl_recv {
+    set req.http.a = "1";
+    if (req.http.b) {
+        set req.http.c = "1";
+    }
+    return (hash);
+}
+"#
+            .to_string(),
+            None,
+        );
+
+        // selection starts mid-statement and ends inside the if, so it snaps to whole statements
+        let range = Range {
+            start: Position {
+                line: 3,
+                character: 10,
+            },
+            end: Position {
+                line: 5,
+                character: 12,
+            },
+        };
+        let (name, edits) = doc
+            .extract_subroutine(range, &get_varnish_builtins())
+            .unwrap();
+        assert_eq!(name, "extracted_2");
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].new_text, "call extracted_2;");
+        assert_eq!(edits[0].range.start, Position::new(3, 4));
+        assert_eq!(edits[0].range.end, Position::new(6, 5));
+        assert_eq!(
+            edits[1].new_text,
+            "\n\nsub extracted_2 {\n    set req.http.a = \"1\";\n    if (req.http.b) {\n        set req.http.c = \"1\";\n    }\n}"
+        );
+        assert_eq!(edits[1].range.start, Position::new(8, 1));
+    }
+
+    #[test]
+    fn extract_inline_probe() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"probe generated_1 {
+    .url = "/";
+}
+
+backend default {
+    .host = "127.0.0.1";
+    .probe = {
+        .request =
+            "GET /healthcheck HTTP/1.1"
+            "Host: example.com";
+        .expected_response = {"multi
+  line"};
+        .interval = 5s;
+    }
+}
+"#
+            .to_string(),
+            None,
+        );
+
+        // outside of the inline probe
+        assert!(!doc.has_inline_probe_at(Position::new(5, 6)));
+        assert!(doc
+            .extract_inline_probe(Position::new(5, 6), &get_varnish_builtins())
+            .is_none());
+
+        assert!(doc.has_inline_probe_at(Position::new(12, 10)));
+
+        let (name, edits) = doc
+            .extract_inline_probe(Position::new(12, 10), &get_varnish_builtins())
+            .unwrap();
+        assert_eq!(name, "generated_2");
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].range.start, Position::new(4, 0));
+        assert_eq!(
+            edits[0].new_text,
+            r#"probe generated_2 {
+    .request =
+        "GET /healthcheck HTTP/1.1"
+        "Host: example.com";
+    .expected_response = {"multi
+  line"};
+    .interval = 5s;
+}
+
+"#
+        );
+        assert_eq!(edits[1].new_text, "generated_2;");
+        assert_eq!(edits[1].range.start, Position::new(6, 13));
+        assert_eq!(edits[1].range.end, Position::new(13, 5));
+    }
+
+    #[test]
+    fn semantic_tokens_can_be_cancelled() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.http.x-test = "test";
+}
+"#
+            .to_string(),
+            None,
+        );
+        assert!(doc
+            .get_semantic_tokens_cancellable(&AtomicBool::new(true))
+            .is_none());
+        assert_eq!(
+            doc.get_semantic_tokens_cancellable(&AtomicBool::new(false)),
+            Some(doc.get_semantic_tokens())
+        );
+    }
+
+    #[test]
+    fn semantic_tokens_delta_after_editing_a_line() {
+        let mut doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.http.x-a = "a";
+    set req.http.x-b = "b";
+    set req.http.x-c = "c";
+}
+"#
+            .to_string(),
+            None,
+        );
+        let previous = doc.get_semantic_tokens();
+        assert!(get_semantic_tokens_edits(&previous, &previous).is_empty());
+
+        assert!(doc.edit(
+            1,
+            vec![(
+                Some(Range::new(Position::new(3, 4), Position::new(3, 27))),
+                "unset req.http.x-b;".to_string()
+            )]
+            .into_iter()
+        ));
+        let tokens = doc.get_semantic_tokens();
+        let edits = get_semantic_tokens_edits(&previous, &tokens);
+        assert_eq!(edits.len(), 1);
+        // the tokens of the lines before the edit are kept
+        assert!(edits[0].start > 0);
+        assert!(edits[0].data.as_ref().unwrap().len() < tokens.len());
+
+        // applying the edit to the previous tokens gives the new ones
+        let mut patched = previous.clone();
+        let start = edits[0].start as usize / 5;
+        let end = start + edits[0].delete_count as usize / 5;
+        patched.splice(start..end, edits[0].data.clone().unwrap());
+        assert_eq!(patched, tokens);
+    }
+
+    #[test]
+    fn edits_are_applied_in_version_order() {
+        let uri = Url::parse("file:///test.vcl").unwrap();
+        let document_map: Arc<DashMap<Url, Document>> = Arc::new(DashMap::new());
+        document_map.insert(
+            uri.clone(),
+            Document::new(uri.clone(), "sub vcl_recv {}\n".to_string(), None).with_version(1),
+        );
+        // the text of each version is the initial text with a statement per version
+        let text_of_version = |version: i32| {
+            let mut text = "sub vcl_recv {".to_string();
+            for _ in 2..=version {
+                text.push_str(" set req.http.x = \"1\";");
+            }
+            text.push_str("}\n");
+            text
+        };
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers = (0..4)
+            .map(|_| {
+                let document_map = document_map.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    while !done.load(AtomicOrdering::Relaxed) {
+                        let doc = document_map.get(&Url::parse("file:///test.vcl").unwrap());
+                        let doc = doc.unwrap();
+                        // the buffer and the tree always belong to the same version
+                        assert_eq!(doc.rope.to_string(), text_of_version(doc.version()));
+                        assert_eq!(doc.ast.root_node().end_byte(), doc.rope.len_bytes());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for version in 2..=50 {
+            let mut doc = document_map.get_mut(&uri).unwrap();
+            let insert_at = Position::new(0, "sub vcl_recv {".len() as u32);
+            // insert the statement in two changes, which must never be observed separately
+            let changes = vec![
+                (Some(Range::new(insert_at, insert_at)), "\";".to_string()),
+                (
+                    Some(Range::new(insert_at, insert_at)),
+                    " set req.http.x = \"1".to_string(),
+                ),
+            ];
+            assert!(doc.edit(version, changes.into_iter()));
+            // a change arriving late for an older version is ignored
+            assert!(!doc.edit(version - 1, vec![(None, "garbage".to_string())].into_iter()));
+        }
+        done.store(true, AtomicOrdering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let doc = document_map.get(&uri).unwrap();
+        assert_eq!(doc.version(), 50);
+        assert_eq!(doc.rope.to_string(), text_of_version(50));
+        assert!(!doc.ast.root_node().has_error());
+    }
+
+    #[test]
+    fn edits_arriving_early_wait_for_the_previous_version() {
+        let uri = Url::parse("file:///test.vcl").unwrap();
+        let mut doc = Document::new(uri, "sub vcl_recv {}\n".to_string(), None).with_version(1);
+        let insert_at = Position::new(0, "sub vcl_recv {".len() as u32);
+        let v2_changes = vec![(
+            Some(Range::new(insert_at, insert_at)),
+            " set req.http.x = \"2\"; ".to_string(),
+        )];
+        // inserted after the statement of version 2
+        let v3_insert_at = Position::new(0, "sub vcl_recv { set req.http.x = \"2\";".len() as u32);
+        let v3_changes = vec![(
+            Some(Range::new(v3_insert_at, v3_insert_at)),
+            " set req.http.y = \"3\";".to_string(),
+        )];
+
+        assert!(!doc.edit(3, v3_changes.into_iter()));
+        assert_eq!(doc.version(), 1);
+        assert_eq!(doc.rope.to_string(), "sub vcl_recv {}\n");
+
+        assert!(doc.edit(2, v2_changes.into_iter()));
+        assert_eq!(doc.version(), 3);
+        assert_eq!(
+            doc.rope.to_string(),
+            "sub vcl_recv { set req.http.x = \"2\"; set req.http.y = \"3\"; }\n"
+        );
+        assert!(!doc.ast.root_node().has_error());
+
+        // a full text doesn't depend on the versions before it
+        assert!(doc.edit(
+            6,
+            vec![(None, "sub vcl_deliver {}\n".to_string())].into_iter()
+        ));
+        assert_eq!(doc.version(), 6);
+        assert_eq!(doc.rope.to_string(), "sub vcl_deliver {}\n");
+    }
+
+    #[test]
+    fn line_breaks_are_the_lsp_ones() {
+        // LF, CRLF and CR, but not e.g. U+2028 (which the rope counts by default)
+        let rope = Rope::from("a\u{2028}b\rc\r\nd\ne");
+        assert_eq!(rope.len_lines(), 4);
+        assert_eq!(rope.line(1).to_string(), "c\r\n");
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let uri = Url::parse("file:///test.vcl").unwrap();
+        let mut doc = Document::new(
+            uri,
+            "vcl 4.1;\r\n\r\nsub vcl_recv {\r\n    if (req.url == req.url) {}\r\n}\r\n".to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].range,
+            Range::new(Position::new(3, 8), Position::new(3, 26))
+        );
+
+        assert!(doc.edit(
+            1,
+            vec![(
+                Some(Range::new(Position::new(3, 8), Position::new(3, 26))),
+                "req.http.x".to_string()
+            )]
+            .into_iter()
+        ));
+        assert_eq!(
+            doc.rope.to_string(),
+            "vcl 4.1;\r\n\r\nsub vcl_recv {\r\n    if (req.http.x) {}\r\n}\r\n"
+        );
+        assert!(!doc.ast.root_node().has_error());
+        assert!(doc
+            .diagnostics(get_varnish_builtins(), &Default::default())
+            .is_empty());
+
+        // the formatter keeps the line endings
+        assert!(doc.edit(
+            2,
+            vec![(
+                Some(Range::new(Position::new(3, 0), Position::new(3, 4))),
+                "  ".to_string()
+            )]
+            .into_iter()
+        ));
+        let options = FormattingOptions {
+            tab_size: 4,
+            insert_spaces: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            doc.format(&options, &Default::default()).as_deref(),
+            Some("vcl 4.1;\r\n\r\nsub vcl_recv {\r\n    if (req.http.x) {}\r\n}\r\n")
+        );
+    }
+
+    #[test]
+    fn lone_crs_are_parsed_as_lf() {
+        assert_eq!(&*lone_crs_as_lf(b"a\r\nb\rc\n", None), b"a\r\nb\nc\n");
+        assert!(matches!(
+            lone_crs_as_lf(b"a\r\nb\n", None),
+            Cow::Borrowed(_)
+        ));
+        // a chunk ending with the CR of a CRLF
+        assert_eq!(&*lone_crs_as_lf(b"a\r", Some(b'\n')), b"a\r");
+        assert_eq!(&*lone_crs_as_lf(b"a\r", Some(b'b')), b"a\n");
+    }
+
+    #[test]
+    fn cr_line_endings() {
+        let uri = Url::parse("file:///test.vcl").unwrap();
+        let mut doc = Document::new(
+            uri,
+            "vcl 4.1;\r# comment\rsub vcl_recv {\r    if (req.url == req.url) {}\r}\r".to_string(),
+            None,
+        );
+        assert!(!doc.ast.root_node().has_error());
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].range,
+            Range::new(Position::new(3, 8), Position::new(3, 26))
+        );
+
+        assert!(doc.edit(
+            1,
+            vec![(
+                Some(Range::new(Position::new(3, 8), Position::new(3, 26))),
+                "req.http.x".to_string()
+            )]
+            .into_iter()
+        ));
+        assert_eq!(
+            doc.rope.to_string(),
+            "vcl 4.1;\r# comment\rsub vcl_recv {\r    if (req.http.x) {}\r}\r"
+        );
+        assert!(!doc.ast.root_node().has_error());
+        assert_eq!(doc.ast.root_node().end_position().row, 5);
+        assert!(doc
+            .diagnostics(get_varnish_builtins(), &Default::default())
+            .is_empty());
+    }
+
+    #[test]
+    fn stale_diagnostics_are_not_published() {
+        let versions = PublishedDiagnosticsVersions::default();
+        let uri = Url::parse("file:///test.vcl").unwrap();
+        let other_uri = Url::parse("file:///other.vcl").unwrap();
+
+        // analysis of version 2 finishes before the analysis of version 1
+        assert!(versions.should_publish(&uri, 2));
+        assert!(!versions.should_publish(&uri, 1));
+        // republishing the same version (e.g. after an include changed) is fine
+        assert!(versions.should_publish(&uri, 2));
+        assert!(versions.should_publish(&uri, 3));
+        assert!(versions.should_publish(&other_uri, 1));
+
+        // versions start over when the document is reopened
+        versions.remove(&uri);
+        assert!(versions.should_publish(&uri, 1));
+    }
+
+    #[test]
+    fn unchanged_diagnostics_are_not_published_again() {
+        let published = LastPublishedDiagnostics::default();
+        let uri = Url::parse("file:///test.vcl").unwrap();
+        let diagnostics = vec![Diagnostic {
+            message: "Reserved keyword".to_string(),
+            ..Default::default()
+        }];
+
+        assert!(published.should_publish(&uri, Some(1), &diagnostics));
+        // e.g. analyzed again after the vmods are reloaded
+        assert!(!published.should_publish(&uri, Some(1), &diagnostics));
+        assert!(published.should_publish(&uri, Some(1), &[]));
+        assert!(!published.should_publish(&uri, Some(1), &[]));
+        // a new version is always published, for the client to know the diagnostics are current
+        assert!(published.should_publish(&uri, Some(2), &[]));
+
+        // the client clears the diagnostics when the document is reopened
+        published.remove(&uri);
+        assert!(published.should_publish(&uri, Some(2), &[]));
+    }
+
+    #[test]
+    fn duration_properties_need_units() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+backend default {
+    .host = "127.0.0.1";
+    .connect_timeout = 0s;
+    .first_byte_timeout = 60;
+    .between_bytes_timeout = 1m;
+    .probe = {
+        .url = "/";
+        .timeout = 0.0ms;
+    }
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].range.start.line, 3);
+        assert_eq!(errors[0].severity, Some(DiagnosticSeverity::WARNING));
+        assert!(errors[0].message.contains("«3.5s»"));
+        assert_eq!(errors[1].range.start.line, 4);
+        assert_eq!(errors[1].severity, Some(DiagnosticSeverity::ERROR));
+        assert_eq!(errors[2].range.start.line, 8);
+        assert_eq!(errors[2].severity, Some(DiagnosticSeverity::WARNING));
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(varnish_builtins::parse_duration("3.5s"), Some(3.5));
+        assert_eq!(varnish_builtins::parse_duration("2m"), Some(120.0));
+        assert_eq!(varnish_builtins::parse_duration("100ms"), Some(0.1));
+        assert_eq!(varnish_builtins::parse_duration("0s"), Some(0.0));
+        assert_eq!(varnish_builtins::parse_duration("5"), None);
+        assert_eq!(varnish_builtins::parse_duration("5q"), None);
+    }
+
+    #[test]
+    fn regsub_backreferences() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.url = regsub(req.url, "^/(\w+)/(\d+)", "/\2/\1");
+    set req.url = regsuball(req.url, "^/(\w+)", "/\2\\3");
+    set req.url = regsub(req.url, "^/(\w+)/(\d+)", "/");
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].range.start.line, 3);
+        assert!(errors[0].message.starts_with("Backreference \\2"));
+
+        let completions = doc
+            .autocomplete_regsub_backreference(Position::new(4, 53))
+            .unwrap();
+        let labels = completions
+            .iter()
+            .map(|item| item.label.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec!["\\0", "\\1", "\\2"]);
+
+        // not the pattern argument
+        assert!(doc
+            .autocomplete_regsub_backreference(Position::new(4, 37))
+            .is_none());
+    }
+
+    #[test]
+    fn backreferences_in_replacement() {
+        assert_eq!(get_backreferences(r"/\2/\1"), vec![2, 1]);
+        assert_eq!(get_backreferences(r"\\3\0"), vec![0]);
+        assert_eq!(unquote_string(r#"{"a"}"#), "a");
+        assert_eq!(unquote_string(r#""""a""""#), "a");
+        assert_eq!(unquote_string(r#""a""#), "a");
+    }
+
+    #[test]
+    fn hover_vcl_version() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            "vcl 4.1;\n\nsub vcl_recv {}\n".to_string(),
+            None,
+        );
+        let (value, range) = doc.get_vcl_version_hover(Point::new(0, 5)).unwrap();
+        assert!(value.starts_with("**VCL 4.1**"));
+        assert!(value.contains("`.resolve`"));
+        assert_eq!(range, Range::new(Position::new(0, 0), Position::new(0, 8)));
+        assert!(doc.get_vcl_version_hover(Point::new(2, 5)).is_none());
+    }
+
+    #[test]
+    fn probes_without_url_or_request() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+probe healthcheck {
+    .interval = 5s;
+}
+
+backend default {
+    .host = "127.0.0.1";
+    .probe = { .timeout = 2s; }
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].range.start, Position::new(1, 18));
+        assert_eq!(errors[0].severity, Some(DiagnosticSeverity::WARNING));
+        assert!(errors[0].message.contains("GET /"));
+        assert_eq!(errors[1].range.start, Position::new(7, 13));
+    }
+
+    #[test]
+    fn inline_probes_are_validated() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+backend default {
+    .host = "127.0.0.1";
+    .probe = {
+        .request =
+            "GET /healthcheck HTTP/1.1"
+            "Host: example.com"
+            "Connection: close";
+        .interval = 5s;
+        .window = 5;
+        .threshold = 6;
+    }
+}
+
+backend other {
+    .host = "127.0.0.2";
+    .probe = {
+        .url = "/";
+        .request = "GET / HTTP/1.1";
+    }
+}
+
+sub vcl_recv {
+    set req.backend_hint = default;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].range.start.line, 10);
+        assert!(errors[0].message.starts_with("Probe .threshold (6)"));
+        assert_eq!(errors[1].range.start.line, 18);
+        assert_eq!(
+            errors[1].message,
+            "Probe .url and .request are mutually exclusive"
+        );
+
+        let result = doc
+            .get_definition_by_point(Point {
+                row: 23,
+                column: 29,
+            })
+            .unwrap();
+        assert_eq!(result.0.row, 1);
+    }
+
+    #[test]
+    fn parse_tree_marks_errors() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.http.x-test = ;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let tree = doc.get_parse_tree();
+        println!("{tree}");
+        assert!(tree.starts_with("(source_file [0:0 - 4:0]"));
+        assert!(tree.contains("ident: (ident [1:4 - 1:12])"));
+        assert!(tree.contains("<-- ERROR") || tree.contains("<-- MISSING"));
+    }
+
+    #[test]
+    fn empty_builtin_subs() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    # TODO
+}
+
+sub vcl_deliver {
+    set resp.http.x-served-by = "varnish";
+}
+
+sub placeholder {}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].range.start.line, 1);
+        assert_eq!(errors[0].severity, Some(DiagnosticSeverity::HINT));
+    }
+
+    #[test]
+    fn redundant_header_writes() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.http.x-redundant = "1";
+    unset req.http.x-redundant;
+
+    set req.http.x-used = "1";
+    set req.http.x-other = req.http.x-used;
+    unset req.http.x-used;
+
+    unset req.http.x-twice;
+    unset req.http.x-twice;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].range.start.line, 2);
+        assert_eq!(errors[0].severity, Some(DiagnosticSeverity::HINT));
+        assert_eq!(errors[1].range.start.line, 10);
+        assert_eq!(
+            errors[1].message,
+            "Redundant unset, req.http.x-twice is already unset"
+        );
+    }
+
+    #[test]
+    fn lists_all_includes() {
+        let doc = Document::new(
+            Url::parse("file:///etc/varnish/vg/varnish.vcl").unwrap(),
+            r#"
+include "config/acl.vcl";
+sub vcl_recv {
+    if (req.url == "/health") {
+        include "config/healthcheck.vcl";
+    }
+
+    if (req.http.host == "www.vg.no") {
+        include "./www_vg_no_routing.vcl";
+    }
+}
+"#
+            .to_string(),
+            None,
+        );
+        let includes = doc.get_includes();
+        println!("includes: {:?}", includes);
+        assert_eq!(
+            includes
+                .iter()
+                .map(|inc| inc.path.to_string_lossy())
+                .collect::<Vec<_>>(),
+            vec![
+                "config/acl.vcl",
+                "config/healthcheck.vcl",
+                "./www_vg_no_routing.vcl",
+            ]
+        );
+
+        /*
+        let includes = includes
+            .into_iter()
+            .map(|include| include.resolve(&vec!["/etc/varnish/".into()].into()))
+            .collect::<Vec<_>>();
+        */
+
+        assert_eq!(
+            includes
+                .iter()
+                .map(|inc| inc.url.as_ref().map(|url| url.to_string()))
+                .collect::<Vec<_>>(),
+            vec![
+                None, // Some("file:///etc/varnish/acl.vcl".to_string()),
+                None, // Some("file:///etc/varnish/healthcheck.vcl".to_string()),
+                Some("file:///etc/varnish/vg/www_vg_no_routing.vcl".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn include_locations_point_at_path_string() {
+        let doc = Document::new(
+            Url::parse("file:///etc/varnish/main.vcl").unwrap(),
+            r#"include "backends.vcl";
+sub vcl_recv {
+    include "./routing.vcl";
+}
+"#
+            .to_string(),
+            None,
+        );
+        let ranges = doc
+            .get_include_locations()
+            .into_iter()
+            .map(|(_include, range)| range)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![
+                Range::new(Position::new(0, 8), Position::new(0, 22)),
+                Range::new(Position::new(2, 12), Position::new(2, 27)),
+            ]
+        );
+    }
+
+    #[test]
+    fn list_all_new_objs() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_init {
+    new static_web = directors.round_robin();
+}
+"#
+            .to_string(),
+            None,
+        );
+        let result = doc.get_all_definitions(&Definitions {
+            properties: BTreeMap::from([(
+                "directors".to_string(),
+                Definition {
+                    ident_str: "directors".to_string(),
+                    r#type: Box::new(Type::Obj(Obj {
+                        name: "mock_directors".to_string(),
+                        properties: BTreeMap::from([(
+                            "round_robin".to_string(),
+                            Type::Func(Func {
+                                r#return: Some(Box::new(Type::Obj(Obj {
+                                    name: "mock_round_robin_director".to_string(),
+                                    properties: BTreeMap::from([(
+                                        "backend".to_string(),
+                                        Type::Func(Default::default()),
+                                    )]),
+                                    ..Default::default()
+                                }))),
+                                ..Default::default()
+                            }),
+                        )]),
+                        ..Default::default()
+                    })),
+                    loc: None,
+                    nested_pos: Default::default(),
+                },
+            )]),
+        });
+        println!("result: {:?}", result);
+        assert_eq!(result.len(), 2);
+    }
+
+    #[test]
+    fn autocomplete_vmod_object_methods_with_provenance() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_init {
+    new rr = directors.round_robin();
+}
+sub vcl_recv {
+    if (rr.) {}
+}
+"#
+            .to_string(),
+            None,
+        );
+        let mut defs = get_varnish_builtins();
+        defs.properties.insert(
+            "directors".to_string(),
+            Definition::new_builtin(
+                "directors".to_string(),
+                Type::Obj(Obj {
+                    name: "directors".to_string(),
+                    properties: BTreeMap::from([(
+                        "round_robin".to_string(),
+                        Type::Func(Func {
+                            name: "round_robin".to_string(),
+                            r#return: Some(Box::new(Type::Obj(Obj {
+                                name: "round_robin".to_string(),
+                                properties: BTreeMap::from([(
+                                    "backend".to_string(),
+                                    Type::Func(Func {
+                                        name: "backend".to_string(),
+                                        ret_type: Some("BACKEND".to_string()),
+                                        doc: Some("Pick a backend".to_string()),
+                                        ..Default::default()
+                                    }),
+                                )]),
+                                ..Default::default()
+                            }))),
+                            ..Default::default()
+                        }),
+                    )]),
+                    ..Default::default()
+                }),
+            ),
+        );
+        let mut map: BTreeMap<String, Definition> = BTreeMap::from_iter(
+            doc.get_all_definitions(&defs)
+                .into_iter()
+                .map(|def| (def.ident_str.to_string(), def)),
+        );
+        defs.properties.append(&mut map);
+
+        let result = doc
+            .autocomplete_for_pos(Position::new(5, 11), defs, VarnishFlavor::default())
+            .unwrap();
+        let backend_item = result.iter().find(|item| item.label == "backend").unwrap();
+        assert_eq!(
+            backend_item.detail.as_deref(),
+            Some("BACKEND backend() (method of directors.round_robin)")
+        );
+        let Some(Documentation::MarkupContent(ref documentation)) = backend_item.documentation
+        else {
+            panic!("expected documentation");
+        };
+        assert!(documentation
+            .value
+            .starts_with("Method of `directors.round_robin`"));
+    }
+
+    #[test]
+    fn monikers() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+backend default {
+    .host = "localhost";
+}
+
+sub set_backend {
+    set req.backend_hint = default;
+}
+
+sub vcl_recv {
+    call set_backend;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let mut scope = get_varnish_builtins();
+        let mut doc_defs = BTreeMap::from_iter(
+            doc.get_all_definitions(&scope)
+                .into_iter()
+                .map(|def| (def.ident_str.to_string(), def)),
+        );
+        scope.properties.append(&mut doc_defs);
+
+        let moniker = doc.get_moniker(Point::new(1, 10), &scope).unwrap();
+        assert_eq!(moniker.scheme, "varnish-lsp");
+        assert_eq!(moniker.identifier, "backend/default");
+        assert_eq!(moniker.kind, Some(MonikerKind::Export));
+
+        let moniker = doc.get_moniker(Point::new(6, 28), &scope).unwrap();
+        assert_eq!(moniker.identifier, "backend/default");
+        assert_eq!(moniker.kind, Some(MonikerKind::Import));
+
+        let moniker = doc.get_moniker(Point::new(10, 10), &scope).unwrap();
+        assert_eq!(moniker.identifier, "sub/set_backend");
+        assert_eq!(moniker.kind, Some(MonikerKind::Import));
+
+        // builtin variables aren't declared in vcl
+        assert!(doc.get_moniker(Point::new(6, 10), &scope).is_none());
+    }
+
+    #[test]
+    fn bool_assignments() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.hash_ignore_busy = "false";
+    set req.hash_always_miss = req.http.x-miss;
+    set req.hash_always_miss = true;
+    set req.http.x-miss = true;
+    set req.http.x-miss = "true";
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.get_error_ranges(&get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].loc.range.start, Position::new(2, 31));
+        assert_eq!(
+            errors[0].message,
+            "Expected BOOL, found STRING. Any string is true, even «\"false\"»"
+        );
+        assert_eq!(
+            errors[1].message,
+            "Expected BOOL, found STRING. The string is true if it is set"
+        );
+        assert_eq!(errors[2].loc.range.start, Position::new(5, 26));
+        assert!(errors[2].message.starts_with("Expected STRING, found BOOL"));
+    }
+
+    #[test]
+    fn call_chains() {
+        assert_eq!(
+            get_call_chain("    set req.http.x = a.b(1).c().d"),
+            Some((vec!["a.b", "c"], "d"))
+        );
+        assert_eq!(
+            get_call_chain("if (a.b(f(1), \"x\")."),
+            Some((vec!["a.b"], ""))
+        );
+        assert_eq!(get_call_chain("if (a.b."), None);
+        assert_eq!(get_call_chain("if ((a)."), None);
+    }
+
+    #[test]
+    fn autocomplete_object_method_chains() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    if (example.builder().child().) {}
+    if (example.builder().nothing().) {}
+}
+"#
+            .to_string(),
+            None,
+        );
+        let child = Type::Obj(Obj {
+            name: "child".to_string(),
+            vmod_name: Some("example".to_string()),
+            properties: BTreeMap::from([(
+                "leaf".to_string(),
+                Type::Func(Func {
+                    name: "leaf".to_string(),
+                    r#return: Some(Box::new(Type::Bool)),
+                    ..Default::default()
+                }),
+            )]),
+            ..Default::default()
+        });
+        let builder = Type::Obj(Obj {
+            name: "builder".to_string(),
+            vmod_name: Some("example".to_string()),
+            properties: BTreeMap::from([
+                (
+                    "child".to_string(),
+                    Type::Func(Func {
+                        name: "child".to_string(),
+                        r#return: Some(Box::new(child)),
+                        ..Default::default()
                     }),
-                    _ => None,
-                },
-                ..Default::default()
-            })
-            .collect::<Vec<_>>();
+                ),
+                (
+                    "nothing".to_string(),
+                    Type::Func(Func {
+                        name: "nothing".to_string(),
+                        r#return: None,
+                        ..Default::default()
+                    }),
+                ),
+            ]),
+            ..Default::default()
+        });
+        let get_defs = || {
+            let mut defs = get_varnish_builtins();
+            defs.properties.insert(
+                "example".to_string(),
+                Definition::new_builtin(
+                    "example".to_string(),
+                    Type::Obj(Obj {
+                        name: "example".to_string(),
+                        properties: BTreeMap::from([(
+                            "builder".to_string(),
+                            Type::Func(Func {
+                                name: "builder".to_string(),
+                                r#return: Some(Box::new(builder.clone())),
+                                ..Default::default()
+                            }),
+                        )]),
+                        ..Default::default()
+                    }),
+                ),
+            );
+            defs
+        };
 
-        suggestions.append(&mut keyword_suggestions);
+        let result = doc
+            .autocomplete_for_pos(Position::new(2, 34), get_defs(), VarnishFlavor::default())
+            .unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].label, "leaf");
+        assert_eq!(
+            result[0].detail.as_deref(),
+            Some("BOOL leaf() (method of example.child)")
+        );
 
-        Some(suggestions)
+        // nothing() returns nothing, so there is nothing to complete
+        assert!(doc
+            .autocomplete_for_pos(Position::new(3, 36), get_defs(), VarnishFlavor::default())
+            .is_none());
     }
 
-    pub fn get_semantic_tokens(&self) -> Vec<SemanticToken> {
-        let node = self.ast.root_node();
-        let mut query_str = VCL_QUERY.to_string();
-        if matches!(self.filetype, FileType::Vtc) {
-            query_str.push_str(VTC_QUERY);
-        }
-        let q = Query::new(&self.ast.language(), query_str.as_str()).unwrap();
-        let mut qc = QueryCursor::new();
+    #[test]
+    fn vmod_restrictions_are_checked() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_pass {
+    example.client_only();
+}
+sub vcl_backend_fetch {
+    example.client_only();
+}
+"#
+            .to_string(),
+            None,
+        );
+        let mut defs = get_varnish_builtins();
+        let scope = crate::vmod::parse_vmod_json(
+            r#"[
+                ["$VMOD", "1.0", "example", "Vmod_example_Func", "0", "", "7", "1"],
+                ["$FUNC", "client_only", [["VOID"], "Vmod_example_Func.f_client_only", ""]],
+                ["$RESTRICT", ["client"]]
+            ]"#,
+        )
+        .unwrap();
+        defs.properties.insert(
+            "example".to_string(),
+            Definition::new_builtin("example".to_string(), scope),
+        );
 
-        let names = q.capture_names();
+        let errors = doc.diagnostics(defs, &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].range.start.line, 5);
+        assert_eq!(errors[0].message, "Cannot be called from vcl_backend_fetch");
+    }
 
-        #[derive(Debug, Eq, PartialEq, PartialOrd, Ord)]
-        struct Token {
-            line: usize,
-            start: usize,
-            token_type: usize, // LEGEND_TYPES is ordered, so we can order by this for precendence
-            modifier_bitset: usize,
-            length: usize,
-        }
+    #[test]
+    fn restriction_hover() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    example.client_only();
+}
+sub vcl_backend_fetch {
+    example.client_only();
+}
+"#
+            .to_string(),
+            None,
+        );
+        let scope = crate::vmod::parse_vmod_json(
+            r#"[
+                ["$VMOD", "1.0", "example", "Vmod_example_Func", "0", "", "7", "1"],
+                ["$FUNC", "client_only", [["VOID"], "Vmod_example_Func.f_client_only", ""]],
+                ["$RESTRICT", ["client", "vcl_init"]],
+                ["$FUNC", "anywhere", [["VOID"], "Vmod_example_Func.f_anywhere", ""]]
+            ]"#,
+        )
+        .unwrap();
+        let Type::Obj(obj) = scope else {
+            panic!("vmod scope should be an obj");
+        };
+        let Some(Type::Func(client_only)) = obj.properties.get("client_only") else {
+            panic!("client_only should be a func");
+        };
+        let Some(Type::Func(anywhere)) = obj.properties.get("anywhere") else {
+            panic!("anywhere should be a func");
+        };
 
-        let mut tokens = vec![];
-        let mut captures = qc
-            .matches(&q, node, self)
-            .flat_map(|m| convert_to_streaming_iterator(m.captures));
-        let mut prev_node: Option<Node> = None;
-        while let Some(c) = captures.next() {
-            let node = c.node;
-            let range = node.range();
-            let mut capture_name = names.get(c.index as usize).unwrap().splitn(2, '.');
-            let name = capture_name.next().unwrap();
-            let modifier_name = capture_name.next();
-            let Some(token_type) = LEGEND_TYPES.iter().position(|s| s.as_str() == name) else {
-                continue;
-            };
-            let modifier = modifier_name.and_then(|modifier_name| {
-                LEGEND_MODIFIERS
-                    .iter()
-                    .position(|s| s.as_str() == modifier_name)
-            });
+        let sub_name = doc.get_sub_name_at_point(Point::new(2, 14));
+        assert_eq!(sub_name.as_deref(), Some("vcl_recv"));
+        assert_eq!(
+            client_only.get_restriction_hover(sub_name.as_deref()),
+            Some("Valid in: client, vcl_init".to_string())
+        );
+        let sub_name = doc.get_sub_name_at_point(Point::new(5, 14));
+        assert_eq!(
+            client_only.get_restriction_hover(sub_name.as_deref()),
+            Some("Valid in: client, vcl_init\n\n**Not valid in vcl_backend_fetch**".to_string())
+        );
+        assert_eq!(doc.get_sub_name_at_point(Point::new(0, 0)), None);
+        assert_eq!(anywhere.get_restriction_hover(Some("vcl_recv")), None);
+    }
 
-            let mut modifier_bitset = 0;
-            if let Some(modifier) = modifier {
-                modifier_bitset |= 1 << modifier;
-            }
+    #[test]
+    fn missing_required_arguments() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.http.a = std.integer(req.http.x);
+    set req.http.b = std.integer(req.http.x, 0);
+    set req.http.c = std.integer(fallback = 0, s = req.http.x);
+    set req.http.d = std.real(req.http.x);
+    set req.http.e = std.real(s = req.http.x, fallback = 0.0);
+    set req.http.f = std.integer();
+    set req.http.g = std.random(1);
+}
+"#
+            .to_string(),
+            None,
+        );
+        let mut defs = get_varnish_builtins();
+        let scope = crate::vmod::parse_vmod_json(
+            r#"[
+                ["$VMOD", "1.0", "std", "Vmod_std_Func", "0", "", "7", "1"],
+                ["$FUNC", "integer", [["INT"], "Vmod_std_Func.f_integer", "", ["STRING", "s"], ["INT", "fallback"]]],
+                ["$FUNC", "real", [["REAL"], "Vmod_std_Func.f_real", "", ["STRING", "s"], ["REAL", "fallback", null, null, true]]],
+                ["$FUNC", "random", [["REAL"], "Vmod_std_Func.f_random", "", ["REAL", "lo"], ["REAL", "hi", "1.0"]]]
+            ]"#,
+        )
+        .unwrap();
+        defs.properties.insert(
+            "std".to_string(),
+            Definition::new_builtin("std".to_string(), scope),
+        );
 
-            let is_multiline = range.start_point.row != range.end_point.row;
-            for row in range.start_point.row..=range.end_point.row {
-                let start = if row == range.start_point.row {
-                    let start_line = self.rope.line(row);
-                    start_line.char_to_utf16_cu(start_line.byte_to_char(range.start_point.column))
-                } else {
-                    0
-                };
+        let errors = doc.diagnostics(defs, &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].range.start.line, 2);
+        assert_eq!(
+            errors[0].message,
+            "Missing required argument fallback (std.integer takes at least 2 arguments, 1 provided)"
+        );
+        assert_eq!(errors[1].range.start.line, 7);
+        assert_eq!(
+            errors[1].message,
+            "Missing required argument s (std.integer takes at least 2 arguments, 0 provided)"
+        );
+    }
 
-                let end_line = self.rope.line(row);
-                let end = if row == range.end_point.row {
-                    end_line.char_to_utf16_cu(end_line.byte_to_char(range.end_point.column))
-                } else {
-                    end_line.len_utf16_cu()
-                };
+    #[test]
+    fn obj_variables() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_hit {
+    if (obj.status == 200 && obj.http.x-foo && obj.ttl > 1s) {}
+    if (obj.uncacheable) {}
+}
+sub vcl_deliver {
+    set resp.http.hits = obj.hits;
+    set resp.http.status = obj.status;
+}
+sub vcl_recv {
+    set req.http.age = obj.age;
+}
+"#
+            .to_string(),
+            None,
+        );
 
-                if !is_multiline && prev_node.is_some_and(|prev_node| prev_node == node) {
-                    let prev_token: &mut Token = tokens.last_mut().unwrap();
-                    if prev_token.token_type == token_type {
-                        prev_token.modifier_bitset |= modifier_bitset;
-                        continue;
-                    }
-                }
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].range.start.line, 3);
+        assert_eq!(
+            errors[0].message,
+            "«obj.uncacheable» does not exist in «vcl_hit»"
+        );
+        assert_eq!(errors[1].range.start.line, 7);
+        assert_eq!(
+            errors[1].message,
+            "«obj.status» does not exist in «vcl_deliver»"
+        );
+        assert_eq!(errors[2].range.start.line, 10);
+        assert_eq!(errors[2].message, "«obj» does not exist in «vcl_recv»");
 
-                tokens.push(Token {
-                    start,
-                    line: row,
-                    length: end - start,
-                    token_type,
-                    modifier_bitset,
-                });
-                prev_node = Some(node);
-            }
-        }
+        let scope = get_varnish_builtins();
+        let (hover, _) = doc.get_variable_hover(Point::new(2, 33), &scope).unwrap();
+        assert_eq!(
+            hover,
+            "`STRING obj.http.x-foo`\n\nThe HTTP headers stored in the object.\n\nReadable from: vcl_hit"
+        );
+        let (hover, _) = doc.get_variable_hover(Point::new(6, 32), &scope).unwrap();
+        assert!(hover.starts_with("`NUMBER obj.hits`\n\nThe count of cache hits"));
+        assert!(hover.ends_with("Readable from: vcl_hit, vcl_deliver"));
+    }
 
-        tokens.sort_unstable();
+    #[test]
+    fn client_and_server_variables() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"acl purgers { "127.0.0.1"; }
+sub vcl_recv {
+    if (client.ip ~ purgers || req.http.x-forwarded-for ~ purgers) {
+        set req.http.x-server = server.hostname + server.ip;
+    }
+    set client.identity = req.http.cookie;
+}
+sub vcl_init {
+    std.log(client.ip + server.hostname);
+}
+"#
+            .to_string(),
+            None,
+        );
+        let get_defs = || {
+            let mut defs = get_varnish_builtins();
+            let mut map: BTreeMap<String, Definition> = BTreeMap::from_iter(
+                doc.get_all_definitions(&defs)
+                    .into_iter()
+                    .map(|def| (def.ident_str.to_string(), def)),
+            );
+            defs.properties.append(&mut map);
+            defs
+        };
 
-        let mut prev_line = 0;
-        let mut prev_start = 0;
-        tokens
-            .into_iter()
-            .map(|tok| {
-                let delta_line = tok.line - prev_line;
-                let delta_start = if delta_line == 0 {
-                    tok.start - prev_start
-                } else {
-                    tok.start
-                };
-                let semtok = SemanticToken {
-                    delta_start: delta_start as u32,
-                    delta_line: delta_line as u32,
-                    length: tok.length as u32,
-                    token_type: tok.token_type as u32,
-                    token_modifiers_bitset: tok.modifier_bitset as u32,
-                };
+        let errors = doc.get_error_ranges(&get_defs(), &Default::default());
+        println!("errors: {:?}", errors);
+        let errors = errors
+            .iter()
+            .map(|error| (error.loc.range.start.line, error.message.as_str()))
+            .collect::<Vec<_>>();
+        assert!(errors.contains(&(2, "Expected IP to match against the acl, found STRING")));
+        assert!(errors.contains(&(8, "«client» does not exist in «vcl_init»")));
+        assert!(!errors.iter().any(|(line, _)| *line == 3 || *line == 5));
+
+        let scope = get_defs();
+        let (hover, _) = doc.get_variable_hover(Point::new(2, 12), &scope).unwrap();
+        assert!(hover.starts_with("`IP client.ip`\n\nThe client's IP address"));
+        assert!(hover.ends_with("Readable from: vcl_recv, vcl_pipe, vcl_pass, vcl_hash, vcl_purge, vcl_miss, vcl_hit, vcl_deliver, vcl_synth, vcl_backend_fetch, vcl_backend_response, vcl_backend_error"));
+        let (hover, _) = doc.get_variable_hover(Point::new(3, 36), &scope).unwrap();
+        assert_eq!(
+            hover,
+            "`STRING server.hostname`\n\nThe host name of the server, as returned by the gethostname(3) system function."
+        );
 
-                prev_line = tok.line;
-                prev_start = tok.start;
-                semtok
-            })
-            .collect()
+        assert!(!varnish_builtins::is_variable_read_only(
+            "client.identity",
+            Some("vcl_recv")
+        ));
+        assert!(varnish_builtins::is_variable_read_only(
+            "client.identity",
+            Some("vcl_backend_fetch")
+        ));
+        assert!(varnish_builtins::is_variable_read_only(
+            "client.ip",
+            Some("vcl_recv")
+        ));
     }
+
+    #[test]
+    fn vmod_enum_and_restricted_string_values() {
+        let get_defs = || {
+            let mut defs = get_varnish_builtins();
+            let scope = crate::vmod::parse_vmod_json(
+                r#"[
+                    ["$VMOD", "1.0", "example", "Vmod_example_Func", "0", "", "7", "1"],
+                    ["$FUNC", "pick", [["STRING"], "Vmod_example_Func.f_pick", "", ["ENUM", "mode", "fast", ["fast", "slow"]], ["STRING", "style", "\"plain\"", ["plain", "fancy"], true]]]
+                ]"#,
+            )
+            .unwrap();
+            defs.properties.insert(
+                "example".to_string(),
+                Definition::new_builtin("example".to_string(), scope),
+            );
+            defs
+        };
+
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.http.a = example.pick(mode = fast, style = "fancy");
+    set req.http.b = example.pick(mode = "fast");
+    set req.http.c = example.pick(mode = slow, style = plain);
+    set req.http.d = example.pick(mode = slow, style = "ugly");
+    set req.http.e = example.pick(mode = slow, style = req.http.style);
 }
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_defs(), &Default::default());
+        println!("errors: {:?}", errors);
+        let messages = |line: u32| {
+            errors
+                .iter()
+                .filter(|error| error.range.start.line == line)
+                .map(|error| error.message.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert!(messages(2).is_empty());
+        assert_eq!(
+            messages(3),
+            vec!["Enum values are not quoted, expected fast"]
+        );
+        assert_eq!(messages(4), vec!["Expected a quoted string, \"plain\""]);
+        assert_eq!(
+            messages(5),
+            vec!["Value \"ugly\" not allowed. Must be one of: \"plain\", \"fancy\""]
+        );
+        assert!(messages(6).is_empty());
 
-pub struct RopeChunkBytesIterator<'a> {
-    chunks: Chunks<'a>,
+        // enum values are completed bare, restricted strings quoted
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    set req.http.a = example.pick(mode = );
+    set req.http.b = example.pick(mode = fast, style = );
 }
-
-impl<'a> Iterator for RopeChunkBytesIterator<'a> {
-    type Item = &'a [u8];
-    fn next(&mut self) -> Option<Self::Item> {
-        self.chunks.next().map(|chunk| chunk.as_bytes())
+"#
+            .to_string(),
+            None,
+        );
+        let result = doc
+            .autocomplete_for_pos(Position::new(2, 41), get_defs(), VarnishFlavor::default())
+            .unwrap();
+        assert_eq!(
+            result.iter().map(|item| &item.label).collect::<Vec<_>>(),
+            vec!["fast", "slow"]
+        );
+        let result = doc
+            .autocomplete_for_pos(Position::new(3, 55), get_defs(), VarnishFlavor::default())
+            .unwrap();
+        assert_eq!(
+            result.iter().map(|item| &item.label).collect::<Vec<_>>(),
+            vec!["\"plain\"", "\"fancy\""]
+        );
     }
+
+    #[test]
+    fn vmod_format_strings() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_deliver {
+    set resp.http.date = std.strftime(now, "%Y-%m-%d %Q");
+    set resp.http.time = std.strftime(now, format = "%H:%M %");
+    set resp.http.x = std.strftime(now, "%");
 }
+"#
+            .to_string(),
+            None,
+        );
+        let get_defs = || {
+            let mut defs = get_varnish_builtins();
+            let mut scope = crate::vmod::parse_vmod_json(
+                r#"[
+                    ["$VMOD", "1.0", "std", "Vmod_std_Func", "0", "", "7", "1"],
+                    ["$FUNC", "strftime", [["STRING"], "Vmod_std_Func.f_strftime", "", ["TIME", "time"], ["STRING", "format"]]]
+                ]"#,
+            )
+            .unwrap();
+            varnish_builtins::mark_format_string_args("std", &mut scope);
+            defs.properties.insert(
+                "std".to_string(),
+                Definition::new_builtin("std".to_string(), scope),
+            );
+            defs
+        };
+        let defs = get_defs();
 
-// TextProvider for tree-sitter to use ropey as efficiently as possible
-impl<'a> TextProvider<&'a [u8]> for &'a Document {
-    type I = RopeChunkBytesIterator<'a>;
-    fn text(&mut self, node: Node) -> Self::I {
-        RopeChunkBytesIterator {
-            chunks: self.rope.byte_slice(node.byte_range()).chunks(),
+        let errors = doc.diagnostics(get_defs(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].message, "Invalid format specifier «%Q»");
+        assert_eq!(
+            errors[0].range,
+            Range::new(Position::new(2, 53), Position::new(2, 55))
+        );
+        assert_eq!(errors[1].message, "Invalid format specifier «%»");
+        assert_eq!(errors[1].range.start, Position::new(3, 59));
+        assert_eq!(errors[2].range.start.line, 4);
+
+        // positional and named format arguments
+        for pos in [Position::new(2, 46), Position::new(3, 57)] {
+            let completions = doc.autocomplete_format_string(pos, &defs).unwrap();
+            assert!(completions.iter().any(|item| item.label == "%Y"));
         }
+        let completions = doc
+            .autocomplete_format_string(Position::new(4, 42), &defs)
+            .unwrap();
+        let Some(CompletionTextEdit::Edit(ref edit)) = completions[0].text_edit else {
+            panic!("completion should have a text edit");
+        };
+        assert_eq!(edit.range.start, Position::new(4, 41));
     }
-}
 
-impl PartialEq for VmodImport {
-    fn eq(&self, other: &Self) -> bool {
-        self.name == other.name
+    #[test]
+    fn strftime_placeholders() {
+        let format = varnish_builtins::FormatString::Strftime;
+        assert!(format
+            .get_invalid_placeholders("%Y-%m-%dT%H:%M:%S%z %%")
+            .is_empty());
+        assert!(format.get_invalid_placeholders("%Ey %Od").is_empty());
+        assert_eq!(
+            format.get_invalid_placeholders("a%qb %E"),
+            vec![(1, "%q".to_string()), (5, "%E".to_string())]
+        );
     }
+
+    #[test]
+    fn scope_at_point() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+import example;
+
+backend default {
+    .host = "localhost";
 }
 
-impl Eq for VmodImport {}
+sub vcl_backend_response {
+    set beresp.ttl = 1s;
+}
+"#
+            .to_string(),
+            None,
+        );
+        let mut defs = get_varnish_builtins();
+        let vmod_scope = crate::vmod::parse_vmod_json(
+            r#"[
+                ["$VMOD", "1.0", "example", "Vmod_example_Func", "0", "", "7", "1"],
+                ["$FUNC", "hello", [["STRING"], "Vmod_example_Func.f_hello", ""]]
+            ]"#,
+        )
+        .unwrap();
+        defs.properties.insert(
+            "example".to_string(),
+            Definition {
+                loc: doc
+                    .get_vmod_imports()
+                    .first()
+                    .map(|import| import.loc.clone()),
+                ..Definition::new_builtin("example".to_string(), vmod_scope)
+            },
+        );
+        let mut doc_defs = BTreeMap::from_iter(
+            doc.get_all_definitions(&defs)
+                .into_iter()
+                .map(|def| (def.ident_str.to_string(), def)),
+        );
+        defs.properties.append(&mut doc_defs);
 
-impl Include {
-    pub fn resolve(mut self, vcl_paths: &[PathBuf]) -> Self {
-        if self.url.is_none() {
-            self.url = vcl_paths.iter().find_map(|search_path| {
-                let path = search_path.join(&self.path);
-                if path.exists() {
-                    Url::from_file_path(path).ok()
-                } else {
-                    None
-                }
-            });
-        }
-        self
+        let symbols = doc.get_scope_at_point(
+            Point::new(8, 10),
+            &defs,
+            &["example".to_string()],
+            VarnishFlavor::default(),
+        );
+        let get_symbol = |name: &str| symbols.iter().find(|symbol| symbol.name == name);
+        assert_eq!(get_symbol("beresp").unwrap().source, ScopeSource::Builtin);
+        // req doesn't exist in vcl_backend_response
+        assert!(get_symbol("req").is_none());
+        assert_eq!(get_symbol("example").unwrap().source, ScopeSource::Vmod);
+        let hello = get_symbol("example.hello").unwrap();
+        assert_eq!(hello.source, ScopeSource::Vmod);
+        assert_eq!(hello.r#type, "STRING hello()");
+        let backend = get_symbol("default").unwrap();
+        assert_eq!(backend.source, ScopeSource::Document);
+        assert_eq!(backend.r#type, "BACKEND");
+        assert_eq!(
+            get_symbol("vcl_backend_response").unwrap().r#type,
+            "SUBROUTINE"
+        );
+
+        // outside subroutines, all builtins are listed
+        let symbols =
+            doc.get_scope_at_point(Point::new(0, 0), &defs, &[], VarnishFlavor::default());
+        assert!(symbols.iter().any(|symbol| symbol.name == "req"));
     }
+
+    #[test]
+    fn vmod_func_documentation() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_recv {
+    example.join(req.url, sep = "");
+    example.
 }
+"#
+            .to_string(),
+            None,
+        );
+        let get_defs = || {
+            let mut defs = get_varnish_builtins();
+            let scope = crate::vmod::parse_vmod_json(
+                r#"[
+                    ["$VMOD", "1.0", "example", "Vmod_example_Func", "0", "", "7", "1"],
+                    ["$FUNC", "join", [["STRING"], "Vmod_example_Func.f_join", "", ["STRING", "s"], ["STRING", "sep"]]]
+                ]"#,
+            )
+            .unwrap();
+            defs.properties.insert(
+                "example".to_string(),
+                Definition::new_builtin("example".to_string(), scope),
+            );
+            defs
+        };
 
-// Misc helper functions
+        // undocumented functions show their signature
+        let result = doc
+            .autocomplete_for_pos(Position::new(3, 12), get_defs(), VarnishFlavor::default())
+            .unwrap();
+        let join_item = result.iter().find(|item| item.label == "join").unwrap();
+        let Some(Documentation::MarkupContent(ref documentation)) = join_item.documentation else {
+            panic!("expected documentation");
+        };
+        assert_eq!(
+            documentation.value,
+            "```vcl\nSTRING join(STRING s, STRING sep)\n```"
+        );
 
-fn point_to_position(point: Point) -> Position {
-    Position {
-        line: point.row as u32,
-        character: point.column as u32,
+        let signature_help = doc
+            .get_signature_help(Point::new(2, 20), &get_defs())
+            .unwrap();
+        assert_eq!(
+            signature_help.signatures[0].label,
+            "STRING join(STRING s, STRING sep)"
+        );
+        assert_eq!(signature_help.active_parameter, Some(0));
+        let signature_help = doc
+            .get_signature_help(Point::new(2, 30), &get_defs())
+            .unwrap();
+        assert_eq!(signature_help.active_parameter, Some(1));
+        assert!(doc
+            .get_signature_help(Point::new(2, 10), &get_defs())
+            .is_none());
     }
-}
 
-fn ts_range_to_lsp_range(input: tree_sitter::Range) -> Range {
-    Range {
-        start: point_to_position(input.start_point),
-        end: point_to_position(input.end_point),
+    #[test]
+    fn vmod_imports_from_path() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+import std;
+import example from "/usr/lib/varnish/vmods/libvmod_example.so";
+"#
+            .to_string(),
+            None,
+        );
+        let imports = doc.get_vmod_imports();
+        assert_eq!(imports.len(), 2);
+        assert_eq!(imports[0].from, None);
+        assert_eq!(
+            imports[1].from,
+            Some(PathBuf::from("/usr/lib/varnish/vmods/libvmod_example.so"))
+        );
     }
-}
 
-fn get_probe_backend_fields(r#type: Type, text: &str) -> Vec<CompletionItem> {
-    let map = match r#type {
-        Type::Probe => get_probe_field_types(),
-        _ => get_backend_field_types(),
-    };
-    map.keys()
-        .filter(|field| field.starts_with(text))
-        .map(|field| CompletionItem {
-            label: field.to_string(),
-            detail: Some(field.to_string()),
-            kind: Some(CompletionItemKind::PROPERTY),
-            ..Default::default()
-        })
-        .collect()
+    #[test]
+    fn hash_data_outside_vcl_hash() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub hash_host {
+    hash_data(req.http.host);
 }
-
-pub fn node_to_type(node: &Node) -> Option<Type> {
-    match node.kind() {
-        "string" => Some(Type::String),
-        "string_list" => Some(Type::String),
-        "number" => Some(Type::Number),
-        "duration" => Some(Type::Duration),
-        "bool" => Some(Type::Bool),
-        "inline_probe" => Some(Type::Probe),
-        "literal" => node_to_type(&node.child(0)?),
-        _ => None,
-    }
+sub hash_url {
+    hash_data(req.url);
 }
-
-fn point_to_tuple(point: Point) -> (usize, usize) {
-    (point.row, point.column)
+sub vcl_hash {
+    hash_data(req.url);
+    call hash_host;
 }
-
-fn get_toplev_declaration_from_node(node: Node) -> Node {
-    let mut node = node;
-    loop {
-        let Some(parent_node) = node.parent() else {
-            break;
-        };
-        if parent_node.kind() == "toplev_declaration" {
-            break;
-        }
-        node = parent_node;
-    }
-    node
+sub vcl_recv {
+    hash_data(req.url);
+    call hash_url;
 }
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].range.start.line, 5);
+        assert_eq!(
+            errors[0].message,
+            "hash_data() can only be called from vcl_hash, but hash_url is called from vcl_recv"
+        );
+        assert_eq!(errors[1].range.start.line, 12);
+        assert_eq!(
+            errors[1].message,
+            "hash_data() can only be called from vcl_hash, not vcl_recv"
+        );
+    }
 
-fn find_parent(node: Node, kind: String) -> Option<Node> {
-    let mut node = node;
-    loop {
-        let parent_node = node.parent()?;
-        node = parent_node;
-        if node.kind() == kind {
-            return Some(node);
+    #[test]
+    fn return_actions_per_subroutine() {
+        // an allowed and a disallowed action for each builtin subroutine
+        let cases = [
+            ("vcl_recv", "hash", "lookup"),
+            ("vcl_pipe", "pipe", "pass"),
+            ("vcl_pass", "fetch", "pass"),
+            ("vcl_hash", "lookup", "pass"),
+            ("vcl_purge", "synth(200)", "deliver"),
+            ("vcl_miss", "fetch", "deliver"),
+            ("vcl_hit", "deliver", "fetch"),
+            ("vcl_deliver", "deliver", "pass"),
+            ("vcl_synth", "deliver", "synth(500)"),
+            ("vcl_backend_fetch", "fetch", "deliver"),
+            ("vcl_backend_response", "deliver", "fetch"),
+            ("vcl_backend_error", "retry", "error"),
+            ("vcl_init", "ok", "deliver"),
+            ("vcl_fini", "ok", "fail"),
+        ];
+        for (sub_name, allowed, disallowed) in cases {
+            let doc = Document::new(
+                Url::parse("file:///test.vcl").unwrap(),
+                format!(
+                    "sub {sub_name} {{\n    if (true) {{\n        return ({allowed});\n    }}\n    return ({disallowed});\n}}\n"
+                ),
+                None,
+            );
+            let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+            assert_eq!(errors.len(), 1, "{sub_name}: {errors:?}");
+            assert_eq!(errors[0].range.start.line, 4);
+            let action = disallowed.split('(').next().unwrap();
+            assert!(
+                errors[0]
+                    .message
+                    .starts_with(&format!("return ({action}) is not allowed in {sub_name}")),
+                "{}",
+                errors[0].message
+            );
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::collections::BTreeMap;
-
-    use crate::varnish_builtins::get_varnish_builtins;
-    use crate::varnish_builtins::{Func, Obj};
-
-    use super::*;
 
     #[test]
-    fn autocomplete_expands_h_to_http() {
+    fn purge_and_ban_contexts() {
         let doc = Document::new(
             Url::parse("file:///test.vcl").unwrap(),
             r#"
 sub vcl_recv {
-    set req.h
+    if (req.method == "PURGE") {
+        return (purge);
+    }
+    ban("req.url ~ " + req.url + " && obj.http.x-tag == a && bereq.url ~ /b");
+}
+sub vcl_hit {
+    return (purge);
+}
+sub vcl_backend_response {
+    ban("obj.status == 404 && beresp.http.x ~ .");
 }
 "#
             .to_string(),
             None,
         );
-        let result = doc
-            .autocomplete_for_pos(
-                Position {
-                    line: 2,
-                    character: 12,
-                },
-                get_varnish_builtins(),
-            )
-            .unwrap();
-        println!("result: {:?}", result);
-        assert!(result.iter().any(|item| item.label == "http"));
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        assert_eq!(errors.len(), 3, "{errors:?}");
+        assert_eq!(errors[0].range.start.line, 5);
+        assert_eq!(
+            errors[0].message,
+            "Bans can't test «bereq.url», only req.url, req.http.*, obj.status, obj.http.*, obj.ttl, obj.age, obj.grace, obj.keep"
+        );
+        assert_eq!(errors[1].range.start.line, 8);
+        assert!(errors[1]
+            .message
+            .ends_with(". return (purge) is only allowed in vcl_recv"));
+        assert_eq!(errors[2].range.start.line, 11);
+        assert!(errors[2]
+            .message
+            .starts_with("Bans can't test «beresp.http.x»"));
+
+        assert_eq!(
+            varnish_builtins::get_invalid_ban_fields("a && req.http.host == b && x", true),
+            vec!["x"]
+        );
+        assert!(varnish_builtins::get_invalid_ban_fields("obj.http. ~ a", false) == ["obj.http."]);
     }
 
     #[test]
-    fn autocomplete_lists_all_on_req() {
+    fn return_actions_follow_flavor_and_callers() {
         let doc = Document::new(
             Url::parse("file:///test.vcl").unwrap(),
             r#"
+sub pass_it {
+    return (pass);
+}
+sub vcl_backend_fetch {
+    return (error);
+}
+sub vcl_hash {
+    call pass_it;
+}
 sub vcl_recv {
-    set req.
+    call pass_it;
 }
 "#
             .to_string(),
             None,
         );
-        let result = doc
-            .autocomplete_for_pos(
-                Position {
-                    line: 2,
-                    character: 11,
-                },
-                get_varnish_builtins(),
-            )
-            .unwrap();
-        assert!(!result.is_empty());
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert_eq!(
+            errors[0].message,
+            "return (pass) is not allowed in vcl_hash, which calls pass_it. return (pass) is only allowed in vcl_recv, vcl_miss, vcl_hit, vcl_backend_response"
+        );
+        assert_eq!(
+            errors[1].message,
+            "return (error) is not allowed in vcl_backend_fetch, expected one of fail, fetch, abandon"
+        );
+
+        let lint_config = LintConfig {
+            flavor: VarnishFlavor::Varnish7,
+            ..Default::default()
+        };
+        let errors = doc.diagnostics(get_varnish_builtins(), &lint_config);
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert_eq!(errors[0].range.start.line, 2);
     }
 
-    #[test]
-    fn get_all_vmod_imports() {
+    #[test]
+    fn deprecated_variables() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"vcl 4.1;
+sub vcl_recv {
+    set req.esi = false;
+}
+sub vcl_backend_response {
+    set beresp.storage_hint = "s0";
+}
+"#
+            .to_string(),
+            None,
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert_eq!(errors[0].range.start.line, 2);
+        assert_eq!(
+            errors[0].message,
+            "«req.esi» is replaced by «resp.do_esi» in vcl 4.1"
+        );
+        assert_eq!(errors[0].severity, Some(DiagnosticSeverity::ERROR));
+        assert_eq!(errors[1].range.start.line, 5);
+        assert_eq!(
+            errors[1].message,
+            "«beresp.storage_hint» is deprecated in varnish-plus-6.0, use «beresp.storage» instead"
+        );
+        assert_eq!(errors[1].severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(errors[1].tags, Some(vec![DiagnosticTag::DEPRECATED]));
+
+        // req.esi is fine in vcl 4.0, and beresp.storage_hint is gone in varnish 7
         let doc = Document::new(
             Url::parse("file:///test.vcl").unwrap(),
-            r#"
-import brotli;
-import jwt;
-import xkey;
+            r#"vcl 4.0;
+sub vcl_recv {
+    set req.esi = false;
+}
+sub vcl_backend_response {
+    set beresp.storage_hint = "s0";
+}
 "#
             .to_string(),
             None,
         );
-        let vmod_names: Vec<_> = doc
-            .get_vmod_imports()
-            .iter()
-            .map(|import| import.name.clone())
-            .collect();
-        println!("imports: {:?}", vmod_names);
-        assert_eq!(vmod_names, vec!["brotli", "jwt", "xkey"]);
+        let lint_config = LintConfig {
+            flavor: VarnishFlavor::Varnish7,
+            ..Default::default()
+        };
+        let errors = doc.diagnostics(get_varnish_builtins(), &lint_config);
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert_eq!(errors[0].range.start.line, 5);
+        assert_eq!(
+            errors[0].message,
+            "«beresp.storage_hint» was removed in varnish-7, use «beresp.storage» instead"
+        );
+        assert_eq!(
+            varnish_builtins::get_deprecated_variable_replacement(
+                VarnishFlavor::Varnish7,
+                "beresp.storage_hint"
+            ),
+            None
+        );
     }
 
     #[test]
-    fn get_all_subroutines() {
+    fn flavor_directive() {
         let doc = Document::new(
             Url::parse("file:///test.vcl").unwrap(),
-            r#"
-sub vcl_init {}
-sub vcl_recv {}
-sub my_custom_sub {}
+            r#"vcl 4.1;
+# varnish-lsp flavor: varnish-7
+sub vcl_backend_fetch {
+    return (error);
+}
 "#
             .to_string(),
             None,
         );
-        let result = doc.get_subroutines();
-        println!("subroutines: {:?}", result);
-        assert_eq!(result.len(), 3);
-        assert_eq!(result[0], "vcl_init");
-        assert_eq!(result[1], "vcl_recv");
-        assert_eq!(result[2], "my_custom_sub");
-    }
+        assert_eq!(
+            doc.get_flavor_directive(),
+            Some((
+                "varnish-7".to_string(),
+                Range::new(Position::new(1, 22), Position::new(1, 31))
+            ))
+        );
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        assert_eq!(errors.len(), 0, "{errors:?}");
 
-    #[test]
-    fn get_definition_for_sub() {
         let doc = Document::new(
             Url::parse("file:///test.vcl").unwrap(),
-            r#"
-sub my_custom_sub {}
+            r#"/* varnish-lsp flavor: varnish-8 */
 sub vcl_recv {
-    call my_custom_sub;
+    # varnish-lsp flavor: varnish-7
+    set req.http.x-foo = "bar";
 }
 "#
             .to_string(),
             None,
         );
-        let result = doc
-            .get_definition_by_point(Point { row: 3, column: 9 })
-            .unwrap();
-        assert_eq!(result.0.row, 1);
-        assert_eq!(result.0.column, 0);
-        assert_eq!(result.1.row, 1);
-        assert_eq!(result.1.column, 20);
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert_eq!(
+            errors[0].message,
+            "Unknown varnish flavor «varnish-8», expected one of varnish-6.0, varnish-plus-6.0, varnish-7"
+        );
+        assert_eq!(
+            errors[0].range,
+            Range::new(Position::new(0, 23), Position::new(0, 32))
+        );
     }
 
     #[test]
-    fn get_all_definitions_works() {
+    fn find_unterminated_strings() {
+        assert_eq!(
+            find_unterminated_string(r#""line one" "line two;"#),
+            Some(11..21)
+        );
+        assert_eq!(
+            find_unterminated_string("\"a\" # \"b\n\"c;\nd\""),
+            Some(9..12)
+        );
+        assert_eq!(
+            find_unterminated_string(
+                r#"{"a " b"} """x
+y""" "z\"""#
+            ),
+            None
+        );
+        assert_eq!(find_unterminated_string(r#"/* " */ "ok""#), None);
+    }
+
+    #[test]
+    fn unterminated_string_in_concatenation() {
         let doc = Document::new(
             Url::parse("file:///test.vcl").unwrap(),
             r#"
-acl my_ips {}
-sub my_custom_sub {}
-sub vcl_recv {}
+sub vcl_recv {
+    set req.http.x-foo = "line one" "line two;
+}
 "#
             .to_string(),
             None,
         );
-        let result = doc.get_all_definitions(&get_varnish_builtins());
-        assert_eq!(result.len(), 3);
+        let errors = doc.diagnostics(get_varnish_builtins(), &Default::default());
+        println!("errors: {:?}", errors);
+        let error = errors
+            .iter()
+            .find(|error| error.message.starts_with("Unterminated string"))
+            .unwrap();
+        assert_eq!(
+            error.range,
+            Range::new(Position::new(2, 36), Position::new(2, 46))
+        );
+        assert!(!errors
+            .iter()
+            .any(|error| error.range.start == Position::new(2, 25)));
     }
 
     #[test]
-    fn nested_identifier_before_if_works() {
+    fn implicit_return_hints() {
         let doc = Document::new(
             Url::parse("file:///test.vcl").unwrap(),
             r#"
-acl a_not_this {}
-backend a_match_this {}
 sub vcl_recv {
-    if (req.
-
-    if (true) {}
+    if (req.method == "PURGE") {
+        return (purge);
+    } elsif (req.url ~ "^/admin") {
+        return (pass);
+    }
+}
+sub vcl_hash {
+    if (req.http.host) {
+        hash_data(req.http.host);
+        return (lookup);
+    } else {
+        return (lookup);
+    }
+}
+sub vcl_deliver {
+    unset resp.http.x-varnish;
+}
+sub custom {
 }
 "#
             .to_string(),
             None,
         );
-        let result = doc.autocomplete_for_pos(
-            Position {
-                line: 4,
-                character: 11,
-            },
-            get_varnish_builtins(),
+        let full_range = Range::new(Position::new(0, 0), Position::new(100, 0));
+        let hints = doc.get_implicit_return_hints(full_range);
+        assert_eq!(hints.len(), 2, "{hints:?}");
+        assert_eq!(hints[0].position, Position::new(7, 1));
+        assert!(
+            matches!(&hints[0].label, InlayHintLabel::String(label) if label == "⟶ return(hash)")
         );
-        println!("result: {:?}", result);
-        assert_eq!(
-            result.unwrap()[0].detail,
-            Some("BACKEND backend_hint".to_string())
+        assert_eq!(hints[1].position, Position::new(18, 1));
+        assert!(
+            matches!(&hints[1].label, InlayHintLabel::String(label) if label == "⟶ return(deliver)")
         );
+
+        let hints =
+            doc.get_implicit_return_hints(Range::new(Position::new(10, 0), Position::new(20, 0)));
+        assert_eq!(hints.len(), 1, "{hints:?}");
     }
 
-    #[ignore]
     #[test]
-    fn autocomplete_backend_after_equal_sign() {
-        let mut scope = get_varnish_builtins();
-
-        scope.properties.insert(
-            "localhost".to_string(),
-            Definition::new_builtin("localhost".to_string(), Type::Backend),
+    fn redundant_acl_entries() {
+        let entry = |negated, address, prefix_len| {
+            (negated, AclNetwork::parse(address, prefix_len).unwrap())
+        };
+        assert_eq!(AclNetwork::parse("10.1.2.3", Some("33")), None);
+        assert_eq!(AclNetwork::parse("localhost", None), None);
+        assert_eq!(
+            AclNetwork::parse("10.1.2.3", Some("8")),
+            AclNetwork::parse("10.0.0.0", Some("8"))
         );
-        scope.properties.insert(
-            "localhost_probe".to_string(),
-            Definition::new_builtin("localhost_probe".to_string(), Type::Probe),
+
+        let entries = vec![
+            entry(false, "10.0.0.0", Some("8")),
+            entry(false, "10.1.2.3", None),
+            entry(true, "10.2.0.0", Some("16")),
+            entry(false, "10.2.3.4", None),
+            entry(true, "10.2.3.0", Some("24")),
+            entry(false, "0.0.0.0", Some("0")),
+            entry(false, "2001:db8::", Some("32")),
+            entry(false, "2001:db8::1", None),
+            entry(false, "::", Some("0")),
+            entry(false, "10.0.0.0", Some("8")),
+        ];
+        assert_eq!(
+            get_redundant_acl_entries(&entries),
+            vec![(0, 5), (1, 0), (4, 2), (6, 8), (7, 6), (9, 0)]
         );
+    }
 
+    #[test]
+    fn redundant_acl_entries_lint() {
         let doc = Document::new(
             Url::parse("file:///test.vcl").unwrap(),
             r#"
-backend localhost {}
-sub vcl_recv {
-    set req.backend_hint = 
+acl purgers {
+    "10.0.0.0"/8;
+    "10.1.2.3";
+    !"10.2.0.0"/16;
+    "10.2.3.4";
 }
 "#
             .to_string(),
             None,
         );
-        let result = doc.autocomplete_for_pos(
-            Position {
-                line: 3,
-                character: 26,
-            },
-            scope,
+        let lint_config = LintConfig {
+            redundant_acl_entries: LintLevel::Hint,
+            ..Default::default()
+        };
+        let errors = doc.diagnostics(get_varnish_builtins(), &lint_config);
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert_eq!(
+            errors[0].message,
+            "[redundant_acl_entries] «\"10.1.2.3\"» is already covered by «\"10.0.0.0\"/8»"
         );
-        println!("result: {:?}", result);
-        let result = result.unwrap();
-        /*
+        assert_eq!(errors[0].range.start.line, 3);
         assert_eq!(
-            result.first().unwrap().detail,
-            Some("localhost".to_string())
+            errors[0].related_information.as_ref().unwrap()[0]
+                .location
+                .range
+                .start
+                .line,
+            2
         );
-        assert_eq!(result.len(), 1);
-        */
-        assert!(!result.is_empty());
-        assert!(result.iter().any(|item| item.label == *"localhost"));
-        assert!(!result.iter().any(|item| item.label == *"localhost_probe"));
+        assert!(doc
+            .diagnostics(get_varnish_builtins(), &Default::default())
+            .is_empty());
     }
 
     #[test]
-    fn autocomplete_backend_def_properties() {
+    fn backend_with_probe_works() {
         let doc = Document::new(
             Url::parse("file:///test.vcl").unwrap(),
             r#"
-backend localhost {
-    .po
+probe my_probe {
+    .request =
+        "GET /healthz HTTP/1.1"
+        "Host: localhost"
+        "User-Agent: varnish-probe"
+        "Connection: close";
+    .interval = 1s;
+    .timeout = 3.0s;
+    .window = 5;
+    .threshold = 3;
+    .initial = 3;
+    .expected_response = 200;
+}
+
+backend my_backend {
+    .host = "195.88.54.16";
+    .port = "80";
+    .probe = my_probe;
 }
 "#
             .to_string(),
             None,
         );
-        let result = doc.autocomplete_for_pos(
-            Position {
-                line: 2,
-                character: 6,
-            },
-            get_varnish_builtins(),
+
+        let mut defs = get_varnish_builtins();
+        let doc_defs = doc.get_all_definitions(&defs);
+        assert_eq!(doc_defs.len(), 2, "Should return two definitions");
+        let mut map: BTreeMap<String, Definition> = BTreeMap::from_iter(
+            doc_defs
+                .iter()
+                .map(|def| (def.ident_str.to_string(), def.clone())),
         );
-        println!("result: {:?}", result);
-        let result = result.unwrap();
-        assert_eq!(result.first().unwrap().detail, Some("port".to_string()));
-        assert_eq!(result.len(), 1);
+        defs.properties.append(&mut map);
+        let errors = doc.diagnostics(defs, &Default::default());
+        assert_eq!(errors.len(), 0, "Should produce no errors");
     }
 
     #[test]
-    fn lists_all_includes() {
+    fn director_backend_arguments() {
+        assert_eq!(get_edit_distance("wbe1", "web1"), 2);
+        assert_eq!(get_edit_distance("", "web"), 3);
+        assert_eq!(
+            get_closest_name("web", ["web1", "api"].into_iter()),
+            Some("web1")
+        );
+        assert_eq!(get_closest_name("nope", ["web1", "api"].into_iter()), None);
+
         let doc = Document::new(
-            Url::parse("file:///etc/varnish/vg/varnish.vcl").unwrap(),
+            Url::parse("file:///test.vcl").unwrap(),
             r#"
-include "config/acl.vcl";
-sub vcl_recv {
-    if (req.url == "/health") {
-        include "config/healthcheck.vcl";
-    }
+acl purgers { "127.0.0.1"; }
+backend web1 { .host = "127.0.0.1"; }
+backend web2 { .host = "127.0.0.2"; }
 
-    if (req.http.host == "www.vg.no") {
-        include "./www_vg_no_routing.vcl";
-    }
+sub vcl_init {
+    new rr = directors.round_robin();
+    rr.add_backend(web1);
+    rr.add_backend(web3);
+    rr.add_backend(purgers);
+    rr.add_backend(nothing_like_it);
 }
 "#
             .to_string(),
             None,
         );
-        let includes = doc.get_includes();
-        println!("includes: {:?}", includes);
-        assert_eq!(
-            includes
-                .iter()
-                .map(|inc| inc.path.to_string_lossy())
-                .collect::<Vec<_>>(),
-            vec![
-                "config/acl.vcl",
-                "config/healthcheck.vcl",
-                "./www_vg_no_routing.vcl",
-            ]
+        let mut defs = get_varnish_builtins();
+        defs.properties.insert(
+            "directors".to_string(),
+            Definition::new_builtin(
+                "directors".to_string(),
+                Type::Obj(Obj {
+                    name: "directors".to_string(),
+                    properties: BTreeMap::from([(
+                        "round_robin".to_string(),
+                        Type::Func(Func {
+                            name: "round_robin".to_string(),
+                            r#return: Some(Box::new(Type::Obj(Obj {
+                                name: "round_robin".to_string(),
+                                properties: BTreeMap::from([(
+                                    "add_backend".to_string(),
+                                    Type::Func(Func {
+                                        name: "add_backend".to_string(),
+                                        args: vec![FuncArg {
+                                            r#type: Some(Type::Backend),
+                                            ..Default::default()
+                                        }],
+                                        ..Default::default()
+                                    }),
+                                )]),
+                                ..Default::default()
+                            }))),
+                            ..Default::default()
+                        }),
+                    )]),
+                    ..Default::default()
+                }),
+            ),
+        );
+        let doc_defs = doc.get_all_definitions(&defs);
+        defs.properties.extend(
+            doc_defs
+                .into_iter()
+                .map(|def| (def.ident_str.to_string(), def)),
         );
 
-        /*
-        let includes = includes
-            .into_iter()
-            .map(|include| include.resolve(&vec!["/etc/varnish/".into()].into()))
-            .collect::<Vec<_>>();
-        */
-
+        let errors = doc.diagnostics(defs, &Default::default());
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].range.start, Position::new(8, 19));
         assert_eq!(
-            includes
-                .iter()
-                .map(|inc| inc.url.as_ref().map(|url| url.to_string()))
-                .collect::<Vec<_>>(),
-            vec![
-                None, // Some("file:///etc/varnish/acl.vcl".to_string()),
-                None, // Some("file:///etc/varnish/healthcheck.vcl".to_string()),
-                Some("file:///etc/varnish/vg/www_vg_no_routing.vcl".to_string()),
-            ]
+            errors[0].message,
+            "Backend «web3» is not defined (did you mean «web1»?)"
+        );
+        assert_eq!(errors[1].message, "ACL cannot cast into BACKEND");
+        assert_eq!(
+            errors[2].message,
+            "Backend «nothing_like_it» is not defined"
         );
     }
 
     #[test]
-    fn list_all_new_objs() {
+    fn probes_used_before_they_are_defined() {
         let doc = Document::new(
             Url::parse("file:///test.vcl").unwrap(),
             r#"
-sub vcl_init {
-    new static_web = directors.round_robin();
+probe early {
+    .url = "/";
+}
+backend a {
+    .host = "127.0.0.1";
+    .probe = early;
+}
+backend b {
+    .host = "127.0.0.1";
+    .probe = late;
+}
+probe late {
+    .url = "/";
+}
+"#
+            .to_string(),
+            None,
+        );
+        let mut defs = get_varnish_builtins();
+        let doc_defs = doc.get_all_definitions(&defs);
+        defs.properties.extend(
+            doc_defs
+                .into_iter()
+                .map(|def| (def.ident_str.to_string(), def)),
+        );
+
+        let errors = doc.diagnostics(defs, &Default::default());
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert_eq!(
+            errors[0].message,
+            "Probe «late» is used before it is defined"
+        );
+        assert_eq!(errors[0].range.start, Position::new(10, 13));
+        let related_information = errors[0].related_information.as_ref().unwrap();
+        assert_eq!(related_information[0].location.range.start.line, 12);
+
+        // a probe in a file included after the backend
+        let main_doc = Document::new(
+            Url::parse("file:///main.vcl").unwrap(),
+            r#"vcl 4.1;
+backend a {
+    .host = "127.0.0.1";
+    .probe = included;
 }
+include "probes.vcl";
 "#
             .to_string(),
             None,
         );
-        let result = doc.get_all_definitions(&Definitions {
-            properties: BTreeMap::from([(
-                "directors".to_string(),
-                Definition {
-                    ident_str: "directors".to_string(),
-                    r#type: Box::new(Type::Obj(Obj {
-                        name: "mock_directors".to_string(),
-                        properties: BTreeMap::from([(
-                            "round_robin".to_string(),
-                            Type::Func(Func {
-                                r#return: Some(Box::new(Type::Obj(Obj {
-                                    name: "mock_round_robin_director".to_string(),
-                                    properties: BTreeMap::from([(
-                                        "backend".to_string(),
-                                        Type::Func(Default::default()),
-                                    )]),
-                                    ..Default::default()
-                                }))),
-                                ..Default::default()
-                            }),
-                        )]),
-                        ..Default::default()
-                    })),
-                    loc: None,
-                    nested_pos: Default::default(),
-                },
-            )]),
-            ..Default::default()
-        });
-        println!("result: {:?}", result);
-        assert_eq!(result.len(), 2);
+        let included_doc = Document::new(
+            Url::parse("file:///probes.vcl").unwrap(),
+            "probe included {\n    .url = \"/\";\n}\n".to_string(),
+            Some(vec![(5, 8)]),
+        );
+        let mut defs = get_varnish_builtins();
+        for doc in [&main_doc, &included_doc] {
+            let doc_defs = doc.get_all_definitions(&defs);
+            defs.properties.extend(
+                doc_defs
+                    .into_iter()
+                    .map(|def| (def.ident_str.to_string(), def)),
+            );
+        }
+        let errors = main_doc.diagnostics(defs, &Default::default());
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert_eq!(
+            errors[0].message,
+            "Probe «included» is used before it is defined"
+        );
+        let related_information = errors[0].related_information.as_ref().unwrap();
+        assert_eq!(
+            related_information[0].location.uri.as_str(),
+            "file:///probes.vcl"
+        );
     }
 
     #[test]
-    fn backend_with_probe_works() {
+    fn fileread_in_request() {
         let doc = Document::new(
             Url::parse("file:///test.vcl").unwrap(),
             r#"
-probe my_probe {
-    .request =
-        "GET /healthz HTTP/1.1"
-        "Host: localhost"
-        "User-Agent: varnish-probe"
-        "Connection: close";
-    .interval = 1s;
-    .timeout = 3.0s;
-    .window = 5;
-    .threshold = 3;
-    .initial = 3;
-    .expected_response = 200;
+sub vcl_init {
+    std.log(std.fileread("/etc/varnish/init.txt"));
 }
-
-backend my_backend {
-    .host = "195.88.54.16";
-    .port = "80";
-    .probe = my_probe;
+sub vcl_recv {
+    set req.http.x-allowed = std.fileread("/etc/varnish/allowed.txt");
+}
+sub add_banner {
+    set resp.http.x-banner = std.fileread("/etc/varnish/banner.txt");
+}
+sub vcl_deliver {
+    call add_banner;
 }
 "#
             .to_string(),
             None,
         );
 
-        let mut defs = get_varnish_builtins();
-        let doc_defs = doc.get_all_definitions(&defs);
-        assert_eq!(doc_defs.len(), 2, "Should return two definitions");
-        let mut map: BTreeMap<String, Definition> = BTreeMap::from_iter(
-            doc_defs
-                .iter()
-                .map(|def| (def.ident_str.to_string(), def.clone())),
-        );
-        defs.properties.append(&mut map);
-        let errors = doc.diagnostics(defs, &Default::default());
-        assert_eq!(errors.len(), 0, "Should produce no errors");
+        // the return type is known without the std vmod
+        let point = Point::new(5, 30);
+        let mut call_node = doc
+            .ast
+            .root_node()
+            .descendant_for_point_range(point, point)
+            .unwrap();
+        while call_node.kind() != "ident_call_expr" {
+            call_node = call_node.parent().unwrap();
+        }
+        assert!(matches!(
+            doc.get_expr_type(call_node, &get_varnish_builtins()),
+            Some(Type::String)
+        ));
+
+        let get_defs = || {
+            let mut defs = get_varnish_builtins();
+            let scope = crate::vmod::parse_vmod_json(
+                r#"[
+                    ["$VMOD", "1.0", "std", "Vmod_std_Func", "0", "", "7", "1"],
+                    ["$FUNC", "fileread", [["STRING"], "Vmod_std_Func.f_fileread", "", ["STRING", "path"]]],
+                    ["$FUNC", "log", [["VOID"], "Vmod_std_Func.f_log", "", ["STRING", "s"]]]
+                ]"#,
+            )
+            .unwrap();
+            defs.properties.insert(
+                "std".to_string(),
+                Definition::new_builtin("std".to_string(), scope),
+            );
+            defs
+        };
+        let lint_config = LintConfig {
+            fileread_in_request: LintLevel::Hint,
+            ..Default::default()
+        };
+        let errors = doc.diagnostics(get_defs(), &lint_config);
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert_eq!(errors[0].range.start.line, 5);
+        assert!(errors[0]
+            .message
+            .starts_with("[fileread_in_request] std.fileread() in vcl_recv only reads"));
+        assert_eq!(errors[1].range.start.line, 8);
+        assert!(errors[1]
+            .message
+            .starts_with("[fileread_in_request] std.fileread() in vcl_deliver only reads"));
+        assert!(doc.diagnostics(get_defs(), &Default::default()).is_empty());
     }
 
     #[test]
@@ -2257,4 +10504,429 @@ backend my_backend {
             "\n\n".to_string(),
         );
     }
+
+    #[test]
+    fn embedded_vcls() {
+        let doc = Document::new(
+            Url::parse("file:///test.vtc").unwrap(),
+            r#"varnishtest "embedded vcl"
+
+server s1 {
+    rxreq
+    txresp
+} -start
+
+varnish v1 -vcl+backend {
+    sub vcl_recv {
+        set req.http.x = "y";
+    }
+} -start
+
+varnish v2 -vcl { backend b { .host = "localhost"; } } -start
+"#
+            .to_string(),
+            None,
+        );
+        let embedded_vcls = doc.get_embedded_vcls();
+        assert_eq!(embedded_vcls.len(), 2);
+
+        let embedded_vcl = &embedded_vcls[0];
+        assert_eq!(
+            embedded_vcl.prefix,
+            r#"vcl 4.1; backend s1 { .host = "127.0.0.1"; } "#
+        );
+        assert_eq!(embedded_vcl.start, Position::new(7, 25));
+        assert_eq!(embedded_vcl.end, Position::new(11, 0));
+        assert_eq!(embedded_vcl.instance, "v1");
+        assert_eq!(
+            embedded_vcl.range,
+            Range::new(Position::new(7, 11), Position::new(11, 1))
+        );
+        assert!(embedded_vcl.text.starts_with(&embedded_vcl.prefix));
+        assert!(embedded_vcl.text.ends_with("    }\n"));
+        // «x» in «req.http.x»
+        assert_eq!(
+            embedded_vcl.to_vtc_range(Range::new(Position::new(2, 21), Position::new(2, 22))),
+            Some(Range::new(Position::new(9, 21), Position::new(9, 22)))
+        );
+        // the backend added for the server
+        assert_eq!(
+            embedded_vcl.to_vtc_range(Range::new(Position::new(0, 17), Position::new(0, 19))),
+            None
+        );
+
+        let embedded_vcl = &embedded_vcls[1];
+        assert_eq!(embedded_vcl.prefix, "vcl 4.1; ");
+        assert_eq!(embedded_vcl.start, Position::new(13, 17));
+        // «b» in «backend b»
+        let offset = embedded_vcl.prefix.len() as u32;
+        assert_eq!(
+            embedded_vcl.to_vtc_range(Range::new(
+                Position::new(0, offset + 9),
+                Position::new(0, offset + 10)
+            )),
+            Some(Range::new(Position::new(13, 26), Position::new(13, 27)))
+        );
+
+        let vcl_doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            "vcl 4.1;".to_string(),
+            None,
+        );
+        assert!(vcl_doc.get_embedded_vcls().is_empty());
+    }
+
+    #[test]
+    fn folding_ranges() {
+        let doc = Document::new(
+            Url::parse("file:///test.vtc").unwrap(),
+            r#"varnishtest "folding"
+
+server s1 {
+    rxreq
+    txresp
+} -start
+
+varnish v1 -vcl+backend {
+    sub vcl_recv {
+        if (req.url == "/") {
+            return (pass);
+        }
+    }
+} -start
+"#
+            .to_string(),
+            None,
+        );
+        let folding_ranges = doc
+            .get_folding_ranges()
+            .into_iter()
+            .map(|folding_range| {
+                (
+                    folding_range.start_line,
+                    folding_range.end_line,
+                    folding_range.kind,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            folding_ranges,
+            vec![
+                (2, 4, None),
+                (7, 12, Some(FoldingRangeKind::Region)),
+                (8, 11, None),
+                (9, 10, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn misspelled_headers() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"
+sub vcl_deliver {
+    set resp.http.Conten-Type = "text/plain";
+    set resp.http.cach-control = "no-cache";
+    set resp.http.X-Cache = "HIT";
+    set resp.http.Content-Type = "text/html";
+    unset resp.http.x-varnish;
+}
+"#
+            .to_string(),
+            None,
+        );
+
+        // off by default
+        let errors = doc.get_error_ranges(&get_varnish_builtins(), &Default::default());
+        assert!(errors
+            .iter()
+            .all(|error| !error.message.starts_with("[misspelled_headers]")));
+
+        let lint_config = LintConfig {
+            misspelled_headers: LintLevel::Hint,
+            prefer_lowercase_headers: LintLevel::Disabled,
+            ..Default::default()
+        };
+        let errors = doc.get_error_ranges(&get_varnish_builtins(), &lint_config);
+        println!("errors: {:?}", errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].message,
+            "[misspelled_headers] Unknown header «Conten-Type», did you mean «Content-Type»?"
+        );
+        assert_eq!(errors[0].severity, DiagnosticSeverity::HINT);
+        assert_eq!(errors[0].loc.range.start, Position::new(2, 8));
+        let data = errors[0].data.as_ref().unwrap();
+        assert_eq!(data.r#type, LintErrorInternalType::MisspelledHeader);
+        assert_eq!(data.replace_with, "resp.http.Content-Type");
+        assert_eq!(
+            errors[1].data.as_ref().unwrap().replace_with,
+            "resp.http.cache-control"
+        );
+
+        // custom headers can be added to the dictionary
+        let lint_config = LintConfig {
+            http_headers: ["Conten-Type", "Content-Type", "Cache-Control"]
+                .map(String::from)
+                .to_vec(),
+            ..lint_config
+        };
+        let errors = doc.get_error_ranges(&get_varnish_builtins(), &lint_config);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].loc.range.start.line, 3);
+    }
+
+    #[test]
+    fn unused_backends_and_probes() {
+        let main_doc = Document::new(
+            Url::parse("file:///main.vcl").unwrap(),
+            r#"vcl 4.1;
+
+include "backends.vcl";
+
+backend old_api {
+    .host = "old.example.com";
+}
+
+sub vcl_init {
+    new vdir = directors.round_robin();
+    vdir.add_backend(api);
+}
+"#
+            .to_string(),
+            None,
+        );
+        let included_doc = Document::new(
+            Url::parse("file:///backends.vcl").unwrap(),
+            r#"backend first {
+    .host = "first.example.com";
+}
+
+probe health {
+    .url = "/health";
+}
+
+probe unused_probe {
+    .url = "/";
+}
+
+backend api {
+    .host = "api.example.com";
+    .probe = health;
+}
+"#
+            .to_string(),
+            Some(vec![(2, 0)]),
+        );
+
+        let mut declarations = main_doc.get_backend_and_probe_declarations();
+        declarations.append(&mut included_doc.get_backend_and_probe_declarations());
+        assert_eq!(declarations.len(), 5);
+        let mut referenced_idents = main_doc.get_referenced_idents();
+        referenced_idents.extend(included_doc.get_referenced_idents());
+        assert!(!referenced_idents.contains("old_api"));
+
+        let errors = get_unused_declaration_errors(
+            &declarations,
+            &referenced_idents,
+            DiagnosticSeverity::HINT,
+        );
+        let names = errors
+            .iter()
+            .map(|error| error.message.as_str())
+            .collect::<Vec<_>>();
+        // «first» is the default backend, since it is declared first (in the include)
+        assert_eq!(
+            names,
+            vec![
+                "[unused_backends_and_probes] «old_api» is never used",
+                "[unused_backends_and_probes] «unused_probe» is never used",
+            ]
+        );
+        // removing the declaration removes its line break too
+        assert_eq!(
+            errors[0].loc.range,
+            Range::new(Position::new(4, 0), Position::new(7, 0))
+        );
+        let data = errors[1].data.as_ref().unwrap();
+        assert_eq!(data.quickfix_label, "Remove unused probe «unused_probe»");
+        assert_eq!(data.replace_with, "");
+    }
+
+    #[test]
+    fn autocomplete_postfix() {
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            r#"vcl 4.1;
+
+sub vcl_recv {
+    set req.http.x = req.url.to
+    set req.http.y = req.http.host.q
+    set req.http.z = req.restarts.to
+}
+"#
+            .to_string(),
+            None,
+        );
+        let scope = get_varnish_builtins();
+        let completions = doc
+            .autocomplete_postfix(Position::new(3, 31), &scope)
+            .unwrap();
+        assert_eq!(
+            completions
+                .iter()
+                .map(|completion| completion.label.as_str())
+                .collect::<Vec<_>>(),
+            vec![".tolower", ".toupper"]
+        );
+        assert_eq!(
+            completions[0].text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit::new(
+                Range::new(Position::new(3, 21), Position::new(3, 31)),
+                "std.tolower(req.url)".to_string()
+            )))
+        );
+        assert_eq!(
+            completions[0].filter_text.as_deref(),
+            Some("req.url.tolower")
+        );
+        // std is not imported, so the import is added after the vcl version
+        assert_eq!(
+            completions[0].additional_text_edits,
+            Some(vec![TextEdit::new(
+                Range::new(Position::new(1, 0), Position::new(1, 0)),
+                "import std;\n".to_string()
+            )])
+        );
+
+        let completions = doc
+            .autocomplete_postfix(Position::new(4, 36), &scope)
+            .unwrap();
+        assert_eq!(completions.len(), 1);
+        assert_eq!(
+            completions[0].text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit::new(
+                Range::new(Position::new(4, 21), Position::new(4, 36)),
+                "std.querysort(req.http.host)".to_string()
+            )))
+        );
+
+        // only strings
+        assert!(doc
+            .autocomplete_postfix(Position::new(5, 36), &scope)
+            .is_none());
+
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            "vcl 4.1;\nimport std;\nsub vcl_recv {\n    set req.url = req.url.toupper\n}\n"
+                .to_string(),
+            None,
+        );
+        let completions = doc
+            .autocomplete_postfix(Position::new(3, 33), &scope)
+            .unwrap();
+        assert_eq!(completions.len(), 1);
+        assert_eq!(completions[0].additional_text_edits, None);
+
+        // std imported under an alias
+        let doc = Document::new(
+            Url::parse("file:///test.vcl").unwrap(),
+            "vcl 4.1;\nimport std as s;\nsub vcl_recv {\n    set req.url = req.url.toupper\n}\n"
+                .to_string(),
+            None,
+        );
+        let completions = doc
+            .autocomplete_postfix(Position::new(3, 33), &scope)
+            .unwrap();
+        assert_eq!(
+            completions[0].text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit::new(
+                Range::new(Position::new(3, 18), Position::new(3, 33)),
+                "s.toupper(req.url)".to_string()
+            )))
+        );
+        assert_eq!(completions[0].additional_text_edits, None);
+        assert_eq!(doc.unalias_vmod_ident("s.fileread"), "std.fileread");
+        assert_eq!(doc.unalias_vmod_ident("req.url"), "req.url");
+    }
+
+    #[test]
+    fn resolve_include_across_vcl_paths() {
+        let dir = std::env::temp_dir().join(format!("varnishls-vcl-path-{}", std::process::id()));
+        let including_dir = dir.join("site");
+        let vcl_paths = vec![dir.join("first"), dir.join("second")];
+        for path in [&including_dir, &vcl_paths[0], &vcl_paths[1]] {
+            std::fs::create_dir_all(path).unwrap();
+        }
+        std::fs::write(including_dir.join("local.vcl"), "").unwrap();
+        std::fs::write(vcl_paths[0].join("local.vcl"), "").unwrap();
+        std::fs::write(vcl_paths[0].join("shared.vcl"), "").unwrap();
+        std::fs::write(vcl_paths[1].join("shared.vcl"), "").unwrap();
+        std::fs::write(vcl_paths[1].join("fallback.vcl"), "").unwrap();
+
+        let include = |path: &str| Include {
+            url: None,
+            path: PathBuf::from(path),
+            nested_pos: vec![],
+            including_dir: Some(including_dir.clone()),
+        };
+        let resolved_path = |path: &str| {
+            include(path)
+                .resolve(&vcl_paths)
+                .url
+                .map(|url| url.to_file_path().unwrap())
+        };
+
+        // the including directory first, then each vcl path in order
+        assert_eq!(
+            resolved_path("local.vcl"),
+            Some(including_dir.join("local.vcl"))
+        );
+        assert_eq!(
+            resolved_path("shared.vcl"),
+            Some(vcl_paths[0].join("shared.vcl"))
+        );
+        assert_eq!(
+            resolved_path("fallback.vcl"),
+            Some(vcl_paths[1].join("fallback.vcl"))
+        );
+
+        let missing = include("missing.vcl");
+        assert_eq!(
+            missing.get_search_paths(&vcl_paths),
+            vec![
+                including_dir.join("missing.vcl"),
+                vcl_paths[0].join("missing.vcl"),
+                vcl_paths[1].join("missing.vcl"),
+            ]
+        );
+        let missing = missing.resolve(&vcl_paths);
+        assert!(missing.url.is_none());
+        assert!(!missing.exists());
+
+        // «./» and «../» are only relative to the including directory
+        assert_eq!(
+            resolved_path("./local.vcl"),
+            Some(including_dir.join("./local.vcl"))
+        );
+        let relative = include("./fallback.vcl");
+        assert_eq!(
+            relative.get_search_paths(&vcl_paths),
+            vec![including_dir.join("./fallback.vcl")]
+        );
+        assert!(!relative.resolve(&vcl_paths).exists());
+        assert_eq!(
+            include("../first/shared.vcl").get_search_paths(&vcl_paths),
+            vec![including_dir.join("../first/shared.vcl")]
+        );
+
+        // absolute paths are not searched for
+        let absolute_path = vcl_paths[1].join("fallback.vcl");
+        let absolute = include(absolute_path.to_str().unwrap());
+        assert_eq!(absolute.get_search_paths(&vcl_paths), vec![absolute_path]);
+        assert!(absolute.resolve(&vcl_paths).exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
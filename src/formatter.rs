@@ -0,0 +1,395 @@
+use tower_lsp::lsp_types::FormattingOptions;
+use tree_sitter::{Node, Tree};
+
+use crate::config::{BraceStyle, FormatConfig};
+use crate::parser;
+
+/// Nodes formatted as a single token, even though they might have children
+const ATOMIC_NODE_KINDS: &[&str] = &["string", "COMMENT", "inline_c"];
+
+/// Declarations whose opening brace is placed by the brace style
+const BRACE_STYLE_NODE_KINDS: &[&str] = &[
+    "sub_declaration",
+    "backend_declaration",
+    "probe_declaration",
+    "acl_declaration",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatStyle {
+    pub indent: String,
+    pub brace_style: BraceStyle,
+    pub max_blank_lines: Option<usize>,
+}
+
+impl FormatStyle {
+    /// The configured style, with the indentation not configured taken from the client
+    pub fn new(config: &FormatConfig, options: &FormattingOptions) -> Self {
+        let use_tabs = config.use_tabs.unwrap_or(!options.insert_spaces);
+        let indent = if use_tabs {
+            "\t".to_string()
+        } else {
+            let indent_width = config
+                .indent_width
+                .filter(|indent_width| *indent_width > 0)
+                .unwrap_or(options.tab_size as usize);
+            " ".repeat(indent_width)
+        };
+        FormatStyle {
+            indent,
+            brace_style: config.brace_style(),
+            max_blank_lines: config.max_blank_lines,
+        }
+    }
+}
+
+impl Default for FormatStyle {
+    fn default() -> Self {
+        FormatStyle {
+            indent: "    ".to_string(),
+            brace_style: BraceStyle::default(),
+            max_blank_lines: None,
+        }
+    }
+}
+
+/**
+ * Re-indent a vcl document by its brace and parenthesis depth, and strip trailing whitespace.
+ * The opening braces of declarations are placed by the brace style, and blank lines beyond the
+ * max are removed.
+ *
+ * Only whitespace between tokens is changed, so the formatted document parses to the same tree,
+ * and formatting a formatted document is a no-op. Lines starting or ending within a multi-line
+ * token (e.g. a «{"…"}» string or inline C) are left as they are.
+ *
+ * Returns None if the document has syntax errors, as the tree can't be trusted.
+ */
+pub fn format_vcl(text: &str, tree: &Tree, style: &FormatStyle) -> Option<String> {
+    if tree.root_node().has_error() {
+        return None;
+    }
+
+    // moving braces only changes whitespace, so the tree of the result is the same
+    let text_with_braces = place_declaration_braces(text, tree, style.brace_style);
+    let reparsed_tree;
+    let (text, root_node) = match text_with_braces {
+        Some(ref text_with_braces) => {
+            reparsed_tree = parser::vcl().parse(text_with_braces, None)?;
+            (text_with_braces.as_str(), reparsed_tree.root_node())
+        }
+        None => (text, tree.root_node()),
+    };
+    let indent = style.indent.as_str();
+
+    let leaves = get_leaves(root_node);
+    let inside_token = |byte: usize| {
+        let idx = leaves.partition_point(|leaf| leaf.start_byte() < byte);
+        idx > 0 && byte < leaves[idx - 1].end_byte()
+    };
+
+    let mut formatted = String::with_capacity(text.len());
+    let mut leaf_idx = 0;
+    let mut brace_depth: usize = 0;
+    let mut paren_depth: usize = 0;
+    // last token before the current line, ignoring comments
+    let mut prev_kind: Option<&str> = None;
+    let mut line_start = 0;
+    let mut blank_lines = 0;
+
+    for line in text.split_inclusive('\n') {
+        let line_end = line_start + line.len();
+        let (content, line_ending) = match line.strip_suffix("\r\n") {
+            Some(content) => (content, "\r\n"),
+            None => match line.strip_suffix('\n') {
+                Some(content) => (content, "\n"),
+                None => (line, ""),
+            },
+        };
+        let content_end = line_start + content.len();
+
+        // the depth at the start of the line
+        while let Some(leaf) = leaves.get(leaf_idx) {
+            if leaf.start_byte() >= line_start {
+                break;
+            }
+            match leaf.kind() {
+                "{" => brace_depth += 1,
+                "}" => brace_depth = brace_depth.saturating_sub(1),
+                "(" => paren_depth += 1,
+                ")" => paren_depth = paren_depth.saturating_sub(1),
+                _ => {}
+            }
+            match leaf.kind() {
+                "COMMENT" => {}
+                // inline C is a statement of its own
+                "inline_c" => prev_kind = None,
+                kind => prev_kind = Some(kind),
+            }
+            leaf_idx += 1;
+        }
+
+        if inside_token(line_start) {
+            // e.g. the lines of a multi-line string
+            if inside_token(content_end) {
+                formatted.push_str(content);
+            } else {
+                formatted.push_str(content.trim_end());
+            }
+            formatted.push_str(line_ending);
+            line_start = line_end;
+            continue;
+        }
+
+        let trimmed = content.trim_start();
+        let trimmed = if inside_token(content_end) {
+            trimmed
+        } else {
+            trimmed.trim_end()
+        };
+        if trimmed.is_empty() {
+            blank_lines += 1;
+            if style
+                .max_blank_lines
+                .is_none_or(|max_blank_lines| blank_lines <= max_blank_lines)
+            {
+                formatted.push_str(line_ending);
+            }
+            line_start = line_end;
+            continue;
+        }
+        blank_lines = 0;
+
+        let first_kind = leaves
+            .get(leaf_idx)
+            .filter(|leaf| leaf.start_byte() < content_end)
+            .map(|leaf| leaf.kind());
+        let mut level = brace_depth + paren_depth;
+        if matches!(first_kind, Some("}" | ")")) {
+            level = level.saturating_sub(1);
+        }
+        // a statement continued from the previous line (e.g. a multi-line probe request)
+        let is_continuation = paren_depth == 0
+            && prev_kind.is_some_and(|kind| !matches!(kind, ";" | "{" | "}" | "(" | ","))
+            && !matches!(first_kind, Some("{" | "}" | ")"));
+        if is_continuation {
+            level += 1;
+        }
+
+        formatted.push_str(&indent.repeat(level));
+        formatted.push_str(trimmed);
+        formatted.push_str(line_ending);
+        line_start = line_end;
+    }
+
+    Some(formatted)
+}
+
+/**
+ * Move the opening brace of each declaration to the line of its name (same-line), or to the
+ * line right after it (next-line). Braces after a comment are left alone. Returns None if nothing moved.
+ */
+fn place_declaration_braces(text: &str, tree: &Tree, brace_style: BraceStyle) -> Option<String> {
+    let line_ending = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let leaves = get_leaves(tree.root_node());
+    let mut moved = String::with_capacity(text.len());
+    let mut copied_until = 0;
+    for (prev_leaf, leaf) in leaves.iter().zip(leaves.iter().skip(1)) {
+        let is_declaration_brace = leaf.kind() == "{"
+            && leaf
+                .parent()
+                .is_some_and(|parent| BRACE_STYLE_NODE_KINDS.contains(&parent.kind()));
+        if !is_declaration_brace || prev_leaf.kind() == "COMMENT" {
+            continue;
+        }
+        let whitespace = &text[prev_leaf.end_byte()..leaf.start_byte()];
+        let replacement = match brace_style {
+            BraceStyle::SameLine if whitespace != " " => " ",
+            BraceStyle::NextLine if whitespace.matches('\n').count() != 1 => line_ending,
+            _ => continue,
+        };
+        moved.push_str(&text[copied_until..prev_leaf.end_byte()]);
+        moved.push_str(replacement);
+        copied_until = leaf.start_byte();
+    }
+    if copied_until == 0 {
+        return None;
+    }
+    moved.push_str(&text[copied_until..]);
+    Some(moved)
+}
+
+/// The tokens of the tree in order, with e.g. strings as a single token
+fn get_leaves(root_node: Node) -> Vec<Node> {
+    let mut leaves = vec![];
+    let mut cursor = root_node.walk();
+    let mut recurse = true;
+    loop {
+        let node = cursor.node();
+        if recurse {
+            let is_leaf = node.child_count() == 0 || ATOMIC_NODE_KINDS.contains(&node.kind());
+            if is_leaf {
+                if !node.is_missing() && node.start_byte() < node.end_byte() {
+                    leaves.push(node);
+                }
+            } else if cursor.goto_first_child() {
+                continue;
+            }
+        }
+        if cursor.goto_next_sibling() {
+            recurse = true;
+        } else if cursor.goto_parent() {
+            recurse = false;
+        } else {
+            break;
+        }
+    }
+    leaves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    const CORPUS: &[(&str, &str)] = &[
+        ("test.vcl", include_str!("../test.vcl")),
+        ("probes.vcl", include_str!("../test-data/format/probes.vcl")),
+        ("nested.vcl", include_str!("../test-data/format/nested.vcl")),
+        (
+            "unformatted.vcl",
+            include_str!("../test-data/format/unformatted.vcl"),
+        ),
+        (
+            "unformatted.formatted.vcl",
+            include_str!("../test-data/format/unformatted.formatted.vcl"),
+        ),
+    ];
+
+    fn format(text: &str) -> Option<String> {
+        format_with_style(text, &FormatStyle::default())
+    }
+
+    fn format_with_style(text: &str, style: &FormatStyle) -> Option<String> {
+        let tree = parser::vcl().parse(text, None).unwrap();
+        format_vcl(text, &tree, style)
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for (name, text) in CORPUS {
+            let formatted = format(text).unwrap_or_else(|| panic!("{name} should format"));
+            assert_eq!(
+                format(&formatted).as_ref(),
+                Some(&formatted),
+                "formatting {name} twice should be a no-op"
+            );
+        }
+    }
+
+    #[test]
+    fn formatting_preserves_the_tree() {
+        for (name, text) in CORPUS {
+            let formatted = format(text).unwrap();
+            let tree = parser::vcl().parse(text, None).unwrap();
+            let formatted_tree = parser::vcl().parse(&formatted, None).unwrap();
+            assert_eq!(
+                tree.root_node().to_sexp(),
+                formatted_tree.root_node().to_sexp(),
+                "formatting {name} should not change its syntax tree"
+            );
+        }
+    }
+
+    #[test]
+    fn formatted_corpus_is_unchanged() {
+        for name in ["test.vcl", "probes.vcl", "nested.vcl"] {
+            let (_, text) = CORPUS.iter().find(|(n, _)| *n == name).unwrap();
+            assert_eq!(format(text).as_deref(), Some(*text), "{name} is formatted");
+        }
+    }
+
+    #[test]
+    fn format_unformatted() {
+        assert_eq!(
+            format(include_str!("../test-data/format/unformatted.vcl")).as_deref(),
+            Some(include_str!(
+                "../test-data/format/unformatted.formatted.vcl"
+            ))
+        );
+    }
+
+    #[test]
+    fn brace_styles() {
+        let text = include_str!("../test-data/format/braces.vcl");
+        let same_line = FormatStyle {
+            max_blank_lines: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_with_style(text, &same_line).as_deref(),
+            Some(include_str!("../test-data/format/braces.same-line.vcl"))
+        );
+
+        let next_line = FormatStyle {
+            indent: "  ".to_string(),
+            brace_style: BraceStyle::NextLine,
+            max_blank_lines: Some(1),
+        };
+        let formatted = format_with_style(text, &next_line).unwrap();
+        assert_eq!(
+            formatted,
+            include_str!("../test-data/format/braces.next-line.vcl")
+        );
+        assert_eq!(
+            format_with_style(&formatted, &next_line).as_ref(),
+            Some(&formatted)
+        );
+        assert_eq!(
+            parser::vcl()
+                .parse(text, None)
+                .unwrap()
+                .root_node()
+                .to_sexp(),
+            parser::vcl()
+                .parse(&formatted, None)
+                .unwrap()
+                .root_node()
+                .to_sexp()
+        );
+    }
+
+    #[test]
+    fn style_from_config_and_formatting_options() {
+        let options = FormattingOptions {
+            tab_size: 2,
+            insert_spaces: true,
+            ..Default::default()
+        };
+        let style = FormatStyle::new(&FormatConfig::default(), &options);
+        assert_eq!(style.indent, "  ");
+        assert_eq!(style.brace_style, BraceStyle::SameLine);
+
+        let config = FormatConfig {
+            indent_width: Some(8),
+            brace_style: Some("next-line".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(FormatStyle::new(&config, &options).indent, " ".repeat(8));
+        let config = FormatConfig {
+            use_tabs: Some(true),
+            ..config
+        };
+        let style = FormatStyle::new(&config, &options);
+        assert_eq!(style.indent, "\t");
+        assert_eq!(style.brace_style, BraceStyle::NextLine);
+    }
+
+    #[test]
+    fn keeps_line_endings_and_refuses_syntax_errors() {
+        assert_eq!(
+            format("sub vcl_recv {\r\nreturn (pass);\r\n}\r\n").as_deref(),
+            Some("sub vcl_recv {\r\n    return (pass);\r\n}\r\n")
+        );
+        assert_eq!(format("sub vcl_recv {\n  set req.url = ;\n"), None);
+    }
+}
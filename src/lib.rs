@@ -1,9 +1,12 @@
 pub mod backend;
 pub mod config;
 pub mod document;
+pub mod formatter;
 pub mod parser;
 pub mod safe_regex;
+pub mod sarif;
 pub mod static_autocomplete_items;
 pub mod varnish_builtins;
+pub mod varnishd;
 pub mod vcc;
 pub mod vmod;
@@ -6,10 +6,11 @@ use std::path::PathBuf;
 use std::process::ExitCode;
 use tokio::fs;
 use tower_lsp::lsp_types::{DiagnosticSeverity, Url};
-use tower_lsp::{LspService, Server};
+use tower_lsp::Server;
 
-use varnishls::backend::{read_config, Backend};
-use varnishls::document::Include;
+use varnishls::backend::{build_service, read_config, Backend};
+use varnishls::document::{dedup_lint_errors, get_call_cycle_errors, Document, Include};
+use varnishls::sarif::lint_errors_to_sarif;
 use varnishls::vmod::{read_vmod_lib, read_vmod_lib_by_name};
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
@@ -20,6 +21,13 @@ pub enum LintLevel {
     Hint,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum OutputFormat {
+    Text,
+    /// SARIF 2.1.0, e.g. for GitHub code scanning
+    Sarif,
+}
+
 #[derive(Debug, Parser)]
 #[clap(name = "varnishls")]
 #[clap(long_version(option_env!("LONG_VERSION").unwrap_or(env!("CARGO_PKG_VERSION"))))]
@@ -47,6 +55,8 @@ enum Command {
         file_path: PathBuf,
         #[clap(long, default_value = "error")]
         level: LintLevel,
+        #[clap(long, default_value = "text")]
+        format: OutputFormat,
         /// Debug mode
         #[clap(short, long)]
         debug: bool,
@@ -67,6 +77,18 @@ enum Command {
         /// Files to lint
         path: PathBuf,
     },
+
+    ParseTree {
+        /// File to parse
+        file_path: PathBuf,
+    },
+
+    /// Print the version, the config in effect and the vmods found, e.g. for issue reports
+    Status {
+        /// Print as JSON
+        #[clap(long)]
+        json: bool,
+    },
 }
 
 #[tokio::main]
@@ -99,14 +121,14 @@ async fn main() -> ExitCode {
                     let (mut stream, _) = listener.accept().await.unwrap();
                     tokio::spawn(async move {
                         let (input, output) = stream.split();
-                        let (service, socket) = LspService::build(Backend::new).finish();
+                        let (service, socket) = build_service();
                         Server::new(input, output, socket).serve(service).await;
                     });
                 }
             } else if stdio {
                 let stdin = tokio::io::stdin();
                 let stdout = tokio::io::stdout();
-                let (service, socket) = LspService::build(Backend::new).finish();
+                let (service, socket) = build_service();
                 Server::new(stdin, stdout, socket).serve(service).await;
             } else {
                 let mut cmd = Cli::command();
@@ -120,6 +142,7 @@ async fn main() -> ExitCode {
         Command::Lint {
             file_path,
             level,
+            format,
             debug,
         } => {
             if debug {
@@ -133,7 +156,12 @@ async fn main() -> ExitCode {
             debug!("lint start");
 
             let backend: Backend = Default::default();
+            if let Some(ref grammar_error) = backend.grammar_error {
+                eprintln!("{grammar_error}");
+                return ExitCode::from(1);
+            }
             let mut error_count = 0;
+            let mut sarif_errors = vec![];
 
             let severity_filter = match level {
                 LintLevel::Warning => DiagnosticSeverity::WARNING,
@@ -149,13 +177,14 @@ async fn main() -> ExitCode {
             let mut root_uri = Url::from_file_path(cwd).unwrap();
             // Fix workspace directory missing slash
             root_uri.set_path(format!("{}/", root_uri.path()).as_str());
-            backend.set_root_uri(root_uri).await;
+            backend.set_root_uri(root_uri.clone()).await;
             let initial_include_uri =
                 Url::from_file_path(fs::canonicalize(file_path.clone()).await.unwrap()).unwrap();
             let initial_include = Include {
                 url: Some(initial_include_uri.clone()),
                 path: file_path,
                 nested_pos: Default::default(),
+                including_dir: None,
             };
             backend.set_config(config.clone()).await;
             backend.read_new_includes(vec![initial_include]).await;
@@ -165,16 +194,34 @@ async fn main() -> ExitCode {
                 .get_all_definitions_across_all_documents(Some(&initial_include_uri))
                 .await;
 
+            let all_sub_calls = backend
+                .document_map
+                .iter()
+                .flat_map(|doc| doc.get_sub_calls())
+                .collect::<Vec<_>>();
+            let mut call_cycle_errors = get_call_cycle_errors(&all_sub_calls);
+
             for doc in backend.document_map.iter() {
                 let len_lines = doc.rope.len_lines();
                 // debug!("hei {}", doc.url);
-                let errors = doc.get_error_ranges(&scope, &config.lint);
+                let mut errors = doc.get_error_ranges(&scope, &config.lint);
+                let (mut doc_call_cycle_errors, other_call_cycle_errors) = call_cycle_errors
+                    .into_iter()
+                    .partition(|error| error.loc.uri == doc.url);
+                call_cycle_errors = other_call_cycle_errors;
+                errors.append(&mut doc_call_cycle_errors);
+                let errors = dedup_lint_errors(errors);
                 for error in errors {
                     if error.severity <= severity_filter {
                         if error.severity == DiagnosticSeverity::ERROR {
                             error_count += 1;
                         }
 
+                        if format == OutputFormat::Sarif {
+                            sarif_errors.push(error);
+                            continue;
+                        }
+
                         // print error message
                         println!(
                             "{}: {}:{}:{} - {}",
@@ -233,19 +280,28 @@ async fn main() -> ExitCode {
                 }
             }
 
-            println!("Took: {}ms", now.elapsed().as_millis());
+            match format {
+                OutputFormat::Text => println!("Took: {}ms", now.elapsed().as_millis()),
+                OutputFormat::Sarif => {
+                    let sarif = lint_errors_to_sarif(&sarif_errors, Some(&root_uri));
+                    println!("{}", serde_json::to_string_pretty(&sarif).unwrap());
+                }
+            }
 
             if error_count > 0 {
                 return ExitCode::from(1);
             }
         }
         Command::InspectVmod { name, path, json } => {
-            let vmod = if path.is_some() {
-                read_vmod_lib(name, PathBuf::from(path.unwrap()))
+            let vmod = if let Some(path) = path {
+                read_vmod_lib(name, PathBuf::from(path), Default::default())
                     .await
                     .expect("Failed to parse vmod")
             } else {
-                match read_vmod_lib_by_name(name, vec![]).await.unwrap() {
+                match read_vmod_lib_by_name(name, vec![], Default::default())
+                    .await
+                    .unwrap()
+                {
                     Some(vmod) => vmod,
                     None => {
                         panic!("VMOD not found");
@@ -265,6 +321,37 @@ async fn main() -> ExitCode {
             let scope = varnishls::vcc::parse_vcc(src);
             println!("scope: {:?}", scope);
         }
+        Command::ParseTree { file_path } => {
+            let src = fs::read_to_string(&file_path)
+                .await
+                .expect("Could not read file");
+            let url = Url::from_file_path(fs::canonicalize(&file_path).await.unwrap()).unwrap();
+
+            let doc = Document::new(url, src, None);
+            print!("{}", doc.get_parse_tree());
+        }
+        Command::Status { json } => {
+            let backend: Backend = Default::default();
+            let cwd = std::env::current_dir().unwrap();
+            let config = read_config(&cwd).await.unwrap_or_default();
+            backend.set_config(config).await;
+            // read the main vcl and its vmods, to count the vmods found
+            backend.read_main_vcl().await;
+            backend.get_all_definitions_across_all_documents(None).await;
+
+            let status = backend.get_status().await.unwrap();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status).unwrap());
+            } else {
+                println!("varnishls {}", status.version);
+                println!("flavor: {}", status.flavor.name());
+                println!("vmod_paths: {:?}", status.vmod_paths);
+                println!("vcc_paths: {:?}", status.vcc_paths);
+                println!("loaded vmods: {}", status.loaded_vmods);
+                println!("open documents: {}", status.open_documents);
+                println!("loaded documents: {}", status.loaded_documents);
+            }
+        }
     }
 
     ExitCode::SUCCESS
@@ -1,4 +1,4 @@
-use tree_sitter::Parser;
+use tree_sitter::{Language, Parser, LANGUAGE_VERSION, MIN_COMPATIBLE_LANGUAGE_VERSION};
 use tree_sitter_vcl;
 use tree_sitter_vtc;
 
@@ -17,3 +17,38 @@ pub fn vtc() -> Parser {
         .unwrap();
     parser
 }
+
+/**
+ * Check that the grammars can be loaded by the tree-sitter library varnishls is built with
+ * (which `vcl()` and `vtc()` would otherwise panic on), e.g. after regenerating a grammar with
+ * another tree-sitter cli version.
+ */
+pub fn check_grammars() -> Result<(), String> {
+    let vcl: Language = tree_sitter_vcl::LANGUAGE.into();
+    let vtc: Language = tree_sitter_vtc::LANGUAGE.into();
+    check_grammar_version("vcl", vcl.version())?;
+    check_grammar_version("vtc", vtc.version())
+}
+
+fn check_grammar_version(name: &str, version: usize) -> Result<(), String> {
+    if (MIN_COMPATIBLE_LANGUAGE_VERSION..=LANGUAGE_VERSION).contains(&version) {
+        return Ok(());
+    }
+    Err(format!(
+        "The tree-sitter-{name} grammar (language version {version}) is incompatible with the tree-sitter library (language versions {MIN_COMPATIBLE_LANGUAGE_VERSION}-{LANGUAGE_VERSION}). Regenerate it with «make tree-sitter-{name}» and rebuild varnishls."
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grammar_versions() {
+        assert!(check_grammar_version("vcl", LANGUAGE_VERSION).is_ok());
+        let err = check_grammar_version("vcl", LANGUAGE_VERSION + 1).unwrap_err();
+        assert!(err.starts_with("The tree-sitter-vcl grammar"));
+        assert!(err.contains("make tree-sitter-vcl"));
+        assert!(check_grammar_version("vtc", MIN_COMPATIBLE_LANGUAGE_VERSION - 1).is_err());
+    }
+}
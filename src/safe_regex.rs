@@ -25,6 +25,12 @@ pub fn is_regex_safe(re_str: String) -> Result<bool, SafeRegexError> {
     Ok(true)
 }
 
+/// Number of capture groups in a regex, not counting the implicit whole match group
+pub fn count_capture_groups(re_str: &str) -> Option<usize> {
+    let hir = regex_syntax::parse(re_str).ok()?;
+    Some(hir.properties().explicit_captures_len())
+}
+
 fn walk(ast: &Ast, star_height: u8, total_reps: &mut u8) -> bool {
     match ast {
         Ast::Concat(concat) => {
@@ -90,6 +96,14 @@ mod tests {
         assert_eq!(result, Err(SafeRegexError::StarHeightError));
     }
 
+    #[test]
+    fn capture_groups() {
+        assert_eq!(count_capture_groups(r"^/nyheter/i/(\w+)"), Some(1));
+        assert_eq!(count_capture_groups(r"^/(a|b)/(?:c)/(?P<d>\d+)"), Some(2));
+        assert_eq!(count_capture_groups(r"^/$"), Some(0));
+        assert_eq!(count_capture_groups("{{{{{{{{["), None);
+    }
+
     #[test]
     fn slow() {
         let re = ".*".repeat(26);
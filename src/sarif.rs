@@ -0,0 +1,338 @@
+use serde_json::{json, Value};
+use tower_lsp::lsp_types::{DiagnosticSeverity, Url};
+
+use crate::document::LintError;
+
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+/// Rule id for lints without a name of their own
+const DEFAULT_RULE_ID: &str = "vcl";
+
+/// Split a lint message into its rule id and the message, e.g. «[empty_builtin_sub] Empty …»
+fn split_rule_id(message: &str) -> (&str, &str) {
+    message
+        .strip_prefix('[')
+        .and_then(|message| message.split_once("] "))
+        .filter(|(rule_id, _)| rule_id.chars().all(|c| c.is_ascii_lowercase() || c == '_'))
+        .unwrap_or((DEFAULT_RULE_ID, message))
+}
+
+fn get_level(severity: DiagnosticSeverity) -> &'static str {
+    match severity {
+        DiagnosticSeverity::ERROR => "error",
+        DiagnosticSeverity::WARNING => "warning",
+        _ => "note",
+    }
+}
+
+/// The artifact location of a document, relative to root_uri when it is inside it
+fn get_artifact_location(uri: &Url, root_uri: Option<&Url>) -> Value {
+    match root_uri.and_then(|root_uri| root_uri.make_relative(uri)) {
+        Some(relative) if !relative.starts_with("../") => {
+            json!({ "uri": relative, "uriBaseId": "SRCROOT" })
+        }
+        _ => json!({ "uri": uri.as_str() }),
+    }
+}
+
+/**
+ * Render lint errors as a SARIF 2.1.0 log, e.g. for GitHub code scanning. Named lints (with a
+ * «[name]» message prefix) become rules of their own, the rest are reported as «vcl».
+ *
+ * Lines and columns are 1-based in SARIF, and columns count UTF-16 code units like LSP does.
+ */
+pub fn lint_errors_to_sarif(errors: &[LintError], root_uri: Option<&Url>) -> Value {
+    let mut rule_ids: Vec<&str> = vec![];
+    let results = errors
+        .iter()
+        .map(|error| {
+            let (rule_id, message) = split_rule_id(&error.message);
+            let rule_index = rule_ids
+                .iter()
+                .position(|id| *id == rule_id)
+                .unwrap_or_else(|| {
+                    rule_ids.push(rule_id);
+                    rule_ids.len() - 1
+                });
+            let range = error.loc.range;
+            json!({
+                "ruleId": rule_id,
+                "ruleIndex": rule_index,
+                "level": get_level(error.severity),
+                "message": { "text": message },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": get_artifact_location(&error.loc.uri, root_uri),
+                        "region": {
+                            "startLine": range.start.line + 1,
+                            "startColumn": range.start.character + 1,
+                            "endLine": range.end.line + 1,
+                            "endColumn": range.end.character + 1,
+                        },
+                    },
+                }],
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let rules = rule_ids
+        .iter()
+        .map(|rule_id| {
+            json!({
+                "id": rule_id,
+                "shortDescription": {
+                    "text": match *rule_id {
+                        DEFAULT_RULE_ID => "VCL diagnostic".to_string(),
+                        _ => format!("{rule_id} lint"),
+                    },
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut run = json!({
+        "tool": {
+            "driver": {
+                "name": "varnishls",
+                "version": env!("CARGO_PKG_VERSION"),
+                "rules": rules,
+            },
+        },
+        "columnKind": "utf16CodeUnits",
+        "results": results,
+    });
+    if let Some(root_uri) = root_uri {
+        run["originalUriBaseIds"] = json!({ "SRCROOT": { "uri": root_uri.as_str() } });
+    }
+
+    json!({
+        "$schema": SARIF_SCHEMA,
+        "version": "2.1.0",
+        "runs": [run],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower_lsp::lsp_types::{Location, Position, Range};
+
+    fn lint_error(uri: &str, message: &str, severity: DiagnosticSeverity) -> LintError {
+        LintError {
+            message: message.to_string(),
+            severity,
+            loc: Location {
+                uri: Url::parse(uri).unwrap(),
+                range: Range::new(Position::new(2, 4), Position::new(2, 10)),
+            },
+            data: None,
+            related_information: None,
+            tags: None,
+        }
+    }
+
+    #[test]
+    fn rule_ids() {
+        assert_eq!(
+            split_rule_id("[empty_builtin_sub] Empty vcl_recv."),
+            ("empty_builtin_sub", "Empty vcl_recv.")
+        );
+        assert_eq!(split_rule_id("Syntax error"), ("vcl", "Syntax error"));
+        assert_eq!(
+            split_rule_id("[varnishd -C] Symbol not found"),
+            ("vcl", "[varnishd -C] Symbol not found")
+        );
+    }
+
+    const SCHEMA: &str = include_str!("../test-data/sarif/sarif-schema-2.1.0.json");
+
+    /**
+     * Validate a value against a JSON schema, supporting the keywords the vendored SARIF schema
+     * uses. Returns the JSON pointers and reasons of the invalid values.
+     */
+    fn validate(value: &Value, schema: &Value, root_schema: &Value, path: &str) -> Vec<String> {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let definition = root_schema
+                .pointer(reference.trim_start_matches('#'))
+                .unwrap_or_else(|| panic!("{reference} is not in the schema"));
+            return validate(value, definition, root_schema, path);
+        }
+
+        let mut errors = vec![];
+        let type_matches = |r#type: &str| match r#type {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => panic!("unsupported type {type}"),
+        };
+        let types = match &schema["type"] {
+            Value::String(r#type) => vec![r#type.as_str()],
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !types.is_empty() && !types.into_iter().any(type_matches) {
+            errors.push(format!("{path}: expected type {}", schema["type"]));
+            return errors;
+        }
+        if let Some(allowed) = schema["enum"].as_array() {
+            if !allowed.contains(value) {
+                errors.push(format!("{path}: {value} is not one of {}", schema["enum"]));
+            }
+        }
+        if let (Some(minimum), Some(number)) = (schema["minimum"].as_i64(), value.as_i64()) {
+            if number < minimum {
+                errors.push(format!("{path}: {number} is less than {minimum}"));
+            }
+        }
+
+        if let Some(object) = value.as_object() {
+            for required in schema["required"].as_array().into_iter().flatten() {
+                if !object.contains_key(required.as_str().unwrap()) {
+                    errors.push(format!("{path}: missing required property {required}"));
+                }
+            }
+            for (key, property) in object {
+                let property_path = format!("{path}/{key}");
+                match (&schema["properties"][key], &schema["additionalProperties"]) {
+                    (Value::Null, Value::Bool(false)) => {
+                        errors.push(format!("{property_path}: not allowed"));
+                    }
+                    (Value::Null, Value::Object(_)) => errors.extend(validate(
+                        property,
+                        &schema["additionalProperties"],
+                        root_schema,
+                        &property_path,
+                    )),
+                    (Value::Null, _) => {}
+                    (property_schema, _) => errors.extend(validate(
+                        property,
+                        property_schema,
+                        root_schema,
+                        &property_path,
+                    )),
+                }
+            }
+        }
+        if let Some(items) = value.as_array() {
+            for (idx, item) in items.iter().enumerate() {
+                errors.extend(validate(
+                    item,
+                    &schema["items"],
+                    root_schema,
+                    &format!("{path}/{idx}"),
+                ));
+                if schema["uniqueItems"] == true && items[..idx].contains(item) {
+                    errors.push(format!("{path}/{idx}: duplicate item"));
+                }
+            }
+        }
+        if let Some(any_of) = schema["anyOf"].as_array() {
+            if !any_of
+                .iter()
+                .any(|schema| validate(value, schema, root_schema, path).is_empty())
+            {
+                errors.push(format!("{path}: matches none of {}", schema["anyOf"]));
+            }
+        }
+        errors
+    }
+
+    fn assert_valid_sarif(sarif: &Value) {
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        let errors = validate(sarif, &schema, &schema, "");
+        assert!(errors.is_empty(), "{errors:#?}");
+        // not expressible in the schema
+        for run in sarif["runs"].as_array().unwrap() {
+            let rules = &run["tool"]["driver"]["rules"];
+            for result in run["results"].as_array().unwrap() {
+                let rule_index = result["ruleIndex"].as_u64().unwrap() as usize;
+                assert_eq!(rules[rule_index]["id"], result["ruleId"]);
+            }
+        }
+    }
+
+    #[test]
+    fn sarif_schema_validation() {
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        let sarif = json!({
+            "version": "2.1.0",
+            "runs": [{
+                "tool": { "driver": { "rules": [{ "id": "vcl" }, { "id": "vcl" }] } },
+                "results": [{
+                    "level": "hint",
+                    "message": {},
+                    "locations": [{ "physicalLocation": { "region": { "startLine": 0 } } }],
+                    "hint": true,
+                }],
+            }],
+        });
+        assert_eq!(
+            validate(&sarif, &schema, &schema, ""),
+            vec![
+                "/runs/0/results/0/hint: not allowed",
+                "/runs/0/results/0/level: \"hint\" is not one of [\"none\",\"note\",\"warning\",\"error\"]",
+                "/runs/0/results/0/locations/0/physicalLocation/region/startLine: 0 is less than 1",
+                "/runs/0/results/0/locations/0/physicalLocation: matches none of [{\"required\":[\"address\"]},{\"required\":[\"artifactLocation\"]}]",
+                "/runs/0/results/0/message: matches none of [{\"required\":[\"text\"]},{\"required\":[\"id\"]}]",
+                "/runs/0/tool/driver: missing required property \"name\"",
+                "/runs/0/tool/driver/rules/1: duplicate item",
+            ]
+        );
+    }
+
+    #[test]
+    fn sarif_log() {
+        let root_uri = Url::parse("file:///repo/").unwrap();
+        let errors = vec![
+            lint_error(
+                "file:///repo/default.vcl",
+                "Syntax error",
+                DiagnosticSeverity::ERROR,
+            ),
+            lint_error(
+                "file:///repo/includes/recv.vcl",
+                "[empty_builtin_sub] Empty vcl_recv.",
+                DiagnosticSeverity::WARNING,
+            ),
+            lint_error(
+                "file:///etc/varnish/other.vcl",
+                "Not found",
+                DiagnosticSeverity::HINT,
+            ),
+        ];
+        let sarif = lint_errors_to_sarif(&errors, Some(&root_uri));
+        assert_valid_sarif(&sarif);
+
+        let run = &sarif["runs"][0];
+        assert_eq!(run["originalUriBaseIds"]["SRCROOT"]["uri"], "file:///repo/");
+        assert_eq!(run["tool"]["driver"]["rules"].as_array().unwrap().len(), 2);
+
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(results[0]["ruleId"], "vcl");
+        assert_eq!(results[0]["level"], "error");
+        assert_eq!(
+            results[0]["locations"][0]["physicalLocation"],
+            json!({
+                "artifactLocation": { "uri": "default.vcl", "uriBaseId": "SRCROOT" },
+                "region": { "startLine": 3, "startColumn": 5, "endLine": 3, "endColumn": 11 },
+            })
+        );
+        assert_eq!(results[1]["ruleId"], "empty_builtin_sub");
+        assert_eq!(results[1]["ruleIndex"], 1);
+        assert_eq!(results[1]["level"], "warning");
+        assert_eq!(results[1]["message"]["text"], "Empty vcl_recv.");
+        assert_eq!(
+            results[1]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "includes/recv.vcl"
+        );
+        assert_eq!(results[2]["ruleIndex"], 0);
+        assert_eq!(results[2]["level"], "note");
+        assert_eq!(
+            results[2]["locations"][0]["physicalLocation"]["artifactLocation"],
+            json!({ "uri": "file:///etc/varnish/other.vcl" })
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use tower_lsp::lsp_types::*;
 
+use crate::varnish_builtins::Type;
+
 fn get_if_completion() -> CompletionItem {
     CompletionItem {
         label: "if".to_string(),
@@ -42,6 +44,25 @@ pub fn source_file() -> Vec<CompletionItem> {
     statements
 }
 
+/// Literal values to suggest when comparing with a value of a type (e.g. «beresp.ttl > »)
+pub fn literals_for_type(r#type: &Type) -> Vec<CompletionItem> {
+    let literals: &[&str] = match r#type {
+        Type::Duration => &["0s", "1s", "10s", "1m", "5m", "1h", "1d", "1w"],
+        Type::Bool => &["true", "false"],
+        Type::Number => &["0", "1"],
+        _ => &[],
+    };
+    literals
+        .iter()
+        .map(|literal| CompletionItem {
+            label: literal.to_string(),
+            detail: Some(r#type.to_string()),
+            kind: Some(CompletionItemKind::VALUE),
+            ..Default::default()
+        })
+        .collect()
+}
+
 pub fn subroutine() -> Vec<CompletionItem> {
     vec![
         get_if_completion(),
@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 use std::mem::discriminant;
 use tower_lsp::lsp_types::Location;
@@ -47,6 +48,21 @@ impl Type {
             // match same type
             discriminant(self) == discriminant(other)
     }
+
+    /// Whether the value is converted to STRING when concatenated with a string, e.g. when
+    /// appended to a header
+    pub fn is_string_compatible(&self) -> bool {
+        !matches!(
+            self,
+            Type::Obj(_)
+                | Type::Func(_)
+                | Type::Acl
+                | Type::Sub
+                | Type::Probe
+                | Type::Blob
+                | Type::Body
+        )
+    }
 }
 
 impl std::fmt::Display for Type {
@@ -118,6 +134,38 @@ impl Definitions {
         scope.get_type_property(last_ident)
     }
 
+    /// The type returned by a chain of calls, e.g. «["a.b", "c"]» for «a.b().c()», following
+    /// the methods of the objects returned. None if a call in the chain returns nothing.
+    pub fn get_call_chain_return_type(&self, calls: &[&str]) -> Option<&Type> {
+        let (first_call, calls) = calls.split_first()?;
+        let mut func_type =
+            self.get_type_property_by_nested_idents(first_call.split('.').collect());
+        for call in calls {
+            let Some(Type::Func(func)) = func_type else {
+                return None;
+            };
+            let mut scope: &dyn HasTypeProperties = match func.r#return.as_deref() {
+                Some(Type::Obj(obj)) => obj,
+                _ => return None,
+            };
+            // the method might be nested in the object returned, e.g. «c.d» for «a.b().c.d()»
+            let mut idents = call.split('.').peekable();
+            while let Some(ident) = idents.next() {
+                func_type = scope.get_type_property(ident);
+                if idents.peek().is_some() {
+                    let Some(Type::Obj(obj)) = func_type else {
+                        return None;
+                    };
+                    scope = obj;
+                }
+            }
+        }
+        match func_type? {
+            Type::Func(func) => func.r#return.as_deref(),
+            _ => None,
+        }
+    }
+
     pub fn get_type_properties_by_idents(
         &self,
         idents: Vec<&str>,
@@ -142,7 +190,7 @@ impl Definitions {
                     if let Some(ref search_type) = options.search_type {
                         scope_contains_type(property, search_type, true)
                     } else if options.must_be_writable.unwrap_or(false) {
-                        let is_writable = scope.obj().map_or(false, |obj| !obj.read_only);
+                        let is_writable = scope.obj().is_some_and(|obj| !obj.read_only);
                         is_writable || scope_contains_writable(property)
                     } else {
                         // match on everything
@@ -200,6 +248,8 @@ pub struct Obj {
     pub read_only: bool,
     pub definition: Option<Definition>,
     pub is_http_headers: bool,
+    /// import name of the vmod, for objects created with «new» (e.g. «directors»)
+    pub vmod_name: Option<String>,
 }
 
 impl HasTypeProperties for Obj {
@@ -226,6 +276,133 @@ pub struct FuncArg {
     pub optional: bool,
     pub r#type: Option<Type>,
     pub default_value: Option<String>,
+    /// set if the argument is a format string, with placeholders to validate and complete
+    pub format: Option<FormatString>,
+    /// values a STRING argument is restricted to, passed as quoted strings (unlike the bare
+    /// values of an ENUM argument)
+    pub values: Option<Vec<String>>,
+}
+
+/// Syntax of the placeholders in a format string argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatString {
+    /// strftime(3) conversion specifications, e.g. «%Y-%m-%d»
+    Strftime,
+}
+
+/// Format string arguments of vmod functions, as (vmod, function, argument, format)
+const FORMAT_STRING_ARGS: &[(&str, &str, &str, FormatString)] = &[
+    ("std", "strftime", "format", FormatString::Strftime),
+    ("utils", "time_format", "format", FormatString::Strftime),
+];
+
+const STRFTIME_SPECIFIERS: &[(char, &str)] = &[
+    ('a', "Abbreviated weekday name (e.g. Thu)"),
+    ('A', "Full weekday name (e.g. Thursday)"),
+    ('b', "Abbreviated month name (e.g. Aug)"),
+    ('B', "Full month name (e.g. August)"),
+    ('c', "Date and time representation"),
+    ('C', "Century (e.g. 20)"),
+    ('d', "Day of the month, zero-padded (01-31)"),
+    ('D', "Short MM/DD/YY date, same as %m/%d/%y"),
+    ('e', "Day of the month, space-padded ( 1-31)"),
+    ('F', "Short YYYY-MM-DD date, same as %Y-%m-%d"),
+    ('g', "Week-based year, last two digits (00-99)"),
+    ('G', "Week-based year (e.g. 2024)"),
+    ('h', "Abbreviated month name, same as %b"),
+    ('H', "Hour in 24h format (00-23)"),
+    ('I', "Hour in 12h format (01-12)"),
+    ('j', "Day of the year (001-366)"),
+    ('k', "Hour in 24h format, space-padded ( 0-23)"),
+    ('l', "Hour in 12h format, space-padded ( 1-12)"),
+    ('m', "Month as a decimal number (01-12)"),
+    ('M', "Minute (00-59)"),
+    ('n', "New-line character"),
+    ('p', "AM or PM designation"),
+    ('r', "12-hour clock time (e.g. 02:55:02 PM)"),
+    ('R', "24-hour HH:MM time, same as %H:%M"),
+    ('s', "Seconds since the Epoch"),
+    ('S', "Second (00-61)"),
+    ('t', "Horizontal-tab character"),
+    ('T', "ISO 8601 time format (HH:MM:SS), same as %H:%M:%S"),
+    ('u', "ISO 8601 weekday as number with Monday as 1 (1-7)"),
+    (
+        'U',
+        "Week number with the first Sunday as the first day of week one (00-53)",
+    ),
+    ('V', "ISO 8601 week number (01-53)"),
+    ('w', "Weekday as a decimal number with Sunday as 0 (0-6)"),
+    (
+        'W',
+        "Week number with the first Monday as the first day of week one (00-53)",
+    ),
+    ('x', "Date representation"),
+    ('X', "Time representation"),
+    ('y', "Year, last two digits (00-99)"),
+    ('Y', "Year (e.g. 2024)"),
+    ('z', "ISO 8601 offset from UTC in timezone (e.g. +0100)"),
+    ('Z', "Timezone name or abbreviation (e.g. CET)"),
+    ('%', "A % sign"),
+];
+
+impl FormatString {
+    /// Valid placeholders and their descriptions
+    pub fn specifiers(&self) -> &'static [(char, &'static str)] {
+        match self {
+            FormatString::Strftime => STRFTIME_SPECIFIERS,
+        }
+    }
+
+    /// Invalid placeholders in a format string, as (byte offset, placeholder)
+    pub fn get_invalid_placeholders(&self, format: &str) -> Vec<(usize, String)> {
+        let mut invalid = vec![];
+        let mut chars = format.char_indices().peekable();
+        while let Some((idx, c)) = chars.next() {
+            if c != '%' {
+                continue;
+            }
+            // alternative representations, e.g. «%Ey» and «%Od»
+            let modifier = chars.next_if(|(_, c)| matches!(c, 'E' | 'O'));
+            match chars.next() {
+                Some((_, specifier))
+                    if self
+                        .specifiers()
+                        .iter()
+                        .any(|(valid, _)| *valid == specifier) => {}
+                Some((end_idx, specifier)) => {
+                    invalid.push((idx, format[idx..end_idx + specifier.len_utf8()].to_string()))
+                }
+                None => invalid.push((
+                    idx,
+                    format!(
+                        "%{}",
+                        modifier.map(|(_, c)| c.to_string()).unwrap_or_default()
+                    ),
+                )),
+            }
+        }
+        invalid
+    }
+}
+
+/// Mark the format string arguments of the functions of a vmod
+pub fn mark_format_string_args(vmod_name: &str, vmod_scope: &mut Type) {
+    let Type::Obj(vmod_obj) = vmod_scope else {
+        return;
+    };
+    for (vmod, func_name, arg_name, format) in FORMAT_STRING_ARGS {
+        if *vmod != vmod_name {
+            continue;
+        }
+        let Some(Type::Func(func)) = vmod_obj.properties.get_mut(*func_name) else {
+            continue;
+        };
+        for arg in func.args.iter_mut() {
+            if arg.name.as_deref() == Some(*arg_name) {
+                arg.format = Some(*format);
+            }
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -239,37 +416,97 @@ pub struct Func {
     pub restricted: Option<Vec<String>>,
 }
 
+impl FuncArg {
+    /// e.g. «[STRING name = "default"]»
+    pub fn get_label(&self) -> String {
+        let mut str = String::new();
+        if let Some(ref r#type) = self.r#type {
+            str.push_str(format!("{}", r#type).as_str());
+        }
+        if let Some(ref arg_name) = self.name {
+            str.push_str(format!(" {}", arg_name).as_str());
+        }
+        if let Some(ref default_value) = self.default_value {
+            str.push_str(format!(" = {}", default_value).as_str());
+        }
+        if self.optional {
+            str = format!("[{}]", str);
+        }
+        str
+    }
+}
+
 impl Func {
     pub fn get_signature_string(&self) -> String {
         format!(
             "({})",
             self.args
                 .iter()
-                .filter_map(|arg| {
-                    let mut str = String::new();
-                    if let Some(ref r#type) = arg.r#type {
-                        str.push_str(format!("{}", r#type).as_str());
-                    }
-                    if let Some(ref arg_name) = arg.name {
-                        str.push_str(format!(" {}", arg_name).as_str());
-                    }
-                    if let Some(ref default_value) = arg.default_value {
-                        str.push_str(format!(" = {}", default_value).as_str());
-                    }
-                    if arg.optional {
-                        str = format!("[{}]", str);
-                    }
-
-                    if !str.is_empty() {
-                        Some(str)
-                    } else {
-                        None
-                    }
-                })
+                .map(|arg| arg.get_label())
+                .filter(|str| !str.is_empty())
                 .collect::<Vec<String>>()
                 .join(", ")
         )
     }
+
+    /// Whether the function returns a BOOL, e.g. «std.healthy()», and fits in a condition
+    pub fn returns_bool(&self) -> bool {
+        match self.r#return.as_deref() {
+            Some(r#return) => matches!(r#return, Type::Bool),
+            None => self.ret_type.as_deref() == Some("BOOL"),
+        }
+    }
+
+    /// Whether a function restricted with $RESTRICT can be called from a builtin subroutine,
+    /// by the subroutine name or its context, e.g. «vcl_recv» or «client». Custom subroutines
+    /// might be called from anywhere, so they are always allowed.
+    pub fn is_callable_from(&self, sub_name: &str) -> bool {
+        let Some(ref restricted) = self.restricted else {
+            return true;
+        };
+        !sub_name.starts_with("vcl_")
+            || std::iter::once(sub_name)
+                .chain(get_sub_context(sub_name))
+                .any(|search| restricted.iter().any(|name| name == search))
+    }
+
+    /// Hover section listing where a restricted function can be called, noting when the
+    /// subroutine at point isn't one of them. None for unrestricted functions.
+    pub fn get_restriction_hover(&self, sub_name: Option<&str>) -> Option<String> {
+        let restricted = self.restricted.as_ref()?;
+        let mut value = format!("Valid in: {}", restricted.join(", "));
+        if let Some(sub_name) = sub_name.filter(|sub_name| !self.is_callable_from(sub_name)) {
+            value.push_str(&format!("\n\n**Not valid in {sub_name}**"));
+        }
+        Some(value)
+    }
+
+    /// e.g. «STRING», or «VOID» if the function returns nothing
+    pub fn get_return_type_label(&self) -> String {
+        match (&self.ret_type, &self.r#return) {
+            (Some(ret_type), _) => ret_type.to_string(),
+            (None, Some(r#return)) => format!("{}", r#return),
+            (None, None) => "VOID".to_string(),
+        }
+    }
+
+    /// Signature with return type, e.g. «STRING get(STRING name)»
+    pub fn get_signature_label(&self) -> String {
+        format!(
+            "{} {}{}",
+            self.get_return_type_label(),
+            self.name,
+            self.get_signature_string()
+        )
+    }
+
+    /// The vmod documentation, or the signature if the function is undocumented
+    pub fn get_documentation(&self) -> String {
+        match self.doc {
+            Some(ref doc) if !doc.trim().is_empty() => doc.to_string(),
+            _ => format!("```vcl\n{}\n```", self.get_signature_label()),
+        }
+    }
 }
 
 const DEFAULT_REQUEST_HEADERS: &[&str] = &[
@@ -340,11 +577,185 @@ pub const BACKEND_FIELDS: &[&str] = &[
     "proxy_header",
 ];
 
+/// VCL language version, as declared by e.g. «vcl 4.1;»
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VclVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl VclVersion {
+    pub const V4_0: VclVersion = VclVersion { major: 4, minor: 0 };
+    pub const V4_1: VclVersion = VclVersion { major: 4, minor: 1 };
+
+    pub fn parse(version_str: &str) -> Option<VclVersion> {
+        let (major, minor) = version_str.trim().split_once('.')?;
+        Some(VclVersion {
+            major: major.parse().ok()?,
+            minor: minor.parse().ok()?,
+        })
+    }
+}
+
+impl std::fmt::Display for VclVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+// VCL versions and a short summary of what they enable, shown when hovering «vcl 4.x;»
+const VCL_VERSION_SUMMARIES: &[(VclVersion, &str)] = &[
+    (
+        VclVersion::V4_0,
+        "Supported by Varnish 4.0 and later.\n\n\
+         The baseline VCL 4 syntax, with separate client side (`req`, `resp`) and backend \
+         side (`bereq`, `beresp`) subroutines.\n\n\
+         Compared to 4.1, backends can't use `.path` or `.resolve`, and `local.socket`, \
+         `local.endpoint`, `req.is_hitmiss` and `req.is_hitpass` are not available.",
+    ),
+    (
+        VclVersion::V4_1,
+        "Supported by Varnish 6.0 and later.\n\n\
+         Adds to 4.0:\n\
+         - Unix domain socket backends (`.path`)\n\
+         - `.resolve` on backends\n\
+         - `local.socket` and `local.endpoint`\n\
+         - `req.is_hitmiss` and `req.is_hitpass`",
+    ),
+];
+
+/// Markdown summary of what a VCL version enables
+pub fn get_vcl_version_summary(version: VclVersion) -> Option<&'static str> {
+    VCL_VERSION_SUMMARIES
+        .iter()
+        .find(|(summary_version, _)| *summary_version == version)
+        .map(|(_, summary)| *summary)
+}
+
+/// The vcl version replacing a variable and the variable replacing it, e.g. «resp.do_esi» for
+/// «req.esi» in VCL 4.1
+pub fn get_variable_replaced_in_vcl_version(variable: &str) -> Option<(VclVersion, &'static str)> {
+    match variable {
+        "req.esi" => Some((VclVersion::V4_1, "resp.do_esi")),
+        _ => None,
+    }
+}
+
+/// Backend fields not available in all VCL versions
+pub fn get_backend_field_min_vcl_version(field: &str) -> Option<VclVersion> {
+    match field {
+        "path" | "resolve" => Some(VclVersion::V4_1),
+        _ => None,
+    }
+}
+
+/**
+ * Whether a builtin variable (e.g. «resp.http.x-foo») is read-only. Some variables are always
+ * read-only, others depend on which builtin subroutine they're used from. sub_name is the
+ * enclosing subroutine, if any.
+ */
+pub fn is_variable_read_only(ident: &str, sub_name: Option<&str>) -> bool {
+    let parts = ident.split('.').collect::<Vec<_>>();
+    let prop = parts.get(1).copied().unwrap_or_default();
+    // only builtin subroutines are known to be called from a specific context
+    let sub_name = sub_name.filter(|sub_name| sub_name.starts_with("vcl_"));
+
+    match parts[0] {
+        "req_top" | "obj" | "server" | "local" | "remote" | "now" => true,
+        // client.identity can be set while handling a request, e.g. for the hash director
+        "client" => {
+            prop != "identity"
+                || sub_name.is_some_and(|sub_name| get_sub_context(sub_name) != Some("client"))
+        }
+        "req" => matches!(
+            prop,
+            "xid" | "restarts" | "is_hitmiss" | "is_hitpass" | "can_gzip"
+        ),
+        "bereq" => matches!(prop, "xid" | "retries" | "is_bgfetch" | "uncacheable"),
+        "beresp" => {
+            matches!(prop, "age" | "backend" | "was_304")
+                || sub_name.is_some_and(|sub_name| {
+                    !matches!(sub_name, "vcl_backend_response" | "vcl_backend_error")
+                })
+        }
+        "resp" => {
+            matches!(prop, "is_streaming" | "backend")
+                || sub_name.is_some_and(|sub_name| !matches!(sub_name, "vcl_deliver" | "vcl_synth"))
+        }
+        "sess" => prop == "xid",
+        _ => false,
+    }
+}
+
+/// Parse a VCL duration literal (e.g. «3.5s» or «2m») into seconds
+pub fn parse_duration(duration_str: &str) -> Option<f64> {
+    let duration_str = duration_str.trim();
+    let unit_idx = duration_str.find(|c: char| c.is_ascii_alphabetic())?;
+    let (number, unit) = duration_str.split_at(unit_idx);
+    let multiplier = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 60.0 * 60.0,
+        "d" => 60.0 * 60.0 * 24.0,
+        "w" => 60.0 * 60.0 * 24.0 * 7.0,
+        "y" => 60.0 * 60.0 * 24.0 * 365.0,
+        _ => return None,
+    };
+    Some(number.parse::<f64>().ok()? * multiplier)
+}
+
+/// Common service names (from /etc/services) a backend .port can be given as
+const SERVICE_PORTS: &[(&str, u16)] = &[
+    ("http", 80),
+    ("www", 80),
+    ("https", 443),
+    ("http-alt", 8080),
+    ("webcache", 8080),
+    ("ftp", 21),
+    ("ssh", 22),
+    ("smtp", 25),
+    ("domain", 53),
+    ("imap", 143),
+    ("imaps", 993),
+    ("pop3", 110),
+    ("pop3s", 995),
+    ("ldap", 389),
+    ("ldaps", 636),
+    ("mysql", 3306),
+    ("postgresql", 5432),
+    ("redis", 6379),
+    ("memcache", 11211),
+];
+
+/// The port number of a known service name, e.g. «https»
+pub fn get_service_port(name: &str) -> Option<u16> {
+    SERVICE_PORTS
+        .iter()
+        .find(|(service, _)| *service == name)
+        .map(|(_, port)| *port)
+}
+
+/// A backend .connect_timeout longer than this (in seconds) hints at a slow backend
+pub const LONG_CONNECT_TIMEOUT: f64 = 10.0;
+
+/// Sane default values for duration backend and probe properties
+pub fn get_duration_field_default(field: &str) -> Option<&'static str> {
+    match field {
+        "connect_timeout" => Some("3.5s"),
+        "first_byte_timeout" | "between_bytes_timeout" => Some("60s"),
+        "timeout" => Some("2s"),
+        "interval" => Some("5s"),
+        _ => None,
+    }
+}
+
 pub fn get_backend_field_types<'a>() -> HashMap<&'a str, Type> {
     HashMap::from([
         ("host", Type::String),
         ("port", Type::Number), // can be string
         ("path", Type::String),
+        ("resolve", Type::String), // 4.1, when to resolve .host (e.g. "now")
         ("host_header", Type::String),
         ("connect_timeout", Type::Duration),
         ("first_byte_timeout", Type::Duration),
@@ -364,9 +775,546 @@ pub fn get_backend_field_types<'a>() -> HashMap<&'a str, Type> {
     ])
 }
 
+/// Return type of commonly used vmod functions, for when the vmod couldn't be read, e.g. when
+/// its vcc file and shared object are not installed where varnishls looks
+pub fn get_known_vmod_func_return_type(ident: &str) -> Option<Type> {
+    match ident {
+        "std.fileread" | "std.tolower" | "std.toupper" => Some(Type::String),
+        _ => None,
+    }
+}
+
+/// Builtin state subroutines, which have a default implementation in builtin.vcl
+pub const BUILTIN_SUBROUTINES: &[&str] = &[
+    "vcl_recv",
+    "vcl_pipe",
+    "vcl_pass",
+    "vcl_hash",
+    "vcl_purge",
+    "vcl_miss",
+    "vcl_hit",
+    "vcl_deliver",
+    "vcl_synth",
+    "vcl_backend_fetch",
+    "vcl_backend_response",
+    "vcl_backend_error",
+    "vcl_init",
+    "vcl_fini",
+];
+
+/// What the builtin.vcl implementation of each state subroutine returns when nothing else
+/// applies, i.e. what falling through the end of the subroutine usually ends in
+const SUB_DEFAULT_RETURN_ACTIONS: &[(&str, &str)] = &[
+    ("vcl_recv", "hash"),
+    ("vcl_pipe", "pipe"),
+    ("vcl_pass", "fetch"),
+    ("vcl_hash", "lookup"),
+    ("vcl_purge", "synth(200, \"Purged\")"),
+    ("vcl_miss", "fetch"),
+    ("vcl_hit", "deliver"),
+    ("vcl_deliver", "deliver"),
+    ("vcl_synth", "deliver"),
+    ("vcl_backend_fetch", "fetch"),
+    ("vcl_backend_response", "deliver"),
+    ("vcl_backend_error", "deliver"),
+    ("vcl_init", "ok"),
+    ("vcl_fini", "ok"),
+];
+
+/// The action a builtin subroutine returns by default, e.g. «lookup» for vcl_hash
+pub fn get_sub_default_return_action(sub_name: &str) -> Option<&'static str> {
+    SUB_DEFAULT_RETURN_ACTIONS
+        .iter()
+        .find(|(name, _)| *name == sub_name)
+        .map(|(_, action)| *action)
+}
+
+/// Builtin subroutines in roughly the order a request goes through them
+pub const SUBROUTINE_EXECUTION_ORDER: &[&str] = &[
+    "vcl_init",
+    "vcl_recv",
+    "vcl_pipe",
+    "vcl_hash",
+    "vcl_purge",
+    "vcl_hit",
+    "vcl_miss",
+    "vcl_pass",
+    "vcl_backend_fetch",
+    "vcl_backend_response",
+    "vcl_backend_error",
+    "vcl_deliver",
+    "vcl_synth",
+    "vcl_fini",
+];
+
+/// The vmod `$Restrict` context a builtin subroutine runs in
+pub fn get_sub_context(sub_name: &str) -> Option<&'static str> {
+    match sub_name {
+        "vcl_recv" | "vcl_pipe" | "vcl_pass" | "vcl_hash" | "vcl_purge" | "vcl_miss"
+        | "vcl_hit" | "vcl_deliver" | "vcl_synth" => Some("client"),
+        "vcl_backend_fetch" | "vcl_backend_response" | "vcl_backend_error" => Some("backend"),
+        "vcl_init" | "vcl_fini" => Some("housekeeping"),
+        _ => None,
+    }
+}
+
+/// The builtin subroutines where a variable namespace exists. Other namespaces exist everywhere.
+pub const VARIABLE_SUBROUTINES: &[(&str, &[&str])] = &[
+    (
+        "req",
+        &[
+            "vcl_recv",
+            "vcl_deliver",
+            "vcl_synth",
+            "vcl_miss",
+            "vcl_hit",
+            "vcl_pass",
+            "vcl_purge",
+            "vcl_pipe",
+            "vcl_hash",
+        ],
+    ),
+    (
+        "req_top",
+        &[
+            "vcl_recv",
+            "vcl_deliver",
+            "vcl_synth",
+            "vcl_miss",
+            "vcl_hit",
+            "vcl_pass",
+            "vcl_purge",
+            "vcl_pipe",
+            "vcl_hash",
+        ],
+    ),
+    (
+        "bereq",
+        &[
+            "vcl_backend_fetch",
+            "vcl_backend_response",
+            "vcl_pipe",
+            "vcl_backend_error",
+        ],
+    ),
+    ("beresp", &["vcl_backend_response", "vcl_backend_error"]),
+    ("resp", &["vcl_deliver", "vcl_miss", "vcl_synth"]),
+    ("obj", &["vcl_hit", "vcl_deliver"]),
+    // the response stored in the object is only readable before delivery starts
+    ("obj.http", &["vcl_hit"]),
+    ("obj.proto", &["vcl_hit"]),
+    ("obj.status", &["vcl_hit"]),
+    ("obj.reason", &["vcl_hit"]),
+    ("obj.uncacheable", &["vcl_deliver"]),
+    // the connection is known while handling a request, but not in vcl_init and vcl_fini
+    ("client", CLIENT_AND_BACKEND_SUBS),
+    ("server.ip", CLIENT_AND_BACKEND_SUBS),
+    ("local", CLIENT_AND_BACKEND_SUBS),
+    ("remote", CLIENT_AND_BACKEND_SUBS),
+];
+
+const CLIENT_AND_BACKEND_SUBS: &[&str] = &[
+    "vcl_recv",
+    "vcl_pipe",
+    "vcl_pass",
+    "vcl_hash",
+    "vcl_purge",
+    "vcl_miss",
+    "vcl_hit",
+    "vcl_deliver",
+    "vcl_synth",
+    "vcl_backend_fetch",
+    "vcl_backend_response",
+    "vcl_backend_error",
+];
+
+/// The most specific entry of VARIABLE_SUBROUTINES for a variable, e.g. «obj.http» for
+/// «obj.http.x-foo»
+pub fn get_variable_subroutines(variable: &str) -> Option<(&'static str, &'static [&'static str])> {
+    VARIABLE_SUBROUTINES
+        .iter()
+        .filter(|(name, _)| {
+            variable
+                .strip_prefix(name)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        })
+        .max_by_key(|(name, _)| name.len())
+        .copied()
+}
+
+/// Whether e.g. «beresp» or «obj.status» exists in the builtin subroutine. Variables the flavor
+/// removed exist nowhere.
+pub fn is_variable_available_in_sub(flavor: VarnishFlavor, variable: &str, sub_name: &str) -> bool {
+    get_removed_variable_replacement(flavor, variable).is_none()
+        && get_variable_subroutines(variable).is_none_or(|(_, subs)| subs.contains(&sub_name))
+}
+
+/// Descriptions of builtin variables, from the vcl-var documentation
+const VARIABLE_DOCS: &[(&str, &str)] = &[
+    ("obj.ttl", "The object's remaining time to live."),
+    ("obj.grace", "The object's remaining grace period."),
+    ("obj.keep", "The object's remaining keep period."),
+    ("obj.age", "The age of the object."),
+    (
+        "obj.hits",
+        "The count of cache hits on this object. A value of 0 in vcl_deliver indicates a cache miss.",
+    ),
+    (
+        "obj.uncacheable",
+        "Whether the object is uncacheable (pass, hit-for-pass or hit-for-miss).",
+    ),
+    ("obj.proto", "The HTTP protocol version stored in the object."),
+    ("obj.status", "The HTTP status code stored in the object."),
+    ("obj.reason", "The HTTP reason phrase stored in the object."),
+    (
+        "obj.time",
+        "The time the object was created, from the perspective of the server which generated it. Roughly «now - obj.age».",
+    ),
+    (
+        "obj.can_esi",
+        "Whether the object can be ESI processed, i.e. whether setting resp.do_esi in vcl_deliver would process the response body.",
+    ),
+    ("obj.http", "The HTTP headers stored in the object."),
+    ("client.ip", "The client's IP address, either the same as remote.ip or what the PROXY protocol told us."),
+    (
+        "client.identity",
+        "Identification of the client, used to load balance in the client director. Defaults to client.ip.",
+    ),
+    ("server.ip", "The IP address of the socket on which the client connection was received, either the same as local.ip or what the PROXY protocol told us."),
+    ("server.hostname", "The host name of the server, as returned by the gethostname(3) system function."),
+    ("server.identity", "The identity of the server, as set by the -i parameter. If -i is not passed to varnishd, the return value from gethostname(3) system function will be used."),
+    ("local.ip", "The IP address (and port number) of the local end of the TCP connection, for instance 192.168.1.1:81."),
+    ("local.endpoint", "The address of the -a socket the session was accepted on."),
+    ("local.socket", "The name of the -a socket the session was accepted on."),
+    ("remote.ip", "The IP address of the other end of the TCP connection. This can either be the clients IP, or the outgoing IP of a proxy server."),
+];
+
+/// Description of a builtin variable, e.g. «obj.ttl». Headers (e.g. «obj.http.x-foo») get the
+/// description of their object's headers.
+pub fn get_variable_doc(variable: &str) -> Option<&'static str> {
+    let variable = variable.to_lowercase();
+    let mut parts = variable.splitn(3, '.');
+    let variable = match (parts.next(), parts.next()) {
+        (Some(namespace), Some("http")) => format!("{namespace}.http"),
+        _ => variable.clone(),
+    };
+    VARIABLE_DOCS
+        .iter()
+        .find(|(name, _)| *name == variable)
+        .map(|(_, doc)| *doc)
+}
+
+/// The contexts (e.g. «backend») a variable namespace exists in, if it doesn't exist everywhere.
+/// Variables the flavor removed exist in no context.
+pub fn get_variable_contexts(flavor: VarnishFlavor, namespace: &str) -> Option<Vec<&'static str>> {
+    if get_removed_variable_replacement(flavor, namespace).is_some() {
+        return Some(vec![]);
+    }
+    let (_, subs) = VARIABLE_SUBROUTINES
+        .iter()
+        .find(|(name, _)| *name == namespace)?;
+    let mut contexts = vec![];
+    for context in subs.iter().filter_map(|sub_name| get_sub_context(sub_name)) {
+        if !contexts.contains(&context) {
+            contexts.push(context);
+        }
+    }
+    Some(contexts)
+}
+
+/// The Varnish release a vcl is written for, selecting e.g. the return actions of each subroutine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum VarnishFlavor {
+    #[serde(rename = "varnish-6.0")]
+    Varnish60,
+    #[default]
+    #[serde(rename = "varnish-plus-6.0")]
+    VarnishPlus60,
+    #[serde(rename = "varnish-7")]
+    Varnish7,
+}
+
+// return actions allowed in each builtin subroutine in Varnish 6.0, from libvcc's generate.py
+const RETURN_ACTIONS_6_0: &[(&str, &[&str])] = &[
+    (
+        "vcl_recv",
+        &[
+            "fail", "synth", "restart", "pass", "pipe", "hash", "purge", "vcl",
+        ],
+    ),
+    ("vcl_pipe", &["fail", "synth", "pipe"]),
+    ("vcl_pass", &["fail", "synth", "restart", "fetch"]),
+    ("vcl_hash", &["fail", "lookup"]),
+    ("vcl_purge", &["fail", "synth", "restart"]),
+    ("vcl_miss", &["fail", "synth", "restart", "pass", "fetch"]),
+    (
+        "vcl_hit",
+        &["fail", "synth", "restart", "pass", "miss", "deliver"],
+    ),
+    ("vcl_deliver", &["fail", "synth", "restart", "deliver"]),
+    ("vcl_synth", &["fail", "restart", "deliver"]),
+    ("vcl_backend_fetch", &["fail", "fetch", "abandon"]),
+    (
+        "vcl_backend_response",
+        &["fail", "deliver", "retry", "abandon", "pass"],
+    ),
+    (
+        "vcl_backend_error",
+        &["fail", "deliver", "retry", "abandon"],
+    ),
+    ("vcl_init", &["ok", "fail"]),
+    ("vcl_fini", &["ok"]),
+];
+
+// Varnish 7 dropped return(miss) from vcl_hit, and added return(error) to the backend fetch subs
+const RETURN_ACTIONS_7: &[(&str, &[&str])] = &[
+    (
+        "vcl_recv",
+        &[
+            "fail", "synth", "restart", "pass", "pipe", "hash", "purge", "vcl",
+        ],
+    ),
+    ("vcl_pipe", &["fail", "synth", "pipe"]),
+    ("vcl_pass", &["fail", "synth", "restart", "fetch"]),
+    ("vcl_hash", &["fail", "lookup"]),
+    ("vcl_purge", &["fail", "synth", "restart"]),
+    ("vcl_miss", &["fail", "synth", "restart", "pass", "fetch"]),
+    ("vcl_hit", &["fail", "synth", "restart", "pass", "deliver"]),
+    ("vcl_deliver", &["fail", "synth", "restart", "deliver"]),
+    ("vcl_synth", &["fail", "restart", "deliver"]),
+    ("vcl_backend_fetch", &["fail", "fetch", "abandon", "error"]),
+    (
+        "vcl_backend_response",
+        &["fail", "deliver", "retry", "abandon", "pass", "error"],
+    ),
+    (
+        "vcl_backend_error",
+        &["fail", "deliver", "retry", "abandon"],
+    ),
+    ("vcl_init", &["ok", "fail"]),
+    ("vcl_fini", &["ok"]),
+];
+
+// variables deprecated in Varnish 6.0, and what to use instead. «req.esi» depends on the vcl
+// version instead, see get_variable_replaced_in_vcl_version()
+const DEPRECATED_VARIABLES_6_0: &[(&str, &str)] = &[("beresp.storage_hint", "beresp.storage")];
+
+// none of the variables in the Varnish 7.x docs are deprecated
+const DEPRECATED_VARIABLES_7: &[(&str, &str)] = &[];
+
+// variables removed in Varnish 7.0, and what to use instead
+const REMOVED_VARIABLES_7: &[(&str, &str)] = &[("beresp.storage_hint", "beresp.storage")];
+
+impl VarnishFlavor {
+    pub const ALL: &'static [VarnishFlavor] = &[
+        VarnishFlavor::Varnish60,
+        VarnishFlavor::VarnishPlus60,
+        VarnishFlavor::Varnish7,
+    ];
+
+    /// The name used in the config and in «# varnish-lsp flavor: …» comments
+    pub fn name(&self) -> &'static str {
+        match self {
+            VarnishFlavor::Varnish60 => "varnish-6.0",
+            VarnishFlavor::VarnishPlus60 => "varnish-plus-6.0",
+            VarnishFlavor::Varnish7 => "varnish-7",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<VarnishFlavor> {
+        Self::ALL
+            .iter()
+            .find(|flavor| flavor.name() == name)
+            .copied()
+    }
+
+    fn return_actions(&self) -> &'static [(&'static str, &'static [&'static str])] {
+        match self {
+            // varnish plus 6.0 follows the 6.0 LTS release here
+            VarnishFlavor::Varnish60 | VarnishFlavor::VarnishPlus60 => RETURN_ACTIONS_6_0,
+            VarnishFlavor::Varnish7 => RETURN_ACTIONS_7,
+        }
+    }
+
+    fn deprecated_variables(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            VarnishFlavor::Varnish60 | VarnishFlavor::VarnishPlus60 => DEPRECATED_VARIABLES_6_0,
+            VarnishFlavor::Varnish7 => DEPRECATED_VARIABLES_7,
+        }
+    }
+
+    fn removed_variables(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            VarnishFlavor::Varnish60 | VarnishFlavor::VarnishPlus60 => &[],
+            VarnishFlavor::Varnish7 => REMOVED_VARIABLES_7,
+        }
+    }
+}
+
+/// The variable to use instead of a deprecated one, e.g. «beresp.storage» for
+/// «beresp.storage_hint»
+pub fn get_deprecated_variable_replacement(
+    flavor: VarnishFlavor,
+    variable: &str,
+) -> Option<&'static str> {
+    flavor
+        .deprecated_variables()
+        .iter()
+        .find(|(name, _)| *name == variable)
+        .map(|(_, replacement)| *replacement)
+}
+
+/// The variable to use instead of one the flavor no longer has
+pub fn get_removed_variable_replacement(
+    flavor: VarnishFlavor,
+    variable: &str,
+) -> Option<&'static str> {
+    flavor
+        .removed_variables()
+        .iter()
+        .find(|(name, _)| *name == variable)
+        .map(|(_, replacement)| *replacement)
+}
+
+/// The actions «return (…)» accepts in a builtin subroutine
+pub fn get_sub_return_actions(
+    flavor: VarnishFlavor,
+    sub_name: &str,
+) -> Option<&'static [&'static str]> {
+    flavor
+        .return_actions()
+        .iter()
+        .find(|(name, _)| *name == sub_name)
+        .map(|(_, actions)| *actions)
+}
+
+/// The builtin subroutines «return (…)» accepts an action in, e.g. vcl_recv for purge
+pub fn get_return_action_subs(flavor: VarnishFlavor, action: &str) -> Vec<&'static str> {
+    flavor
+        .return_actions()
+        .iter()
+        .filter(|(_, actions)| actions.contains(&action))
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Fields ban expressions can test. Bans are matched against cached objects, so the backend
+/// request and response aren't available.
+pub const BAN_FIELDS: &[&str] = &[
+    "req.url",
+    "req.http.",
+    "obj.status",
+    "obj.http.",
+    "obj.ttl",
+    "obj.age",
+    "obj.grace",
+    "obj.keep",
+];
+
+/**
+ * Fields of a ban expression (e.g. «obj.http.x-url ~ ^/a && req.http.host == a») that can't be
+ * tested by a ban. With skip_first, the first condition is a continuation of a value, e.g. the
+ * expression is a string literal after a concatenated value.
+ */
+pub fn get_invalid_ban_fields(expression: &str, skip_first: bool) -> Vec<&str> {
+    expression
+        .split("&&")
+        .skip(if skip_first { 1 } else { 0 })
+        .filter_map(|condition| {
+            let field = condition
+                .trim_start()
+                .split(|c: char| c.is_whitespace() || matches!(c, '=' | '!' | '~' | '<' | '>'))
+                .next()?;
+            let is_valid = field.is_empty()
+                || BAN_FIELDS
+                    .iter()
+                    .any(|ban_field| match ban_field.strip_suffix('.') {
+                        // headers, e.g. «obj.http.»
+                        Some(_) => field.starts_with(ban_field) && field.len() > ban_field.len(),
+                        None => field == *ban_field,
+                    });
+            (!is_valid).then_some(field)
+        })
+        .collect()
+}
+
+/// HTTP methods known not to be typos when comparing req.method or bereq.method
+pub const HTTP_METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH", "PURGE", "BAN",
+];
+
+/// Well-known HTTP headers, which header names one or two edits away from are likely typos
+pub const HTTP_HEADERS: &[&str] = &[
+    "Accept",
+    "Accept-Charset",
+    "Accept-Encoding",
+    "Accept-Language",
+    "Accept-Ranges",
+    "Access-Control-Allow-Credentials",
+    "Access-Control-Allow-Headers",
+    "Access-Control-Allow-Methods",
+    "Access-Control-Allow-Origin",
+    "Access-Control-Expose-Headers",
+    "Access-Control-Max-Age",
+    "Access-Control-Request-Headers",
+    "Access-Control-Request-Method",
+    "Age",
+    "Allow",
+    "Authorization",
+    "Cache-Control",
+    "Connection",
+    "Content-Disposition",
+    "Content-Encoding",
+    "Content-Language",
+    "Content-Length",
+    "Content-Location",
+    "Content-Range",
+    "Content-Security-Policy",
+    "Content-Type",
+    "Cookie",
+    "Date",
+    "ETag",
+    "Expect",
+    "Expires",
+    "Forwarded",
+    "From",
+    "Host",
+    "If-Match",
+    "If-Modified-Since",
+    "If-None-Match",
+    "If-Range",
+    "If-Unmodified-Since",
+    "Keep-Alive",
+    "Last-Modified",
+    "Link",
+    "Location",
+    "Origin",
+    "Pragma",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Range",
+    "Referer",
+    "Retry-After",
+    "Server",
+    "Set-Cookie",
+    "Strict-Transport-Security",
+    "Surrogate-Control",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "User-Agent",
+    "Vary",
+    "Via",
+    "WWW-Authenticate",
+    "X-Forwarded-For",
+    "X-Forwarded-Host",
+    "X-Forwarded-Proto",
+];
+
 pub const RETURN_METHODS: &[&str] = &[
     "hit", "miss", "pass", "pipe", "retry", "restart", "fail", "synth", "hash", "deliver",
-    "abandon", "lookup", "error", "purge",
+    "abandon", "lookup", "error", "purge", "vcl",
 ];
 pub fn get_varnish_builtins() -> Definitions {
     let req: Type = Type::Obj(Obj {
@@ -401,6 +1349,7 @@ pub fn get_varnish_builtins() -> Definitions {
             ("can_gzip".to_string(), Type::Bool),
             ("hash_ignore_busy".to_string(), Type::Bool),
             ("hash_always_miss".to_string(), Type::Bool),
+            ("esi".to_string(), Type::Bool),
             ("xid".to_string(), Type::String),
         ]),
         ..Obj::default()
@@ -481,6 +1430,7 @@ pub fn get_varnish_builtins() -> Definitions {
             ("reason".to_string(), Type::String),
             ("backend".to_string(), Type::Backend),
             ("is_streaming".to_string(), Type::Bool),
+            ("do_esi".to_string(), Type::Bool),
             ("body".to_string(), Type::Body),
         ]),
         ..Obj::default()
@@ -537,6 +1487,11 @@ pub fn get_varnish_builtins() -> Definitions {
             ("age".to_string(), Type::Duration),
             ("hits".to_string(), Type::Number),
             ("uncacheable".to_string(), Type::Bool),
+            ("proto".to_string(), Type::String),
+            ("status".to_string(), Type::Number),
+            ("reason".to_string(), Type::String),
+            ("time".to_string(), Type::Time),
+            ("can_esi".to_string(), Type::Bool),
             (
                 "http".to_string(),
                 Type::Obj(Obj {
@@ -563,7 +1518,7 @@ pub fn get_varnish_builtins() -> Definitions {
         name: "client".to_string(),
         read_only: true,
         properties: BTreeMap::from([
-            ("ip".to_string(), Type::String),
+            ("ip".to_string(), Type::IP),
             ("identity".to_string(), Type::String),
         ]),
         ..Obj::default()
@@ -573,7 +1528,7 @@ pub fn get_varnish_builtins() -> Definitions {
         name: "server".to_string(),
         read_only: true,
         properties: BTreeMap::from([
-            ("ip".to_string(), Type::String),
+            ("ip".to_string(), Type::IP),
             ("hostname".to_string(), Type::String),
             ("identity".to_string(), Type::String),
         ]),
@@ -584,7 +1539,7 @@ pub fn get_varnish_builtins() -> Definitions {
         name: "local".to_string(),
         read_only: true,
         properties: BTreeMap::from([
-            ("ip".to_string(), Type::String),
+            ("ip".to_string(), Type::IP),
             ("endpoint".to_string(), Type::String),
             ("socket".to_string(), Type::String),
         ]),
@@ -594,7 +1549,7 @@ pub fn get_varnish_builtins() -> Definitions {
     let remote: Type = Type::Obj(Obj {
         name: "remote".to_string(),
         read_only: true,
-        properties: BTreeMap::from([("ip".to_string(), Type::String)]),
+        properties: BTreeMap::from([("ip".to_string(), Type::IP)]),
         ..Obj::default()
     });
 
@@ -0,0 +1,403 @@
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tower_lsp::lsp_types::{Position, Range};
+
+/// A location in varnishd's output, with 0-based line. The column and length are as printed,
+/// with tabs expanded to the next multiple of 8.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompilerLocation {
+    pub path: PathBuf,
+    pub line: u32,
+    pub column: u32,
+    pub length: u32,
+}
+
+/// An error reported by `varnishd -C`, with the locations of its context (e.g. the subroutine
+/// it is in, or where a redefined symbol was first defined)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompilerError {
+    pub location: CompilerLocation,
+    pub message: String,
+    pub related: Vec<(CompilerLocation, String)>,
+}
+
+/// Lines in varnishd's output which aren't part of an error message
+fn is_boilerplate_line(line: &str) -> bool {
+    line.is_empty()
+        || line == "Error:"
+        || line == "Message from VCC-compiler:"
+        || line == "VCL compilation failed"
+        || line == "(That was just a warning)"
+        || line.starts_with("Running VCC-compiler failed")
+        || (line.starts_with("(program line ") && line.ends_with("), at"))
+}
+
+/// Messages describing the context of the previous error, rather than a new error
+fn is_related_message(message: &str) -> bool {
+    message.starts_with("...") || message.starts_with("First defined") || message.is_empty()
+}
+
+/// Parse the location of an error, e.g. «('/etc/varnish/default.vcl' Line 5 Pos 9)»
+fn parse_location(line: &str) -> Option<(PathBuf, u32, u32)> {
+    let line = line.strip_prefix("('")?.strip_suffix(')')?;
+    let (path, position) = line.rsplit_once("' Line ")?;
+    let (line_nr, column) = position.split_once(" Pos ")?;
+    Some((
+        path.into(),
+        line_nr.parse::<u32>().ok()?.checked_sub(1)?,
+        column.parse::<u32>().ok()?.checked_sub(1)?,
+    ))
+}
+
+/// Convert a column with tabs expanded to the next multiple of 8 (as varnishd prints them)
+/// into a character column of the line
+pub fn visual_to_char_column(line_text: &str, visual_column: u32) -> u32 {
+    let mut visual = 0;
+    for (char_column, c) in line_text.chars().enumerate() {
+        if visual >= visual_column {
+            return char_column as u32;
+        }
+        visual = match c {
+            '\t' => (visual & !7) + 8,
+            _ => visual + 1,
+        };
+    }
+    line_text.chars().count() as u32
+}
+
+/// The range of a location, given the text of its line when available
+pub fn location_range(location: &CompilerLocation, line_text: Option<&str>) -> Range {
+    let (start, end) = match line_text {
+        Some(line_text) => (
+            visual_to_char_column(line_text, location.column),
+            visual_to_char_column(line_text, location.column + location.length),
+        ),
+        None => (location.column, location.column + location.length),
+    };
+    Range::new(
+        Position::new(location.line, start),
+        Position::new(location.line, end),
+    )
+}
+
+/// Resolve a path in varnishd's output, which is relative to the working directory or one of
+/// the vcl_paths when the file was given or included with a relative path
+pub fn resolve_path(path: &Path, main_vcl: &Path, vcl_paths: &[PathBuf]) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+    main_vcl
+        .parent()
+        .into_iter()
+        .chain(vcl_paths.iter().map(PathBuf::as_path))
+        .map(|dir| dir.join(path))
+        .find(|candidate| candidate.is_file())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/**
+ * Parse the errors printed by `varnishd -C`. Each error is a message followed by its location,
+ * the source line and a line marking the offending token, e.g.:
+ *
+ * ```text
+ * Message from VCC-compiler:
+ * Symbol not found: 'foo' (expected type BOOL):
+ * ('/etc/varnish/default.vcl' Line 8 Pos 9)
+ *     if (foo) {
+ * --------###---
+ * ```
+ *
+ * Blocks following an error, starting with e.g. «...in subroutine» or «First defined at:»,
+ * are added to its related locations. Errors without a location (e.g. a vmod failing to load)
+ * are reported on the first line of `main_vcl`.
+ */
+pub fn parse_compiler_errors(output: &str, main_vcl: &Path) -> Vec<CompilerError> {
+    let mut errors: Vec<CompilerError> = vec![];
+    let mut message_lines: Vec<&str> = vec![];
+    let mut lines = output.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        let Some((path, line_nr, column)) = parse_location(line.trim()) else {
+            let line = line.trim();
+            if !is_boilerplate_line(line) {
+                message_lines.push(line);
+            }
+            continue;
+        };
+
+        // skip the source line, and measure the marked token
+        lines.next();
+        let mut length = 1;
+        if let Some(marker) = lines
+            .next_if(|marker| !marker.is_empty() && marker.chars().all(|c| matches!(c, '-' | '#')))
+        {
+            length = marker.chars().filter(|c| *c == '#').count().max(1) as u32;
+        }
+
+        let location = CompilerLocation {
+            path,
+            line: line_nr,
+            column,
+            length,
+        };
+        let message = message_lines.join(" ").trim_end_matches(':').to_string();
+        message_lines.clear();
+        match errors.last_mut() {
+            Some(error) if is_related_message(&message) => {
+                error.related.push((location, message));
+            }
+            _ => errors.push(CompilerError {
+                location,
+                message,
+                related: vec![],
+            }),
+        }
+    }
+
+    if !message_lines.is_empty() {
+        errors.push(CompilerError {
+            location: CompilerLocation {
+                path: main_vcl.to_path_buf(),
+                line: 0,
+                column: 0,
+                length: 0,
+            },
+            message: message_lines.join(" "),
+            related: vec![],
+        });
+    }
+
+    errors
+}
+
+/// Compile a vcl file with `varnishd -C`, in a temporary working directory removed afterwards.
+/// vcl_path and vmod_path are passed on to varnishd, so includes and imports resolve as in the
+/// server.
+pub async fn compile(
+    varnishd: &Path,
+    vcl_file: &Path,
+    vcl_paths: &[PathBuf],
+    vmod_paths: &[PathBuf],
+) -> Result<Vec<CompilerError>, String> {
+    let started_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let work_dir = std::env::temp_dir().join(format!(
+        "varnishls-varnishd-{}-{started_at}",
+        std::process::id()
+    ));
+    tokio::fs::create_dir_all(&work_dir)
+        .await
+        .map_err(|err| format!("Could not create {}: {err}", work_dir.display()))?;
+
+    let join_paths = |paths: &[PathBuf]| {
+        paths
+            .iter()
+            .map(|path| path.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(":")
+    };
+    let mut command = Command::new(varnishd);
+    command
+        .arg("-C")
+        .arg("-n")
+        .arg(&work_dir)
+        .arg("-f")
+        .arg(vcl_file);
+    if !vcl_paths.is_empty() {
+        command
+            .arg("-p")
+            .arg(format!("vcl_path={}", join_paths(vcl_paths)));
+    }
+    if !vmod_paths.is_empty() {
+        command
+            .arg("-p")
+            .arg(format!("vmod_path={}", join_paths(vmod_paths)));
+    }
+
+    let output = command.output().await;
+    let _ = tokio::fs::remove_dir_all(&work_dir).await;
+    let output = output.map_err(|err| format!("Could not run {}: {err}", varnishd.display()))?;
+    if output.status.success() {
+        return Ok(vec![]);
+    }
+    Ok(parse_compiler_errors(
+        &String::from_utf8_lossy(&output.stderr),
+        vcl_file,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(path: &str, line: u32, column: u32, length: u32) -> CompilerLocation {
+        CompilerLocation {
+            path: path.into(),
+            line,
+            column,
+            length,
+        }
+    }
+
+    #[test]
+    fn parses_compiler_errors() {
+        let output = r#"Error:
+Message from VCC-compiler:
+Symbol not found: 'foo' (expected type BOOL):
+('/etc/varnish/default.vcl' Line 8 Pos 9)
+    if (foo) {
+--------###---
+
+Running VCC-compiler failed, exited with 2
+VCL compilation failed
+"#;
+        let errors = parse_compiler_errors(output, Path::new("/etc/varnish/default.vcl"));
+        assert_eq!(
+            errors,
+            vec![CompilerError {
+                location: location("/etc/varnish/default.vcl", 7, 8, 3),
+                message: "Symbol not found: 'foo' (expected type BOOL)".to_string(),
+                related: vec![],
+            }]
+        );
+
+        let output = r#"Message from VCC-compiler:
+Expected ';' got '}'
+(program line 12), at
+('/etc/varnish/backends.vcl' Line 5 Pos 1)
+}
+#
+
+Running VCC-compiler failed, exited with 2
+"#;
+        let errors = parse_compiler_errors(output, Path::new("/etc/varnish/default.vcl"));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].location,
+            location("/etc/varnish/backends.vcl", 4, 0, 1)
+        );
+        assert_eq!(errors[0].message, "Expected ';' got '}'");
+    }
+
+    #[test]
+    fn compiler_errors_with_context() {
+        let output = r#"Error:
+Message from VCC-compiler:
+Not available in subroutine 'vcl_backend_response'.
+('/etc/varnish/default.vcl' Line 9 Pos 13)
+        set req.url = "/";
+------------#######-------
+
+...in subroutine "vcl_backend_response"
+('/etc/varnish/default.vcl' Line 8 Pos 5)
+sub vcl_backend_response {
+----####################--
+
+Running VCC-compiler failed, exited with 2
+VCL compilation failed
+"#;
+        let errors = parse_compiler_errors(output, Path::new("/etc/varnish/default.vcl"));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "Not available in subroutine 'vcl_backend_response'."
+        );
+        assert_eq!(
+            errors[0].location,
+            location("/etc/varnish/default.vcl", 8, 12, 7)
+        );
+        assert_eq!(
+            errors[0].related,
+            vec![(
+                location("/etc/varnish/default.vcl", 7, 4, 20),
+                "...in subroutine \"vcl_backend_response\"".to_string()
+            )]
+        );
+
+        let output = r#"Error:
+Message from VCC-compiler:
+Backend 'default' redefined:
+('/etc/varnish/backends.vcl' Line 1 Pos 9)
+backend default {
+--------#######--
+First defined at:
+('/etc/varnish/default.vcl' Line 3 Pos 9)
+backend default {
+--------#######--
+
+Running VCC-compiler failed, exited with 2
+VCL compilation failed
+"#;
+        let errors = parse_compiler_errors(output, Path::new("/etc/varnish/default.vcl"));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Backend 'default' redefined");
+        assert_eq!(
+            errors[0].related[0].0.path,
+            PathBuf::from("/etc/varnish/default.vcl")
+        );
+        assert_eq!(errors[0].related[0].1, "First defined at");
+    }
+
+    #[test]
+    fn compiler_errors_without_location() {
+        let output =
+            "Message from VCC-compiler:\nCould not load VMOD nope\nVCL compilation failed\n";
+        let errors = parse_compiler_errors(output, Path::new("/etc/varnish/default.vcl"));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].location.path,
+            PathBuf::from("/etc/varnish/default.vcl")
+        );
+        assert_eq!(errors[0].message, "Could not load VMOD nope");
+    }
+
+    #[test]
+    fn resolves_relative_paths() {
+        let dir = std::env::temp_dir().join(format!("varnishls-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("includes")).unwrap();
+        std::fs::write(dir.join("includes/backends.vcl"), "").unwrap();
+        let main_vcl = dir.join("default.vcl");
+
+        assert_eq!(
+            resolve_path(Path::new("/abs/backends.vcl"), &main_vcl, &[]),
+            PathBuf::from("/abs/backends.vcl")
+        );
+        assert_eq!(
+            resolve_path(Path::new("includes/backends.vcl"), &main_vcl, &[]),
+            dir.join("includes/backends.vcl")
+        );
+        assert_eq!(
+            resolve_path(
+                Path::new("backends.vcl"),
+                &main_vcl,
+                &[dir.join("includes")]
+            ),
+            dir.join("includes/backends.vcl")
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tab_expanded_ranges() {
+        let location = location("/etc/varnish/default.vcl", 8, 12, 7);
+        assert_eq!(
+            location_range(&location, Some("\t    set req.url = \"/\";")),
+            Range::new(Position::new(8, 5), Position::new(8, 12))
+        );
+        assert_eq!(
+            location_range(&location, None),
+            Range::new(Position::new(8, 12), Position::new(8, 19))
+        );
+    }
+
+    #[test]
+    fn tab_expanded_columns() {
+        assert_eq!(visual_to_char_column("\tset req.url = 1;", 8), 1);
+        assert_eq!(visual_to_char_column("\t\tif (foo) {", 20), 6);
+        assert_eq!(visual_to_char_column("  \tx", 8), 3);
+        assert_eq!(visual_to_char_column("    if (foo) {", 8), 8);
+        assert_eq!(visual_to_char_column("x", 10), 1);
+    }
+}
@@ -90,7 +90,7 @@ impl<'a> Iterator for Tokenizer<'a> {
         macro_rules! emit_token {
             ($value:expr) => {
                 Some(&slice[token_start..$value])
-            }
+            };
         }
 
         for (i, c) in slice.char_indices() {
@@ -238,6 +238,8 @@ fn parse_func_args<'a>(toks: &mut Peekable<impl Iterator<Item = &'a str>>) -> Ve
                 optional,
                 r#type,
                 default_value,
+                format: None,
+                values: None,
             });
         }
 
@@ -1,9 +1,14 @@
-use goblin::elf::Elf;
+use goblin::container::Ctx;
+use goblin::elf::{header, section_header, Elf, SectionHeader, Symtab};
+use goblin::strtab::Strtab;
 use serde_json::{self, Value as SerdeValue};
+use std::collections::{BTreeSet, HashMap};
 use std::error::Error;
-use std::ffi::CStr;
+use std::io::SeekFrom;
 use std::os::raw::c_char;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
 use crate::varnish_builtins::{Func, FuncArg, Obj, Type};
 
@@ -33,9 +38,66 @@ pub struct VmodData {
     pub json: String,
     pub abi: String,
     pub scope: Type,
+    /// rows of the vmod json which failed to parse and were left out of the scope
+    pub row_errors: Vec<String>,
 }
 
-fn parse_vmod_func_args(serde_value_arr: &[SerdeValue]) -> Vec<FuncArg> {
+/// Vmod type names, e.g. of in-house vmods, mapped to a type the parser knows
+#[derive(Clone, Debug, Default)]
+pub struct VmodTypeMappings {
+    /// argument types, e.g. `MY_ENUM` → `Type::String`
+    pub args: HashMap<String, Type>,
+    /// return types
+    pub returns: HashMap<String, Type>,
+}
+
+/// Vmod argument type names with a fixed type, and that custom type names can map to
+pub const VMOD_TYPE_NAMES: &[&str] = &[
+    "STRING",
+    "STRING_LIST",
+    "REGEX",
+    "STRANDS",
+    "BOOL",
+    "INT",
+    "REAL",
+    "IP",
+    "DURATION",
+    "TIME",
+    "BYTES",
+    "BLOB",
+    "BACKEND",
+    "PROBE",
+    "ACL",
+    "HEADER",
+];
+
+/// The type of a vmod type name in VMOD_TYPE_NAMES, e.g. «DURATION»
+pub fn parse_vmod_type_name(name: &str) -> Option<Type> {
+    match name {
+        "STRING" => Some(Type::String),
+        "STRING_LIST" => Some(Type::String),
+        "REGEX" => Some(Type::String),
+        "STRANDS" => Some(Type::String),
+        "BOOL" => Some(Type::Bool),
+        "INT" => Some(Type::Number),
+        "REAL" => Some(Type::Number),
+        "IP" => Some(Type::IP),
+        "DURATION" => Some(Type::Duration),
+        "TIME" => Some(Type::Time),
+        "BYTES" => Some(Type::String), // for now
+        "BLOB" => Some(Type::Blob),
+        "BACKEND" => Some(Type::Backend),
+        "PROBE" => Some(Type::Probe),
+        "ACL" => Some(Type::Acl),
+        "HEADER" => Some(Type::String),
+        _ => None,
+    }
+}
+
+fn parse_vmod_func_args(
+    serde_value_arr: &[SerdeValue],
+    type_mappings: &VmodTypeMappings,
+) -> Vec<FuncArg> {
     serde_value_arr
         .iter()
         .filter_map(|arg| -> Option<_> {
@@ -56,41 +118,26 @@ fn parse_vmod_func_args(serde_value_arr: &[SerdeValue]) -> Vec<FuncArg> {
                 Some(SerdeValue::Bool(bool)) => *bool,
                 _ => false,
             };
+            // the values of an enum, or the values a string is restricted to
+            let values = match arg_arr.get(3) {
+                Some(SerdeValue::Array(values)) => Some(
+                    values
+                        .iter()
+                        .filter_map(|val| match val {
+                            SerdeValue::String(str) => Some(str.to_string()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            };
             let r#type = match input_type {
-                "STRING" => Some(Type::String),
-                "STRING_LIST" => Some(Type::String),
-                "REGEX" => Some(Type::String),
-                "STRANDS" => Some(Type::String),
-                "BOOL" => Some(Type::Bool),
-                "INT" => Some(Type::Number),
-                "REAL" => Some(Type::Number),
-                "IP" => Some(Type::IP),
-                "DURATION" => Some(Type::Duration),
-                "TIME" => Some(Type::Time),
-                "BYTES" => Some(Type::String), // for now
-                "BLOB" => Some(Type::Blob),
-                "BACKEND" => Some(Type::Backend),
-                "PROBE" => Some(Type::Probe),
-                "ACL" => Some(Type::Acl),
                 "HTTP" => Some(Type::Obj(Default::default())), // for now
-                "HEADER" => Some(Type::String),
-                "ENUM" => {
-                    let enum_values = match arg_arr.get(3) {
-                        Some(SerdeValue::Array(values)) => Some(
-                            values
-                                .iter()
-                                .filter_map(|val| match val {
-                                    SerdeValue::String(str) => Some(str.to_string()),
-                                    _ => None,
-                                })
-                                .collect::<Vec<_>>(),
-                        ),
-                        _ => None,
-                    }?;
-                    Some(Type::Enum(enum_values))
-                }
-                _ => None,
+                "ENUM" => Some(Type::Enum(values.clone()?)),
+                _ => parse_vmod_type_name(input_type)
+                    .or_else(|| type_mappings.args.get(input_type).cloned()),
             };
+            let values = values.filter(|_| matches!(r#type, Some(Type::String)));
 
             // return None if no type
             r#type.as_ref()?;
@@ -100,6 +147,8 @@ fn parse_vmod_func_args(serde_value_arr: &[SerdeValue]) -> Vec<FuncArg> {
                 default_value,
                 optional,
                 r#type,
+                format: None,
+                values,
             })
         })
         .collect::<Vec<_>>()
@@ -107,6 +156,7 @@ fn parse_vmod_func_args(serde_value_arr: &[SerdeValue]) -> Vec<FuncArg> {
 
 fn parse_vmod_json_func(
     serde_value_arr: &[SerdeValue],
+    type_mappings: &VmodTypeMappings,
 ) -> Result<Func, Box<dyn Error + Send + Sync>> {
     let name = serde_value_arr
         .get(1)
@@ -136,7 +186,7 @@ fn parse_vmod_json_func(
         .filter_map(|result| result.ok())
         .collect();
 
-    let args = parse_vmod_func_args(&signature_arr[3..]);
+    let args = parse_vmod_func_args(&signature_arr[3..], type_mappings);
     let ret_type = ret_types.first().ok_or("Missing return type")?.as_str();
     let r#return: Option<Box<Type>> = match ret_type {
         "BACKEND" => Some(Box::new(Type::Backend)),
@@ -145,7 +195,7 @@ fn parse_vmod_json_func(
         "INT" => Some(Box::new(Type::Number)),
         "BOOL" => Some(Box::new(Type::Bool)),
         "VOID" => None,
-        _ => None,
+        _ => type_mappings.returns.get(ret_type).cloned().map(Box::new),
     };
 
     Ok(Func {
@@ -159,6 +209,7 @@ fn parse_vmod_json_func(
 
 fn parse_vmod_json_obj(
     serde_value_arr: &[SerdeValue],
+    type_mappings: &VmodTypeMappings,
 ) -> Result<Func, Box<dyn Error + Send + Sync>> {
     let name = serde_value_arr
         .get(1)
@@ -173,10 +224,25 @@ fn parse_vmod_json_obj(
         ..Default::default()
     };
 
+    let mut last_method_name: Option<String> = None;
     for method_serde_val in serde_value_arr[6..].iter() {
         let method_arr = method_serde_val.as_array().ok_or("Method is not array")?;
-        let func = parse_vmod_json_func(method_arr)?;
-        obj.properties.insert(func.name.clone(), Type::Func(func));
+        match method_arr.first().and_then(|val| val.as_str()) {
+            Some("$RESTRICT") => {
+                if let Some(Type::Func(method)) = last_method_name
+                    .as_ref()
+                    .and_then(|name| obj.properties.get_mut(name))
+                {
+                    method.restricted = parse_vmod_json_restrict(method_arr);
+                }
+            }
+            Some("$METHOD") | None => {
+                let func = parse_vmod_json_func(method_arr, type_mappings)?;
+                last_method_name = Some(func.name.clone());
+                obj.properties.insert(func.name.clone(), Type::Func(func));
+            }
+            _ => {}
+        }
     }
 
     let mut func = Func {
@@ -189,7 +255,7 @@ fn parse_vmod_json_obj(
     if let Some(SerdeValue::Array(ref vmod_init_def)) = serde_value_arr.get(4) {
         if let Some(SerdeValue::Array(ref array_containing_signature)) = vmod_init_def.get(1) {
             if let Some(signature_items) = array_containing_signature.get(4..) {
-                func.args = parse_vmod_func_args(signature_items);
+                func.args = parse_vmod_func_args(signature_items, type_mappings);
             }
         }
     }
@@ -197,14 +263,50 @@ fn parse_vmod_json_obj(
     Ok(func)
 }
 
+/// Parse a `$RESTRICT` row, e.g. ["$RESTRICT", ["vcl_pipe", "backend", "housekeeping"]]
+fn parse_vmod_json_restrict(serde_value_arr: &[SerdeValue]) -> Option<Vec<String>> {
+    let restricted: Vec<String> = serde_value_arr
+        .get(1..)?
+        .iter()
+        .flat_map(|val| match val {
+            SerdeValue::Array(values) => values.iter().collect::<Vec<_>>(),
+            val => vec![val],
+        })
+        .filter_map(|val| val.as_str())
+        .flat_map(|str| str.split_whitespace())
+        .map(|str| str.to_string())
+        .collect();
+
+    if restricted.is_empty() {
+        None
+    } else {
+        Some(restricted)
+    }
+}
+
 pub fn parse_vmod_json(json: &str) -> Result<Type, Box<dyn Error + Send + Sync>> {
-    let json_parsed: Vec<Vec<SerdeValue>> = serde_json::from_str(json)?;
+    parse_vmod_json_partial(json, &Default::default()).map(|(scope, _row_errors)| scope)
+}
+
+/// Parse vmod json, skipping rows which fail to parse. Returns the scope and an error message
+/// for each skipped row. Type names the parser doesn't know are looked up in type_mappings.
+pub fn parse_vmod_json_partial(
+    json: &str,
+    type_mappings: &VmodTypeMappings,
+) -> Result<(Type, Vec<String>), Box<dyn Error + Send + Sync>> {
+    let json_parsed: Vec<Vec<SerdeValue>> =
+        serde_json::from_str(json).map_err(|err| format!("Invalid vmod JSON: {err}"))?;
+    let mut row_errors = vec![];
     let mut vmod_obj = Obj {
         read_only: true,
         ..Default::default()
     };
+    // (alias, target) pairs, resolved when all functions are parsed
+    let mut aliases: Vec<(&str, &str)> = vec![];
+    // $RESTRICT applies to the preceding $FUNC/$METHOD row
+    let mut last_func_path: Option<(String, Option<String>)> = None;
 
-    for row in json_parsed.iter() {
+    for (row_idx, row) in json_parsed.iter().enumerate() {
         let row_type = match row.first() {
             Some(SerdeValue::String(str)) => str.as_str(),
             _ => continue,
@@ -234,99 +336,548 @@ pub fn parse_vmod_json(json: &str) -> Result<Type, Box<dyn Error + Send + Sync>>
                 */
             }
             "$FUNC" => {
-                if let Ok(func) = parse_vmod_json_func(row) {
-                    vmod_obj
-                        .properties
-                        .insert(func.name.clone(), Type::Func(func));
+                last_func_path = None;
+                match parse_vmod_json_func(row, type_mappings) {
+                    Ok(func) => {
+                        last_func_path = Some((func.name.clone(), None));
+                        vmod_obj
+                            .properties
+                            .insert(func.name.clone(), Type::Func(func));
+                    }
+                    Err(err) => row_errors.push(format!("row {row_idx} ($FUNC): {err}")),
                 }
             }
             "$OBJ" => {
-                if let Ok(func) = parse_vmod_json_obj(row) {
-                    vmod_obj
-                        .properties
-                        .insert(func.name.clone(), Type::Func(func));
+                last_func_path = None;
+                match parse_vmod_json_obj(row, type_mappings) {
+                    Ok(func) => {
+                        vmod_obj
+                            .properties
+                            .insert(func.name.clone(), Type::Func(func));
+                    }
+                    Err(err) => row_errors.push(format!("row {row_idx} ($OBJ): {err}")),
+                }
+            }
+            "$METHOD" => {
+                // top level method rows are named after their object, e.g. "obj.method"
+                last_func_path = None;
+                let mut func = match parse_vmod_json_func(row, type_mappings) {
+                    Ok(func) => func,
+                    Err(err) => {
+                        row_errors.push(format!("row {row_idx} ($METHOD): {err}"));
+                        continue;
+                    }
+                };
+                let Some((obj_name, method_name)) = func.name.split_once('.') else {
+                    continue;
+                };
+                let (obj_name, method_name) = (obj_name.to_string(), method_name.to_string());
+                let Some(obj) = get_vmod_obj_mut(&mut vmod_obj, &obj_name) else {
+                    continue;
+                };
+                func.name = method_name.clone();
+                obj.properties.insert(method_name.clone(), Type::Func(func));
+                last_func_path = Some((obj_name, Some(method_name)));
+            }
+            "$RESTRICT" => {
+                let func = match last_func_path {
+                    Some((ref name, None)) => vmod_obj.properties.get_mut(name),
+                    Some((ref obj_name, Some(ref method_name))) => {
+                        get_vmod_obj_mut(&mut vmod_obj, obj_name)
+                            .and_then(|obj| obj.properties.get_mut(method_name))
+                    }
+                    None => None,
+                };
+                if let Some(Type::Func(func)) = func {
+                    func.restricted = parse_vmod_json_restrict(row);
+                }
+            }
+            "$ALIAS" => {
+                // e.g. ["$ALIAS", "alias_name", "func_name"]
+                if let (Some(SerdeValue::String(alias)), Some(SerdeValue::String(target))) =
+                    (row.get(1), row.get(2))
+                {
+                    aliases.push((alias, target));
                 }
             }
             _ => {}
         }
     }
 
-    Ok(Type::Obj(vmod_obj))
+    for (alias, target) in aliases {
+        let Some(Type::Func(target_func)) = vmod_obj.properties.get(target) else {
+            continue;
+        };
+        let alias_func = Func {
+            name: alias.to_string(),
+            ..target_func.clone()
+        };
+        vmod_obj
+            .properties
+            .insert(alias.to_string(), Type::Func(alias_func));
+    }
+
+    Ok((Type::Obj(vmod_obj), row_errors))
+}
+
+fn get_vmod_obj_mut<'a>(vmod_obj: &'a mut Obj, obj_name: &str) -> Option<&'a mut Obj> {
+    let Some(Type::Func(constructor)) = vmod_obj.properties.get_mut(obj_name) else {
+        return None;
+    };
+    match constructor.r#return.as_deref_mut() {
+        Some(Type::Obj(obj)) => Some(obj),
+        _ => None,
+    }
+}
+
+/// Render a vmod scope as a vcc-like stub interface, e.g. for goto definition
+pub fn render_vmod_stub(name: &str, path: &Path, scope: &Type) -> String {
+    let mut stub = format!(
+        "# Generated by varnishls from {}\n\n$Module {name}\n",
+        path.display()
+    );
+    let Type::Obj(obj) = scope else {
+        return stub;
+    };
+
+    let push_doc = |stub: &mut String, func: &Func| {
+        if let Some(ref doc) = func.doc {
+            stub.push_str(&format!("\n{}\n", doc.trim_end()));
+        }
+    };
+
+    for property in obj.properties.values() {
+        let Type::Func(func) = property else {
+            continue;
+        };
+        match func.r#return.as_deref() {
+            Some(Type::Obj(methods)) if func.ret_type.as_ref() == Some(&func.name) => {
+                stub.push_str(&format!(
+                    "\n$Object {}{}\n",
+                    func.name,
+                    func.get_signature_string()
+                ));
+                push_doc(&mut stub, func);
+                for method in methods.properties.values() {
+                    let Type::Func(method) = method else {
+                        continue;
+                    };
+                    let label = method.get_signature_label();
+                    let label = label.replacen(
+                        &format!(" {}(", method.name),
+                        &format!(" .{}(", method.name),
+                        1,
+                    );
+                    stub.push_str(&format!("\n$Method {label}\n"));
+                    push_doc(&mut stub, method);
+                }
+            }
+            _ => {
+                stub.push_str(&format!("\n$Function {}\n", func.get_signature_label()));
+                push_doc(&mut stub, func);
+            }
+        }
+    }
+
+    stub
+}
+
+/// Read `len` bytes at `offset` in the file
+async fn read_file_range(file: &mut File, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.read_exact(&mut buf).await?;
+    Ok(buf)
 }
 
+/// Read a nul terminated string at `offset` in the file
+async fn read_file_c_string(
+    file: &mut File,
+    offset: u64,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut bytes = vec![];
+    let mut chunk = [0; 4096];
+    file.seek(SeekFrom::Start(offset)).await?;
+    loop {
+        let len = file.read(&mut chunk).await?;
+        if len == 0 {
+            return Err("Unterminated string in vmod".into());
+        }
+        if let Some(nul_idx) = chunk[..len].iter().position(|byte| *byte == 0) {
+            bytes.extend_from_slice(&chunk[..nul_idx]);
+            break;
+        }
+        bytes.extend_from_slice(&chunk[..len]);
+    }
+    Ok(String::from_utf8_lossy(&bytes).to_string())
+}
+
+/// Read the vmod data from a vmod shared object.
+///
+/// Only the ELF headers, the dynamic symbol table and the vmod data strings are read, as vmods
+/// can be large and this runs for every vmod that isn't cached.
 pub async fn read_vmod_lib(
     vmod_name: String,
     path: PathBuf,
+    type_mappings: VmodTypeMappings,
 ) -> Result<VmodData, Box<dyn Error + Send + Sync>> {
-    let file = tokio::fs::read(path).await?;
-    let elf = Elf::parse(&file)?;
+    let mut file = File::open(path).await?;
+
+    let header_bytes = read_file_range(&mut file, 0, header::header64::SIZEOF_EHDR).await?;
+    let header = Elf::parse_header(&header_bytes)?;
+    let ctx = Ctx::new(header.container()?, header.endianness()?);
+
+    let section_headers_bytes = read_file_range(
+        &mut file,
+        header.e_shoff,
+        header.e_shnum as usize * header.e_shentsize as usize,
+    )
+    .await?;
+    let section_headers =
+        SectionHeader::parse_from(&section_headers_bytes, 0, header.e_shnum as usize, ctx)?;
+
+    let dynsym_sh = section_headers
+        .iter()
+        .find(|sh| sh.sh_type == section_header::SHT_DYNSYM)
+        .ok_or("Could not find dynamic symbol table")?;
+    let dynstr_sh = section_headers
+        .get(dynsym_sh.sh_link as usize)
+        .ok_or("Could not find dynamic string table")?;
+    let dynsym_bytes =
+        read_file_range(&mut file, dynsym_sh.sh_offset, dynsym_sh.sh_size as usize).await?;
+    let dynstr_bytes =
+        read_file_range(&mut file, dynstr_sh.sh_offset, dynstr_sh.sh_size as usize).await?;
+    let dynsyms = Symtab::parse(
+        &dynsym_bytes,
+        0,
+        dynsym_sh
+            .sh_size
+            .checked_div(dynsym_sh.sh_entsize)
+            .ok_or("Invalid dynamic symbol table")? as usize,
+        ctx,
+    )?;
+    let dynstrtab = Strtab::parse(&dynstr_bytes, 0, dynstr_bytes.len(), 0)?;
 
     // Find symbol in symbol table
     let vmod_data_symbol_name = format!("Vmod_{}_Data", vmod_name);
-    let vmd_sym = elf
-        .dynsyms
+    let vmd_sym = dynsyms
         .iter()
         .find(|sym| {
-            elf.dynstrtab
+            dynstrtab
                 .get_at(sym.st_name)
                 .map(|sym_name| sym_name == vmod_data_symbol_name)
                 .unwrap_or(false)
         })
-        .ok_or("Could not find vmod data symbol")?;
+        .ok_or_else(|| format!("Could not find symbol {vmod_data_symbol_name}"))?;
 
     // Section for the symbol data
-    let sec = &elf
-        .section_headers
+    let sec = section_headers
         .get(vmd_sym.st_shndx)
-        .expect("Could not find section");
+        .ok_or("Could not find section")?;
 
     // Offset in binary for symbol value
-    let offset = sec.sh_offset as usize + vmd_sym.st_value as usize - sec.sh_addr as usize;
-    // Transmute a pointer to the offset in the file, into a pointer to a VmodDataCStruct
-    let vmd = unsafe { &*std::mem::transmute::<*const u8, *const VmodDataCStruct>(&file[offset]) };
-
-    let mut json: &str =
-        &(unsafe { CStr::from_ptr(file[(vmd.json as usize)..].as_ptr() as *const c_char) }
-            .to_string_lossy());
+    let offset = sec.sh_offset + vmd_sym.st_value - sec.sh_addr;
+    let vmd_bytes =
+        read_file_range(&mut file, offset, std::mem::size_of::<VmodDataCStruct>()).await?;
+    // Copy the bytes into a VmodDataCStruct. The pointers in it are offsets in the file.
+    let (
+        vrt_major,
+        vrt_minor,
+        [json_offset, name_offset, file_id_offset, proto_offset, abi_offset],
+    ) = {
+        let vmd = unsafe { std::ptr::read_unaligned(vmd_bytes.as_ptr() as *const VmodDataCStruct) };
+        (
+            vmd.vrt_major as usize,
+            vmd.vrt_minor as usize,
+            [vmd.json, vmd.name, vmd.file_id, vmd.proto, vmd.abi].map(|ptr| ptr as u64),
+        )
+    };
 
+    let json_str = read_file_c_string(&mut file, json_offset)
+        .await
+        .map_err(|err| format!("Could not read vmod JSON: {err}"))?;
+    let mut json: &str = &json_str;
     if json.starts_with("VMOD_JSON_SPEC\u{2}") {
         json = &(json[(json.find('\u{2}').unwrap() + 1)..json.find('\u{3}').unwrap()]);
     }
 
-    let vmod_json_data = parse_vmod_json(json)?;
+    let (vmod_json_data, row_errors) = parse_vmod_json_partial(json, &type_mappings)?;
     return Ok(VmodData {
-        vrt_major: vmd.vrt_major as usize,
-        vrt_minor: vmd.vrt_minor as usize,
-        name: unsafe { CStr::from_ptr((file[(vmd.name as usize)..].as_ptr()) as *const c_char) }
-            .to_string_lossy()
-            .to_string(),
-        file_id: unsafe {
-            CStr::from_ptr((file[(vmd.file_id as usize)..].as_ptr()) as *const c_char)
-        }
-        .to_string_lossy()
-        .to_string(),
-        proto: unsafe { CStr::from_ptr((file[(vmd.proto as usize)..].as_ptr()) as *const c_char) }
-            .to_string_lossy()
-            .to_string(),
-        abi: unsafe { CStr::from_ptr((file[(vmd.abi as usize)..].as_ptr()) as *const c_char) }
-            .to_string_lossy()
-            .to_string(),
+        vrt_major,
+        vrt_minor,
+        name: read_file_c_string(&mut file, name_offset).await?,
+        file_id: read_file_c_string(&mut file, file_id_offset).await?,
+        proto: read_file_c_string(&mut file, proto_offset).await?,
+        abi: read_file_c_string(&mut file, abi_offset)
+            .await
+            .map_err(|err| format!("Could not read vmod ABI: {err}"))?,
         json: json.to_string(),
         scope: vmod_json_data,
+        row_errors,
     });
 }
 
 pub async fn read_vmod_lib_by_name(
     name: String,
     search_paths: Vec<PathBuf>,
+    type_mappings: VmodTypeMappings,
 ) -> Result<Option<VmodData>, Box<dyn Error + Send + Sync>> {
     let file_name = format!("libvmod_{}.so", name);
     for search_path in search_paths {
         let path = search_path.join(&file_name);
         if path.exists() {
-            return Ok(Some(read_vmod_lib(name, path).await?));
+            return Ok(Some(read_vmod_lib(name, path, type_mappings).await?));
         }
     }
 
     Ok(None)
 }
+
+/// Names of the vmods in vmod_paths (libvmod_{name}.so) and vcc_paths (e.g. vmod_{name}.vcc or
+/// libvmod_{name}/), found by listing the directories
+pub fn find_vmod_names(vmod_paths: &[PathBuf], vcc_paths: &[PathBuf]) -> BTreeSet<String> {
+    let file_names = |dir: &PathBuf| {
+        std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect::<Vec<_>>()
+    };
+    let vmod_names = vmod_paths
+        .iter()
+        .flat_map(file_names)
+        .filter_map(|file_name| {
+            file_name
+                .strip_prefix("libvmod_")?
+                .strip_suffix(".so")
+                .map(str::to_string)
+        });
+    let vcc_names = vcc_paths
+        .iter()
+        .flat_map(file_names)
+        .filter_map(|file_name| {
+            let name = file_name
+                .strip_prefix("libvmod_")
+                .or_else(|| file_name.strip_prefix("vmod_"))?;
+            Some(name.strip_suffix(".vcc").unwrap_or(name).to_string())
+        });
+    vmod_names
+        .chain(vcc_names)
+        .filter(|name| !name.is_empty() && !name.contains('.'))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vmod_names_from_directories() {
+        let dir = std::env::temp_dir().join(format!("varnishls-vmods-{}", std::process::id()));
+        let vmod_dir = dir.join("vmods");
+        let vcc_dir = dir.join("vcc");
+        std::fs::create_dir_all(&vmod_dir).unwrap();
+        std::fs::create_dir_all(vcc_dir.join("libvmod_header")).unwrap();
+        for file in ["libvmod_std.so", "libvmod_directors.so", "README"] {
+            std::fs::write(vmod_dir.join(file), "").unwrap();
+        }
+        for file in ["vmod_xkey.vcc", "libvmod_std.vcc", "vmod_xkey.rst"] {
+            std::fs::write(vcc_dir.join(file), "").unwrap();
+        }
+
+        let names = find_vmod_names(&[vmod_dir, dir.join("missing")], &[vcc_dir]);
+        assert_eq!(
+            names.into_iter().collect::<Vec<_>>(),
+            vec!["directors", "header", "std", "xkey"]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn aliased_functions() {
+        let json = r#"[
+            ["$VMOD", "1.0", "example", "Vmod_example_Func", "0", "", "7", "1"],
+            ["$FUNC", "integer", [["INT"], "Vmod_example_Func.f_integer", "", ["STRING", "s"]]],
+            ["$ALIAS", "int", "integer"],
+            ["$ALIAS", "missing_alias", "missing"]
+        ]"#;
+        let Type::Obj(scope) = parse_vmod_json(json).unwrap() else {
+            panic!("vmod scope should be an obj");
+        };
+
+        let Some(Type::Func(func)) = scope.properties.get("integer") else {
+            panic!("integer should be a func");
+        };
+        let Some(Type::Func(alias)) = scope.properties.get("int") else {
+            panic!("int should be an alias of integer");
+        };
+        assert_eq!(alias.name, "int");
+        assert_eq!(alias.ret_type, func.ret_type);
+        assert_eq!(alias.args.len(), 1);
+        assert!(!scope.properties.contains_key("missing_alias"));
+    }
+
+    #[test]
+    fn restricted_functions_and_methods() {
+        let json = r#"[
+            ["$VMOD", "1.0", "example", "Vmod_example_Func", "0", "", "7", "1"],
+            ["$FUNC", "fetch_only", [["VOID"], "Vmod_example_Func.f_fetch_only", ""]],
+            ["$RESTRICT", ["vcl_backend_fetch", "housekeeping"]],
+            ["$OBJ", "thing", {}, "struct vmod_example_thing",
+                ["$INIT", [["VOID"], "Vmod_example_Func.thing__init", ""]],
+                ["$FINI", [["VOID"], "Vmod_example_Func.thing__fini", ""]],
+                ["$METHOD", "get", [["STRING"], "Vmod_example_Func.thing_get", ""]],
+                ["$RESTRICT", ["client"]]
+            ],
+            ["$METHOD", "thing.set", [["VOID"], "Vmod_example_Func.thing_set", "", ["STRING", "s"]]],
+            ["$RESTRICT", ["vcl_init"]]
+        ]"#;
+        let Type::Obj(scope) = parse_vmod_json(json).unwrap() else {
+            panic!("vmod scope should be an obj");
+        };
+
+        let Some(Type::Func(func)) = scope.properties.get("fetch_only") else {
+            panic!("fetch_only should be a func");
+        };
+        assert_eq!(
+            func.restricted,
+            Some(vec![
+                "vcl_backend_fetch".to_string(),
+                "housekeeping".to_string()
+            ])
+        );
+
+        let Some(Type::Func(constructor)) = scope.properties.get("thing") else {
+            panic!("thing should be an object constructor");
+        };
+        let Some(Type::Obj(ref obj)) = constructor.r#return.as_deref() else {
+            panic!("thing should return an obj");
+        };
+        let Some(Type::Func(get)) = obj.properties.get("get") else {
+            panic!("thing.get should be a method");
+        };
+        assert_eq!(get.restricted, Some(vec!["client".to_string()]));
+        let Some(Type::Func(set)) = obj.properties.get("set") else {
+            panic!("thing.set should be a method");
+        };
+        assert_eq!(set.name, "set");
+        assert_eq!(set.args.len(), 1);
+        assert_eq!(set.restricted, Some(vec!["vcl_init".to_string()]));
+    }
+
+    #[test]
+    fn partially_parsed_vmod_json() {
+        let json = r#"[
+            ["$VMOD", "1.0", "example", "Vmod_example_Func", "0", "", "7", "1"],
+            ["$FUNC", "hello", [["STRING"], "Vmod_example_Func.f_hello", ""]],
+            ["$FUNC", "broken", [[], "Vmod_example_Func.f_broken", ""]],
+            ["$FUNC"]
+        ]"#;
+        let (scope, row_errors) = parse_vmod_json_partial(json, &Default::default()).unwrap();
+        let Type::Obj(scope) = scope else {
+            panic!("vmod scope should be an obj");
+        };
+        assert!(scope.properties.contains_key("hello"));
+        assert!(!scope.properties.contains_key("broken"));
+        assert_eq!(
+            row_errors,
+            vec![
+                "row 2 ($FUNC): Missing return type".to_string(),
+                "row 3 ($FUNC): Missing VMOD func name".to_string(),
+            ]
+        );
+
+        let err = parse_vmod_json_partial(r#"[["$VMOD", "1.0""#, &Default::default()).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid vmod JSON: "));
+        assert!(err.to_string().contains("line 1"));
+    }
+
+    #[test]
+    fn custom_type_mappings() {
+        let json = r#"[
+            ["$VMOD", "1.0", "example", "Vmod_example_Func", "0", "", "7", "1"],
+            ["$FUNC", "lookup", [["MY_RESULT"], "Vmod_example_Func.f_lookup", "", ["MY_KEY", "key"]]]
+        ]"#;
+        let get_lookup = |type_mappings: &VmodTypeMappings| {
+            let (Type::Obj(mut scope), _) = parse_vmod_json_partial(json, type_mappings).unwrap()
+            else {
+                panic!("vmod scope should be an obj");
+            };
+            let Some(Type::Func(func)) = scope.properties.remove("lookup") else {
+                panic!("lookup should be a func");
+            };
+            func
+        };
+
+        let func = get_lookup(&Default::default());
+        assert!(func.r#return.is_none());
+        assert!(func.args.is_empty());
+
+        let func = get_lookup(&VmodTypeMappings {
+            args: HashMap::from([("MY_KEY".to_string(), Type::String)]),
+            returns: HashMap::from([("MY_RESULT".to_string(), Type::Duration)]),
+        });
+        assert_eq!(func.ret_type, Some("MY_RESULT".to_string()));
+        assert!(matches!(func.r#return.as_deref(), Some(Type::Duration)));
+        assert_eq!(func.args.len(), 1);
+        assert!(matches!(func.args[0].r#type, Some(Type::String)));
+    }
+
+    #[test]
+    fn enum_and_restricted_string_args() {
+        let json = r#"[
+            ["$VMOD", "1.0", "example", "Vmod_example_Func", "0", "", "7", "1"],
+            ["$FUNC", "pick", [["STRING"], "Vmod_example_Func.f_pick", "", ["ENUM", "mode", "fast", ["fast", "slow"]], ["STRING", "style", "\"plain\"", ["plain", "fancy"], true], ["STRING", "s"]]]
+        ]"#;
+        let Type::Obj(scope) = parse_vmod_json(json).unwrap() else {
+            panic!("vmod is not an obj");
+        };
+        let Some(Type::Func(func)) = scope.properties.get("pick") else {
+            panic!("pick is not a func");
+        };
+        assert!(
+            matches!(func.args[0].r#type, Some(Type::Enum(ref values)) if values == &["fast", "slow"])
+        );
+        assert_eq!(func.args[0].values, None);
+        assert!(matches!(func.args[1].r#type, Some(Type::String)));
+        assert_eq!(
+            func.args[1].values,
+            Some(vec!["plain".to_string(), "fancy".to_string()])
+        );
+        assert_eq!(func.args[2].values, None);
+    }
+
+    #[test]
+    fn vmod_stub() {
+        let json = r#"[
+            ["$VMOD", "1.0", "example", "Vmod_example_Func", "0", "", "7", "1"],
+            ["$FUNC", "hello", [["STRING"], "Vmod_example_Func.f_hello", "", ["STRING", "s"]]],
+            ["$OBJ", "thing", {}, "struct vmod_example_thing",
+                ["$INIT", [["VOID"], "Vmod_example_Func.thing__init", ""]],
+                ["$FINI", [["VOID"], "Vmod_example_Func.thing__fini", ""]],
+                ["$METHOD", "get", [["STRING"], "Vmod_example_Func.thing_get", ""]]
+            ]
+        ]"#;
+        let scope = parse_vmod_json(json).unwrap();
+        let stub = render_vmod_stub(
+            "example",
+            Path::new("/usr/lib/varnish/vmods/libvmod_example.so"),
+            &scope,
+        );
+        assert_eq!(
+            stub,
+            "# Generated by varnishls from /usr/lib/varnish/vmods/libvmod_example.so
+
+$Module example
+
+$Function STRING hello(STRING s)
+
+$Object thing()
+
+$Method STRING .get()
+"
+        );
+    }
+}